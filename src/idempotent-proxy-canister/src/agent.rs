@@ -1,20 +1,29 @@
 use candid::{CandidType, Nat};
 use http::Uri;
 use ic_cdk::api::management_canister::http_request::{
-    http_request, CanisterHttpRequestArgument, HttpHeader, HttpResponse, TransformArgs,
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
     TransformContext,
 };
 use serde::{Deserialize, Serialize};
 
+// Only the status of a health check response is kept, its body is dropped.
+const HEALTH_CHECK_RESPONSE_BYTES: u64 = 1024;
+
 #[derive(CandidType, Default, Clone, Deserialize, Serialize)]
 pub struct Agent {
     pub name: String, // used as a prefix for idempotency_key and message in sign_proxy_token to separate different business processes.
     pub endpoint: String,
     pub max_cycles: u64,
     pub proxy_token: Option<String>,
+    #[serde(default)]
+    pub weight: Option<u32>, // of the agent in random selections, 1 by default
 }
 
 impl Agent {
+    pub fn weight(&self) -> u32 {
+        self.weight.unwrap_or(1)
+    }
+
     fn build_request(&self, req: &mut CanisterHttpRequestArgument) -> Result<(), String> {
         if !req.headers.iter().any(|h| h.name == "idempotency-key") {
             Err("idempotency-key header is missing".to_string())?;
@@ -85,6 +94,35 @@ impl Agent {
             }),
         }
     }
+
+    // A GET of the health check path on the endpoint, only a 2xx response means
+    // the proxy node is up.
+    pub async fn health_check(&self, path: &str) -> Result<(), String> {
+        let mut headers = vec![];
+        if let Some(proxy_token) = &self.proxy_token {
+            headers.push(HttpHeader {
+                name: "proxy-authorization".to_string(),
+                value: format!("Bearer {}", proxy_token),
+            });
+        }
+        let req = CanisterHttpRequestArgument {
+            url: format!("{}{}", self.endpoint, path),
+            method: HttpMethod::GET,
+            max_response_bytes: Some(HEALTH_CHECK_RESPONSE_BYTES),
+            body: None,
+            transform: Some(TransformContext::from_name(
+                "inner_transform_health_check".to_string(),
+                vec![],
+            )),
+            headers,
+        };
+
+        match http_request(req, self.max_cycles as u128).await {
+            Ok((res,)) if res.status >= 200u64 && res.status < 300u64 => Ok(()),
+            Ok((res,)) => Err(format!("status {}", res.status)),
+            Err((code, message)) => Err(format!("code: {code:?}, error: {message}")),
+        }
+    }
 }
 
 #[ic_cdk::query(hidden = true)]
@@ -96,3 +134,13 @@ fn inner_transform_response(args: TransformArgs) -> HttpResponse {
         headers: vec![],
     }
 }
+
+#[ic_cdk::query(hidden = true)]
+fn inner_transform_health_check(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        // Only the status matters, the body may differ between replicas
+        body: vec![],
+        headers: vec![],
+    }
+}
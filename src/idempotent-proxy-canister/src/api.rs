@@ -1,11 +1,20 @@
 use candid::{CandidType, Nat, Principal};
-use ciborium::into_writer;
 use futures::FutureExt;
 use ic_cdk::api::management_canister::http_request::{CanisterHttpRequestArgument, HttpResponse};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
-use crate::{agent::Agent, cose::CoseClient, store};
+use crate::{
+    agent::Agent,
+    consensus,
+    cose::CoseClient,
+    cycles::CostEstimate,
+    is_controller_or_manager,
+    queue::{self, TaskOptions, TaskStatus},
+    selection::Selection,
+    store,
+    store::AgentHealth,
+};
 
 const MILLISECONDS: u64 = 1_000_000;
 
@@ -13,6 +22,7 @@ const MILLISECONDS: u64 = 1_000_000;
 pub struct StateInfo {
     pub ecdsa_key_name: String,
     pub proxy_token_public_key: String,
+    pub proxy_token_key_id: String,
     pub proxy_token_refresh_interval: u64, // seconds
     pub agents: Vec<Agent>,
     pub managers: BTreeSet<Principal>,
@@ -22,6 +32,10 @@ pub struct StateInfo {
     pub incoming_cycles: u128,
     pub uncollectible_cycles: u128,
     pub cose: Option<CoseClient>,
+    pub health_check_interval: u64, // seconds
+    pub health_check_path: String,
+    pub agents_health: BTreeMap<String, AgentHealth>, // by agent endpoint
+    pub selections: BTreeMap<String, Selection>,      // by agent name, "" for the default one
 }
 
 #[ic_cdk::query]
@@ -29,6 +43,7 @@ fn state_info() -> StateInfo {
     store::state::with(|s| StateInfo {
         ecdsa_key_name: s.ecdsa_key_name.clone(),
         proxy_token_public_key: s.proxy_token_public_key.clone(),
+        proxy_token_key_id: s.proxy_token_key_id.clone(),
         proxy_token_refresh_interval: s.proxy_token_refresh_interval,
         agents: s
             .agents
@@ -38,6 +53,7 @@ fn state_info() -> StateInfo {
                 endpoint: a.endpoint.clone(),
                 max_cycles: a.max_cycles,
                 proxy_token: None,
+                weight: a.weight,
             })
             .collect(),
         managers: s.managers.clone(),
@@ -47,6 +63,10 @@ fn state_info() -> StateInfo {
         incoming_cycles: s.incoming_cycles,
         uncollectible_cycles: s.uncollectible_cycles,
         cose: s.cose.clone(),
+        health_check_interval: s.health_check_interval,
+        health_check_path: s.health_check_path.clone(),
+        agents_health: s.agents_health.clone(),
+        selections: s.selections.clone(),
     })
 }
 
@@ -58,24 +78,69 @@ fn caller_info(id: Principal) -> Option<(u128, u64)> {
 #[ic_cdk::query]
 async fn proxy_http_request_cost(req: CanisterHttpRequestArgument) -> u128 {
     let calc = store::state::cycles_calculator();
-    calc.ingress_cost(ic_cdk::api::call::arg_data_raw_size())
-        + calc.http_outcall_request_cost(calc.count_request_bytes(&req), 1)
-        + calc.http_outcall_response_cost(req.max_response_bytes.unwrap_or(10240) as usize, 1)
+    calc.estimate(ic_cdk::api::call::arg_data_raw_size(), &req, 1)
+        .total
+}
+
+/// The cycles to attach to a call of the request by `agents` agents, by all agents of the
+/// default selection if null. Calls with fewer cycles are rejected before any outcall.
+#[ic_cdk::query]
+fn estimate_cost(req: CanisterHttpRequestArgument, agents: Option<u64>) -> CostEstimate {
+    let agents = agents.map(|n| n as usize).unwrap_or_else(|| {
+        let (agents, selection) = parallel_agents(&ParallelCallOptions::default());
+        selection.count(agents.len())
+    });
+    // the ingress of the proxy call with the request, not of this call with its options
+    let ingress_bytes = candid::encode_one(&req)
+        .map(|b| b.len())
+        .unwrap_or_default();
+    let calc = store::state::cycles_calculator();
+    calc.estimate(ingress_bytes, &req, agents)
+}
+
+// Rejects a call when the attached cycles can't pay for its outcalls.
+fn check_budget(estimate: &CostEstimate) -> Result<(), HttpResponse> {
+    let available = ic_cdk::api::call::msg_cycles_available128();
+    if available < estimate.total {
+        return Err(HttpResponse {
+            status: Nat::from(402u64),
+            body: format!(
+                "insufficient cycles: {} attached, {} required for {} agents",
+                available, estimate.total, estimate.agents
+            )
+            .into_bytes(),
+            headers: vec![],
+        });
+    }
+    Ok(())
 }
 
 #[ic_cdk::query]
 async fn parallel_call_cost(req: CanisterHttpRequestArgument) -> u128 {
-    let agents = store::state::get_agents();
+    parallel_cost(&req, &ParallelCallOptions::default())
+}
+
+#[ic_cdk::query]
+async fn parallel_call_with_cost(
+    req: CanisterHttpRequestArgument,
+    opts: ParallelCallOptions,
+) -> u128 {
+    parallel_cost(&req, &opts)
+}
+
+fn parallel_cost(req: &CanisterHttpRequestArgument, opts: &ParallelCallOptions) -> u128 {
+    let (agents, selection) = parallel_agents(opts);
     let calc = store::state::cycles_calculator();
-    calc.ingress_cost(ic_cdk::api::call::arg_data_raw_size())
-        + calc.http_outcall_request_cost(calc.count_request_bytes(&req), agents.len())
-        + calc.http_outcall_response_cost(
-            req.max_response_bytes.unwrap_or(10240) as usize,
-            agents.len(),
-        )
+    calc.estimate(
+        ic_cdk::api::call::arg_data_raw_size(),
+        req,
+        selection.count(agents.len()),
+    )
+    .total
 }
 
-/// Proxy HTTP request by all agents in sequence until one returns an status <= 500 result.
+/// Proxy HTTP request by all agents in sequence until one returns an status <= 500 result,
+/// the unhealthy agents are tried last.
 #[ic_cdk::update]
 async fn proxy_http_request(req: CanisterHttpRequestArgument) -> HttpResponse {
     let caller = ic_cdk::caller();
@@ -98,14 +163,21 @@ async fn proxy_http_request(req: CanisterHttpRequestArgument) -> HttpResponse {
 
     let balance = ic_cdk::api::call::msg_cycles_available128();
     let calc = store::state::cycles_calculator();
-    store::state::receive_cycles(
-        calc.ingress_cost(ic_cdk::api::call::arg_data_raw_size()),
-        false,
-    );
+    // the budget covers the first agent, the next ones are tried while the cycles last
+    let estimate = calc.estimate(ic_cdk::api::call::arg_data_raw_size(), &req, 1);
+    if let Err(res) = check_budget(&estimate) {
+        return res;
+    }
+    store::state::receive_cycles(estimate.ingress, false);
 
     let req_size = calc.count_request_bytes(&req);
     let mut last_err: Option<HttpResponse> = None;
     for agent in agents {
+        if last_err.is_some()
+            && ic_cdk::api::call::msg_cycles_available128() < estimate.requests + estimate.responses
+        {
+            break;
+        }
         store::state::receive_cycles(calc.http_outcall_request_cost(req_size, 1), false);
         match agent.call(req.clone()).await {
             Ok(res) => {
@@ -130,10 +202,60 @@ async fn proxy_http_request(req: CanisterHttpRequestArgument) -> HttpResponse {
     last_err.unwrap()
 }
 
-/// Proxy HTTP request by all agents in parallel and return the result if all are the same,
-/// or a 500 HttpResponse with all result.
+/// Proxy HTTP request by the healthy agents of the default selection in parallel and return
+/// the result if all (or a quorum of them) are the same, or a 500 HttpResponse with all result.
 #[ic_cdk::update]
 async fn parallel_call_all_ok(req: CanisterHttpRequestArgument) -> HttpResponse {
+    parallel_call(req, ParallelCallOptions::default(), true).await
+}
+
+/// Proxy HTTP request by the healthy agents of the default selection in parallel and return
+/// the first (status <= 500) result.
+#[ic_cdk::update]
+async fn parallel_call_any_ok(req: CanisterHttpRequestArgument) -> HttpResponse {
+    parallel_call(req, ParallelCallOptions::default(), false).await
+}
+
+/// Like parallel_call_all_ok, with the agent group and the selection of the call.
+#[ic_cdk::update]
+async fn parallel_call_with(
+    req: CanisterHttpRequestArgument,
+    opts: ParallelCallOptions,
+) -> HttpResponse {
+    if let Some(Err(err)) = opts.selection.as_ref().map(Selection::validate) {
+        return HttpResponse {
+            status: Nat::from(400u64),
+            body: err.into_bytes(),
+            headers: vec![],
+        };
+    }
+    parallel_call(req, opts, true).await
+}
+
+#[derive(CandidType, Clone, Default, Deserialize, Serialize)]
+pub struct ParallelCallOptions {
+    pub group: Option<String>, // the agents of the name, all agents by default
+    pub selection: Option<Selection>, // the selection of the group by default
+}
+
+// The healthy agents of the group, and the selection to pick the ones to call from them.
+fn parallel_agents(opts: &ParallelCallOptions) -> (Vec<Agent>, Selection) {
+    let mut agents = store::state::get_parallel_agents();
+    if let Some(group) = &opts.group {
+        agents.retain(|a| &a.name == group);
+    }
+    let selection = opts
+        .selection
+        .clone()
+        .unwrap_or_else(|| store::state::get_selection(opts.group.as_deref().unwrap_or_default()));
+    (agents, selection)
+}
+
+async fn parallel_call(
+    req: CanisterHttpRequestArgument,
+    opts: ParallelCallOptions,
+    all_ok: bool,
+) -> HttpResponse {
     let caller = ic_cdk::caller();
     if !store::state::is_allowed(&caller) {
         return HttpResponse {
@@ -143,7 +265,15 @@ async fn parallel_call_all_ok(req: CanisterHttpRequestArgument) -> HttpResponse
         };
     }
 
-    let agents = store::state::get_agents();
+    let (agents, selection) = parallel_agents(&opts);
+    // every replica picks the same agents
+    let seed = [
+        caller.as_slice(),
+        &ic_cdk::api::time().to_be_bytes(),
+        req.url.as_bytes(),
+    ]
+    .concat();
+    let agents = selection.select(agents, &seed);
     if agents.is_empty() {
         return HttpResponse {
             status: Nat::from(503u64),
@@ -151,46 +281,46 @@ async fn parallel_call_all_ok(req: CanisterHttpRequestArgument) -> HttpResponse
             headers: vec![],
         };
     }
+    // only the calls whose responses must agree need a quorum
+    let quorum = match selection.quorum(agents.len()) {
+        Ok(quorum) => quorum,
+        Err(err) if all_ok => {
+            return HttpResponse {
+                status: Nat::from(503u64),
+                body: err.into_bytes(),
+                headers: vec![],
+            };
+        }
+        Err(_) => 1,
+    };
 
     let balance = ic_cdk::api::call::msg_cycles_available128();
     let calc = store::state::cycles_calculator();
-    let cycles = calc.ingress_cost(ic_cdk::api::call::arg_data_raw_size())
-        + calc.http_outcall_request_cost(calc.count_request_bytes(&req), agents.len());
-    store::state::receive_cycles(cycles, false);
+    let estimate = calc.estimate(ic_cdk::api::call::arg_data_raw_size(), &req, agents.len());
+    if let Err(res) = check_budget(&estimate) {
+        return res;
+    }
+    store::state::receive_cycles(estimate.ingress + estimate.requests, false);
 
-    let results =
-        futures::future::try_join_all(agents.iter().map(|agent| agent.call(req.clone()))).await;
-    let result = match results {
-        Err(res) => res,
-        Ok(res) => {
-            let mut results = res.into_iter();
-            let base_result = results.next().unwrap_or_else(|| HttpResponse {
-                status: Nat::from(503u64),
-                body: "no agents available".as_bytes().to_vec(),
-                headers: vec![],
-            });
-
-            let cycles = calc
-                .http_outcall_response_cost(calc.count_response_bytes(&base_result), agents.len());
-            store::state::receive_cycles(cycles, true);
-
-            let mut inconsistent_results: Vec<_> =
-                results.filter(|result| result != &base_result).collect();
-            if !inconsistent_results.is_empty() {
-                inconsistent_results.push(base_result);
-                let mut buf = vec![];
-                into_writer(&inconsistent_results, &mut buf)
-                    .expect("failed to encode inconsistent results");
-                HttpResponse {
-                    status: Nat::from(500u64),
-                    body: buf,
-                    headers: vec![],
-                }
-            } else {
-                base_result
-            }
+    let result = if all_ok {
+        let results =
+            futures::future::join_all(agents.iter().map(|agent| agent.call(req.clone()))).await;
+        let (res, diverged) = consensus::agree(&agents, results, quorum);
+        store::state::record_divergences(&diverged);
+        res
+    } else {
+        match futures::future::select_ok(agents.iter().map(|agent| agent.call(req.clone()).boxed()))
+            .await
+        {
+            Ok((res, _)) => res,
+            Err(res) => res,
         }
     };
+    if result.status <= 500u64 {
+        let cycles =
+            calc.http_outcall_response_cost(calc.count_response_bytes(&result), agents.len());
+        store::state::receive_cycles(cycles, true);
+    }
 
     store::state::update_caller_state(
         &caller,
@@ -200,50 +330,47 @@ async fn parallel_call_all_ok(req: CanisterHttpRequestArgument) -> HttpResponse
     result
 }
 
-/// Proxy HTTP request by all agents in parallel and return the first (status <= 500) result.
+/// Queues the request, the canister retries it with backoff until it succeeds (2xx), fails with
+/// another final response, or runs out of attempts or time. The cycles of all attempts are
+/// charged when it is submitted.
 #[ic_cdk::update]
-async fn parallel_call_any_ok(req: CanisterHttpRequestArgument) -> HttpResponse {
+fn submit_task(req: CanisterHttpRequestArgument, opts: TaskOptions) -> Result<u64, String> {
     let caller = ic_cdk::caller();
     if !store::state::is_allowed(&caller) {
-        return HttpResponse {
-            status: Nat::from(403u64),
-            body: "caller is not allowed".as_bytes().to_vec(),
-            headers: vec![],
-        };
+        return Err("caller is not allowed".to_string());
     }
 
-    let agents = store::state::get_agents();
-    if agents.is_empty() {
-        return HttpResponse {
-            status: Nat::from(503u64),
-            body: "no agents available".as_bytes().to_vec(),
-            headers: vec![],
-        };
+    let cycles = task_cost(&req, &opts)?;
+    let available = ic_cdk::api::call::msg_cycles_available128();
+    if available < cycles {
+        return Err(format!(
+            "insufficient cycles: {} attached, {} required",
+            available, cycles
+        ));
     }
-
-    let balance = ic_cdk::api::call::msg_cycles_available128();
-    let calc = store::state::cycles_calculator();
-    let cycles = calc.ingress_cost(ic_cdk::api::call::arg_data_raw_size())
-        + calc.http_outcall_request_cost(calc.count_request_bytes(&req), agents.len());
+    let id = queue::submit(caller, req, &opts)?;
     store::state::receive_cycles(cycles, false);
+    store::state::update_caller_state(&caller, cycles, ic_cdk::api::time() / MILLISECONDS);
+    Ok(id)
+}
 
-    let result =
-        futures::future::select_ok(agents.iter().map(|agent| agent.call(req.clone()).boxed()))
-            .await;
-    let result = match result {
-        Ok((res, _)) => {
-            let cycles =
-                calc.http_outcall_response_cost(calc.count_response_bytes(&res), agents.len());
-            store::state::receive_cycles(cycles, true);
-            res
-        }
-        Err(res) => res,
-    };
+#[ic_cdk::query]
+fn submit_task_cost(req: CanisterHttpRequestArgument, opts: TaskOptions) -> Result<u128, String> {
+    task_cost(&req, &opts)
+}
 
-    store::state::update_caller_state(
-        &caller,
-        balance - ic_cdk::api::call::msg_cycles_available128(),
-        ic_cdk::api::time() / MILLISECONDS,
-    );
-    result
+fn task_cost(req: &CanisterHttpRequestArgument, opts: &TaskOptions) -> Result<u128, String> {
+    let attempts = queue::max_attempts(opts)?;
+    let calc = store::state::cycles_calculator();
+    let estimate = calc.estimate(ic_cdk::api::call::arg_data_raw_size(), req, 1);
+    Ok(estimate.ingress + (estimate.requests + estimate.responses) * attempts as u128)
+}
+
+/// The status of a task, for the caller that submitted it and the managers.
+#[ic_cdk::query]
+fn get_task_status(id: u64) -> Option<TaskStatus> {
+    let caller = ic_cdk::caller();
+    store::queue::get(id)
+        .filter(|task| task.caller == caller || is_controller_or_manager().is_ok())
+        .map(|task| task.status)
 }
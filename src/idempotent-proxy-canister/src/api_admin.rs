@@ -2,7 +2,7 @@ use candid::Principal;
 use ic_cose_types::{validate_principals, ANONYMOUS};
 use std::collections::BTreeSet;
 
-use crate::{agent, is_controller, is_controller_or_manager, store, tasks};
+use crate::{agent, is_controller, is_controller_or_manager, selection::Selection, store, tasks};
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_add_managers(mut args: BTreeSet<Principal>) -> Result<(), String> {
@@ -67,6 +67,70 @@ async fn admin_set_agents(agents: Vec<agent::Agent>) -> Result<(), String> {
     Ok(())
 }
 
+// Sets the selection of the agents named `group` in parallel calls, or the default one
+// with an empty group; None removes it.
+#[ic_cdk::update(guard = "is_controller_or_manager")]
+fn admin_set_selection(group: String, selection: Option<Selection>) -> Result<(), String> {
+    if let Some(selection) = &selection {
+        selection.validate()?;
+    }
+    store::state::with_mut(|r| {
+        match selection {
+            Some(selection) => r.selections.insert(group, selection),
+            None => r.selections.remove(&group),
+        };
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn validate2_admin_set_selection(
+    _group: String,
+    selection: Option<Selection>,
+) -> Result<String, String> {
+    if let Some(selection) = &selection {
+        selection.validate()?;
+    }
+    Ok("ok".to_string())
+}
+
+// Switches the proxy tokens to a new key generation and returns its public key, the proxy
+// should verify it as ECDSA_PUB_KEY_<KEY_ID> before the rotation, and retire the old key
+// once its tokens have expired.
+#[ic_cdk::update(guard = "is_controller")]
+async fn admin_rotate_proxy_token_key(key_id: String) -> Result<String, String> {
+    validate_key_id(&key_id)?;
+    let (mut signer, proxy_token_refresh_interval, agents) =
+        store::state::with(|s| (s.signer(), s.proxy_token_refresh_interval, s.agents.clone()));
+    signer.key_id = key_id.clone();
+    let public_key = signer.ecdsa_public_key().await?;
+    store::state::with_mut(|r| {
+        r.proxy_token_key_id = key_id;
+        r.proxy_token_public_key = public_key.clone();
+    });
+    tasks::update_proxy_token(signer, proxy_token_refresh_interval, agents).await;
+    Ok(public_key)
+}
+
+#[ic_cdk::update]
+fn validate2_admin_rotate_proxy_token_key(key_id: String) -> Result<String, String> {
+    validate_key_id(&key_id)?;
+    Ok("ok".to_string())
+}
+
+// The proxy takes key ids from variable names, in lowercase.
+fn validate_key_id(key_id: &str) -> Result<(), String> {
+    if key_id.is_empty()
+        || key_id.len() > 32
+        || !key_id
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+    {
+        return Err(format!("invalid key id: {:?}", key_id));
+    }
+    Ok(())
+}
+
 // Use validate2_admin_add_managers instead of validate_admin_add_managers
 #[ic_cdk::update]
 fn validate_admin_add_managers(args: BTreeSet<Principal>) -> Result<(), String> {
@@ -0,0 +1,174 @@
+use candid::Nat;
+use ciborium::into_writer;
+use ic_cdk::api::management_canister::http_request::{HttpHeader, HttpResponse};
+
+use crate::agent::Agent;
+
+// Responses agree when their status and body are byte-identical, the headers are stripped
+// by the transform of the outcalls.
+fn same(a: &HttpResponse, b: &HttpResponse) -> bool {
+    a.status == b.status && a.body == b.body
+}
+
+// The response that most of the agents returned, if at least `quorum` of them did and no
+// other response was returned as often, with the agreement as x-consensus (e.g. 2/3) and
+// the agents that failed or returned another response as x-diverged-agents, and the
+// endpoints of those agents.
+// Otherwise the last failure if too many calls failed to reach the quorum, or a 500 response
+// with all results.
+pub fn agree(
+    agents: &[Agent],
+    results: Vec<Result<HttpResponse, HttpResponse>>,
+    quorum: usize,
+) -> (HttpResponse, Vec<String>) {
+    let total = results.len();
+    let oks: Vec<Option<&HttpResponse>> = results.iter().map(|r| r.as_ref().ok()).collect();
+    let mut best: Option<(&HttpResponse, usize)> = None;
+    let mut tied = false;
+    for res in oks.iter().flatten() {
+        let count = oks.iter().flatten().filter(|r| same(r, res)).count();
+        match best {
+            Some((b, c)) if count < c || same(b, res) => {}
+            Some((_, c)) if count == c => tied = true,
+            _ => {
+                best = Some((*res, count));
+                tied = false;
+            }
+        }
+    }
+    // two responses with as many agents can't make a consensus
+    let agreed = best.filter(|(_, count)| !tied && *count >= quorum);
+
+    if let Some((res, count)) = agreed {
+        let diverged: Vec<&Agent> = agents
+            .iter()
+            .zip(oks.iter())
+            .filter(|(_, r)| !r.is_some_and(|r| same(r, res)))
+            .map(|(agent, _)| agent)
+            .collect();
+        let mut res = res.clone();
+        res.headers.push(HttpHeader {
+            name: "x-consensus".to_string(),
+            value: format!("{}/{}", count, total),
+        });
+        if !diverged.is_empty() {
+            res.headers.push(HttpHeader {
+                name: "x-diverged-agents".to_string(),
+                value: diverged
+                    .iter()
+                    .map(|a| format!("{}@{}", a.name, a.endpoint))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            });
+        }
+        return (
+            res,
+            diverged.into_iter().map(|a| a.endpoint.clone()).collect(),
+        );
+    }
+
+    let oks: Vec<HttpResponse> = oks.into_iter().flatten().cloned().collect();
+    if oks.len() < quorum {
+        if let Some(Err(err)) = results.into_iter().rev().find(|r| r.is_err()) {
+            return (err, vec![]);
+        }
+    }
+    if oks.is_empty() {
+        return (
+            HttpResponse {
+                status: Nat::from(503u64),
+                body: "no agents available".as_bytes().to_vec(),
+                headers: vec![],
+            },
+            vec![],
+        );
+    }
+
+    let mut buf = vec![];
+    into_writer(&oks, &mut buf).expect("failed to encode inconsistent results");
+    (
+        HttpResponse {
+            status: Nat::from(500u64),
+            body: buf,
+            headers: vec![],
+        },
+        vec![],
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn agents(n: usize) -> Vec<Agent> {
+        (0..n)
+            .map(|i| Agent {
+                name: format!("agent{}", i),
+                endpoint: format!("https://proxy{}.example.com", i),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn ok(body: &str) -> Result<HttpResponse, HttpResponse> {
+        Ok(HttpResponse {
+            status: Nat::from(200u64),
+            body: body.as_bytes().to_vec(),
+            headers: vec![],
+        })
+    }
+
+    fn err(status: u64) -> Result<HttpResponse, HttpResponse> {
+        Err(HttpResponse {
+            status: Nat::from(status),
+            body: vec![],
+            headers: vec![],
+        })
+    }
+
+    fn header<'a>(res: &'a HttpResponse, name: &str) -> Option<&'a str> {
+        res.headers
+            .iter()
+            .find(|h| h.name == name)
+            .map(|h| h.value.as_str())
+    }
+
+    #[test]
+    fn test_agree() {
+        let agents = agents(4);
+
+        let (res, diverged) = agree(&agents[..3], vec![ok("a"), ok("b"), ok("a")], 2);
+        assert_eq!(res.body, b"a");
+        assert_eq!(header(&res, "x-consensus"), Some("2/3"));
+        assert_eq!(
+            header(&res, "x-diverged-agents"),
+            Some("agent1@https://proxy1.example.com")
+        );
+        assert_eq!(diverged, vec!["https://proxy1.example.com"]);
+
+        // all agents agree
+        let (res, diverged) = agree(&agents[..2], vec![ok("a"), ok("a")], 2);
+        assert_eq!(header(&res, "x-consensus"), Some("2/2"));
+        assert_eq!(header(&res, "x-diverged-agents"), None);
+        assert!(diverged.is_empty());
+
+        // a failed agent diverges
+        let (res, diverged) = agree(&agents[..3], vec![err(503), ok("a"), ok("a")], 2);
+        assert_eq!(res.body, b"a");
+        assert_eq!(diverged, vec!["https://proxy0.example.com"]);
+
+        // too many failures for the quorum
+        let (res, diverged) = agree(&agents[..3], vec![err(503), ok("a"), err(502)], 2);
+        assert_eq!(res.status, Nat::from(502u64));
+        assert!(diverged.is_empty());
+
+        // responses returned as often don't agree
+        let (res, _) = agree(&agents, vec![ok("a"), ok("b"), ok("b"), ok("a")], 2);
+        assert_eq!(res.status, Nat::from(500u64));
+        let (res, _) = agree(&agents[..3], vec![ok("a"), ok("b"), ok("a")], 3);
+        assert_eq!(res.status, Nat::from(500u64));
+
+        let (res, _) = agree(&[], vec![], 1);
+        assert_eq!(res.status, Nat::from(503u64));
+    }
+}
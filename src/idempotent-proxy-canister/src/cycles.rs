@@ -1,4 +1,19 @@
+use candid::CandidType;
 use ic_cdk::api::management_canister::http_request::{CanisterHttpRequestArgument, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+// The response size cycles are reserved for when a request has no max_response_bytes.
+pub const DEFAULT_MAX_RESPONSE_BYTES: u64 = 10240;
+
+// The cycles a call needs at most, for max_response_bytes from each agent.
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CostEstimate {
+    pub agents: u64,
+    pub ingress: u128,
+    pub requests: u128,
+    pub responses: u128,
+    pub total: u128,
+}
 
 #[derive(Clone)]
 pub struct Calculator {
@@ -72,4 +87,85 @@ impl Calculator {
         let cost_per_node = Self::HTTP_OUTCALL_RESPONSE_COST_PER_BYTE * response_bytes as u64;
         cost_per_node as u128 * (self.subnet_size * duplicates as u64) as u128
     }
+
+    pub fn estimate(
+        &self,
+        ingress_bytes: usize,
+        req: &CanisterHttpRequestArgument,
+        agents: usize,
+    ) -> CostEstimate {
+        let ingress = self.ingress_cost(ingress_bytes);
+        let requests = self.http_outcall_request_cost(self.count_request_bytes(req), agents);
+        let responses = self.http_outcall_response_cost(
+            req.max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES) as usize,
+            agents,
+        );
+        CostEstimate {
+            agents: agents as u64,
+            ingress,
+            requests,
+            responses,
+            total: ingress + requests + responses,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ic_cdk::api::management_canister::http_request::{HttpHeader, HttpMethod};
+
+    fn request(max_response_bytes: Option<u64>) -> CanisterHttpRequestArgument {
+        CanisterHttpRequestArgument {
+            url: "https://example.com".to_string(), // 19 bytes
+            method: HttpMethod::POST,
+            headers: vec![HttpHeader {
+                name: "idempotency-key".to_string(), // 15 bytes
+                value: "abc".to_string(),            // 3 bytes
+            }],
+            body: Some(vec![0; 100]),
+            max_response_bytes,
+            transform: None,
+        }
+    }
+
+    #[test]
+    fn test_estimate() {
+        let calc = Calculator {
+            subnet_size: 13,
+            service_fee: 10_000_000,
+        };
+        let req = request(Some(1000));
+        assert_eq!(calc.count_request_bytes(&req), 19 + 100 + 15 + 3 + 3);
+
+        let estimate = calc.estimate(500, &req, 3);
+        assert_eq!(estimate.agents, 3);
+        assert_eq!(estimate.ingress, (1_200_000 + 2_000 * 500) * 13);
+        let request_per_node = 3_000_000 + 60_000 * 13 + 400 * (150 + 140) as u128 + 1_000_000;
+        assert_eq!(estimate.requests, 10_000_000 + request_per_node * 13 * 3);
+        assert_eq!(estimate.responses, 800 * 1000 * 13 * 3);
+        assert_eq!(
+            estimate.total,
+            estimate.ingress + estimate.requests + estimate.responses
+        );
+
+        // the response cost of every agent grows with its calls
+        let one = calc.estimate(500, &req, 1);
+        assert_eq!(one.ingress, estimate.ingress);
+        assert_eq!(one.responses * 3, estimate.responses);
+
+        // responses are charged for DEFAULT_MAX_RESPONSE_BYTES without max_response_bytes
+        let estimate = calc.estimate(500, &request(None), 1);
+        assert_eq!(
+            estimate.responses,
+            800 * DEFAULT_MAX_RESPONSE_BYTES as u128 * 13
+        );
+
+        // free on a subnet of size 0
+        let calc = Calculator {
+            subnet_size: 0,
+            service_fee: 0,
+        };
+        assert_eq!(calc.estimate(500, &req, 3).total, 0);
+    }
 }
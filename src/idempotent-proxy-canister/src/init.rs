@@ -2,7 +2,7 @@ use candid::CandidType;
 use serde::Deserialize;
 use std::time::Duration;
 
-use crate::{cose::CoseClient, store, tasks};
+use crate::{cose::CoseClient, queue, store, tasks};
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum ChainArgs {
@@ -17,6 +17,8 @@ pub struct InitArgs {
     subnet_size: u64,       // set to 0 to disable receiving cycles
     service_fee: u64,       // in cycles
     cose: Option<CoseClient>,
+    health_check_interval: Option<u64>, // seconds, 300 by default, 0 disables the health checks
+    health_check_path: Option<String>,  // "/healthz" by default
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -25,6 +27,8 @@ pub struct UpgradeArgs {
     subnet_size: Option<u64>,
     service_fee: Option<u64>, // in cycles
     cose: Option<CoseClient>,
+    health_check_interval: Option<u64>, // seconds
+    health_check_path: Option<String>,
 }
 
 #[ic_cdk::init]
@@ -45,6 +49,11 @@ fn init(args: Option<ChainArgs>) {
                     100_000_000
                 };
                 s.cose = args.cose;
+                s.health_check_interval = args.health_check_interval.unwrap_or(300);
+                if let Some(path) = args.health_check_path {
+                    validate_health_check_path(&path);
+                    s.health_check_path = path;
+                }
             });
         }
         ChainArgs::Upgrade(_) => {
@@ -65,6 +74,13 @@ fn init(args: Option<ChainArgs>) {
     ic_cdk_timers::set_timer_interval(Duration::from_secs(proxy_token_refresh_interval), || {
         ic_cdk::spawn(tasks::refresh_proxy_token())
     });
+
+    let health_check_interval = store::state::with(|s| s.health_check_interval);
+    if health_check_interval > 0 {
+        ic_cdk_timers::set_timer_interval(Duration::from_secs(health_check_interval), || {
+            ic_cdk::spawn(tasks::check_agents_health())
+        });
+    }
 }
 
 #[ic_cdk::pre_upgrade]
@@ -75,6 +91,8 @@ fn pre_upgrade() {
 #[ic_cdk::post_upgrade]
 fn post_upgrade(args: Option<ChainArgs>) {
     store::state::load();
+    // the pending tasks are in stable memory, their timer is not
+    queue::schedule();
 
     match args {
         Some(ChainArgs::Upgrade(args)) => {
@@ -95,6 +113,13 @@ fn post_upgrade(args: Option<ChainArgs>) {
                 if let Some(cose) = args.cose {
                     s.cose = Some(cose);
                 }
+                if let Some(health_check_interval) = args.health_check_interval {
+                    s.health_check_interval = health_check_interval;
+                }
+                if let Some(path) = args.health_check_path {
+                    validate_health_check_path(&path);
+                    s.health_check_path = path;
+                }
             });
         }
         Some(ChainArgs::Init(_)) => {
@@ -116,4 +141,17 @@ fn post_upgrade(args: Option<ChainArgs>) {
     ic_cdk_timers::set_timer_interval(Duration::from_secs(proxy_token_refresh_interval), || {
         ic_cdk::spawn(tasks::refresh_proxy_token())
     });
+
+    let health_check_interval = store::state::with(|s| s.health_check_interval);
+    if health_check_interval > 0 {
+        ic_cdk_timers::set_timer_interval(Duration::from_secs(health_check_interval), || {
+            ic_cdk::spawn(tasks::check_agents_health())
+        });
+    }
+}
+
+fn validate_health_check_path(path: &str) {
+    if !path.starts_with('/') {
+        ic_cdk::trap("health_check_path must start with /");
+    }
 }
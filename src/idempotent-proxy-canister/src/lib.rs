@@ -5,15 +5,21 @@ use std::collections::BTreeSet;
 mod agent;
 mod api;
 mod api_admin;
+mod consensus;
 mod cose;
 mod cycles;
 mod ecdsa;
 mod init;
+mod queue;
+mod selection;
 mod store;
 mod tasks;
 
-use api::StateInfo;
+use api::{ParallelCallOptions, StateInfo};
+use cycles::CostEstimate;
 use init::ChainArgs;
+use queue::{TaskOptions, TaskStatus};
+use selection::Selection;
 
 fn is_controller() -> Result<(), String> {
     let caller = ic_cdk::caller();
@@ -0,0 +1,486 @@
+use candid::{CandidType, Nat, Principal};
+use ciborium::{from_reader, into_writer};
+use ic_cdk::api::management_canister::http_request::{
+    CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse,
+};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::{storable::Bound, Storable};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    time::Duration,
+};
+
+use crate::store;
+
+const MILLISECONDS: u64 = 1_000_000;
+const DEFAULT_MAX_ATTEMPTS: u8 = 10;
+pub const MAX_ATTEMPTS: u8 = 20;
+const MAX_DEADLINE: u64 = 7 * 24 * 3600 * 1000; // in milliseconds, from the submission
+const MIN_BACKOFF: u64 = 10 * 1000; // in milliseconds, doubled after every attempt
+const MAX_BACKOFF: u64 = 3600 * 1000;
+// Finished tasks are kept for get_task_status until they are this old.
+const RETENTION: u64 = 2 * MAX_DEADLINE;
+// Tasks attempted together by a run of the queue.
+const BATCH_SIZE: usize = 10;
+// Runs the queue again if a run traps after an await and never sets the next timer.
+const WATCHDOG: Duration = Duration::from_secs(600);
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TaskOptions {
+    pub max_attempts: Option<u8>, // 10 by default, at most 20
+    pub deadline: Option<u64>,    // UNIX timestamp in milliseconds, 7 days from now at most
+    // a method of the caller canister notified with the TaskStatus when the task finishes
+    pub callback: Option<String>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum TaskState {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct TaskStatus {
+    pub id: u64,
+    pub state: TaskState,
+    pub attempts: u8,
+    pub max_attempts: u8,
+    pub created_at: u64, // UNIX timestamp, in milliseconds
+    pub deadline: u64,   // UNIX timestamp, in milliseconds
+    pub next_attempt_at: Option<u64>,
+    pub response: Option<HttpResponse>, // the last response of the upstream
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub callback: Option<String>,
+    #[serde(default)]
+    pub callback_error: Option<String>, // the notification could not be sent
+}
+
+// The outcall of a task, CanisterHttpRequestArgument without the transform.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TaskRequest {
+    pub url: String,
+    pub method: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<ByteBuf>,
+    pub max_response_bytes: Option<u64>,
+}
+
+impl TaskRequest {
+    pub fn new(req: CanisterHttpRequestArgument) -> Self {
+        Self {
+            url: req.url,
+            method: match req.method {
+                HttpMethod::GET => "get",
+                HttpMethod::HEAD => "head",
+                HttpMethod::POST => "post",
+            }
+            .to_string(),
+            headers: req.headers.into_iter().map(|h| (h.name, h.value)).collect(),
+            body: req.body.map(ByteBuf::from),
+            max_response_bytes: req.max_response_bytes,
+        }
+    }
+
+    pub fn to_argument(&self) -> CanisterHttpRequestArgument {
+        CanisterHttpRequestArgument {
+            url: self.url.clone(),
+            method: match self.method.as_str() {
+                "head" => HttpMethod::HEAD,
+                "post" => HttpMethod::POST,
+                _ => HttpMethod::GET,
+            },
+            headers: self
+                .headers
+                .iter()
+                .map(|(name, value)| HttpHeader {
+                    name: name.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+            body: self.body.as_ref().map(|b| b.to_vec()),
+            max_response_bytes: self.max_response_bytes,
+            transform: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Task {
+    pub caller: Principal,
+    pub request: TaskRequest,
+    pub status: TaskStatus,
+}
+
+impl Storable for Task {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode Task data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode Task data")
+    }
+}
+
+pub fn max_attempts(opts: &TaskOptions) -> Result<u8, String> {
+    match opts.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS) {
+        n if n == 0 || n > MAX_ATTEMPTS => Err(format!(
+            "max_attempts must be between 1 and {}",
+            MAX_ATTEMPTS
+        )),
+        n => Ok(n),
+    }
+}
+
+// Queues the outcall, it is attempted right away.
+pub fn submit(
+    caller: Principal,
+    req: CanisterHttpRequestArgument,
+    opts: &TaskOptions,
+) -> Result<u64, String> {
+    if !req.headers.iter().any(|h| h.name == "idempotency-key") {
+        // the proxy deduplicates the attempts
+        return Err("idempotency-key header is missing".to_string());
+    }
+    let max_attempts = max_attempts(opts)?;
+    if let Some(method) = &opts.callback {
+        validate_callback(&caller, method)?;
+    }
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let deadline = match opts.deadline {
+        None => now_ms + MAX_DEADLINE,
+        Some(deadline) if deadline > now_ms && deadline <= now_ms + MAX_DEADLINE => deadline,
+        Some(_) => return Err("deadline must be within 7 days from now".to_string()),
+    };
+
+    let id = store::state::with_mut(|s| {
+        s.next_task_id += 1;
+        s.task_queue.insert(s.next_task_id, now_ms);
+        s.next_task_id
+    });
+    store::queue::insert(
+        id,
+        Task {
+            caller,
+            request: TaskRequest::new(req),
+            status: TaskStatus {
+                id,
+                state: TaskState::Pending,
+                attempts: 0,
+                max_attempts,
+                created_at: now_ms,
+                deadline,
+                next_attempt_at: Some(now_ms),
+                response: None,
+                last_error: None,
+                callback: opts.callback.clone(),
+                callback_error: None,
+            },
+        },
+    );
+    schedule();
+    Ok(id)
+}
+
+thread_local! {
+    static TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    static RUNNING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Sets the timer for the next due task, a running queue sets it when it is done.
+// Timers don't survive upgrades, post_upgrade calls it again.
+pub fn schedule() {
+    if RUNNING.get() {
+        return;
+    }
+    if let Some(timer) = TIMER.take() {
+        ic_cdk_timers::clear_timer(timer);
+    }
+    let next = store::state::with(|s| s.task_queue.values().min().copied());
+    if let Some(next) = next {
+        let now_ms = ic_cdk::api::time() / MILLISECONDS;
+        let delay = Duration::from_millis(next.saturating_sub(now_ms));
+        let timer = ic_cdk_timers::set_timer(delay, || ic_cdk::spawn(run()));
+        TIMER.set(Some(timer));
+    }
+}
+
+// Clears RUNNING when the run ends, or when a trap drops the run in the cleanup.
+struct Running;
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.set(false);
+    }
+}
+
+async fn run() {
+    TIMER.set(None);
+    if RUNNING.get() {
+        // fired by the watchdog, the running queue sets the timer when it is done
+        return;
+    }
+    RUNNING.set(true);
+    let running = Running;
+    let watchdog = ic_cdk_timers::set_timer(WATCHDOG, || ic_cdk::spawn(run()));
+    TIMER.set(Some(watchdog));
+
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let due: Vec<u64> = store::state::with(|s| {
+        s.task_queue
+            .iter()
+            .filter(|(_, at)| **at <= now_ms)
+            .map(|(id, _)| *id)
+            .take(BATCH_SIZE)
+            .collect()
+    });
+    futures::future::join_all(due.into_iter().map(attempt)).await;
+    store::queue::purge(now_ms.saturating_sub(RETENTION));
+    drop(running);
+    schedule();
+}
+
+async fn attempt(id: u64) {
+    let mut task = match store::queue::get(id) {
+        Some(task) => task,
+        None => {
+            store::state::with_mut(|s| s.task_queue.remove(&id));
+            return;
+        }
+    };
+
+    // One outcall per attempt as charged by submit_task, the retries go to the next agents.
+    let agents = store::state::get_agents();
+    let result = match agents.get(task.status.attempts as usize % agents.len().max(1)) {
+        Some(agent) => agent.call(task.request.to_argument()).await,
+        None => Err(HttpResponse {
+            status: Nat::from(503u64),
+            body: "no agents available".as_bytes().to_vec(),
+            headers: vec![],
+        }),
+    };
+
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let status = &mut task.status;
+    let retry = record_result(status, result);
+    schedule_retry(status, retry, now_ms);
+
+    if status.state != TaskState::Pending {
+        notify(&task.caller, status);
+    }
+
+    let next = status.next_attempt_at;
+    store::state::with_mut(|s| match next {
+        Some(next) => s.task_queue.insert(id, next),
+        None => s.task_queue.remove(&id),
+    });
+    store::queue::insert(id, task);
+}
+
+// Counts the attempt and keeps its outcome, true if it should be retried.
+fn record_result(status: &mut TaskStatus, result: Result<HttpResponse, HttpResponse>) -> bool {
+    status.attempts += 1;
+    match result {
+        Ok(res) if res.status >= 200u64 && res.status < 300u64 => {
+            status.state = TaskState::Succeeded;
+            status.response = Some(res);
+            status.last_error = None;
+            false
+        }
+        // timeouts, rate limits and server errors are retried, other responses are final
+        Ok(res) if res.status == 408u64 || res.status == 429u64 || res.status >= 500u64 => {
+            status.last_error = Some(format!("status {}", res.status));
+            status.response = Some(res);
+            true
+        }
+        Ok(res) => {
+            status.state = TaskState::Failed;
+            status.last_error = Some(format!("status {}", res.status));
+            status.response = Some(res);
+            false
+        }
+        Err(res) => {
+            status.last_error = Some(String::from_utf8_lossy(&res.body).to_string());
+            true
+        }
+    }
+}
+
+// The delay before the next attempt after `attempts` attempts, in milliseconds.
+fn backoff(attempts: u8) -> u64 {
+    MIN_BACKOFF
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+// Sets the next attempt of a retried task, or fails it when it is out of attempts or
+// the next attempt would miss the deadline.
+fn schedule_retry(status: &mut TaskStatus, retry: bool, now_ms: u64) {
+    let next = now_ms + backoff(status.attempts);
+    status.next_attempt_at = None;
+    if retry {
+        if status.attempts < status.max_attempts && next < status.deadline {
+            status.next_attempt_at = Some(next);
+        } else {
+            status.state = TaskState::Failed;
+        }
+    }
+}
+
+// Only canisters can be called back, their ids end with 0x01.
+fn validate_callback(caller: &Principal, method: &str) -> Result<(), String> {
+    if caller.as_slice().last() != Some(&0x01) {
+        return Err("callback is only supported for canister callers".to_string());
+    }
+    if method.is_empty() || method.len() > 64 {
+        return Err(format!("invalid callback method: {:?}", method));
+    }
+    Ok(())
+}
+
+// Notifies the caller canister of the finished task without awaiting a reply, so a slow or
+// failing callback never holds the queue.
+fn notify(caller: &Principal, status: &mut TaskStatus) {
+    if let Some(method) = status.callback.clone() {
+        if let Err(code) = ic_cdk::notify(*caller, &method, (status.clone(),)) {
+            status.callback_error = Some(format!("notify {} failed: {:?}", method, code));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn status(max_attempts: u8, deadline: u64) -> TaskStatus {
+        TaskStatus {
+            id: 1,
+            state: TaskState::Pending,
+            attempts: 0,
+            max_attempts,
+            created_at: 0,
+            deadline,
+            next_attempt_at: Some(0),
+            response: None,
+            last_error: None,
+            callback: None,
+            callback_error: None,
+        }
+    }
+
+    fn response(code: u64) -> HttpResponse {
+        HttpResponse {
+            status: Nat::from(code),
+            body: vec![],
+            headers: vec![],
+        }
+    }
+
+    #[test]
+    fn test_record_result() {
+        let mut s = status(10, MAX_DEADLINE);
+        assert!(!record_result(&mut s, Ok(response(200))));
+        assert_eq!(s.state, TaskState::Succeeded);
+        assert_eq!(s.attempts, 1);
+        assert!(s.last_error.is_none());
+
+        for code in [408, 429, 500, 503] {
+            let mut s = status(10, MAX_DEADLINE);
+            assert!(record_result(&mut s, Ok(response(code))), "{}", code);
+            assert_eq!(s.state, TaskState::Pending);
+            assert_eq!(s.last_error, Some(format!("status {}", code)));
+            assert!(s.response.is_some());
+        }
+
+        let mut s = status(10, MAX_DEADLINE);
+        assert!(!record_result(&mut s, Ok(response(404))));
+        assert_eq!(s.state, TaskState::Failed);
+        assert_eq!(s.last_error, Some("status 404".to_string()));
+
+        let mut s = status(10, MAX_DEADLINE);
+        let mut err = response(0);
+        err.body = b"outcall failed".to_vec();
+        assert!(record_result(&mut s, Err(err)));
+        assert_eq!(s.state, TaskState::Pending);
+        assert_eq!(s.last_error, Some("outcall failed".to_string()));
+        assert!(s.response.is_none());
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), MIN_BACKOFF);
+        assert_eq!(backoff(1), MIN_BACKOFF);
+        assert_eq!(backoff(2), 2 * MIN_BACKOFF);
+        assert_eq!(backoff(3), 4 * MIN_BACKOFF);
+        assert_eq!(backoff(9), 256 * MIN_BACKOFF);
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(MAX_ATTEMPTS), MAX_BACKOFF);
+        assert_eq!(backoff(u8::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_schedule_retry() {
+        let now = 1_000_000;
+        let mut s = status(3, now + MAX_DEADLINE);
+        s.attempts = 1;
+        schedule_retry(&mut s, true, now);
+        assert_eq!(s.state, TaskState::Pending);
+        assert_eq!(s.next_attempt_at, Some(now + MIN_BACKOFF));
+
+        // out of attempts
+        s.attempts = 3;
+        schedule_retry(&mut s, true, now);
+        assert_eq!(s.state, TaskState::Failed);
+        assert_eq!(s.next_attempt_at, None);
+
+        // the next attempt would miss the deadline
+        let mut s = status(3, now + MIN_BACKOFF);
+        s.attempts = 1;
+        schedule_retry(&mut s, true, now);
+        assert_eq!(s.state, TaskState::Failed);
+        assert_eq!(s.next_attempt_at, None);
+
+        // not retried
+        let mut s = status(3, now + MAX_DEADLINE);
+        s.attempts = 1;
+        s.state = TaskState::Succeeded;
+        schedule_retry(&mut s, false, now);
+        assert_eq!(s.state, TaskState::Succeeded);
+        assert_eq!(s.next_attempt_at, None);
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let opts = |n| TaskOptions {
+            max_attempts: n,
+            ..Default::default()
+        };
+        assert_eq!(max_attempts(&opts(None)), Ok(DEFAULT_MAX_ATTEMPTS));
+        assert_eq!(max_attempts(&opts(Some(1))), Ok(1));
+        assert_eq!(max_attempts(&opts(Some(MAX_ATTEMPTS))), Ok(MAX_ATTEMPTS));
+        assert!(max_attempts(&opts(Some(0))).is_err());
+        assert!(max_attempts(&opts(Some(MAX_ATTEMPTS + 1))).is_err());
+    }
+
+    #[test]
+    fn test_validate_callback() {
+        // canister ids end with 0x01
+        let canister = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        assert!(validate_callback(&canister, "on_task").is_ok());
+        assert!(validate_callback(&canister, "").is_err());
+        assert!(validate_callback(&canister, &"a".repeat(64)).is_ok());
+        assert!(validate_callback(&canister, &"a".repeat(65)).is_err());
+
+        let user = Principal::self_authenticating(b"user");
+        assert!(validate_callback(&user, "on_task").is_err());
+        assert!(validate_callback(&Principal::anonymous(), "on_task").is_err());
+    }
+}
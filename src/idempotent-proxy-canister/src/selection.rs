@@ -0,0 +1,164 @@
+use candid::CandidType;
+use ic_cose_types::cose::sha3_256;
+use serde::{Deserialize, Serialize};
+
+use crate::agent::Agent;
+
+// Which agents a parallel call goes to, and how many of them must agree.
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Selection {
+    // all agents, their responses must be the same
+    #[default]
+    All,
+    // n agents picked at random by weight, their responses must be the same
+    Subset(u8),
+    // n agents picked at random by weight, `quorum` of them must return the same response
+    Quorum {
+        n: u8,
+        quorum: u8,
+    },
+}
+
+impl Selection {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Selection::All => Ok(()),
+            Selection::Subset(0) => Err("subset size must be at least 1".to_string()),
+            Selection::Subset(_) => Ok(()),
+            Selection::Quorum { n, quorum } if *quorum == 0 || quorum > n => {
+                Err(format!("invalid quorum {} of {}", quorum, n))
+            }
+            Selection::Quorum { .. } => Ok(()),
+        }
+    }
+
+    // The number of agents selected out of `total`.
+    pub fn count(&self, total: usize) -> usize {
+        match self {
+            Selection::All => total,
+            Selection::Subset(n) | Selection::Quorum { n, .. } => total.min(*n as usize),
+        }
+    }
+
+    // The number of the same responses needed out of `selected` calls, an error if
+    // fewer agents than the quorum could be selected.
+    pub fn quorum(&self, selected: usize) -> Result<usize, String> {
+        match self {
+            Selection::Quorum { quorum, .. } if selected < *quorum as usize => {
+                Err("not enough agents for quorum".to_string())
+            }
+            Selection::Quorum { quorum, .. } => Ok(*quorum as usize),
+            _ => Ok(selected),
+        }
+    }
+
+    // Picks the agents by weighted random sampling without replacement, seeded so that
+    // every replica picks the same ones. Agents of weight 0 are only picked by All.
+    pub fn select(&self, agents: Vec<Agent>, seed: &[u8]) -> Vec<Agent> {
+        let n = match self {
+            Selection::All => return agents,
+            Selection::Subset(n) | Selection::Quorum { n, .. } => *n as usize,
+        };
+        let mut candidates: Vec<Agent> = agents.into_iter().filter(|a| a.weight() > 0).collect();
+        let mut selected = Vec::with_capacity(n);
+        let mut round: u64 = 0;
+        while selected.len() < n && !candidates.is_empty() {
+            let total: u64 = candidates.iter().map(|a| a.weight() as u64).sum();
+            let mut point = random(seed, round) % total;
+            round += 1;
+            let i = candidates
+                .iter()
+                .position(|a| {
+                    let w = a.weight() as u64;
+                    if point < w {
+                        return true;
+                    }
+                    point -= w;
+                    false
+                })
+                .unwrap_or(0);
+            selected.push(candidates.remove(i));
+        }
+        selected
+    }
+}
+
+fn random(seed: &[u8], round: u64) -> u64 {
+    let mut data = seed.to_vec();
+    data.extend_from_slice(&round.to_be_bytes());
+    let digest = sha3_256(&data);
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn agent(name: &str, weight: u32) -> Agent {
+        Agent {
+            name: name.to_string(),
+            endpoint: format!("https://{}.example.com", name),
+            weight: Some(weight),
+            ..Default::default()
+        }
+    }
+
+    fn names(agents: &[Agent]) -> Vec<&str> {
+        agents.iter().map(|a| a.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_selection() {
+        assert!(Selection::All.validate().is_ok());
+        assert!(Selection::Subset(0).validate().is_err());
+        assert!(Selection::Quorum { n: 3, quorum: 2 }.validate().is_ok());
+        assert!(Selection::Quorum { n: 3, quorum: 0 }.validate().is_err());
+        assert!(Selection::Quorum { n: 2, quorum: 3 }.validate().is_err());
+
+        assert_eq!(Selection::All.count(5), 5);
+        assert_eq!(Selection::Subset(3).count(2), 2);
+        assert_eq!(Selection::Quorum { n: 3, quorum: 2 }.count(5), 3);
+        assert_eq!(Selection::Subset(3).quorum(3), Ok(3));
+        assert_eq!(Selection::Quorum { n: 3, quorum: 2 }.quorum(3), Ok(2));
+        assert_eq!(Selection::Quorum { n: 3, quorum: 2 }.quorum(2), Ok(2));
+        assert_eq!(
+            Selection::Quorum { n: 3, quorum: 2 }.quorum(1),
+            Err("not enough agents for quorum".to_string())
+        );
+    }
+
+    #[test]
+    fn test_select() {
+        let agents = vec![agent("a", 1), agent("b", 9), agent("c", 0)];
+        assert_eq!(
+            names(&Selection::All.select(agents.clone(), b"seed")),
+            vec!["a", "b", "c"]
+        );
+
+        // agents of weight 0 are never picked, the others at most once
+        let selected = Selection::Subset(3).select(agents.clone(), b"seed");
+        let mut picked = names(&selected);
+        picked.sort();
+        assert_eq!(picked, vec!["a", "b"]);
+
+        // every replica picks the same agents
+        let selection = Selection::Quorum { n: 1, quorum: 1 };
+        assert_eq!(
+            names(&selection.select(agents.clone(), b"seed")),
+            names(&selection.select(agents.clone(), b"seed"))
+        );
+
+        // by weight
+        let picked_b = (0..1000u32)
+            .filter(|i| {
+                let selected = Selection::Subset(1).select(agents.clone(), &i.to_be_bytes());
+                names(&selected) == vec!["b"]
+            })
+            .count();
+        assert!(
+            picked_b > 800 && picked_b < 980,
+            "picked b {} times",
+            picked_b
+        );
+    }
+}
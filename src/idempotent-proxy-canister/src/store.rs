@@ -1,11 +1,11 @@
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD as base64_url, Engine};
-use candid::Principal;
+use candid::{CandidType, Principal};
 use ciborium::{from_reader, into_writer};
 use ic_cose_types::cose::{format_error, sha3_256};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     storable::Bound,
-    DefaultMemoryImpl, StableCell, Storable,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
 };
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
@@ -20,10 +20,30 @@ use crate::{
     cose::CoseClient,
     cycles::Calculator,
     ecdsa::{public_key_with, sign_with},
+    queue::Task,
+    selection::Selection,
 };
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+// An agent is unhealthy after this many failed health checks in a row.
+const MAX_HEALTH_CHECK_FAILURES: u32 = 2;
+
+#[derive(CandidType, Clone, Default, Deserialize, Serialize)]
+pub struct AgentHealth {
+    pub failures: u32,   // failed health checks in a row
+    pub checked_at: u64, // UNIX timestamp, in milliseconds
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub divergences: u64, // responses that differed from the consensus of parallel calls
+}
+
+impl AgentHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.failures < MAX_HEALTH_CHECK_FAILURES
+    }
+}
+
 #[derive(Clone, Default, Deserialize, Serialize)]
 pub struct State {
     pub ecdsa_key_name: String,
@@ -45,6 +65,22 @@ pub struct State {
 
     #[serde(default)]
     pub cose: Option<CoseClient>,
+    #[serde(default)]
+    pub proxy_token_key_id: String, // the key generation, empty for the first one
+
+    #[serde(default)]
+    pub health_check_interval: u64, // seconds, 0 disables the health checks
+    #[serde(default)]
+    pub health_check_path: String,
+    #[serde(default)]
+    pub agents_health: BTreeMap<String, AgentHealth>, // by agent endpoint
+    #[serde(default)]
+    pub selections: BTreeMap<String, Selection>, // by agent name, "" for the default one
+
+    #[serde(default)]
+    pub next_task_id: u64,
+    #[serde(default)]
+    pub task_queue: BTreeMap<u64, u64>, // pending task ids and their next attempt times
 }
 
 impl State {
@@ -52,14 +88,22 @@ impl State {
         Signer {
             key_name: self.ecdsa_key_name.clone(),
             cose: self.cose.clone(),
+            key_id: self.proxy_token_key_id.clone(),
         }
     }
+
+    // Agents that were never checked are healthy.
+    pub fn is_healthy(&self, agent: &Agent) -> bool {
+        self.agents_health
+            .get(&agent.endpoint)
+            .is_none_or(|h| h.is_healthy())
+    }
 }
 
 impl Storable for State {
     const BOUND: Bound = Bound::Unbounded;
 
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         let mut buf = vec![];
         into_writer(self, &mut buf).expect("failed to encode State data");
         Cow::Owned(buf)
@@ -73,24 +117,35 @@ impl Storable for State {
 pub struct Signer {
     pub key_name: String,
     pub cose: Option<CoseClient>,
+    pub key_id: String,
 }
 
 static SIGN_PROXY_TOKEN_PATH: &[u8] = b"sign_proxy_token";
 
 impl Signer {
+    // The key of a generation is derived with its key id, the first one has none.
+    fn derivation_path(&self) -> Vec<Vec<u8>> {
+        let mut path = vec![SIGN_PROXY_TOKEN_PATH.to_vec()];
+        if !self.key_id.is_empty() {
+            path.push(self.key_id.as_bytes().to_vec());
+        }
+        path
+    }
+
     pub async fn ecdsa_public_key(&self) -> Result<String, String> {
+        let path = self.derivation_path();
         match self.cose {
             Some(ref cose) => cose
-                .ecdsa_public_key(vec![ByteBuf::from(SIGN_PROXY_TOKEN_PATH)])
+                .ecdsa_public_key(path.into_iter().map(ByteBuf::from).collect())
                 .await
                 .map(|v| base64_url.encode(v)),
-            None => public_key_with(&self.key_name, vec![SIGN_PROXY_TOKEN_PATH.to_vec()])
+            None => public_key_with(&self.key_name, path)
                 .await
                 .map(|v| base64_url.encode(v.public_key)),
         }
     }
 
-    // use Idempotent Proxy's Token: Token(pub u64, pub String, pub ByteBuf);
+    // use Idempotent Proxy's Token: Token(pub u64, pub String, pub ByteBuf, scope, key id);
     // https://github.com/ldclabs/idempotent-proxy/blob/main/src/idempotent-proxy-types/src/auth.rs#L15
     pub async fn sign_proxy_token(
         &self,
@@ -102,26 +157,33 @@ impl Signer {
             .expect("failed to encode Token in CBOR format");
         let digest = sha3_256(&buf);
 
+        let path = self.derivation_path();
         let sig = match self.cose {
             Some(ref cose) => {
                 cose.ecdsa_sign(
-                    vec![ByteBuf::from(SIGN_PROXY_TOKEN_PATH)],
+                    path.into_iter().map(ByteBuf::from).collect(),
                     ByteBuf::from(digest),
                 )
                 .await
             }
-            None => sign_with(&self.key_name, vec![SIGN_PROXY_TOKEN_PATH.to_vec()], digest)
+            None => sign_with(&self.key_name, path, digest)
                 .await
                 .map(ByteBuf::from),
         };
 
         buf.clear();
-        into_writer(&(expire_at, message, sig?), &mut buf).map_err(format_error)?;
+        // the key id follows the (empty) scope, it selects the key generation on the proxy
+        match self.key_id.as_str() {
+            "" => into_writer(&(expire_at, message, sig?), &mut buf),
+            key_id => into_writer(&(expire_at, message, sig?, None::<()>, key_id), &mut buf),
+        }
+        .map_err(format_error)?;
         Ok(base64_url.encode(buf))
     }
 }
 
 const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
+const TASKS_MEMORY_ID: MemoryId = MemoryId::new(1);
 
 thread_local! {
     static STATE: RefCell<State> = RefCell::new(State::default());
@@ -136,13 +198,111 @@ thread_local! {
         ).expect("failed to init STATE_STORE store")
     );
 
+    static TASKS: RefCell<StableBTreeMap<u64, Task, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(TASKS_MEMORY_ID)),
+        )
+    );
+}
+
+pub mod queue {
+    use super::*;
+
+    pub fn get(id: u64) -> Option<Task> {
+        TASKS.with_borrow(|r| r.get(&id))
+    }
+
+    pub fn insert(id: u64, task: Task) {
+        TASKS.with_borrow_mut(|r| r.insert(id, task));
+    }
+
+    // Removes the finished tasks created before `before`, ids grow with the creation time.
+    pub fn purge(before: u64) {
+        TASKS.with_borrow_mut(|r| {
+            let expired: Vec<u64> = r
+                .iter()
+                .take_while(|(_, task)| task.status.created_at < before)
+                .filter(|(_, task)| task.status.state != crate::queue::TaskState::Pending)
+                .map(|(id, _)| id)
+                .collect();
+            for id in expired {
+                r.remove(&id);
+            }
+        });
+    }
 }
 
 pub mod state {
     use super::*;
 
+    // The agents in failover order, the unhealthy ones are tried last.
     pub fn get_agents() -> Vec<Agent> {
-        STATE.with(|r| r.borrow().agents.clone())
+        STATE.with(|r| {
+            let s = r.borrow();
+            let (mut agents, unhealthy): (Vec<Agent>, Vec<Agent>) =
+                s.agents.iter().cloned().partition(|a| s.is_healthy(a));
+            agents.extend(unhealthy);
+            agents
+        })
+    }
+
+    // The agents to call in parallel, the unhealthy ones are left out unless all are,
+    // so a dead proxy node doesn't break the consensus of every call.
+    pub fn get_parallel_agents() -> Vec<Agent> {
+        STATE.with(|r| {
+            let s = r.borrow();
+            let agents: Vec<Agent> = s
+                .agents
+                .iter()
+                .filter(|a| s.is_healthy(a))
+                .cloned()
+                .collect();
+            if agents.is_empty() {
+                s.agents.clone()
+            } else {
+                agents
+            }
+        })
+    }
+
+    // The selection of the agent group, the default one if it has none.
+    pub fn get_selection(group: &str) -> Selection {
+        STATE.with(|r| {
+            let s = r.borrow();
+            s.selections
+                .get(group)
+                .or_else(|| s.selections.get(""))
+                .cloned()
+                .unwrap_or_default()
+        })
+    }
+
+    pub fn record_divergences(endpoints: &[String]) {
+        STATE.with(|r| {
+            let mut s = r.borrow_mut();
+            for endpoint in endpoints {
+                let health = s.agents_health.entry(endpoint.clone()).or_default();
+                health.divergences = health.divergences.saturating_add(1);
+            }
+        });
+    }
+
+    pub fn record_health_check(endpoint: &str, result: Result<(), String>, now_ms: u64) {
+        STATE.with(|r| {
+            let mut s = r.borrow_mut();
+            let health = s.agents_health.entry(endpoint.to_string()).or_default();
+            health.checked_at = now_ms;
+            match result {
+                Ok(()) => {
+                    health.failures = 0;
+                    health.last_error = None;
+                }
+                Err(err) => {
+                    health.failures = health.failures.saturating_add(1);
+                    health.last_error = Some(err);
+                }
+            }
+        });
     }
 
     pub fn cycles_calculator() -> Calculator {
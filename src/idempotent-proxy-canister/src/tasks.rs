@@ -3,6 +3,8 @@ use std::collections::BTreeMap;
 use crate::{agent::Agent, store};
 
 const SECONDS: u64 = 1_000_000_000;
+const MILLISECONDS: u64 = 1_000_000;
+const DEFAULT_HEALTH_CHECK_PATH: &str = "/healthz";
 
 pub async fn refresh_proxy_token() {
     let (signer, proxy_token_refresh_interval, agents) =
@@ -39,3 +41,31 @@ pub async fn update_proxy_token(
 
     store::state::with_mut(|r| r.agents = agents);
 }
+
+pub async fn check_agents_health() {
+    let (path, agents) = store::state::with(|s| (s.health_check_path.clone(), s.agents.clone()));
+    let path = if path.is_empty() {
+        DEFAULT_HEALTH_CHECK_PATH.to_string()
+    } else {
+        path
+    };
+    // agents may share an endpoint, e.g. with different names
+    let mut endpoints: BTreeMap<String, Agent> = BTreeMap::new();
+    for agent in agents {
+        endpoints.entry(agent.endpoint.clone()).or_insert(agent);
+    }
+
+    let results =
+        futures::future::join_all(endpoints.values().map(|agent| agent.health_check(&path))).await;
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::state::with_mut(|s| {
+        s.agents_health
+            .retain(|endpoint, _| endpoints.contains_key(endpoint))
+    });
+    for (endpoint, result) in endpoints.keys().zip(results) {
+        if let Err(err) = &result {
+            ic_cdk::print(format!("health check of {endpoint} failed: {err}"));
+        }
+        store::state::record_health_check(endpoint, result, now_ms);
+    }
+}
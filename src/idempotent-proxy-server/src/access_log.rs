@@ -0,0 +1,243 @@
+use serde::Serialize;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc,
+    },
+};
+
+use crate::journal::{JournalRecord, Outcome};
+
+// An access log line, the journal record with the cache result and the response size.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct AccessRecord<'a> {
+    #[serde(flatten)]
+    pub record: &'a JournalRecord,
+    pub cache: &'static str, // hit or miss
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
+enum Sink {
+    Off,
+    Stdout, // with the server logs, under the "access" target
+    File(mpsc::SyncSender<String>),
+}
+
+// Writes one JSON line per handled request to stdout or to a file rotated by size.
+// Lines are written by a dedicated thread, they are dropped if it falls behind.
+#[derive(Debug)]
+pub struct AccessLog {
+    sink: Sink,
+    dropped: AtomicU64,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        AccessLog {
+            sink: Sink::Off,
+            dropped: AtomicU64::new(0),
+        }
+    }
+}
+
+impl AccessLog {
+    pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Self, String> {
+        let (mut target, mut max_size, mut max_files) = (String::new(), 100u64, 5u32);
+        for (k, v) in vars {
+            match k.as_str() {
+                "ACCESS_LOG" => target = v.trim().to_string(),
+                "ACCESS_LOG_MAX_SIZE" => {
+                    max_size = v
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("invalid ACCESS_LOG_MAX_SIZE value: {}", v))?
+                }
+                "ACCESS_LOG_MAX_FILES" => {
+                    max_files = v
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("invalid ACCESS_LOG_MAX_FILES value: {}", v))?
+                }
+                _ => {}
+            }
+        }
+        let sink = match target.as_str() {
+            "" => Sink::Off,
+            "stdout" => Sink::Stdout,
+            path => {
+                let writer = RotatingFile::open(path, max_size * 1024 * 1024, max_files)?;
+                let (tx, rx) = mpsc::sync_channel::<String>(10_000);
+                std::thread::spawn(move || writer.run(rx));
+                Sink::File(tx)
+            }
+        };
+        Ok(AccessLog {
+            sink,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self.sink, Sink::Off)
+    }
+
+    // The lines dropped because the writer fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn log(&self, record: &JournalRecord, bytes: u64) {
+        let line = AccessRecord {
+            record,
+            cache: if record.outcome == Outcome::Replayed {
+                "hit"
+            } else {
+                "miss"
+            },
+            bytes,
+        };
+        match &self.sink {
+            Sink::Off => {}
+            Sink::Stdout => {
+                log::info!(target: "access",
+                    agent = line.record.agent,
+                    method = line.record.method,
+                    url = line.record.url,
+                    idempotency_key = line.record.idempotency_key,
+                    cache = line.cache,
+                    outcome = format!("{:?}", line.record.outcome).to_lowercase(),
+                    status = line.record.status,
+                    elapsed = line.record.elapsed,
+                    bytes = line.bytes;
+                    "");
+            }
+            Sink::File(tx) => {
+                let line = serde_json::to_string(&line).unwrap_or_default();
+                if tx.try_send(line).is_err() {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+// `path` is rotated to `path.1` once it reaches max_size bytes, `path.1` to `path.2`
+// and so on, the oldest of max_files rotated files is deleted.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: u32,
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_size: u64, max_files: u32) -> Result<Self, String> {
+        let path = PathBuf::from(path);
+        let file = open_append(&path)?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or_default();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn run(mut self, rx: mpsc::Receiver<String>) {
+        while let Ok(line) = rx.recv() {
+            if let Err(err) = self.write(&line) {
+                log::error!(target: "access", "write {}: {}", self.path.display(), err);
+            }
+        }
+    }
+
+    fn write(&mut self, line: &str) -> Result<(), String> {
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 >= self.max_size {
+            self.rotate()?;
+        }
+        self.file
+            .write_all(format!("{}\n", line).as_bytes())
+            .map_err(|err| err.to_string())?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), String> {
+        let rotated = |i: u32| PathBuf::from(format!("{}.{}", self.path.display(), i));
+        if self.max_files == 0 {
+            let _ = fs::remove_file(&self.path);
+        } else {
+            let _ = fs::remove_file(rotated(self.max_files));
+            for i in (1..self.max_files).rev() {
+                let _ = fs::rename(rotated(i), rotated(i + 1));
+            }
+            fs::rename(&self.path, rotated(1)).map_err(|err| err.to_string())?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| format!("open access log {}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use idempotent_proxy_types::unix_ms;
+
+    #[test]
+    fn test_rotating_file() {
+        let path = std::env::temp_dir().join(format!("idempotent-proxy-access-{}.log", unix_ms()));
+        let record = JournalRecord {
+            at: 1,
+            agent: "alice".to_string(),
+            method: "POST".to_string(),
+            url: "https://httpbin.org/post".to_string(),
+            idempotency_key: "alice:POST:k1".to_string(),
+            status: 200,
+            outcome: Outcome::Replayed,
+            elapsed: 10,
+        };
+        let line = serde_json::to_string(&AccessRecord {
+            record: &record,
+            cache: "hit",
+            bytes: 42,
+        })
+        .unwrap();
+        assert!(line.contains(r#""agent":"alice""#));
+        assert!(line.contains(r#""outcome":"replayed","elapsed":10,"cache":"hit","bytes":42"#));
+
+        let mut file =
+            RotatingFile::open(path.to_str().unwrap(), line.len() as u64 * 2, 1).unwrap();
+        for _ in 0..5 {
+            file.write(&line).unwrap();
+        }
+        let rotated = PathBuf::from(format!("{}.1", path.display()));
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert_eq!(fs::read_to_string(&rotated).unwrap().lines().count(), 1);
+        assert!(!PathBuf::from(format!("{}.2", path.display())).exists());
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&rotated);
+
+        let log = AccessLog::from_vars(std::iter::empty()).unwrap();
+        assert!(!log.is_enabled());
+        log.log(&record, 42);
+        assert!(AccessLog::from_vars(
+            vec![("ACCESS_LOG_MAX_SIZE".to_string(), "x".to_string())].into_iter()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use futures::StreamExt;
+use rustls_acme::{acme::ACME_TLS_ALPN_NAME, axum::AxumAcceptor, caches::DirCache, AcmeConfig};
+use std::sync::Arc;
+
+use crate::routes::{parse_bool, split_list};
+
+// Certificates provisioned and renewed with ACME for the domains, answering the
+// TLS-ALPN-01 challenges on the TLS listener itself, so it must be reachable on port 443.
+// ACME_DOMAINS="proxy.example.com", ACME_CONTACTS="ops@example.com",
+// ACME_CACHE_DIR=./acme, ACME_PRODUCTION=true (Let's Encrypt staging by default)
+// or ACME_DIRECTORY=<url> for another CA.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Acme {
+    pub domains: Vec<String>,
+    pub contacts: Vec<String>, // mailto: urls
+    pub cache_dir: String,
+    pub directory: Option<String>,
+    pub production: bool,
+}
+
+impl Acme {
+    // None if no domains are configured.
+    pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Option<Self>, String> {
+        let mut acme = Acme {
+            domains: Vec::new(),
+            contacts: Vec::new(),
+            cache_dir: "./acme".to_string(),
+            directory: None,
+            production: false,
+        };
+        for (k, v) in vars {
+            match k.as_str() {
+                "ACME_DOMAINS" => {
+                    acme.domains = split_list(&v)
+                        .into_iter()
+                        .map(|d| d.to_ascii_lowercase())
+                        .collect()
+                }
+                "ACME_CONTACTS" => {
+                    acme.contacts = split_list(&v)
+                        .into_iter()
+                        .map(|c| match c.contains(':') {
+                            true => c,
+                            false => format!("mailto:{}", c),
+                        })
+                        .collect()
+                }
+                "ACME_CACHE_DIR" if !v.trim().is_empty() => acme.cache_dir = v.trim().to_string(),
+                "ACME_DIRECTORY" if !v.trim().is_empty() => {
+                    let url = reqwest::Url::parse(v.trim())
+                        .map_err(|err| format!("invalid ACME_DIRECTORY {}: {}", v, err))?;
+                    acme.directory = Some(url.to_string());
+                }
+                "ACME_PRODUCTION" => acme.production = parse_bool(&v)?,
+                _ => {}
+            }
+        }
+        Ok((!acme.domains.is_empty()).then_some(acme))
+    }
+
+    // The acceptor for axum_server, it serves the certificates as they are issued
+    // and renewed by a background task.
+    pub fn acceptor(&self) -> AxumAcceptor {
+        let config = AcmeConfig::new(self.domains.clone())
+            .contact(self.contacts.iter())
+            .cache(DirCache::new(self.cache_dir.clone()));
+        let config = match &self.directory {
+            Some(url) => config.directory(url),
+            None => config.directory_lets_encrypt(self.production),
+        };
+        let mut state = config.state();
+        let mut tls = (*state.default_rustls_config()).clone();
+        tls.alpn_protocols = vec![
+            b"h2".to_vec(),
+            b"http/1.1".to_vec(),
+            ACME_TLS_ALPN_NAME.to_vec(),
+        ];
+        let acceptor = state.axum_acceptor(Arc::new(tls));
+
+        tokio::spawn(async move {
+            while let Some(event) = state.next().await {
+                match event {
+                    Ok(event) => log::info!(target: "acme", "{:?}", event),
+                    Err(err) => log::error!(target: "acme", "{:?}", err),
+                }
+            }
+        });
+        acceptor
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_vars;
+
+    #[test]
+    fn test_acme() {
+        assert_eq!(
+            Acme::from_vars(test_vars(vec![("ACME_CONTACTS", "a@b.c")])),
+            Ok(None)
+        );
+
+        let acme = Acme::from_vars(test_vars(vec![
+            ("ACME_DOMAINS", "Proxy.example.com, api.example.com"),
+            ("ACME_CONTACTS", "ops@example.com,mailto:sec@example.com"),
+            ("ACME_PRODUCTION", "true"),
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            acme,
+            Acme {
+                domains: vec![
+                    "proxy.example.com".to_string(),
+                    "api.example.com".to_string()
+                ],
+                contacts: vec![
+                    "mailto:ops@example.com".to_string(),
+                    "mailto:sec@example.com".to_string()
+                ],
+                cache_dir: "./acme".to_string(),
+                directory: None,
+                production: true,
+            }
+        );
+
+        assert!(Acme::from_vars(test_vars(vec![
+            ("ACME_DOMAINS", "proxy.example.com"),
+            ("ACME_DIRECTORY", "not a url"),
+        ]))
+        .is_err());
+    }
+}
@@ -0,0 +1,304 @@
+use http::StatusCode;
+use idempotent_proxy_types::unix_ms;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, RwLock},
+};
+
+use crate::agents::AgentSet;
+use crate::cache::{chunk_key, ResponseData, Storage};
+use crate::cluster::{ClusterEvent, Revocation, SharedConfig};
+use crate::handler::AppState;
+use crate::routes::Routes;
+use crate::shards::Sharded;
+
+const MAX_RECENT_ERRORS: usize = 100;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Inflight {
+    pub agent: String,
+    pub method: String,
+    pub url: String,
+    pub started_at: u64, // unix timestamp in milliseconds
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorRecord {
+    pub agent: String,
+    pub method: String,
+    pub url: String, // redacted
+    pub status: u16,
+    pub message: String, // redacted
+    pub at: u64,         // unix timestamp in milliseconds
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AgentStats {
+    pub requests: u64,
+    pub cache_hits: u64,
+    pub errors: u64,
+}
+
+// Runtime state for the control plane: in-flight requests, agent counters
+// and the reloadable route configs.
+#[derive(Debug, Default)]
+pub struct AdminState {
+    pub agents: AgentSet, // agents allowed to call the control plane
+    inflight: Sharded<RwLock<BTreeMap<String, Inflight>>>, // written twice per forwarded request
+    stats: RwLock<BTreeMap<String, AgentStats>>,
+    errors: RwLock<VecDeque<ErrorRecord>>,
+    routes: RwLock<Arc<Routes>>,
+}
+
+impl AdminState {
+    pub fn new(agents: AgentSet, routes: Routes) -> Self {
+        Self {
+            agents,
+            routes: RwLock::new(Arc::new(routes)),
+            ..Default::default()
+        }
+    }
+
+    pub fn routes(&self) -> Arc<Routes> {
+        self.routes.read().unwrap().clone()
+    }
+
+    pub fn set_routes(&self, routes: Routes) {
+        *self.routes.write().unwrap() = Arc::new(routes);
+    }
+
+    pub fn start(&self, key: &str, agent: &str, method: &str, url: &str) {
+        self.inflight.get(key).write().unwrap().insert(
+            key.to_string(),
+            Inflight {
+                agent: agent.to_string(),
+                method: method.to_string(),
+                url: url.to_string(),
+                started_at: unix_ms(),
+            },
+        );
+    }
+
+    pub fn finish(&self, key: &str) {
+        self.inflight.get(key).write().unwrap().remove(key);
+    }
+
+    pub fn inflight(&self) -> Vec<(String, Inflight)> {
+        let mut inflight: Vec<(String, Inflight)> = self
+            .inflight
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        inflight.sort_by(|a, b| a.0.cmp(&b.0));
+        inflight
+    }
+
+    pub fn record(&self, agent: &str, f: impl FnOnce(&mut AgentStats)) {
+        let mut stats = self.stats.write().unwrap();
+        match stats.get_mut(agent) {
+            Some(s) => f(s),
+            None => {
+                let mut s = AgentStats::default();
+                f(&mut s);
+                stats.insert(agent.to_string(), s);
+            }
+        }
+    }
+
+    // Keeps the last MAX_RECENT_ERRORS failed requests for the admin UI.
+    pub fn record_error(&self, record: ErrorRecord) {
+        let mut errors = self.errors.write().unwrap();
+        if errors.len() >= MAX_RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(record);
+    }
+
+    // Returns the recent errors, newest first.
+    pub fn errors(&self) -> Vec<ErrorRecord> {
+        self.errors.read().unwrap().iter().rev().cloned().collect()
+    }
+
+    // Returns the stats of the agent, or of all agents if `agent` is empty.
+    pub fn stats(&self, agent: &str) -> Vec<(String, AgentStats)> {
+        let stats = self.stats.read().unwrap();
+        if agent.is_empty() {
+            stats.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+        } else {
+            stats
+                .get(agent)
+                .map(|v| vec![(agent.to_string(), v.clone())])
+                .unwrap_or_default()
+        }
+    }
+}
+
+// Control plane actions shared by the gRPC service and the admin UI.
+impl AppState {
+    // Resolves the agent of a `Bearer <token>` credential, the agent must be an admin agent.
+    pub fn authorize_admin(&self, token: &str) -> Result<String, (StatusCode, String)> {
+        let agent = self
+            .verify_token(token)
+            .map_err(|err| (StatusCode::UNAUTHORIZED, err))?;
+        if self.cluster.is_revoked(&agent) {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                format!("agent {} is revoked", agent),
+            ));
+        }
+        if self.is_token_revoked(token, &agent) {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                format!("token of agent {} is revoked", agent),
+            ));
+        }
+        if !self.admin.agents.contains(&agent) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("agent {} is not an admin", agent),
+            ));
+        }
+        Ok(agent)
+    }
+
+    pub async fn purge_key(&self, agent: &str, key: &str) -> Result<(), String> {
+        // the chunks of a large body go with its response
+        if let Some(data) = self.cacher.get(key).await? {
+            if let Some(chunks) = ResponseData::try_from(&data[..])
+                .ok()
+                .and_then(|rd| rd.chunks)
+            {
+                for i in 0..chunks.count {
+                    self.cacher.del(&chunk_key(key, i)).await?;
+                }
+            }
+        }
+        self.cacher.del(key).await?;
+        self.cluster
+            .publish(&self.cacher, ClusterEvent::Purge(key.to_string()))
+            .await?;
+        log::warn!(target: "admin",
+            action = "purge_key",
+            agent = agent,
+            idempotency_key = key;
+            "");
+        Ok(())
+    }
+
+    // Purges the cached responses of an agent, returns the number of purged keys.
+    // In-flight locks are released too, their requests complete without caching.
+    pub async fn purge_agent(&self, admin: &str, agent: &str) -> Result<usize, String> {
+        let prefix = self.tenants.agent_key_prefix(agent);
+        let keys: Vec<String> = self
+            .cacher
+            .keys(&prefix)
+            .await?
+            .into_iter()
+            // chunks go with their response
+            .filter(|key| !key.contains(":chunk:"))
+            .collect();
+        for key in &keys {
+            self.purge_key(admin, key).await?;
+        }
+        log::warn!(target: "admin",
+            action = "purge_agent",
+            agent = admin,
+            purged_agent = agent,
+            keys = keys.len();
+            "");
+        Ok(keys.len())
+    }
+
+    // Revokes tokens before their expiration on every instance, it requires CLUSTER_CONFIG_SHARING.
+    pub async fn revoke(
+        &self,
+        admin: &str,
+        revocation: Revocation,
+    ) -> Result<Arc<SharedConfig>, String> {
+        let detail = format!("{:?}", revocation);
+        let cfg = self.cluster.revoke(&self.cacher, revocation).await?;
+        log::warn!(target: "admin",
+            action = "revoke",
+            agent = admin,
+            revocation = detail.as_str();
+            "");
+        Ok(cfg)
+    }
+
+    pub async fn reload_config(&self, agent: &str) -> Result<(), String> {
+        self.reload()?;
+        self.cluster
+            .publish(&self.cacher, ClusterEvent::Reload)
+            .await?;
+        log::warn!(target: "admin", action = "reload_config", agent = agent; "");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_admin_state() {
+        let state = AdminState::new("ops".parse().unwrap(), Routes::default());
+        state.start(
+            "alice:GET:key_001",
+            "alice",
+            "GET",
+            "https://httpbin.org/get",
+        );
+        state.start("bob:GET:key_001", "bob", "GET", "https://httpbin.org/get");
+        assert_eq!(state.inflight().len(), 2);
+        state.finish("alice:GET:key_001");
+        let inflight = state.inflight();
+        assert_eq!(inflight.len(), 1);
+        assert_eq!(inflight[0].0, "bob:GET:key_001");
+        assert_eq!(inflight[0].1.agent, "bob");
+
+        state.record("alice", |s| s.requests += 1);
+        state.record("alice", |s| {
+            s.requests += 1;
+            s.cache_hits += 1
+        });
+        state.record("bob", |s| s.errors += 1);
+        assert_eq!(
+            state.stats("alice"),
+            vec![(
+                "alice".to_string(),
+                AgentStats {
+                    requests: 2,
+                    cache_hits: 1,
+                    errors: 0
+                }
+            )]
+        );
+        assert_eq!(state.stats("").len(), 2);
+        assert!(state.stats("carol").is_empty());
+
+        for i in 0..(MAX_RECENT_ERRORS + 2) {
+            state.record_error(ErrorRecord {
+                agent: "alice".to_string(),
+                method: "GET".to_string(),
+                url: "https://httpbin.org/get".to_string(),
+                status: 502,
+                message: format!("error {}", i),
+                at: i as u64,
+            });
+        }
+        let errors = state.errors();
+        assert_eq!(errors.len(), MAX_RECENT_ERRORS);
+        assert_eq!(
+            errors[0].message,
+            format!("error {}", MAX_RECENT_ERRORS + 1)
+        );
+        assert_eq!(errors[MAX_RECENT_ERRORS - 1].message, "error 2");
+    }
+}
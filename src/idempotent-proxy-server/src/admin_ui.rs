@@ -0,0 +1,482 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    response::{Html, IntoResponse, Response},
+    routing, Json, Router,
+};
+use base64::{engine::general_purpose, Engine};
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use idempotent_proxy_types::unix_ms;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{convert::Infallible, net::SocketAddr};
+
+use crate::agents::AgentPattern;
+use crate::cache::{ResponseData, Storage};
+use crate::cluster::{token_hash, Revocation};
+use crate::handler::{token_expire_at, AppState};
+use crate::journal::JournalFilter;
+use crate::{profiling, resources};
+
+const MAX_PROFILE_SECONDS: u64 = 300;
+
+// A single page without external assets, the data comes from the JSON endpoints below.
+const INDEX_HTML: &str = include_str!("admin_ui.html");
+
+#[derive(Deserialize)]
+struct PurgeRequest {
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct PurgeAgentQuery {
+    agent: String, // as in the journal, e.g. `acme/web` for a tenant agent
+}
+
+// Either a token, as sent without the `Bearer ` prefix, or the tokens of an agent
+// that expire at or before `expire_before` (unix timestamp in seconds).
+#[derive(Deserialize)]
+struct RevokeRequest {
+    token: Option<String>,
+    agent: Option<String>,
+    expire_before: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct JournalQuery {
+    from: Option<u64>,     // unix timestamp in milliseconds, inclusive
+    to: Option<u64>,       // exclusive
+    agent: Option<String>, // an agent name or pattern, e.g. `worker-*`
+}
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    from: Option<u64>,     // unix timestamp in milliseconds, inclusive, 0 by default
+    to: Option<u64>,       // exclusive, now by default
+    agent: Option<String>, // an agent name or pattern, e.g. `acme/*`
+}
+
+#[derive(Deserialize)]
+struct ProfileQuery {
+    seconds: Option<u64>,   // 10 by default
+    frequency: Option<i32>, // samples per second, 99 by default
+}
+
+pub fn router(app: AppState) -> Router {
+    Router::new()
+        .route("/", routing::get(index))
+        .route("/api/overview", routing::get(overview))
+        .route("/api/purge", routing::post(purge))
+        .route("/api/reload", routing::post(reload))
+        .route("/admin/cache", routing::delete(purge_agent))
+        .route(
+            "/admin/cache/:key",
+            routing::get(inspect_key).delete(delete_key),
+        )
+        .route("/admin/revocations", routing::get(revocations).post(revoke))
+        .route("/admin/usage", routing::get(usage))
+        .route("/api/journal", routing::get(journal))
+        .route("/api/analytics", routing::get(analytics))
+        .route("/api/resources", routing::get(resources))
+        .route("/api/profile/cpu", routing::get(cpu_profile))
+        .route("/api/profile/heap", routing::get(heap_profile))
+        .with_state(app)
+}
+
+pub async fn serve(addr: SocketAddr, app: AppState) {
+    let listener = match tokio::net::TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!(target: "server", "admin UI bind failed: {}", err);
+            return;
+        }
+    };
+    log::warn!(target: "server", "admin UI listening on {:?}", addr);
+    if let Err(err) = axum::serve(listener, router(app)).await {
+        log::error!(target: "server", "admin UI failed: {}", err);
+    }
+}
+
+// The page is public, it asks for an admin token and sends it with every API call.
+async fn index() -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_SECURITY_POLICY,
+            "default-src 'none'; script-src 'unsafe-inline'; style-src 'unsafe-inline'; connect-src 'self'",
+        )],
+        Html(INDEX_HTML),
+    )
+}
+
+fn authorize(app: &AppState, headers: &HeaderMap) -> Result<String, (StatusCode, String)> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    app.authorize_admin(token)
+}
+
+fn no_store(body: Value) -> Response {
+    let mut res = Json(body).into_response();
+    res.headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    res
+}
+
+async fn overview(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    authorize(&app, &headers)?;
+    let now = unix_ms();
+    let stats = app.admin.stats("");
+    let totals = stats.iter().fold((0, 0, 0), |acc, (_, s)| {
+        (acc.0 + s.requests, acc.1 + s.cache_hits, acc.2 + s.errors)
+    });
+    Ok(no_store(json!({
+        "instance": app.cluster.instance,
+        "is_leader": app.leader.is_leader(),
+        "now": now,
+        "totals": {
+            "requests": totals.0,
+            "cache_hits": totals.1,
+            "errors": totals.2,
+        },
+        "inflight": app.admin.inflight().into_iter().map(|(key, r)| json!({
+            "key": key,
+            "agent": r.agent,
+            "method": r.method,
+            "url": r.url,
+            "started_at": r.started_at,
+            "elapsed": now.saturating_sub(r.started_at),
+        })).collect::<Vec<_>>(),
+        "agents": stats.into_iter().map(|(agent, s)| json!({
+            "agent": agent,
+            "requests": s.requests,
+            "cache_hits": s.cache_hits,
+            "errors": s.errors,
+        })).collect::<Vec<_>>(),
+        "errors": app.admin.errors().into_iter().map(|e| json!({
+            "agent": e.agent,
+            "method": e.method,
+            "url": e.url,
+            "status": e.status,
+            "message": e.message,
+            "at": e.at,
+        })).collect::<Vec<_>>(),
+    })))
+}
+
+async fn purge(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<PurgeRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    let agent = authorize(&app, &headers)?;
+    if req.key.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "missing key".to_string()));
+    }
+    app.purge_key(&agent, &req.key)
+        .await
+        .map_err(|err| (StatusCode::SERVICE_UNAVAILABLE, err))?;
+    Ok(no_store(json!({ "purged": req.key })))
+}
+
+// The cached response of a (URL encoded) idempotency key as stored, e.g. `alice:POST:key_001`.
+// A body stored in chunks is not loaded, only its size is returned.
+async fn inspect_key(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+    Path(key): Path<String>,
+) -> Result<Response, (StatusCode, String)> {
+    let agent = authorize(&app, &headers)?;
+    let data = app
+        .cacher
+        .get(&key)
+        .await
+        .map_err(|err| (StatusCode::SERVICE_UNAVAILABLE, err))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("key not found: {}", key)))?;
+    let rd =
+        ResponseData::try_from(&data[..]).map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))?;
+    log::warn!(target: "admin",
+        action = "inspect_key",
+        agent = agent,
+        idempotency_key = key;
+        "");
+    let (body, encoding) = match std::str::from_utf8(&rd.body) {
+        Ok(s) => (s.to_string(), "utf8"),
+        Err(_) => (general_purpose::STANDARD.encode(&rd.body), "base64"),
+    };
+    Ok(no_store(json!({
+        "key": key,
+        "status": rd.status,
+        "mime": rd.mime,
+        "headers": rd.headers,
+        "body": body,
+        "body_encoding": encoding,
+        "chunks": rd.chunks.map(|c| json!({"count": c.count, "size": c.size})),
+        "fingerprint": rd.fingerprint,
+    })))
+}
+
+async fn delete_key(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+    Path(key): Path<String>,
+) -> Result<Response, (StatusCode, String)> {
+    let agent = authorize(&app, &headers)?;
+    app.purge_key(&agent, &key)
+        .await
+        .map_err(|err| (StatusCode::SERVICE_UNAVAILABLE, err))?;
+    Ok(no_store(json!({ "purged": key })))
+}
+
+async fn purge_agent(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+    Query(q): Query<PurgeAgentQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let admin = authorize(&app, &headers)?;
+    let agent = q.agent.trim();
+    if agent.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "missing agent".to_string()));
+    }
+    let purged = app
+        .purge_agent(&admin, agent)
+        .await
+        .map_err(|err| (StatusCode::SERVICE_UNAVAILABLE, err))?;
+    Ok(no_store(json!({ "agent": agent, "purged": purged })))
+}
+
+async fn reload(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let agent = authorize(&app, &headers)?;
+    app.reload_config(&agent)
+        .await
+        .map_err(|err| (StatusCode::PRECONDITION_FAILED, err))?;
+    Ok(no_store(json!({ "reloaded": true })))
+}
+
+async fn revocations(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    authorize(&app, &headers)?;
+    let cfg = app.cluster.config();
+    Ok(no_store(json!({
+        "version": cfg.version,
+        "tokens": cfg.revoked_tokens,
+        "agents": cfg.revoked_before,
+    })))
+}
+
+async fn revoke(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<RevokeRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    let admin = authorize(&app, &headers)?;
+    let revocation = match (req.token, req.agent, req.expire_before) {
+        (Some(token), None, None) => {
+            let token = token.trim();
+            let expire_at = token_expire_at(&format!("Bearer {}", token))
+                .ok_or_else(|| (StatusCode::BAD_REQUEST, "invalid token".to_string()))?;
+            Revocation::Token {
+                hash: token_hash(token),
+                expire_at,
+            }
+        }
+        (None, Some(agent), Some(expire_before)) if !agent.trim().is_empty() => Revocation::Agent {
+            agent: agent.trim().to_string(),
+            expire_before,
+        },
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "either token or agent and expire_before are required".to_string(),
+            ))
+        }
+    };
+    let cfg = app
+        .revoke(&admin, revocation)
+        .await
+        .map_err(|err| (StatusCode::PRECONDITION_FAILED, err))?;
+    Ok(no_store(json!({
+        "version": cfg.version,
+        "tokens": cfg.revoked_tokens.len(),
+        "agents": cfg.revoked_before.len(),
+    })))
+}
+
+// Streams the matching journal records as NDJSON, one record per line in time order.
+async fn journal(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+    Query(q): Query<JournalQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let agent = authorize(&app, &headers)?;
+    let filter = JournalFilter {
+        from: q.from,
+        to: q.to,
+        agent: match q.agent.as_deref().map(str::trim) {
+            None | Some("") => None,
+            Some(v) => Some(v.parse().map_err(|err| (StatusCode::BAD_REQUEST, err))?),
+        },
+    };
+    let records = app.journal.query(&filter);
+    log::warn!(target: "admin",
+        action = "export_journal",
+        agent = agent,
+        records = records.len();
+        "");
+    let lines = futures::stream::iter(records.into_iter().map(|r| {
+        let mut line = serde_json::to_vec(&r).unwrap_or_default();
+        line.push(b'\n');
+        Ok::<_, Infallible>(Bytes::from(line))
+    }));
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        Body::from_stream(lines),
+    )
+        .into_response())
+}
+
+// The per agent usage of all the instances for billing, as JSON or as CBOR if accepted.
+async fn usage(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+    Query(q): Query<UsageQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let admin = authorize(&app, &headers)?;
+    if !app.usage.enabled {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "usage accounting is disabled".to_string(),
+        ));
+    }
+    let agent: Option<AgentPattern> = match q.agent.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(v) => Some(v.parse().map_err(|err| (StatusCode::BAD_REQUEST, err))?),
+    };
+    let report = app
+        .usage
+        .report(
+            &app.cacher,
+            agent.as_ref(),
+            q.from.unwrap_or(0),
+            q.to.unwrap_or_else(unix_ms),
+        )
+        .await
+        .map_err(|err| (StatusCode::SERVICE_UNAVAILABLE, err))?;
+    log::warn!(target: "admin",
+        action = "usage_report",
+        agent = admin,
+        agents = report.agents.len();
+        "");
+    let cbor = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/cbor"));
+    if !cbor {
+        let body = serde_json::to_value(&report)
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        return Ok(no_store(body));
+    }
+    let mut body = Vec::new();
+    ciborium::into_writer(&report, &mut body)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/cbor"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+// Hit, miss and conflict rates, duplicate wait durations, top duplicated keys and
+// the per agent breakdown since the instance started.
+async fn analytics(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    authorize(&app, &headers)?;
+    let snapshot = serde_json::to_value(app.analytics.snapshot())
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(no_store(snapshot))
+}
+
+// Process metrics, requests in flight per upstream host and the memory of the
+// in-process subsystems, in entries and approximate bytes.
+async fn resources(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    authorize(&app, &headers)?;
+    let cache = app.cacher.memory_usage().await;
+    let journal = app.journal.usage();
+    let (tracked_keys, tracked_agents) = app.analytics.usage();
+    Ok(no_store(json!({
+        "instance": app.cluster.instance,
+        "process": resources::process_stats(),
+        "active_requests": app.resources.active_requests(),
+        "waiting_requests": app.waiters.total(),
+        "upstreams": app.resources.upstreams(),
+        "circuits": app.breakers.circuits(),
+        "memory": {
+            "cache": cache.map(|(entries, bytes)| json!({"entries": entries, "bytes": bytes})),
+            "journal": {"entries": journal.0, "bytes": journal.1},
+            "analytics": {"keys": tracked_keys, "agents": tracked_agents},
+            "inflight": {"entries": app.admin.inflight().len()},
+        },
+    })))
+}
+
+fn pprof_response(body: Vec<u8>) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "application/octet-stream"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+// A pprof CPU profile of the process, e.g. `go tool pprof -http=: cpu.pb`.
+async fn cpu_profile(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+    Query(q): Query<ProfileQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let agent = authorize(&app, &headers)?;
+    let seconds = q.seconds.unwrap_or(10).clamp(1, MAX_PROFILE_SECONDS);
+    let frequency = q.frequency.unwrap_or(99).clamp(1, 1000);
+    log::warn!(target: "admin",
+        action = "cpu_profile",
+        agent = agent,
+        seconds = seconds;
+        "");
+    profiling::cpu_profile(seconds, frequency)
+        .await
+        .map(pprof_response)
+}
+
+// A pprof heap profile of the allocations sampled by jemalloc.
+async fn heap_profile(
+    State(app): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let agent = authorize(&app, &headers)?;
+    log::warn!(target: "admin",
+        action = "heap_profile",
+        agent = agent;
+        "");
+    profiling::heap_profile().await.map(pprof_response)
+}
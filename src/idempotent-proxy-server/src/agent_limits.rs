@@ -0,0 +1,247 @@
+use idempotent_proxy_types::unix_ms;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use crate::agents::{AgentMap, AgentSet};
+use crate::tenants::RateLimiter;
+
+const PREFIX: &str = "AGENT_LIMITS_";
+const DAY_MS: u64 = 24 * 3600 * 1000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Limit {
+    rate: Option<(u32, u32)>,         // requests per second, burst
+    daily_quota: Option<u64>,         // requests per UTC day
+    max_body_size: Option<usize>,     // in bytes, overrides the MAX_BODY_SIZE of the routes
+    max_response_size: Option<usize>, // in bytes, of any response
+}
+
+struct Usage {
+    limiter: Option<RateLimiter>,
+    day: u64,
+    used: u64,
+}
+
+// A group's agents, rate limit, burst and the rest of its limits, while parsing.
+type Group = (Option<AgentSet>, Option<u32>, Option<u32>, Limit);
+
+// Rate limits and daily quotas per agent, configured in named groups:
+// AGENT_LIMITS_<NAME>_AGENTS="canister-*", AGENT_LIMITS_<NAME>_RATE_LIMIT=10,
+// AGENT_LIMITS_<NAME>_RATE_BURST=20 and AGENT_LIMITS_<NAME>_DAILY_QUOTA=10000.
+// Every agent of a group has its own bucket and quota, counted by each instance.
+// AGENT_LIMITS_<NAME>_MAX_BODY_SIZE and AGENT_LIMITS_<NAME>_MAX_RESPONSE_SIZE bound the
+// request and response bodies of the agents, in bytes.
+#[derive(Default)]
+pub struct AgentLimits {
+    limits: AgentMap<Limit>,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl AgentLimits {
+    pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Self, String> {
+        let mut groups: BTreeMap<String, Group> = BTreeMap::new();
+        for (k, v) in vars {
+            let Some(name_option) = k.strip_prefix(PREFIX) else {
+                continue;
+            };
+            let (name, option) = [
+                "AGENTS",
+                "RATE_LIMIT",
+                "RATE_BURST",
+                "DAILY_QUOTA",
+                "MAX_BODY_SIZE",
+                "MAX_RESPONSE_SIZE",
+            ]
+            .iter()
+            .find_map(|option| {
+                name_option
+                    .strip_suffix(option)
+                    .and_then(|name| name.strip_suffix('_'))
+                    .filter(|name| !name.is_empty())
+                    .map(|name| (name.to_string(), *option))
+            })
+            .ok_or_else(|| format!("unknown agent limits option: {}", k))?;
+            let group = groups.entry(name).or_default();
+            if option == "AGENTS" {
+                group.0 = Some(v.parse()?);
+                continue;
+            }
+            let n: u64 = v
+                .trim()
+                .parse()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or_else(|| format!("invalid {} value: {}", k, v))?;
+            match option {
+                "RATE_LIMIT" => group.1 = Some(n.min(u32::MAX as u64) as u32),
+                "RATE_BURST" => group.2 = Some(n.min(u32::MAX as u64) as u32),
+                "MAX_BODY_SIZE" => group.3.max_body_size = Some(n as usize),
+                "MAX_RESPONSE_SIZE" => group.3.max_response_size = Some(n as usize),
+                _ => group.3.daily_quota = Some(n),
+            }
+        }
+
+        // patterns of the groups apply in the order of the group names
+        let mut limits = AgentMap::default();
+        for (name, (agents, rate, burst, mut limit)) in groups {
+            let Some(agents) = agents else {
+                return Err(format!("{}{} needs AGENTS", PREFIX, name));
+            };
+            limit.rate = match (rate, burst) {
+                (Some(rate), burst) => Some((rate, burst.unwrap_or(rate))),
+                (None, Some(_)) => {
+                    return Err(format!(
+                        "{}{} has RATE_BURST without RATE_LIMIT",
+                        PREFIX, name
+                    ))
+                }
+                (None, None) => None,
+            };
+            if limit == Limit::default() {
+                return Err(format!(
+                    "{}{} needs RATE_LIMIT, DAILY_QUOTA, MAX_BODY_SIZE or MAX_RESPONSE_SIZE",
+                    PREFIX, name
+                ));
+            }
+            for pattern in agents.patterns() {
+                limits.insert(pattern, limit);
+            }
+        }
+        Ok(AgentLimits {
+            limits,
+            usage: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn max_body_size(&self, agent: &str) -> Option<usize> {
+        self.limits.get(agent).and_then(|limit| limit.max_body_size)
+    }
+
+    pub fn max_response_size(&self, agent: &str) -> Option<usize> {
+        self.limits
+            .get(agent)
+            .and_then(|limit| limit.max_response_size)
+    }
+
+    // Counts a request of the agent, returns the reason and the seconds to wait
+    // (for Retry-After) if it is over its rate limit or daily quota.
+    pub fn check(&self, agent: &str) -> Result<(), (String, u64)> {
+        self.check_at(agent, unix_ms())
+    }
+
+    fn check_at(&self, agent: &str, now_ms: u64) -> Result<(), (String, u64)> {
+        let Some(limit) = self
+            .limits
+            .get(agent)
+            .filter(|limit| limit.rate.is_some() || limit.daily_quota.is_some())
+        else {
+            return Ok(());
+        };
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(agent.to_string()).or_insert_with(|| Usage {
+            limiter: limit
+                .rate
+                .map(|(rate, burst)| RateLimiter::new(rate, burst)),
+            day: now_ms / DAY_MS,
+            used: 0,
+        });
+        if usage.day != now_ms / DAY_MS {
+            usage.day = now_ms / DAY_MS;
+            usage.used = 0;
+        }
+        if let Some(quota) = limit.daily_quota {
+            if usage.used >= quota {
+                let next_day = (usage.day + 1) * DAY_MS;
+                return Err((
+                    format!("agent {} daily quota of {} requests exceeded", agent, quota),
+                    (next_day - now_ms).div_ceil(1000),
+                ));
+            }
+        }
+        if let Some(limiter) = &usage.limiter {
+            if !limiter.try_acquire() {
+                return Err((
+                    format!("agent {} rate limit exceeded", agent),
+                    limiter.retry_after().as_secs_f64().ceil().max(1.0) as u64,
+                ));
+            }
+        }
+        usage.used += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_agent_limits() {
+        let vars = [
+            ("AGENT_LIMITS_CANISTERS_AGENTS", "canister-*"),
+            ("AGENT_LIMITS_CANISTERS_RATE_LIMIT", "1"),
+            ("AGENT_LIMITS_CANISTERS_RATE_BURST", "2"),
+            ("AGENT_LIMITS_BATCH_AGENTS", "batch"),
+            ("AGENT_LIMITS_BATCH_DAILY_QUOTA", "3"),
+            ("ALLOW_AGENTS", "batch"),
+            ("AGENT_LIMITS_IC_AGENTS", "ic-*"),
+            ("AGENT_LIMITS_IC_MAX_BODY_SIZE", "1024"),
+            ("AGENT_LIMITS_IC_MAX_RESPONSE_SIZE", "2000000"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        let limits = AgentLimits::from_vars(vars).unwrap();
+        let now = 10 * DAY_MS + 1000;
+
+        assert!(limits.check_at("canister-a", now).is_ok());
+        assert!(limits.check_at("canister-a", now).is_ok());
+        let (msg, retry_after) = limits.check_at("canister-a", now).unwrap_err();
+        assert_eq!(msg, "agent canister-a rate limit exceeded");
+        assert_eq!(retry_after, 1);
+        // every agent has its own bucket
+        assert!(limits.check_at("canister-b", now).is_ok());
+
+        for _ in 0..3 {
+            assert!(limits.check_at("batch", now).is_ok());
+        }
+        let (msg, retry_after) = limits.check_at("batch", now).unwrap_err();
+        assert_eq!(msg, "agent batch daily quota of 3 requests exceeded");
+        assert_eq!(retry_after, (DAY_MS - 1000) / 1000);
+        // the quota is reset every UTC day
+        assert!(limits.check_at("batch", 11 * DAY_MS).is_ok());
+
+        for _ in 0..10 {
+            assert!(limits.check_at("other", now).is_ok());
+        }
+
+        assert_eq!(limits.max_body_size("ic-a"), Some(1024));
+        assert_eq!(limits.max_response_size("ic-a"), Some(2000000));
+        assert_eq!(limits.max_body_size("batch"), None);
+        assert_eq!(limits.max_response_size("other"), None);
+        // size limits alone don't count the requests
+        for _ in 0..10 {
+            assert!(limits.check_at("ic-a", now).is_ok());
+        }
+
+        for vars in [
+            vec![("AGENT_LIMITS_A_RATE_LIMIT", "1")],
+            vec![("AGENT_LIMITS_A_AGENTS", "a")],
+            vec![
+                ("AGENT_LIMITS_A_AGENTS", "a"),
+                ("AGENT_LIMITS_A_RATE_BURST", "1"),
+            ],
+            vec![
+                ("AGENT_LIMITS_A_AGENTS", "a"),
+                ("AGENT_LIMITS_A_RATE_LIMIT", "0"),
+            ],
+            vec![("AGENT_LIMITS_A_UNKNOWN", "1")],
+        ] {
+            let vars = vars
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()));
+            assert!(AgentLimits::from_vars(vars).is_err());
+        }
+    }
+}
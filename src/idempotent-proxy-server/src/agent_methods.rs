@@ -0,0 +1,102 @@
+use http::Method;
+use std::collections::BTreeMap;
+
+use crate::agents::{AgentMap, AgentSet};
+
+const PREFIX: &str = "AGENT_METHODS_";
+
+// The HTTP methods each agent may use, configured in named groups:
+// AGENT_METHODS_<NAME>_AGENTS="reader,report-*" and AGENT_METHODS_<NAME>_METHODS="GET,HEAD".
+// Agents in no group may use any method.
+#[derive(Debug, Default)]
+pub struct AgentMethods(AgentMap<Vec<Method>>);
+
+impl AgentMethods {
+    pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Self, String> {
+        let mut groups: BTreeMap<String, (Option<AgentSet>, Option<Vec<Method>>)> = BTreeMap::new();
+        for (k, v) in vars {
+            let Some(name_option) = k.strip_prefix(PREFIX) else {
+                continue;
+            };
+            if let Some(name) = name_option.strip_suffix("_AGENTS") {
+                groups.entry(name.to_string()).or_default().0 = Some(v.parse()?);
+            } else if let Some(name) = name_option.strip_suffix("_METHODS") {
+                let methods = v
+                    .split(',')
+                    .map(|m| m.trim().to_ascii_uppercase())
+                    .filter(|m| !m.is_empty())
+                    .map(|m| {
+                        Method::from_bytes(m.as_bytes())
+                            .map_err(|_| format!("invalid method {} in {}", m, k))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                groups.entry(name.to_string()).or_default().1 = Some(methods);
+            } else {
+                return Err(format!("unknown agent methods option: {}", k));
+            }
+        }
+
+        // patterns of the groups apply in the order of the group names
+        let mut map = AgentMap::default();
+        for (name, group) in groups {
+            match group {
+                (Some(agents), Some(methods)) => {
+                    for pattern in agents.patterns() {
+                        map.insert(pattern, methods.clone());
+                    }
+                }
+                _ => return Err(format!("{}{} needs both AGENTS and METHODS", PREFIX, name)),
+            }
+        }
+        Ok(AgentMethods(map))
+    }
+
+    pub fn allows(&self, agent: &str, method: &Method) -> bool {
+        self.0
+            .get(agent)
+            .is_none_or(|methods| methods.contains(method))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_agent_methods() {
+        let vars = [
+            ("AGENT_METHODS_READONLY_AGENTS", "reader, report-*"),
+            ("AGENT_METHODS_READONLY_METHODS", "get, HEAD"),
+            ("AGENT_METHODS_WRITER_AGENTS", "writer,report-admin"),
+            ("AGENT_METHODS_WRITER_METHODS", "GET,POST,PUT"),
+            ("ALLOW_AGENTS", "reader,writer"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        let m = AgentMethods::from_vars(vars).unwrap();
+        assert!(m.allows("reader", &Method::GET));
+        assert!(m.allows("reader", &Method::HEAD));
+        assert!(!m.allows("reader", &Method::POST));
+        assert!(!m.allows("report-daily", &Method::DELETE));
+        // exact names win over patterns
+        assert!(m.allows("report-admin", &Method::PUT));
+        assert!(m.allows("writer", &Method::POST));
+        assert!(!m.allows("writer", &Method::DELETE));
+        assert!(m.allows("other", &Method::DELETE));
+
+        let missing = [("AGENT_METHODS_RO_AGENTS", "reader")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()));
+        assert!(AgentMethods::from_vars(missing).is_err());
+        let invalid = [
+            ("AGENT_METHODS_RO_AGENTS", "reader"),
+            ("AGENT_METHODS_RO_METHODS", "GET HEAD"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        assert!(AgentMethods::from_vars(invalid).is_err());
+        assert!(AgentMethods::from_vars(std::iter::empty())
+            .unwrap()
+            .allows("reader", &Method::DELETE));
+    }
+}
@@ -0,0 +1,188 @@
+use regex::Regex;
+use std::{collections::BTreeMap, str::FromStr};
+
+const REGEX_PREFIX: &str = "re:";
+
+// An agent name pattern: an exact name, a glob with `*` and `?` (e.g. `worker-*`),
+// or a regular expression with the `re:` prefix (e.g. `re:^worker-\d+$`).
+#[derive(Clone, Debug)]
+pub enum AgentPattern {
+    Exact(String),
+    Glob(String),
+    Regex(Regex),
+}
+
+impl FromStr for AgentPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(re) = s.strip_prefix(REGEX_PREFIX) {
+            return Regex::new(re)
+                .map(AgentPattern::Regex)
+                .map_err(|err| format!("invalid agent pattern {}: {}", s, err));
+        }
+        if s.contains(['*', '?']) {
+            Ok(AgentPattern::Glob(s.to_string()))
+        } else {
+            Ok(AgentPattern::Exact(s.to_string()))
+        }
+    }
+}
+
+impl AgentPattern {
+    pub fn matches(&self, agent: &str) -> bool {
+        match self {
+            AgentPattern::Exact(name) => name == agent,
+            AgentPattern::Glob(glob) => glob_match(glob.as_bytes(), agent.as_bytes()),
+            AgentPattern::Regex(re) => re.is_match(agent),
+        }
+    }
+}
+
+impl PartialEq for AgentPattern {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (AgentPattern::Exact(a), AgentPattern::Exact(b)) => a == b,
+            (AgentPattern::Glob(a), AgentPattern::Glob(b)) => a == b,
+            (AgentPattern::Regex(a), AgentPattern::Regex(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+// Per agent values keyed by agent patterns. Exact names are looked up first,
+// then the patterns in configuration order, so autoscaled workers with generated
+// names share one entry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AgentMap<T> {
+    exact: BTreeMap<String, T>,
+    patterns: Vec<(AgentPattern, T)>,
+}
+
+impl<T> Default for AgentMap<T> {
+    fn default() -> Self {
+        AgentMap {
+            exact: BTreeMap::new(),
+            patterns: Vec::new(),
+        }
+    }
+}
+
+impl<T> AgentMap<T> {
+    pub fn insert(&mut self, pattern: AgentPattern, value: T) {
+        match pattern {
+            AgentPattern::Exact(name) => {
+                self.exact.insert(name, value);
+            }
+            pattern => self.patterns.push((pattern, value)),
+        }
+    }
+
+    pub fn get(&self, agent: &str) -> Option<&T> {
+        self.exact.get(agent).or_else(|| {
+            self.patterns
+                .iter()
+                .find(|(p, _)| p.matches(agent))
+                .map(|(_, v)| v)
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.patterns.is_empty()
+    }
+}
+
+// A set of agent patterns, e.g. ALLOW_AGENTS="admin,worker-*".
+pub type AgentSet = AgentMap<()>;
+
+impl AgentSet {
+    pub fn contains(&self, agent: &str) -> bool {
+        self.get(agent).is_some()
+    }
+
+    pub fn patterns(&self) -> impl Iterator<Item = AgentPattern> + '_ {
+        self.exact
+            .keys()
+            .map(|name| AgentPattern::Exact(name.clone()))
+            .chain(self.patterns.iter().map(|(p, _)| p.clone()))
+    }
+}
+
+impl FromStr for AgentSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut set = AgentSet::default();
+        for item in s.split(',') {
+            if !item.trim().is_empty() {
+                set.insert(item.parse()?, ());
+            }
+        }
+        Ok(set)
+    }
+}
+
+// Glob matching with `*` (any sequence) and `?` (any byte), backtracking on the last `*`.
+pub(crate) fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((sp, sn)) = star {
+            p = sp + 1;
+            n = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"worker-*", b"worker-"));
+        assert!(glob_match(b"worker-*", b"worker-7f9c"));
+        assert!(!glob_match(b"worker-*", b"workers"));
+        assert!(glob_match(b"*-eu-?", b"batch-eu-1"));
+        assert!(!glob_match(b"*-eu-?", b"batch-eu-12"));
+        assert!(glob_match(b"a*b*c", b"aXbYbZc"));
+        assert!(glob_match(b"*", b""));
+        assert!(!glob_match(b"", b"a"));
+    }
+
+    #[test]
+    fn test_agent_set() {
+        let set: AgentSet = "admin, worker-*, re:^job-\\d+$".parse().unwrap();
+        assert!(set.contains("admin"));
+        assert!(set.contains("worker-abc"));
+        assert!(set.contains("job-42"));
+        assert!(!set.contains("job-x"));
+        assert!(!set.contains("administrator"));
+        assert_eq!(set.patterns().count(), 3);
+
+        assert!("".parse::<AgentSet>().unwrap().is_empty());
+        assert!("re:(".parse::<AgentSet>().is_err());
+    }
+
+    #[test]
+    fn test_agent_map() {
+        let mut map: AgentMap<u32> = AgentMap::default();
+        map.insert("worker-*".parse().unwrap(), 1);
+        map.insert("*".parse().unwrap(), 2);
+        map.insert("worker-main".parse().unwrap(), 3);
+        assert_eq!(map.get("worker-main"), Some(&3));
+        assert_eq!(map.get("worker-1"), Some(&1));
+        assert_eq!(map.get("other"), Some(&2));
+    }
+}
@@ -0,0 +1,342 @@
+use idempotent_proxy_types::unix_ms;
+use reqwest::{Client, Url};
+use serde::Serialize;
+use std::{collections::HashMap, net::IpAddr, process::Stdio, sync::Mutex};
+use tokio::io::AsyncWriteExt;
+
+use crate::redact;
+
+// bounds the tracked subjects and client addresses, stale entries are dropped beyond it
+const MAX_TRACKED: usize = 10000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AlertKind {
+    KeyConflict,        // a duplicate request arrived while the first one was in flight
+    AuthFailures,       // repeated authentication failures from one client address
+    StorageUnavailable, // a request was refused or forwarded unlocked without the storage
+}
+
+impl AlertKind {
+    const ALL: [AlertKind; 3] = [
+        AlertKind::KeyConflict,
+        AlertKind::AuthFailures,
+        AlertKind::StorageUnavailable,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertKind::KeyConflict => "key_conflict",
+            AlertKind::AuthFailures => "auth_failures",
+            AlertKind::StorageUnavailable => "storage_unavailable",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Alert {
+    pub alert: &'static str,
+    pub subject: String, // the agent, the client address or "storage"
+    pub message: String,
+    pub suppressed: u64, // alerts of the subject suppressed by the rate limit since the last one
+    pub timestamp: u64,  // in milliseconds
+}
+
+#[derive(Debug, Default)]
+struct State {
+    last: HashMap<(AlertKind, String), (u64, u64)>, // fired at, suppressed since
+    auth: HashMap<IpAddr, (u64, u32)>,              // window start, failures in the window
+}
+
+// Pages on-call on notable events through a webhook (a JSON POST) and/or a command
+// that reads the JSON alert on stdin. Every kind and subject fires at most once per interval.
+#[derive(Debug, Default)]
+pub struct Alerts {
+    http_client: Option<Client>,
+    webhook_url: Option<Url>,
+    exec: Vec<String>, // the program and its arguments
+    kinds: Vec<AlertKind>,
+    interval: u64,      // in milliseconds
+    auth_failures: u32, // failures from one client address within the window that fire an alert
+    auth_window: u64,   // in milliseconds
+    state: Mutex<State>,
+}
+
+impl Alerts {
+    pub fn from_vars(
+        vars: impl Iterator<Item = (String, String)>,
+        http_client: Client,
+    ) -> Result<Self, String> {
+        let mut alerts = Alerts {
+            kinds: AlertKind::ALL.to_vec(),
+            interval: 300 * 1000,
+            auth_failures: 10,
+            auth_window: 60 * 1000,
+            ..Default::default()
+        };
+        let parse = |k: &str, v: &str| {
+            v.trim()
+                .parse::<u64>()
+                .map_err(|_| format!("invalid {} value: {}", k, v))
+        };
+        for (k, v) in vars {
+            match k.as_str() {
+                "ALERT_WEBHOOK_URL" => {
+                    alerts.webhook_url = Some(
+                        Url::parse(v.trim())
+                            .map_err(|_| "invalid ALERT_WEBHOOK_URL".to_string())?,
+                    )
+                }
+                "ALERT_EXEC" => alerts.exec = v.split_whitespace().map(String::from).collect(),
+                "ALERT_EVENTS" => {
+                    alerts.kinds = v
+                        .split(',')
+                        .map(|s| s.trim())
+                        .filter(|s| !s.is_empty())
+                        .map(|s| {
+                            AlertKind::ALL
+                                .into_iter()
+                                .find(|kind| kind.as_str() == s)
+                                .ok_or_else(|| format!("unknown alert event: {}", s))
+                        })
+                        .collect::<Result<_, _>>()?
+                }
+                "ALERT_INTERVAL" => alerts.interval = parse(&k, &v)? * 1000,
+                "ALERT_AUTH_FAILURES" => alerts.auth_failures = parse(&k, &v)?.max(1) as u32,
+                "ALERT_AUTH_WINDOW" => alerts.auth_window = parse(&k, &v)?.max(1) * 1000,
+                _ => {}
+            }
+        }
+        if alerts.is_enabled() {
+            alerts.http_client = Some(http_client);
+        }
+        Ok(alerts)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.webhook_url.is_some() || !self.exec.is_empty()
+    }
+
+    // Fires an alert unless its kind is not configured or the rate limit suppresses it,
+    // the delivery never blocks the caller.
+    pub fn fire(&self, kind: AlertKind, subject: &str, message: String) {
+        if !self.is_enabled() {
+            return;
+        }
+        let Some(alert) = self.alert(kind, subject, message, unix_ms()) else {
+            return;
+        };
+        log::warn!(target: "alerts",
+            alert = alert.alert,
+            subject = alert.subject,
+            suppressed = alert.suppressed;
+            "{}", alert.message);
+
+        let payload = match serde_json::to_vec(&alert) {
+            Ok(payload) => payload,
+            Err(err) => {
+                log::error!(target: "alerts", "failed to encode alert: {}", err);
+                return;
+            }
+        };
+        if let (Some(client), Some(url)) = (&self.http_client, &self.webhook_url) {
+            let req = client
+                .post(url.clone())
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(payload.clone());
+            let log_url = redact::url(url);
+            tokio::spawn(async move {
+                match req.send().await {
+                    Ok(res) if res.status().is_success() => {}
+                    Ok(res) => {
+                        log::warn!(target: "alerts", url = log_url; "alert webhook rejected: {}", res.status())
+                    }
+                    Err(err) => {
+                        log::warn!(target: "alerts", url = log_url; "alert webhook failed: {}", err.without_url())
+                    }
+                }
+            });
+        }
+        if let Some((program, args)) = self.exec.split_first() {
+            let mut cmd = tokio::process::Command::new(program);
+            cmd.args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .kill_on_drop(true);
+            tokio::spawn(async move {
+                let res = async {
+                    let mut child = cmd.spawn()?;
+                    if let Some(mut stdin) = child.stdin.take() {
+                        stdin.write_all(&payload).await?;
+                    }
+                    child.wait().await
+                }
+                .await;
+                match res {
+                    Ok(status) if status.success() => {}
+                    Ok(status) => log::warn!(target: "alerts", "alert hook exited with {}", status),
+                    Err(err) => log::warn!(target: "alerts", "alert hook failed: {}", err),
+                }
+            });
+        }
+    }
+
+    // Counts an authentication failure from a client address, fires an alert when the
+    // failures within the window reach the threshold.
+    pub fn auth_failed(&self, ip: IpAddr) {
+        if !self.is_enabled() {
+            return;
+        }
+        if let Some(failures) = self.count_auth_failure(ip, unix_ms()) {
+            self.fire(
+                AlertKind::AuthFailures,
+                &ip.to_string(),
+                format!(
+                    "{} authentication failures from {} within {}s",
+                    failures,
+                    ip,
+                    self.auth_window / 1000
+                ),
+            );
+        }
+    }
+
+    fn count_auth_failure(&self, ip: IpAddr, now: u64) -> Option<u32> {
+        let mut state = self.state.lock().unwrap();
+        if state.auth.len() >= MAX_TRACKED && !state.auth.contains_key(&ip) {
+            let window = self.auth_window;
+            state
+                .auth
+                .retain(|_, (start, _)| start.saturating_add(window) > now);
+        }
+        let entry = state.auth.entry(ip).or_insert((now, 0));
+        if entry.0.saturating_add(self.auth_window) <= now {
+            *entry = (now, 0);
+        }
+        entry.1 += 1;
+        (entry.1 >= self.auth_failures).then_some(entry.1)
+    }
+
+    fn alert(&self, kind: AlertKind, subject: &str, message: String, now: u64) -> Option<Alert> {
+        if !self.kinds.contains(&kind) {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.last.len() >= MAX_TRACKED {
+            let interval = self.interval;
+            state
+                .last
+                .retain(|_, (at, _)| at.saturating_add(interval) > now);
+        }
+        let key = (kind, subject.to_string());
+        let suppressed = match state.last.get_mut(&key) {
+            Some((at, suppressed)) if at.saturating_add(self.interval) > now => {
+                *suppressed += 1;
+                return None;
+            }
+            Some((_, suppressed)) => *suppressed,
+            None => 0,
+        };
+        state.last.insert(key, (now, 0));
+        Some(Alert {
+            alert: kind.as_str(),
+            subject: subject.to_string(),
+            message,
+            suppressed,
+            timestamp: now,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn alerts(vars: &[(&str, &str)]) -> Result<Alerts, String> {
+        Alerts::from_vars(
+            vars.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+                .into_iter(),
+            Client::new(),
+        )
+    }
+
+    #[test]
+    fn test_alert_rate_limit() {
+        let a = alerts(&[
+            ("ALERT_WEBHOOK_URL", "https://hooks.example.com/alert"),
+            ("ALERT_EVENTS", "key_conflict, storage_unavailable"),
+            ("ALERT_INTERVAL", "60"),
+        ])
+        .unwrap();
+        assert!(a.is_enabled());
+        assert!(a
+            .alert(AlertKind::AuthFailures, "10.0.0.1", "x".to_string(), 0)
+            .is_none());
+
+        let alert = a
+            .alert(
+                AlertKind::KeyConflict,
+                "alice",
+                "conflict".to_string(),
+                1000,
+            )
+            .unwrap();
+        assert_eq!(alert.alert, "key_conflict");
+        assert_eq!(alert.suppressed, 0);
+        assert!(a
+            .alert(
+                AlertKind::KeyConflict,
+                "alice",
+                "conflict".to_string(),
+                2000
+            )
+            .is_none());
+        assert!(a
+            .alert(
+                AlertKind::KeyConflict,
+                "alice",
+                "conflict".to_string(),
+                3000
+            )
+            .is_none());
+        // other subjects have their own limit
+        assert!(a
+            .alert(AlertKind::KeyConflict, "bob", "conflict".to_string(), 3000)
+            .is_some());
+        let alert = a
+            .alert(
+                AlertKind::KeyConflict,
+                "alice",
+                "conflict".to_string(),
+                61000,
+            )
+            .unwrap();
+        assert_eq!(alert.suppressed, 2);
+
+        assert!(alerts(&[("ALERT_EVENTS", "circuit_open")]).is_err());
+        assert!(!alerts(&[]).unwrap().is_enabled());
+    }
+
+    #[test]
+    fn test_auth_failures() {
+        let a = alerts(&[
+            ("ALERT_EXEC", "/usr/local/bin/page-oncall --team proxy"),
+            ("ALERT_AUTH_FAILURES", "3"),
+            ("ALERT_AUTH_WINDOW", "10"),
+        ])
+        .unwrap();
+        assert_eq!(a.exec.len(), 3);
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        assert_eq!(a.count_auth_failure(ip, 0), None);
+        assert_eq!(a.count_auth_failure(ip, 1000), None);
+        assert_eq!(a.count_auth_failure(ip, 2000), Some(3));
+        assert_eq!(a.count_auth_failure(ip, 3000), Some(4));
+        // a new window starts over
+        assert_eq!(a.count_auth_failure(ip, 10000), None);
+        assert_eq!(
+            a.count_auth_failure("203.0.113.8".parse().unwrap(), 10000),
+            None
+        );
+    }
+}
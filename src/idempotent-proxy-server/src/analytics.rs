@@ -0,0 +1,258 @@
+use idempotent_proxy_types::unix_ms;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use crate::journal::Outcome;
+
+// upper bounds of the duplicate wait histogram buckets, in milliseconds
+const WAIT_BUCKETS: [u64; 8] = [10, 50, 100, 250, 500, 1000, 5000, 10000];
+const TRACKED_KEYS: usize = 100;
+const TOP_KEYS: usize = 10;
+const MAX_AGENTS: usize = 1000;
+const OTHER_AGENTS: &str = "*others*";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Counters {
+    pub requests: u64,
+    pub hits: u64,       // duplicates answered with the cached response
+    pub misses: u64,     // first requests forwarded to the upstream
+    pub conflicts: u64,  // duplicates that arrived while the first request was in flight
+    pub failures: u64,   // forwarded requests that failed and requests refused without storage
+    pub wait_total: u64, // time spent by duplicates waiting for a response, in milliseconds
+    pub wait_max: u64,
+}
+
+impl Counters {
+    fn add(&mut self, outcome: Outcome, elapsed: u64, waited: bool) {
+        self.requests += 1;
+        match outcome {
+            Outcome::Replayed => {
+                self.hits += 1;
+                self.wait_total += elapsed;
+                self.wait_max = self.wait_max.max(elapsed);
+                if waited {
+                    self.conflicts += 1;
+                }
+            }
+            Outcome::Rejected => self.conflicts += 1,
+            Outcome::Proxied => self.misses += 1,
+            Outcome::Failed => {
+                self.misses += 1;
+                self.failures += 1;
+            }
+            Outcome::Unavailable => self.failures += 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct WaitBucket {
+    pub le: Option<u64>, // None for the overflow bucket
+    pub count: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct KeyCount {
+    pub key: String,
+    pub duplicates: u64, // may overcount by the count of the key it evicted
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Snapshot {
+    pub since: u64, // unix timestamp in milliseconds
+    pub totals: Counters,
+    pub hit_rate: f64,
+    pub conflict_rate: f64,
+    pub avg_wait: f64, // in milliseconds
+    pub wait_histogram: Vec<WaitBucket>,
+    pub top_keys: Vec<KeyCount>,
+    pub agents: BTreeMap<String, Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    totals: Counters,
+    waits: [u64; WAIT_BUCKETS.len() + 1],
+    // Space-Saving top-k: a new key replaces the least counted one when full
+    keys: HashMap<String, u64>,
+    agents: HashMap<String, Counters>,
+}
+
+// Idempotency statistics of this instance since it started, updated on every request
+// so that reading them costs no scan.
+#[derive(Debug)]
+pub struct Analytics {
+    since: u64,
+    poll_interval: u64, // a replay slower than one poll waited for an in-flight request
+    inner: Mutex<Inner>,
+}
+
+impl Analytics {
+    pub fn new(poll_interval: u64) -> Self {
+        Analytics {
+            since: unix_ms(),
+            poll_interval,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn record(&self, agent: &str, key: &str, outcome: Outcome, elapsed: u64) {
+        let waited = elapsed >= self.poll_interval;
+        let mut inner = self.inner.lock().unwrap();
+        inner.totals.add(outcome, elapsed, waited);
+
+        let agent = if inner.agents.len() >= MAX_AGENTS && !inner.agents.contains_key(agent) {
+            OTHER_AGENTS
+        } else {
+            agent
+        };
+        match inner.agents.get_mut(agent) {
+            Some(c) => c.add(outcome, elapsed, waited),
+            None => {
+                let mut c = Counters::default();
+                c.add(outcome, elapsed, waited);
+                inner.agents.insert(agent.to_string(), c);
+            }
+        }
+
+        if matches!(outcome, Outcome::Replayed | Outcome::Rejected) {
+            if outcome == Outcome::Replayed {
+                let i = WAIT_BUCKETS
+                    .iter()
+                    .position(|&le| elapsed <= le)
+                    .unwrap_or(WAIT_BUCKETS.len());
+                inner.waits[i] += 1;
+            }
+
+            if let Some(n) = inner.keys.get_mut(key) {
+                *n += 1;
+            } else if inner.keys.len() < TRACKED_KEYS {
+                inner.keys.insert(key.to_string(), 1);
+            } else {
+                let (min_key, min) = inner
+                    .keys
+                    .iter()
+                    .min_by_key(|(_, n)| **n)
+                    .map(|(k, n)| (k.clone(), *n))
+                    .unwrap();
+                inner.keys.remove(&min_key);
+                inner.keys.insert(key.to_string(), min + 1);
+            }
+        }
+    }
+
+    // The tracked keys and agents.
+    pub fn usage(&self) -> (usize, usize) {
+        let inner = self.inner.lock().unwrap();
+        (inner.keys.len(), inner.agents.len())
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let inner = self.inner.lock().unwrap();
+        let t = &inner.totals;
+        let ratio = |n: u64, d: u64| if d == 0 { 0.0 } else { n as f64 / d as f64 };
+        let mut top_keys: Vec<KeyCount> = inner
+            .keys
+            .iter()
+            .map(|(key, n)| KeyCount {
+                key: key.clone(),
+                duplicates: *n,
+            })
+            .collect();
+        top_keys.sort_by(|a, b| b.duplicates.cmp(&a.duplicates).then(a.key.cmp(&b.key)));
+        top_keys.truncate(TOP_KEYS);
+
+        Snapshot {
+            since: self.since,
+            totals: t.clone(),
+            hit_rate: ratio(t.hits, t.requests),
+            conflict_rate: ratio(t.conflicts, t.requests),
+            avg_wait: ratio(t.wait_total, t.hits),
+            wait_histogram: inner
+                .waits
+                .iter()
+                .enumerate()
+                .map(|(i, &count)| WaitBucket {
+                    le: WAIT_BUCKETS.get(i).copied(),
+                    count,
+                })
+                .collect(),
+            top_keys,
+            agents: inner
+                .agents
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_analytics() {
+        let analytics = Analytics::new(100);
+        analytics.record("alice", "alice:POST:k1", Outcome::Proxied, 300);
+        analytics.record("alice", "alice:POST:k1", Outcome::Replayed, 2);
+        analytics.record("alice", "alice:POST:k1", Outcome::Replayed, 250);
+        analytics.record("bob", "bob:POST:k2", Outcome::Failed, 30);
+        analytics.record("bob", "bob:POST:k2", Outcome::Rejected, 0);
+
+        let s = analytics.snapshot();
+        assert_eq!(
+            s.totals,
+            Counters {
+                requests: 5,
+                hits: 2,
+                misses: 2,
+                conflicts: 2,
+                failures: 1,
+                wait_total: 252,
+                wait_max: 250,
+            }
+        );
+        assert_eq!(s.hit_rate, 0.4);
+        assert_eq!(s.conflict_rate, 0.4);
+        assert_eq!(s.avg_wait, 126.0);
+        assert_eq!(s.wait_histogram[0].count, 1);
+        assert_eq!(s.wait_histogram[3].count, 1);
+        assert_eq!(s.wait_histogram.last().unwrap().le, None);
+        assert_eq!(
+            s.top_keys,
+            vec![
+                KeyCount {
+                    key: "alice:POST:k1".to_string(),
+                    duplicates: 2
+                },
+                KeyCount {
+                    key: "bob:POST:k2".to_string(),
+                    duplicates: 1
+                }
+            ]
+        );
+        assert_eq!(s.agents["alice"].hits, 2);
+        assert_eq!(s.agents["bob"].failures, 1);
+        assert_eq!(analytics.usage(), (2, 2));
+    }
+
+    #[test]
+    fn test_top_keys_eviction() {
+        let analytics = Analytics::new(100);
+        for i in 0..TRACKED_KEYS {
+            for _ in 0..3 {
+                analytics.record("a", &format!("k{}", i), Outcome::Replayed, 0);
+            }
+        }
+        analytics.record("a", "hot", Outcome::Replayed, 0);
+        let s = analytics.snapshot();
+        assert_eq!(s.top_keys.len(), TOP_KEYS);
+        assert!(s.top_keys.iter().all(|k| k.duplicates >= 3));
+        assert_eq!(analytics.inner.lock().unwrap().keys.len(), TRACKED_KEYS);
+        assert_eq!(analytics.inner.lock().unwrap().keys["hot"], 4);
+    }
+}
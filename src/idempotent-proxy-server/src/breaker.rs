@@ -0,0 +1,266 @@
+use axum::body::Bytes;
+use http::StatusCode;
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::cache::ResponseData;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    #[default]
+    Closed,
+    Open,     // requests to the host fail fast
+    HalfOpen, // probes decide whether it closes again
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Circuit {
+    pub state: CircuitState,
+    pub requests: u64, // in the current window
+    pub errors: u64,   // in the current window
+    pub trips: u64,
+    pub rejected: u64,
+    window_start: u64,
+    opened_at: u64,
+    probing: u32,
+}
+
+// Tracks the upstream errors (5xx responses and connection failures) per host. A host
+// whose error rate in a window reaches the threshold is opened: its requests fail fast
+// with 503 before taking the idempotency lock. After `open_time` a few probes are let
+// through, the circuit closes if they succeed and opens again otherwise.
+#[derive(Debug, Default)]
+pub struct Breakers {
+    pub threshold: u64, // error rate in percent, 0 disables the breakers
+    pub min_requests: u64,
+    pub window: u64,    // in milliseconds
+    pub open_time: u64, // in milliseconds
+    pub probes: u32,
+    circuits: Mutex<BTreeMap<String, Circuit>>,
+}
+
+impl Breakers {
+    pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Self, String> {
+        let mut breakers = Breakers {
+            threshold: 0,
+            min_requests: 20,
+            window: 10_000,
+            open_time: 30_000,
+            probes: 1,
+            circuits: Mutex::new(BTreeMap::new()),
+        };
+        let parse = |k: &str, v: &str| {
+            v.trim()
+                .parse::<u64>()
+                .map_err(|_| format!("invalid {} value: {}", k, v))
+        };
+        for (k, v) in vars {
+            match k.as_str() {
+                "CIRCUIT_BREAKER_THRESHOLD" => {
+                    breakers.threshold = parse(&k, &v)?;
+                    if breakers.threshold > 100 {
+                        return Err(format!("invalid {} value: {}", k, v));
+                    }
+                }
+                "CIRCUIT_BREAKER_MIN_REQUESTS" => breakers.min_requests = parse(&k, &v)?.max(1),
+                "CIRCUIT_BREAKER_WINDOW" => breakers.window = parse(&k, &v)?.max(1) * 1000,
+                "CIRCUIT_BREAKER_OPEN_TIME" => breakers.open_time = parse(&k, &v)?.max(1) * 1000,
+                "CIRCUIT_BREAKER_PROBES" => breakers.probes = parse(&k, &v)?.clamp(1, 1000) as u32,
+                _ => {}
+            }
+        }
+        Ok(breakers)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.threshold > 0
+    }
+
+    // Checks whether a request to the host may go on, without changing the circuit.
+    // Returns the seconds to retry after if it fails fast.
+    pub fn check(&self, host: &str, now: u64) -> Result<(), u64> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = match circuits.get_mut(host) {
+            Some(circuit) => circuit,
+            None => return Ok(()),
+        };
+        let res = match circuit.state {
+            CircuitState::Closed => Ok(()),
+            CircuitState::Open if now >= circuit.opened_at + self.open_time => Ok(()),
+            CircuitState::Open => Err(self.retry_after(circuit, now)),
+            CircuitState::HalfOpen if circuit.probing < self.probes => Ok(()),
+            CircuitState::HalfOpen => Err(1),
+        };
+        if res.is_err() {
+            circuit.rejected += 1;
+        }
+        res
+    }
+
+    // Admits a request to the host, as a probe if the circuit is half open.
+    // Every admitted request must be followed by `record`.
+    pub fn allow(&self, host: &str, now: u64) -> Result<(), u64> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(host.to_string()).or_default();
+        if circuit.state == CircuitState::Open {
+            if now < circuit.opened_at + self.open_time {
+                circuit.rejected += 1;
+                return Err(self.retry_after(circuit, now));
+            }
+            circuit.state = CircuitState::HalfOpen;
+            circuit.probing = 0;
+        }
+        if circuit.state == CircuitState::HalfOpen {
+            if circuit.probing >= self.probes {
+                circuit.rejected += 1;
+                return Err(1);
+            }
+            circuit.probing += 1;
+        }
+        Ok(())
+    }
+
+    pub fn record(&self, host: &str, success: bool, now: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(host.to_string()).or_default();
+        match circuit.state {
+            CircuitState::Closed => {
+                if now >= circuit.window_start + self.window {
+                    circuit.window_start = now;
+                    circuit.requests = 0;
+                    circuit.errors = 0;
+                }
+                circuit.requests += 1;
+                if !success {
+                    circuit.errors += 1;
+                }
+                if circuit.requests >= self.min_requests
+                    && circuit.errors * 100 >= self.threshold * circuit.requests
+                {
+                    log::warn!(target: "breaker",
+                        host = host,
+                        requests = circuit.requests,
+                        errors = circuit.errors;
+                        "circuit opened");
+                    circuit.state = CircuitState::Open;
+                    circuit.opened_at = now;
+                    circuit.trips += 1;
+                }
+            }
+            CircuitState::HalfOpen if success => {
+                log::warn!(target: "breaker", host = host; "circuit closed");
+                circuit.state = CircuitState::Closed;
+                circuit.window_start = now;
+                circuit.requests = 0;
+                circuit.errors = 0;
+                circuit.probing = 0;
+            }
+            CircuitState::HalfOpen => {
+                log::warn!(target: "breaker", host = host; "circuit opened again, the probe failed");
+                circuit.state = CircuitState::Open;
+                circuit.opened_at = now;
+                circuit.probing = 0;
+                circuit.trips += 1;
+            }
+            // a request admitted before the circuit opened
+            CircuitState::Open => {}
+        }
+    }
+
+    pub fn circuits(&self) -> BTreeMap<String, Circuit> {
+        self.circuits.lock().unwrap().clone()
+    }
+
+    fn retry_after(&self, circuit: &Circuit, now: u64) -> u64 {
+        ((circuit.opened_at + self.open_time).saturating_sub(now) / 1000).max(1)
+    }
+}
+
+// The answer of a request failing fast, not cached.
+pub fn open_circuit(host: &str, retry_after: u64) -> ResponseData {
+    let mut rd = ResponseData::new(StatusCode::SERVICE_UNAVAILABLE.as_u16());
+    rd.headers.push((
+        http::header::RETRY_AFTER.to_string(),
+        retry_after.to_string(),
+    ));
+    rd.body = Bytes::from(format!("circuit breaker of upstream {} is open", host));
+    rd
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_vars;
+
+    #[test]
+    fn test_breakers() {
+        let breakers = Breakers::from_vars(test_vars(vec![])).unwrap();
+        assert!(!breakers.is_enabled());
+        breakers.record("a.com", false, 0);
+        assert!(breakers.circuits().is_empty());
+        assert!(
+            Breakers::from_vars(test_vars(vec![("CIRCUIT_BREAKER_THRESHOLD", "101")])).is_err()
+        );
+
+        let breakers = Breakers::from_vars(test_vars(vec![
+            ("CIRCUIT_BREAKER_THRESHOLD", "50"),
+            ("CIRCUIT_BREAKER_MIN_REQUESTS", "4"),
+            ("CIRCUIT_BREAKER_WINDOW", "10"),
+            ("CIRCUIT_BREAKER_OPEN_TIME", "30"),
+            ("CIRCUIT_BREAKER_PROBES", "1"),
+        ]))
+        .unwrap();
+        assert_eq!(breakers.check("a.com", 0), Ok(()));
+
+        // the errors of an old window are forgotten
+        breakers.record("a.com", false, 0);
+        breakers.record("a.com", false, 1000);
+        breakers.record("a.com", true, 2000);
+        breakers.record("a.com", true, 11_000);
+        breakers.record("a.com", false, 11_000);
+        breakers.record("a.com", true, 12_000);
+        assert_eq!(breakers.circuits()["a.com"].state, CircuitState::Closed);
+        breakers.record("a.com", false, 13_000);
+        assert_eq!(breakers.circuits()["a.com"].state, CircuitState::Open);
+        assert_eq!(breakers.check("a.com", 14_000), Err(29));
+        assert_eq!(breakers.allow("a.com", 14_000), Err(29));
+        assert_eq!(breakers.check("b.com", 14_000), Ok(()));
+
+        // one probe at a time when half open
+        assert_eq!(breakers.check("a.com", 43_000), Ok(()));
+        assert_eq!(breakers.allow("a.com", 43_000), Ok(()));
+        assert_eq!(breakers.circuits()["a.com"].state, CircuitState::HalfOpen);
+        assert_eq!(breakers.check("a.com", 43_000), Err(1));
+        assert_eq!(breakers.allow("a.com", 43_000), Err(1));
+        breakers.record("a.com", false, 44_000);
+        assert_eq!(breakers.circuits()["a.com"].state, CircuitState::Open);
+        assert_eq!(breakers.circuits()["a.com"].trips, 2);
+
+        assert_eq!(breakers.allow("a.com", 74_000), Ok(()));
+        breakers.record("a.com", true, 75_000);
+        let circuit = &breakers.circuits()["a.com"];
+        assert_eq!(circuit.state, CircuitState::Closed);
+        assert_eq!(circuit.requests, 0);
+        assert_eq!(circuit.rejected, 4);
+    }
+
+    #[test]
+    fn test_open_circuit() {
+        let rd = open_circuit("a.com", 7);
+        assert_eq!(rd.status, 503);
+        assert_eq!(
+            rd.headers,
+            vec![("retry-after".to_string(), "7".to_string())]
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use axum::body::{Body, Bytes};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use super::{HybridCacher, Storage};
+
+// every chunk starts with a tag byte, so a one byte chunk is not taken for a lock
+const CHUNK_TAG: u8 = b'c';
+
+// A large body stored in chunks next to its response, under `<key>:chunk:<n>`,
+// so neither caching nor replaying it holds the whole body in memory.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunks {
+    pub count: u32,
+    pub size: u64, // in bytes
+}
+
+pub fn chunk_key(key: &str, i: u32) -> String {
+    format!("{}:chunk:{}", key, i)
+}
+
+// Buffers a streaming body and writes every full chunk to the storage.
+pub struct ChunkWriter<'a> {
+    cacher: &'a HybridCacher,
+    key: &'a str,
+    ttl: u64,
+    chunk_size: usize,
+    buf: Vec<u8>,
+    count: u32,
+    size: u64,
+}
+
+impl<'a> ChunkWriter<'a> {
+    pub fn new(cacher: &'a HybridCacher, key: &'a str, ttl: u64, chunk_size: usize) -> Self {
+        ChunkWriter {
+            cacher,
+            key,
+            ttl,
+            chunk_size,
+            buf: vec![CHUNK_TAG],
+            count: 0,
+            size: 0,
+        }
+    }
+
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), String> {
+        self.size += data.len() as u64;
+        while !data.is_empty() {
+            let n = (self.chunk_size + 1 - self.buf.len()).min(data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.buf.len() == self.chunk_size + 1 {
+                self.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), String> {
+        let mut chunk = Vec::with_capacity(self.chunk_size + 1);
+        chunk.push(CHUNK_TAG);
+        let chunk = std::mem::replace(&mut self.buf, chunk);
+        let key = chunk_key(self.key, self.count);
+        // the storage only sets obtained keys
+        self.cacher.obtain(&key, self.ttl).await?;
+        self.cacher.set(&key, chunk, self.ttl).await?;
+        self.count += 1;
+        Ok(())
+    }
+
+    // Returns the body itself if it fits in one chunk, it is stored inline then.
+    pub fn inline(&mut self) -> Option<Bytes> {
+        if self.count == 0 {
+            Some(Bytes::from(std::mem::take(&mut self.buf)).slice(1..))
+        } else {
+            None
+        }
+    }
+
+    pub async fn finish(&mut self) -> Result<Chunks, String> {
+        if self.buf.len() > 1 {
+            self.flush().await?;
+        }
+        Ok(Chunks {
+            count: self.count,
+            size: self.size,
+        })
+    }
+
+    // Deletes the chunks written so far, the response will not be cached.
+    pub async fn abort(&self) {
+        for i in 0..self.count {
+            let _ = self.cacher.del(&chunk_key(self.key, i)).await;
+        }
+    }
+}
+
+// Reads all the chunks into memory, for consumers that need the whole body.
+pub async fn read_chunks(
+    cacher: &HybridCacher,
+    key: &str,
+    chunks: Chunks,
+) -> Result<Bytes, String> {
+    let mut body = Vec::with_capacity(chunks.size as usize);
+    for i in 0..chunks.count {
+        match cacher.get(&chunk_key(key, i)).await? {
+            Some(chunk) => body.extend_from_slice(&chunk[1..]),
+            None => return Err(format!("missing chunk {} of {}", i, key)),
+        }
+    }
+    Ok(Bytes::from(body))
+}
+
+// Streams the chunks from the storage one by one, a missing chunk (expired or purged)
+// aborts the response.
+pub fn chunked_body(cacher: Arc<HybridCacher>, key: String, chunks: Chunks) -> Body {
+    let stream = futures::stream::unfold(0u32, move |i| {
+        let cacher = cacher.clone();
+        let key = key.clone();
+        async move {
+            if i >= chunks.count {
+                return None;
+            }
+            let res = match cacher.get(&chunk_key(&key, i)).await {
+                Ok(Some(chunk)) => Ok(Bytes::from(chunk).slice(1..)),
+                Ok(None) => Err(std::io::Error::other(format!(
+                    "missing chunk {} of {}",
+                    i, key
+                ))),
+                Err(err) => Err(std::io::Error::other(err)),
+            };
+            // stop after an error
+            let next = if res.is_ok() { i + 1 } else { chunks.count };
+            Some((res, next))
+        }
+    });
+    Body::from_stream(stream)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{CacherEntry, MemoryCacher};
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn test_chunks() {
+        let cacher = Arc::new(HybridCacher::new(
+            10,
+            1000,
+            CacherEntry::Memory(MemoryCacher::default()),
+        ));
+        let mut w = ChunkWriter::new(&cacher, "k", 1000, 4);
+        w.write(b"hello").await.unwrap();
+        w.write(b", world").await.unwrap();
+        assert!(w.inline().is_none());
+        let chunks = w.finish().await.unwrap();
+        assert_eq!(chunks, Chunks { count: 3, size: 12 });
+        assert_eq!(cacher.get("k:chunk:0").await.unwrap().unwrap(), b"chell");
+
+        let body = read_chunks(&cacher, "k", chunks).await.unwrap();
+        assert_eq!(&body[..], b"hello, world");
+        let body = chunked_body(cacher.clone(), "k".to_string(), chunks)
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes();
+        assert_eq!(&body[..], b"hello, world");
+
+        let mut w = ChunkWriter::new(&cacher, "small", 1000, 4);
+        w.write(b"hi").await.unwrap();
+        assert_eq!(w.inline().unwrap(), Bytes::from_static(b"hi"));
+
+        let mut w = ChunkWriter::new(&cacher, "aborted", 1000, 4);
+        w.write(b"hello, world").await.unwrap();
+        w.abort().await;
+        assert!(cacher.get("aborted:chunk:0").await.unwrap().is_none());
+        assert!(read_chunks(&cacher, "aborted", chunks).await.is_err());
+        assert!(chunked_body(cacher.clone(), "aborted".to_string(), chunks)
+            .collect()
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use async_trait::async_trait;
+use aws_sdk_dynamodb::{
+    error::{DisplayErrorContext, SdkError},
+    primitives::Blob,
+    types::AttributeValue,
+    Client,
+};
+use idempotent_proxy_types::unix_ms;
+use std::collections::HashMap;
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::{sleep, Duration},
+};
+
+use super::{Storage, POLLING_TIMEOUT};
+
+// Stores the locks and responses in a DynamoDB table with a string partition key `key`.
+// A lock is an item without a value. Conditional writes make the first request win,
+// `expire_at` (in milliseconds) decides expiry and `ttl` (in seconds) lets the table's
+// TTL setting delete expired items eventually.
+pub struct DynamoClient {
+    client: Client,
+    table: String,
+    // DynamoDB has no pub/sub, notifications stay in the process
+    events: broadcast::Sender<(String, Vec<u8>)>,
+}
+
+impl DynamoClient {
+    // Credentials and region come from the standard AWS environment,
+    // the endpoint can point to DynamoDB Local.
+    pub async fn new(table: &str, endpoint: Option<&str>) -> Self {
+        let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+        if let Some(endpoint) = endpoint {
+            loader = loader.endpoint_url(endpoint);
+        }
+        DynamoClient {
+            client: Client::new(&loader.load().await),
+            table: table.to_string(),
+            events: broadcast::channel(64).0,
+        }
+    }
+
+    // Returns the item of a live key, the value is None while it is a lock.
+    async fn get_raw(&self, key: &str) -> Result<Option<Option<Vec<u8>>>, String> {
+        let res = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .key("key", AttributeValue::S(key.to_string()))
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(sdk_error)?;
+        Ok(res.item.and_then(|item| live_value(&item, unix_ms())))
+    }
+}
+
+fn sdk_error<E, R>(err: SdkError<E, R>) -> String
+where
+    E: std::error::Error + 'static,
+    R: std::fmt::Debug,
+{
+    match err {
+        SdkError::DispatchFailure(_) | SdkError::TimeoutError(_) => {
+            format!("storage unavailable: {}", DisplayErrorContext(&err))
+        }
+        SdkError::ServiceError(err) => err.err().to_string(),
+        err => DisplayErrorContext(&err).to_string(),
+    }
+}
+
+fn live_value(item: &HashMap<String, AttributeValue>, now: u64) -> Option<Option<Vec<u8>>> {
+    let expire_at: u64 = item.get("expire_at")?.as_n().ok()?.parse().ok()?;
+    if expire_at <= now {
+        return None;
+    }
+    Some(
+        item.get("value")
+            .and_then(|v| v.as_b().ok())
+            .map(|b| b.as_ref().to_vec()),
+    )
+}
+
+fn expiry(now: u64, ttl: u64) -> (AttributeValue, AttributeValue) {
+    let expire_at = now + ttl;
+    (
+        AttributeValue::N(expire_at.to_string()),
+        AttributeValue::N(expire_at.div_ceil(1000).to_string()),
+    )
+}
+
+#[async_trait]
+impl Storage for DynamoClient {
+    async fn obtain(&self, key: &str, ttl: u64) -> Result<bool, String> {
+        let now = unix_ms();
+        let (expire_at, ttl) = expiry(now, ttl);
+        let res = self
+            .client
+            .put_item()
+            .table_name(&self.table)
+            .item("key", AttributeValue::S(key.to_string()))
+            .item("expire_at", expire_at)
+            .item("ttl", ttl)
+            .condition_expression("attribute_not_exists(#k) OR expire_at <= :now")
+            .expression_attribute_names("#k", "key")
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+            .send()
+            .await;
+        match res {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError(err))
+                if err.err().is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(sdk_error(err)),
+        }
+    }
+
+    async fn polling_get_with(
+        &self,
+        key: &str,
+        delays: &mut (dyn Iterator<Item = u64> + Send),
+    ) -> Result<Vec<u8>, String> {
+        loop {
+            match self.get_raw(key).await? {
+                None => return Err("not obtained".to_string()),
+                Some(Some(val)) => return Ok(val),
+                Some(None) => {}
+            }
+
+            match delays.next() {
+                Some(delay) => sleep(Duration::from_millis(delay)).await,
+                None => return Err(POLLING_TIMEOUT.to_string()),
+            }
+        }
+    }
+
+    // An item is at most 400 KB, larger bodies need route STREAM_CHUNK_SIZE below it.
+    async fn set(&self, key: &str, val: Vec<u8>, ttl: u64) -> Result<bool, String> {
+        let now = unix_ms();
+        let (expire_at, ttl) = expiry(now, ttl);
+        let res = self
+            .client
+            .update_item()
+            .table_name(&self.table)
+            .key("key", AttributeValue::S(key.to_string()))
+            .update_expression("SET #v = :v, expire_at = :e, #t = :t")
+            .condition_expression("attribute_exists(#k) AND expire_at > :now")
+            .expression_attribute_names("#k", "key")
+            .expression_attribute_names("#v", "value")
+            .expression_attribute_names("#t", "ttl")
+            .expression_attribute_values(":v", AttributeValue::B(Blob::new(val)))
+            .expression_attribute_values(":e", expire_at)
+            .expression_attribute_values(":t", ttl)
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+            .send()
+            .await;
+        match res {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError(err))
+                if err.err().is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(sdk_error(err)),
+        }
+    }
+
+    async fn renew(&self, key: &str, ttl: u64) -> Result<bool, String> {
+        let now = unix_ms();
+        let (expire_at, ttl) = expiry(now, ttl);
+        let res = self
+            .client
+            .update_item()
+            .table_name(&self.table)
+            .key("key", AttributeValue::S(key.to_string()))
+            .update_expression("SET expire_at = :e, #t = :t")
+            .condition_expression(
+                "attribute_exists(#k) AND attribute_not_exists(#v) AND expire_at > :now",
+            )
+            .expression_attribute_names("#k", "key")
+            .expression_attribute_names("#v", "value")
+            .expression_attribute_names("#t", "ttl")
+            .expression_attribute_values(":e", expire_at)
+            .expression_attribute_values(":t", ttl)
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+            .send()
+            .await;
+        match res {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError(err))
+                if err.err().is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(sdk_error(err)),
+        }
+    }
+
+    async fn del(&self, key: &str) -> Result<(), String> {
+        self.client
+            .delete_item()
+            .table_name(&self.table)
+            .key("key", AttributeValue::S(key.to_string()))
+            .send()
+            .await
+            .map_err(sdk_error)?;
+        Ok(())
+    }
+
+    // A scan of the whole table, only used by admin and maintenance calls.
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let mut keys = Vec::new();
+        let mut start: Option<HashMap<String, AttributeValue>> = None;
+        loop {
+            let res = self
+                .client
+                .scan()
+                .table_name(&self.table)
+                .filter_expression("begins_with(#k, :p) AND expire_at > :now")
+                .expression_attribute_names("#k", "key")
+                .expression_attribute_values(":p", AttributeValue::S(prefix.to_string()))
+                .expression_attribute_values(":now", AttributeValue::N(unix_ms().to_string()))
+                .projection_expression("#k")
+                .set_exclusive_start_key(start)
+                .send()
+                .await
+                .map_err(sdk_error)?;
+            keys.extend(
+                res.items()
+                    .iter()
+                    .filter_map(|item| item.get("key")?.as_s().ok().cloned()),
+            );
+            match res.last_evaluated_key {
+                Some(last) if !last.is_empty() => start = Some(last),
+                _ => return Ok(keys),
+            }
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.get_raw(key).await?.flatten())
+    }
+
+    async fn publish(&self, channel: &str, msg: Vec<u8>) -> Result<(), String> {
+        // no subscriber is not an error
+        let _ = self.events.send((channel.to_string(), msg));
+        Ok(())
+    }
+
+    async fn subscribe(&self, channel: &str) -> Result<mpsc::Receiver<Vec<u8>>, String> {
+        let mut events = self.events.subscribe();
+        let channel = channel.to_string();
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok((ch, msg)) if ch == channel => {
+                        if tx.send(msg).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_live_value() {
+        let mut item = HashMap::from([
+            ("key".to_string(), AttributeValue::S("k".to_string())),
+            (
+                "expire_at".to_string(),
+                AttributeValue::N("2000".to_string()),
+            ),
+        ]);
+        assert_eq!(live_value(&item, 1000), Some(None));
+        assert_eq!(live_value(&item, 2000), None);
+        item.insert(
+            "value".to_string(),
+            AttributeValue::B(Blob::new(vec![1, 2])),
+        );
+        assert_eq!(live_value(&item, 1000), Some(Some(vec![1, 2])));
+        item.remove("expire_at");
+        assert_eq!(live_value(&item, 1000), None);
+
+        let (expire_at, ttl) = expiry(1000, 1500);
+        assert_eq!(expire_at, AttributeValue::N("2500".to_string()));
+        assert_eq!(ttl, AttributeValue::N("3".to_string()));
+    }
+}
@@ -1,38 +1,168 @@
 use async_trait::async_trait;
 use idempotent_proxy_types::unix_ms;
 use std::{
-    collections::{
-        hash_map::{Entry, HashMap},
-        BTreeSet,
-    },
-    sync::Arc,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Arc, Mutex},
 };
 use tokio::{
-    sync::RwLock,
+    sync::{broadcast, mpsc, RwLock},
     time::{sleep, Duration},
 };
 
-use super::Cacher;
+use super::{SqliteStore, Storage, POLLING_TIMEOUT};
+use crate::shards::{Sharded, DEFAULT_SHARDS};
 
 #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
 struct PriorityKey(u64, String);
 
 type KV = HashMap<String, (u64, Vec<u8>)>;
 
-#[derive(Clone, Default)]
+// The access order of the keys of a shard, the least recently used come first.
+#[derive(Default)]
+struct Recency {
+    tick: u64,
+    ticks: HashMap<String, u64>,
+    order: BTreeMap<u64, String>,
+}
+
+impl Recency {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        if let Some(tick) = self.ticks.insert(key.to_string(), self.tick) {
+            self.order.remove(&tick);
+        }
+        self.order.insert(self.tick, key.to_string());
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shard {
+    priority_queue: RwLock<BTreeSet<PriorityKey>>,
+    kv: RwLock<KV>,
+    recency: Mutex<Recency>,
+}
+
+#[derive(Clone)]
 pub struct MemoryCacher {
-    priority_queue: Arc<RwLock<BTreeSet<PriorityKey>>>,
-    kv: Arc<RwLock<KV>>,
+    // locks and values are spread over shards by key hash
+    shards: Arc<Sharded<Shard>>,
+    // notifications stay in the process, there is no other instance sharing the storage
+    events: broadcast::Sender<(String, Vec<u8>)>,
+    // per shard, the least recently used responses are evicted beyond it, 0 is unbounded
+    max_entries: usize,
+    persist: Option<SqliteStore>,
+}
+
+impl Default for MemoryCacher {
+    fn default() -> Self {
+        Self::new(DEFAULT_SHARDS)
+    }
 }
 
 impl MemoryCacher {
-    fn clean_expired_values(&self) -> tokio::task::JoinHandle<()> {
-        let kv = self.kv.clone();
-        let priority_queue = self.priority_queue.clone();
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: Arc::new(Sharded::new(shards, Shard::default)),
+            events: broadcast::channel(64).0,
+            max_entries: 0,
+            persist: None,
+        }
+    }
+
+    // Bounds the stored entries, spread evenly over the shards. Locks are never evicted,
+    // so a shard can exceed its bound while many requests are in flight.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        if max_entries > 0 {
+            self.max_entries = max_entries.div_ceil(self.shards.iter().count());
+        }
+        self
+    }
+
+    // Persists the cached responses to a SQLite file and loads those that have not expired.
+    pub async fn with_sqlite(mut self, path: &str) -> Result<Self, String> {
+        let (store, rows) = SqliteStore::open(path)?;
+        for (key, expire_at, value) in rows {
+            let shard = self.shards.get(&key);
+            let mut kv = shard.kv.write().await;
+            let mut pq = shard.priority_queue.write().await;
+            if let Some((expire_at, _)) = kv.insert(key.clone(), (expire_at, value)) {
+                pq.remove(&PriorityKey(expire_at, key.clone()));
+            }
+            pq.insert(PriorityKey(expire_at, key.clone()));
+            self.touch(shard, &key);
+            self.evict(shard, &mut kv, &mut pq, &key);
+        }
+        self.persist = Some(store);
+        Ok(self)
+    }
+
+    // The stored entries and their approximate size in bytes.
+    pub async fn usage(&self) -> (usize, usize) {
+        let mut usage = (0, 0);
+        for shard in self.shards.iter() {
+            let kv = shard.kv.read().await;
+            usage.0 += kv.len();
+            usage.1 += kv
+                .iter()
+                .map(|(k, (_, v))| k.len() + v.len() + 8)
+                .sum::<usize>();
+        }
+        usage
+    }
+
+    fn touch(&self, shard: &Shard, key: &str) {
+        if self.max_entries > 0 {
+            shard.recency.lock().unwrap().touch(key);
+        }
+    }
+
+    // Evicts the least recently used responses of a shard beyond the bound but the
+    // key just written, locks of requests in flight stay.
+    fn evict(&self, shard: &Shard, kv: &mut KV, pq: &mut BTreeSet<PriorityKey>, keep: &str) {
+        if self.max_entries == 0 || kv.len() <= self.max_entries {
+            return;
+        }
+        let now = unix_ms();
+        let mut recency = shard.recency.lock().unwrap();
+        let victims: Vec<String> = recency
+            .order
+            .values()
+            .filter(|key| {
+                key.as_str() != keep
+                    && kv
+                        .get(*key)
+                        .is_some_and(|(expire_at, value)| !value.is_empty() || *expire_at <= now)
+            })
+            .take(kv.len() - self.max_entries)
+            .cloned()
+            .collect();
+        for key in victims {
+            recency.remove(&key);
+            if let Some((expire_at, _)) = kv.remove(&key) {
+                pq.remove(&PriorityKey(expire_at, key.clone()));
+            }
+            if let Some(persist) = &self.persist {
+                persist.delete(&key);
+            }
+        }
+    }
+
+    // Cleans the shard of the key.
+    fn clean_expired_values(&self, key: &str) -> tokio::task::JoinHandle<()> {
+        let shards = self.shards.clone();
+        let i = shards.index(key);
         tokio::spawn(async move {
+            let shard = shards.at(i);
             let now = unix_ms();
-            let mut pq = priority_queue.write().await;
-            let mut kv = kv.write().await;
+            let mut pq = shard.priority_queue.write().await;
+            let mut kv = shard.kv.write().await;
+            let mut recency = shard.recency.lock().unwrap();
             while let Some(PriorityKey(expire_at, key)) = pq.pop_first() {
                 if expire_at > now {
                     pq.insert(PriorityKey(expire_at, key));
@@ -40,110 +170,192 @@ impl MemoryCacher {
                 }
 
                 kv.remove(&key);
+                recency.remove(&key);
             }
         })
     }
 }
 
 #[async_trait]
-impl Cacher for MemoryCacher {
+impl Storage for MemoryCacher {
     async fn obtain(&self, key: &str, ttl: u64) -> Result<bool, String> {
-        let mut kv = self.kv.write().await;
+        let shard = self.shards.get(key);
+        let mut kv = shard.kv.write().await;
         let now = unix_ms();
-        match kv.entry(key.to_string()) {
-            Entry::Occupied(mut entry) => {
-                let (expire_at, value) = entry.get_mut();
-                if *expire_at > now {
-                    return Ok(false);
-                }
-
-                let mut pq = self.priority_queue.write().await;
-                pq.remove(&PriorityKey(*expire_at, key.to_string()));
+        if kv.get(key).is_some_and(|(expire_at, _)| *expire_at > now) {
+            return Ok(false);
+        }
 
-                *expire_at = now + ttl;
-                *value = vec![];
-                pq.insert(PriorityKey(*expire_at, key.to_string()));
-                Ok(true)
-            }
-            Entry::Vacant(entry) => {
-                let expire_at = now + ttl;
-                entry.insert((expire_at, vec![]));
-                self.priority_queue
-                    .write()
-                    .await
-                    .insert(PriorityKey(expire_at, key.to_string()));
-                Ok(true)
-            }
+        let mut pq = shard.priority_queue.write().await;
+        if let Some((expire_at, _)) = kv.insert(key.to_string(), (now + ttl, vec![])) {
+            pq.remove(&PriorityKey(expire_at, key.to_string()));
         }
+        pq.insert(PriorityKey(now + ttl, key.to_string()));
+        self.touch(shard, key);
+        self.evict(shard, &mut kv, &mut pq, key);
+        Ok(true)
     }
 
-    async fn polling_get(
+    async fn polling_get_with(
         &self,
         key: &str,
-        poll_interval: u64,
-        mut counter: u64,
+        delays: &mut (dyn Iterator<Item = u64> + Send),
     ) -> Result<Vec<u8>, String> {
-        while counter > 0 {
-            let kv = self.kv.read().await;
-            let res = kv.get(key);
-            match res {
-                None => return Err("not obtained".to_string()),
-                Some((expire_at, value)) => {
-                    if *expire_at <= unix_ms() {
-                        self.clean_expired_values();
-                    }
+        let shard = self.shards.get(key);
+        loop {
+            {
+                let kv = shard.kv.read().await;
+                match kv.get(key) {
+                    None => return Err("not obtained".to_string()),
+                    Some((expire_at, value)) => {
+                        if *expire_at <= unix_ms() {
+                            self.clean_expired_values(key);
+                        }
 
-                    if !value.is_empty() {
-                        return Ok(value.clone());
+                        if !value.is_empty() {
+                            self.touch(shard, key);
+                            return Ok(value.clone());
+                        }
                     }
                 }
             }
 
-            counter -= 1;
-            sleep(Duration::from_millis(poll_interval)).await;
+            match delays.next() {
+                Some(delay) => sleep(Duration::from_millis(delay)).await,
+                None => return Err(POLLING_TIMEOUT.to_string()),
+            }
         }
-
-        Err(("polling get cache timeout").to_string())
     }
 
     async fn set(&self, key: &str, val: Vec<u8>, ttl: u64) -> Result<bool, String> {
-        let mut kv = self.kv.write().await;
+        let shard = self.shards.get(key);
+        let mut kv = shard.kv.write().await;
         match kv.get_mut(key) {
             Some((expire_at, value)) => {
                 let now = unix_ms();
                 if *expire_at <= now {
                     kv.remove(key);
-                    self.clean_expired_values();
+                    shard.recency.lock().unwrap().remove(key);
+                    self.clean_expired_values(key);
                     return Err("value expired".to_string());
                 }
 
-                let mut pq = self.priority_queue.write().await;
+                let mut pq = shard.priority_queue.write().await;
                 pq.remove(&PriorityKey(*expire_at, key.to_string()));
 
                 *expire_at = now + ttl;
+                if let Some(persist) = &self.persist {
+                    persist.put(key, *expire_at, val.clone());
+                }
                 *value = val;
                 pq.insert(PriorityKey(*expire_at, key.to_string()));
+                self.touch(shard, key);
+                self.evict(shard, &mut kv, &mut pq, key);
                 Ok(true)
             }
             None => Err("not obtained".to_string()),
         }
     }
 
+    async fn renew(&self, key: &str, ttl: u64) -> Result<bool, String> {
+        let shard = self.shards.get(key);
+        let mut kv = shard.kv.write().await;
+        let now = unix_ms();
+        match kv.get_mut(key) {
+            Some((expire_at, value)) if *expire_at > now && value.is_empty() => {
+                let mut pq = shard.priority_queue.write().await;
+                pq.remove(&PriorityKey(*expire_at, key.to_string()));
+                *expire_at = now + ttl;
+                pq.insert(PriorityKey(*expire_at, key.to_string()));
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
     async fn del(&self, key: &str) -> Result<(), String> {
-        let mut kv = self.kv.write().await;
+        let shard = self.shards.get(key);
+        let mut kv = shard.kv.write().await;
         if let Some(val) = kv.remove(key) {
-            let mut pq = self.priority_queue.write().await;
+            let mut pq = shard.priority_queue.write().await;
             pq.remove(&PriorityKey(val.0, key.to_string()));
         }
-        self.clean_expired_values();
+        shard.recency.lock().unwrap().remove(key);
+        if let Some(persist) = &self.persist {
+            persist.delete(key);
+        }
+        self.clean_expired_values(key);
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let now = unix_ms();
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            let kv = shard.kv.read().await;
+            keys.extend(
+                kv.iter()
+                    .filter(|(k, (expire_at, _))| *expire_at > now && k.starts_with(prefix))
+                    .map(|(k, _)| k.clone()),
+            );
+        }
+        Ok(keys)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let shard = self.shards.get(key);
+        let kv = shard.kv.read().await;
+        let value = kv
+            .get(key)
+            .filter(|(expire_at, value)| *expire_at > unix_ms() && !value.is_empty())
+            .map(|(_, value)| value.clone());
+        if value.is_some() {
+            self.touch(shard, key);
+        }
+        Ok(value)
+    }
+
+    async fn publish(&self, channel: &str, msg: Vec<u8>) -> Result<(), String> {
+        // no subscriber is not an error
+        let _ = self.events.send((channel.to_string(), msg));
         Ok(())
     }
+
+    async fn subscribe(&self, channel: &str) -> Result<mpsc::Receiver<Vec<u8>>, String> {
+        let mut events = self.events.subscribe();
+        let channel = channel.to_string();
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok((ch, msg)) if ch == channel => {
+                        if tx.send(msg).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+        Ok(rx)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    // the number of values and of expiry entries in all shards
+    async fn sizes(mc: &MemoryCacher) -> (usize, usize) {
+        let mut sizes = (0, 0);
+        for shard in mc.shards.iter() {
+            sizes.0 += shard.kv.read().await.len();
+            sizes.1 += shard.priority_queue.read().await.len();
+        }
+        sizes
+    }
+
     #[tokio::test]
     async fn memory_cacher() {
         let mc = MemoryCacher::default();
@@ -163,6 +375,10 @@ mod test {
             vec![1, 2, 3, 4]
         );
 
+        assert_eq!(mc.keys("key").await.unwrap(), vec!["key1".to_string()]);
+        assert!(mc.keys("other").await.unwrap().is_empty());
+        assert_eq!(mc.usage().await, (1, 16));
+
         assert!(mc.del("key").await.is_ok());
         assert!(mc.del("key1").await.is_ok());
         assert!(mc.polling_get("key1", 10, 2).await.is_err());
@@ -181,8 +397,8 @@ mod test {
 
         assert!(mc.obtain("key1", 100).await.unwrap());
         sleep(Duration::from_millis(200)).await;
-        let _ = mc.clean_expired_values().await;
-        println!("{:?}", mc.priority_queue.read().await);
+        mc.clean_expired_values("key1").await.unwrap();
+        assert_eq!(sizes(&mc).await, (0, 0));
 
         let res = futures::try_join!(
             mc.obtain("key1", 100),
@@ -195,15 +411,92 @@ mod test {
             _ => panic!("unexpected result"),
         }
 
-        assert_eq!(mc.kv.read().await.len(), 1);
-        assert_eq!(mc.priority_queue.read().await.len(), 1);
+        assert_eq!(sizes(&mc).await, (1, 1));
 
         sleep(Duration::from_millis(200)).await;
-        assert_eq!(mc.kv.read().await.len(), 1);
-        assert_eq!(mc.priority_queue.read().await.len(), 1);
-        let _ = mc.clean_expired_values().await;
+        assert_eq!(sizes(&mc).await, (1, 1));
+        let _ = mc.clean_expired_values("key1").await;
+
+        assert_eq!(sizes(&mc).await, (0, 0));
+    }
+
+    #[tokio::test]
+    async fn memory_shards() {
+        let mc = MemoryCacher::new(4);
+        for i in 0..100 {
+            let key = format!("alice:POST:key_{}", i);
+            assert!(mc.obtain(&key, 1000).await.unwrap());
+            assert!(mc.set(&key, vec![i as u8 + 1], 1000).await.unwrap());
+        }
+        assert_eq!(sizes(&mc).await, (100, 100));
+        assert!(mc
+            .shards
+            .iter()
+            .all(|s| s.kv.try_read().unwrap().len() < 100));
+        assert_eq!(mc.keys("alice:").await.unwrap().len(), 100);
+        assert_eq!(mc.get("alice:POST:key_7").await.unwrap(), Some(vec![8]));
+        mc.del("alice:POST:key_7").await.unwrap();
+        assert_eq!(mc.keys("alice:").await.unwrap().len(), 99);
+    }
+
+    #[tokio::test]
+    async fn memory_get_and_pubsub() {
+        let mc = MemoryCacher::default();
+        assert_eq!(mc.get("key1").await.unwrap(), None);
+        assert!(mc.obtain("key1", 100).await.unwrap());
+        assert_eq!(mc.get("key1").await.unwrap(), None);
+        assert!(mc.set("key1", vec![1, 2], 100).await.is_ok());
+        assert_eq!(mc.get("key1").await.unwrap(), Some(vec![1, 2]));
+
+        let mut rx = mc.subscribe("events").await.unwrap();
+        mc.publish("other", vec![0]).await.unwrap();
+        mc.publish("events", vec![1]).await.unwrap();
+        assert_eq!(rx.recv().await, Some(vec![1]));
+    }
+
+    #[tokio::test]
+    async fn memory_lru() {
+        let mc = MemoryCacher::new(1).with_max_entries(2);
+        for key in ["a", "b"] {
+            assert!(mc.obtain(key, 1000).await.unwrap());
+            assert!(mc.set(key, vec![1], 1000).await.unwrap());
+        }
+        assert_eq!(mc.get("a").await.unwrap(), Some(vec![1]));
+        assert!(mc.obtain("c", 1000).await.unwrap());
+        // b is the least recently used response
+        assert_eq!(mc.get("b").await.unwrap(), None);
+        assert_eq!(mc.get("a").await.unwrap(), Some(vec![1]));
+
+        // locks are never evicted
+        assert!(mc.obtain("d", 1000).await.unwrap());
+        assert!(mc.obtain("e", 1000).await.unwrap());
+        assert_eq!(sizes(&mc).await, (3, 3));
+        assert!(!mc.obtain("c", 1000).await.unwrap());
+        assert!(mc.set("e", vec![2], 1000).await.unwrap());
+        assert_eq!(mc.get("e").await.unwrap(), Some(vec![2]));
+    }
+
+    #[tokio::test]
+    async fn memory_sqlite() {
+        let path = std::env::temp_dir().join(format!("idempotent-proxy-{}.db", unix_ms()));
+        let path = path.to_str().unwrap();
+        {
+            let mc = MemoryCacher::default().with_sqlite(path).await.unwrap();
+            assert!(mc.obtain("key1", 10000).await.unwrap());
+            assert!(mc.set("key1", vec![1, 2], 10000).await.unwrap());
+            assert!(mc.obtain("key2", 10000).await.unwrap());
+            assert!(mc.set("key2", vec![3], 10000).await.unwrap());
+            mc.del("key2").await.unwrap();
+            // an in-flight lock is not persisted
+            assert!(mc.obtain("key3", 10000).await.unwrap());
+        }
+        sleep(Duration::from_millis(200)).await;
 
-        assert!(mc.kv.read().await.is_empty());
-        assert!(mc.priority_queue.read().await.is_empty());
+        let mc = MemoryCacher::default().with_sqlite(path).await.unwrap();
+        assert_eq!(mc.get("key1").await.unwrap(), Some(vec![1, 2]));
+        assert_eq!(mc.get("key2").await.unwrap(), None);
+        assert!(mc.obtain("key3", 10000).await.unwrap());
+        drop(mc);
+        let _ = std::fs::remove_file(path);
     }
 }
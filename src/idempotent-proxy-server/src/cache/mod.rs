@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 use axum::{
-    body::Body,
+    body::{Body, Bytes},
     response::{IntoResponse, Response},
 };
 use ciborium::{from_reader, into_writer, Value};
@@ -8,20 +8,43 @@ use http::{
     header::{HeaderMap, HeaderName, HeaderValue},
     StatusCode,
 };
+use http_body_util::{BodyExt, Full};
 use idempotent_proxy_types::err_string;
 use serde::{Deserialize, Serialize};
-use serde_bytes::ByteBuf;
+use std::{
+    convert::Infallible,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::sync::mpsc;
+
+use crate::json_mask::JsonMask;
 
+mod chunks;
+#[cfg(feature = "dynamodb")]
+mod dynamodb;
 mod memory;
+mod postgres;
 mod redis;
+mod replicated;
+mod sqlite;
 
+pub use chunks::*;
+#[cfg(feature = "dynamodb")]
+pub use dynamodb::*;
 pub use memory::*;
+pub use postgres::*;
 pub use redis::*;
+pub use replicated::*;
+pub use sqlite::*;
+
+// the error of polling for a key that is still locked when the poll delays run out
+pub const POLLING_TIMEOUT: &str = "polling get cache timeout";
 
 pub struct HybridCacher {
     pub poll_interval: u64,
     pub cache_ttl: u64,
     cache: CacherEntry,
+    errors: AtomicU64,
 }
 
 impl HybridCacher {
@@ -30,6 +53,30 @@ impl HybridCacher {
             poll_interval,
             cache_ttl,
             cache,
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    // The failed storage operations, polls that time out or find no lock are not counted.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    fn counted<T>(&self, res: Result<T, String>) -> Result<T, String> {
+        if res.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    // The entries stored in this process and their approximate size, None for external stores.
+    pub async fn memory_usage(&self) -> Option<(usize, usize)> {
+        match &self.cache {
+            CacherEntry::Memory(cacher) => Some(cacher.usage().await),
+            CacherEntry::Replicated(cacher) => Box::pin(cacher.local().memory_usage()).await,
+            #[cfg(feature = "dynamodb")]
+            CacherEntry::DynamoDb(_) => None,
+            CacherEntry::Redis(_) | CacherEntry::Postgres(_) | CacherEntry::Custom(_) => None,
         }
     }
 }
@@ -37,54 +84,177 @@ impl HybridCacher {
 pub enum CacherEntry {
     Memory(MemoryCacher),
     Redis(RedisClient),
+    Postgres(PostgresClient),
+    #[cfg(feature = "dynamodb")]
+    DynamoDb(DynamoClient),
+    Custom(Box<dyn Storage>), // a backend provided by an application embedding the proxy
+    Replicated(Box<Replicated>), // a regional backend replicated between regions
 }
 
+// The storage backend of the idempotency locks and cached responses. Keys expire after
+// their ttl, a lock is a key without a value yet.
 #[async_trait]
-pub trait Cacher {
+pub trait Storage: Send + Sync {
+    // Locks a key that does not exist, returns false if it exists.
     async fn obtain(&self, key: &str, ttl_ms: u64) -> Result<bool, String>;
     async fn polling_get(
         &self,
         key: &str,
         poll_interval_ms: u64,
         counter: u64,
+    ) -> Result<Vec<u8>, String> {
+        self.polling_get_with(
+            key,
+            &mut std::iter::repeat_n(poll_interval_ms, counter as usize),
+        )
+        .await
+    }
+    // Polls a locked key until it has a value, sleeping the next delay (in milliseconds)
+    // between the polls, fails with POLLING_TIMEOUT when the delays run out.
+    async fn polling_get_with(
+        &self,
+        key: &str,
+        delays: &mut (dyn Iterator<Item = u64> + Send),
     ) -> Result<Vec<u8>, String>;
+    // Sets the value of an existing key and its new ttl, returns false if it does not exist.
     async fn set(&self, key: &str, val: Vec<u8>, ttl_ms: u64) -> Result<bool, String>;
+    // Extends the ttl of a key that is still a lock, returns false if it is not.
+    async fn renew(&self, _key: &str, _ttl_ms: u64) -> Result<bool, String> {
+        Ok(false)
+    }
     async fn del(&self, key: &str) -> Result<(), String>;
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, String>;
+    // Returns the value of a key, None if it does not exist or is still a lock.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String>;
+    // Change notifications between the instances sharing the storage.
+    async fn publish(&self, channel: &str, msg: Vec<u8>) -> Result<(), String>;
+    async fn subscribe(&self, channel: &str) -> Result<mpsc::Receiver<Vec<u8>>, String>;
 }
 
 #[async_trait]
-impl Cacher for HybridCacher {
+impl Storage for HybridCacher {
     async fn obtain(&self, key: &str, ttl: u64) -> Result<bool, String> {
-        match &self.cache {
+        let res = match &self.cache {
             CacherEntry::Memory(cacher) => cacher.obtain(key, ttl).await,
             CacherEntry::Redis(cacher) => cacher.obtain(key, ttl).await,
-        }
+            CacherEntry::Postgres(cacher) => cacher.obtain(key, ttl).await,
+            #[cfg(feature = "dynamodb")]
+            CacherEntry::DynamoDb(cacher) => cacher.obtain(key, ttl).await,
+            CacherEntry::Custom(cacher) => cacher.obtain(key, ttl).await,
+            CacherEntry::Replicated(cacher) => cacher.obtain(key, ttl).await,
+        };
+        self.counted(res)
     }
 
-    async fn polling_get(
+    async fn polling_get_with(
         &self,
         key: &str,
-        poll_interval: u64,
-        counter: u64,
+        delays: &mut (dyn Iterator<Item = u64> + Send),
     ) -> Result<Vec<u8>, String> {
-        match &self.cache {
-            CacherEntry::Memory(cacher) => cacher.polling_get(key, poll_interval, counter).await,
-            CacherEntry::Redis(cacher) => cacher.polling_get(key, poll_interval, counter).await,
+        let res = match &self.cache {
+            CacherEntry::Memory(cacher) => cacher.polling_get_with(key, delays).await,
+            CacherEntry::Redis(cacher) => cacher.polling_get_with(key, delays).await,
+            CacherEntry::Postgres(cacher) => cacher.polling_get_with(key, delays).await,
+            #[cfg(feature = "dynamodb")]
+            CacherEntry::DynamoDb(cacher) => cacher.polling_get_with(key, delays).await,
+            CacherEntry::Custom(cacher) => cacher.polling_get_with(key, delays).await,
+            CacherEntry::Replicated(cacher) => cacher.polling_get_with(key, delays).await,
+        };
+        match res {
+            Err(err) if err == POLLING_TIMEOUT || err == "not obtained" => Err(err),
+            res => self.counted(res),
         }
     }
 
     async fn set(&self, key: &str, val: Vec<u8>, ttl: u64) -> Result<bool, String> {
-        match &self.cache {
+        let res = match &self.cache {
             CacherEntry::Memory(cacher) => cacher.set(key, val, ttl).await,
             CacherEntry::Redis(cacher) => cacher.set(key, val, ttl).await,
-        }
+            CacherEntry::Postgres(cacher) => cacher.set(key, val, ttl).await,
+            #[cfg(feature = "dynamodb")]
+            CacherEntry::DynamoDb(cacher) => cacher.set(key, val, ttl).await,
+            CacherEntry::Custom(cacher) => cacher.set(key, val, ttl).await,
+            CacherEntry::Replicated(cacher) => cacher.set(key, val, ttl).await,
+        };
+        self.counted(res)
+    }
+
+    async fn renew(&self, key: &str, ttl: u64) -> Result<bool, String> {
+        let res = match &self.cache {
+            CacherEntry::Memory(cacher) => cacher.renew(key, ttl).await,
+            CacherEntry::Redis(cacher) => cacher.renew(key, ttl).await,
+            CacherEntry::Postgres(cacher) => cacher.renew(key, ttl).await,
+            #[cfg(feature = "dynamodb")]
+            CacherEntry::DynamoDb(cacher) => cacher.renew(key, ttl).await,
+            CacherEntry::Custom(cacher) => cacher.renew(key, ttl).await,
+            CacherEntry::Replicated(cacher) => cacher.renew(key, ttl).await,
+        };
+        self.counted(res)
     }
 
     async fn del(&self, key: &str) -> Result<(), String> {
-        match &self.cache {
+        let res = match &self.cache {
             CacherEntry::Memory(cacher) => cacher.del(key).await,
             CacherEntry::Redis(cacher) => cacher.del(key).await,
-        }
+            CacherEntry::Postgres(cacher) => cacher.del(key).await,
+            #[cfg(feature = "dynamodb")]
+            CacherEntry::DynamoDb(cacher) => cacher.del(key).await,
+            CacherEntry::Custom(cacher) => cacher.del(key).await,
+            CacherEntry::Replicated(cacher) => cacher.del(key).await,
+        };
+        self.counted(res)
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let res = match &self.cache {
+            CacherEntry::Memory(cacher) => cacher.keys(prefix).await,
+            CacherEntry::Redis(cacher) => cacher.keys(prefix).await,
+            CacherEntry::Postgres(cacher) => cacher.keys(prefix).await,
+            #[cfg(feature = "dynamodb")]
+            CacherEntry::DynamoDb(cacher) => cacher.keys(prefix).await,
+            CacherEntry::Custom(cacher) => cacher.keys(prefix).await,
+            CacherEntry::Replicated(cacher) => cacher.keys(prefix).await,
+        };
+        self.counted(res)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let res = match &self.cache {
+            CacherEntry::Memory(cacher) => cacher.get(key).await,
+            CacherEntry::Redis(cacher) => cacher.get(key).await,
+            CacherEntry::Postgres(cacher) => cacher.get(key).await,
+            #[cfg(feature = "dynamodb")]
+            CacherEntry::DynamoDb(cacher) => cacher.get(key).await,
+            CacherEntry::Custom(cacher) => cacher.get(key).await,
+            CacherEntry::Replicated(cacher) => cacher.get(key).await,
+        };
+        self.counted(res)
+    }
+
+    async fn publish(&self, channel: &str, msg: Vec<u8>) -> Result<(), String> {
+        let res = match &self.cache {
+            CacherEntry::Memory(cacher) => cacher.publish(channel, msg).await,
+            CacherEntry::Redis(cacher) => cacher.publish(channel, msg).await,
+            CacherEntry::Postgres(cacher) => cacher.publish(channel, msg).await,
+            #[cfg(feature = "dynamodb")]
+            CacherEntry::DynamoDb(cacher) => cacher.publish(channel, msg).await,
+            CacherEntry::Custom(cacher) => cacher.publish(channel, msg).await,
+            CacherEntry::Replicated(cacher) => cacher.publish(channel, msg).await,
+        };
+        self.counted(res)
+    }
+
+    async fn subscribe(&self, channel: &str) -> Result<mpsc::Receiver<Vec<u8>>, String> {
+        let res = match &self.cache {
+            CacherEntry::Memory(cacher) => cacher.subscribe(channel).await,
+            CacherEntry::Redis(cacher) => cacher.subscribe(channel).await,
+            CacherEntry::Postgres(cacher) => cacher.subscribe(channel).await,
+            #[cfg(feature = "dynamodb")]
+            CacherEntry::DynamoDb(cacher) => cacher.subscribe(channel).await,
+            CacherEntry::Custom(cacher) => cacher.subscribe(channel).await,
+            CacherEntry::Replicated(cacher) => cacher.subscribe(channel).await,
+        };
+        self.counted(res)
     }
 }
 
@@ -92,8 +262,17 @@ impl Cacher for HybridCacher {
 pub struct ResponseData {
     pub status: u16,
     pub headers: Vec<(String, String)>,
-    pub body: ByteBuf,
+    pub body: Bytes, // shares the upstream response buffer when it is not filtered
     pub mime: String,
+    // a large body is stored in chunks, `body` is empty then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Chunks>,
+    // of the request that produced it, see handler::request_fingerprint
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub fingerprint: String,
+    // of a gRPC response, grpc-status and grpc-message
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trailers: Vec<(String, String)>,
 }
 
 impl Default for ResponseData {
@@ -107,8 +286,19 @@ impl ResponseData {
         Self {
             status,
             headers: Vec::new(),
-            body: ByteBuf::new(),
+            body: Bytes::new(),
             mime: "text/plain".to_string(),
+            chunks: None,
+            fingerprint: String::new(),
+            trailers: Vec::new(),
+        }
+    }
+
+    // The size of the body in bytes, stored in chunks or not.
+    pub fn body_size(&self) -> u64 {
+        match &self.chunks {
+            Some(chunks) => chunks.size,
+            None => self.body.len() as u64,
         }
     }
 
@@ -139,26 +329,21 @@ impl ResponseData {
         }
     }
 
-    pub fn with_body(&mut self, body: &[u8], filtering: &str) -> Result<(), String> {
+    pub fn with_body(&mut self, body: &Bytes, filtering: &str) -> Result<(), String> {
         let filtering: Vec<&str> = split_filtering(filtering);
         if self.status >= 300 || filtering.is_empty() {
-            self.body.extend_from_slice(body);
+            self.body = body.clone();
             return Ok(());
         }
 
         match &self.mime {
             v if !filtering.is_empty() && v.contains("application/json") => {
+                let mask = JsonMask::parse(&filtering.join(","))?;
                 let obj: serde_json::Value = serde_json::from_slice(body).map_err(err_string)?;
-                let mut new_obj = serde_json::Map::with_capacity(filtering.len());
-                for k in filtering {
-                    if let Some(v) = obj.get(k) {
-                        new_obj.insert(k.to_string(), v.to_owned());
-                    }
-                }
-                self.body = ByteBuf::from(serde_json::to_vec(&new_obj).map_err(err_string)?);
+                self.body = Bytes::from(serde_json::to_vec(&mask.apply(&obj)).map_err(err_string)?);
             }
             v if !filtering.is_empty() && v.contains("application/cbor") => {
-                let obj: Value = from_reader(body).map_err(err_string)?;
+                let obj: Value = from_reader(&body[..]).map_err(err_string)?;
                 let obj = obj
                     .into_map()
                     .map(|mut list| {
@@ -171,17 +356,18 @@ impl ResponseData {
                     Ok(v) => into_writer(&v, &mut buf).map_err(err_string)?,
                     Err(_) => into_writer(&Value::Map(vec![]), &mut buf).map_err(err_string)?,
                 }
-                self.body = ByteBuf::from(buf);
+                self.body = Bytes::from(buf);
             }
             _ => {
-                self.body.extend_from_slice(body);
+                self.body = body.clone();
             }
         }
         Ok(())
     }
 
     pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
-        let mut buf = Vec::new();
+        // sized for the body and the CBOR envelope, so encoding does not reallocate
+        let mut buf = Vec::with_capacity(self.body.len() + 256);
         into_writer(self, &mut buf).map_err(err_string)?;
         Ok(buf)
     }
@@ -211,9 +397,24 @@ fn split_filtering(filtering: &str) -> Vec<&str> {
 
 impl IntoResponse for ResponseData {
     fn into_response(self) -> Response {
-        let body = self.body.into_vec();
-        let len = body.len();
-        let mut res = Response::new(Body::from(body));
+        let len = self.body.len();
+        let with_trailers = !self.trailers.is_empty();
+        let body = if with_trailers {
+            let mut trailers = HeaderMap::new();
+            for (ref k, v) in self.trailers {
+                trailers.append(
+                    HeaderName::from_bytes(k.as_bytes()).unwrap(),
+                    HeaderValue::from_bytes(v.as_bytes()).unwrap(),
+                );
+            }
+            Body::new(
+                Full::new(self.body)
+                    .with_trailers(async move { Some(Ok::<_, Infallible>(trailers)) }),
+            )
+        } else {
+            Body::from(self.body)
+        };
+        let mut res = Response::new(body);
         *res.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
         for (ref k, v) in self.headers {
             res.headers_mut().append(
@@ -226,8 +427,10 @@ impl IntoResponse for ResponseData {
             http::header::CONTENT_TYPE,
             HeaderValue::from_bytes(self.mime.as_bytes()).unwrap(),
         );
-        res.headers_mut()
-            .insert(http::header::CONTENT_LENGTH, len.into());
+        if !with_trailers {
+            res.headers_mut()
+                .insert(http::header::CONTENT_LENGTH, len.into());
+        }
         res
     }
 }
@@ -249,7 +452,7 @@ mod test {
         let mut rd = ResponseData::new(200);
         rd.headers
             .push(("accept".to_string(), "application/json".to_string()));
-        rd.body.extend_from_slice(b"Hello, World!");
+        rd.body = Bytes::from_static(b"Hello, World!");
         let data = rd.to_bytes().unwrap();
         let rd2 = ResponseData::try_from(data.as_slice()).unwrap();
         assert_eq!(rd2, rd);
@@ -290,12 +493,12 @@ mod test {
         });
 
         rd.with_body(
-            serde_json::to_vec(&body).unwrap().as_slice(),
+            &Bytes::from(serde_json::to_vec(&body).unwrap()),
             "args,url,Origin",
         )
         .unwrap();
         assert_eq!(
-            rd.body.as_slice(),
+            &rd.body[..],
             r#"{"args":{"api-key":"abc123"},"url":"https://httpbin.org/get?api-key=abc123"}"#
                 .as_bytes()
         );
@@ -320,7 +523,8 @@ mod test {
         .unwrap();
         let mut buf = Vec::new();
         into_writer(&body, &mut buf).unwrap();
-        rd.with_body(&buf, "args,url,Origin").unwrap();
+        rd.with_body(&Bytes::from(buf.clone()), "args,url,Origin")
+            .unwrap();
 
         let body = cbor!({
             "args" => {"api-key" => "abc123"},
@@ -329,6 +533,46 @@ mod test {
         .unwrap();
         buf.clear();
         into_writer(&body, &mut buf).unwrap();
-        assert_eq!(rd.body.as_slice(), buf.as_slice());
+        assert_eq!(&rd.body[..], buf.as_slice());
+    }
+
+    #[test]
+    fn test_response_data_zero_copy() {
+        let body = Bytes::from(br#"{"args":{},"url":"https://httpbin.org/get"}"#.to_vec());
+        let mut rd = ResponseData::new(200);
+        rd.mime = "application/json".to_string();
+        rd.with_body(&body, "").unwrap();
+        // the unfiltered body shares the upstream buffer
+        assert_eq!(rd.body.as_ptr(), body.as_ptr());
+
+        // encoded as a CBOR byte string, compatible with previously cached data
+        let data = rd.to_bytes().unwrap();
+        let rd2 = ResponseData::try_from(data.as_slice()).unwrap();
+        assert_eq!(rd2, rd);
+    }
+
+    #[tokio::test]
+    async fn test_custom_storage() {
+        let cacher = HybridCacher::new(
+            10,
+            1000,
+            CacherEntry::Custom(Box::new(MemoryCacher::default())),
+        );
+        assert!(cacher.memory_usage().await.is_none());
+        assert!(cacher.obtain("k", 1000).await.unwrap());
+        assert!(!cacher.obtain("k", 1000).await.unwrap());
+        assert_eq!(cacher.get("k").await.unwrap(), None);
+        assert!(cacher.set("k", b"v".to_vec(), 1000).await.unwrap());
+        assert_eq!(cacher.polling_get("k", 10, 2).await.unwrap(), b"v".to_vec());
+        assert_eq!(cacher.keys("").await.unwrap(), vec!["k".to_string()]);
+        cacher.del("k").await.unwrap();
+        assert_eq!(cacher.get("k").await.unwrap(), None);
+
+        assert!(cacher.polling_get("k", 10, 2).await.is_err());
+        assert!(cacher.obtain("k", 1000).await.unwrap());
+        assert!(cacher.polling_get("k", 10, 2).await.is_err());
+        assert_eq!(cacher.errors(), 0);
+        assert!(cacher.set("other", b"v".to_vec(), 1000).await.is_err());
+        assert_eq!(cacher.errors(), 1);
     }
 }
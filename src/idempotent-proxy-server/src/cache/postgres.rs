@@ -0,0 +1,294 @@
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine};
+use deadpool_postgres::{Config, Pool, PoolConfig, Runtime};
+use futures::StreamExt;
+use tokio::{
+    sync::mpsc,
+    time::{sleep, Duration},
+};
+use tokio_postgres::{AsyncMessage, NoTls};
+
+use super::{Storage, POLLING_TIMEOUT};
+
+// the clock of the database, shared by all the instances
+const NOW_MS: &str = "(extract(epoch from clock_timestamp()) * 1000)::bigint";
+
+// Stores the locks and responses in a table, one row per key. A lock is a row without
+// a value, the primary key makes the first request win, an expired row is taken over.
+pub struct PostgresClient {
+    url: String,
+    pool: Pool,
+    table: String,
+}
+
+impl PostgresClient {
+    // Creates the table if it does not exist and deletes the expired rows every
+    // cleanup interval (in seconds).
+    pub async fn new(url: &str, table: &str, cleanup_interval: u64) -> Result<Self, String> {
+        if table.is_empty()
+            || !table
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        {
+            return Err(format!("invalid table name: {}", table));
+        }
+
+        let mut cfg = Config::new();
+        cfg.url = Some(url.to_string());
+        cfg.pool = Some(PoolConfig::new(10));
+        let pool = cfg
+            .create_pool(Some(Runtime::Tokio1), NoTls)
+            .map_err(|err| err.to_string())?;
+        let client = PostgresClient {
+            url: url.to_string(),
+            pool,
+            table: table.to_string(),
+        };
+
+        let conn = client.conn().await?;
+        conn.batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {t} (key TEXT PRIMARY KEY, value BYTEA, expire_at BIGINT NOT NULL);
+             CREATE INDEX IF NOT EXISTS {i}_expire_at ON {t} (expire_at);",
+            t = client.table,
+            i = client.table.replace('.', "_"),
+        ))
+        .await
+        .map_err(|err| err.to_string())?;
+
+        let pool = client.pool.clone();
+        let cleanup = format!("DELETE FROM {} WHERE expire_at <= {}", client.table, NOW_MS);
+        tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_secs(cleanup_interval.max(1))).await;
+                let res = match pool.get().await {
+                    Ok(conn) => conn
+                        .execute(&cleanup, &[])
+                        .await
+                        .map_err(|err| err.to_string()),
+                    Err(err) => Err(err.to_string()),
+                };
+                match res {
+                    Ok(n) if n > 0 => log::info!(target: "postgres", "deleted {} expired keys", n),
+                    Ok(_) => {}
+                    Err(err) => log::error!(target: "postgres", "cleanup: {}", err),
+                }
+            }
+        });
+        Ok(client)
+    }
+
+    async fn conn(&self) -> Result<deadpool_postgres::Object, String> {
+        self.pool
+            .get()
+            .await
+            .map_err(|err| format!("storage unavailable: {}", err))
+    }
+
+    // Returns the row of a live key, the value is None while it is a lock.
+    async fn get_raw(&self, key: &str) -> Result<Option<Option<Vec<u8>>>, String> {
+        let conn = self.conn().await?;
+        let row = conn
+            .query_opt(
+                &format!(
+                    "SELECT value FROM {} WHERE key = $1 AND expire_at > {}",
+                    self.table, NOW_MS
+                ),
+                &[&key],
+            )
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(row.map(|row| row.get(0)))
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresClient {
+    async fn obtain(&self, key: &str, ttl: u64) -> Result<bool, String> {
+        let conn = self.conn().await?;
+        let n = conn
+            .execute(
+                &format!(
+                    "INSERT INTO {t} (key, value, expire_at) VALUES ($1, NULL, {now} + $2)
+                     ON CONFLICT (key) DO UPDATE SET value = NULL, expire_at = EXCLUDED.expire_at
+                     WHERE {t}.expire_at <= {now}",
+                    t = self.table,
+                    now = NOW_MS
+                ),
+                &[&key, &(ttl as i64)],
+            )
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(n == 1)
+    }
+
+    async fn polling_get_with(
+        &self,
+        key: &str,
+        delays: &mut (dyn Iterator<Item = u64> + Send),
+    ) -> Result<Vec<u8>, String> {
+        loop {
+            match self.get_raw(key).await? {
+                None => return Err("not obtained".to_string()),
+                Some(Some(val)) => return Ok(val),
+                Some(None) => {}
+            }
+
+            match delays.next() {
+                Some(delay) => sleep(Duration::from_millis(delay)).await,
+                None => return Err(POLLING_TIMEOUT.to_string()),
+            }
+        }
+    }
+
+    async fn set(&self, key: &str, val: Vec<u8>, ttl: u64) -> Result<bool, String> {
+        let conn = self.conn().await?;
+        let n = conn
+            .execute(
+                &format!(
+                    "UPDATE {t} SET value = $2, expire_at = {now} + $3
+                     WHERE key = $1 AND expire_at > {now}",
+                    t = self.table,
+                    now = NOW_MS
+                ),
+                &[&key, &val, &(ttl as i64)],
+            )
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(n == 1)
+    }
+
+    async fn renew(&self, key: &str, ttl: u64) -> Result<bool, String> {
+        let conn = self.conn().await?;
+        let n = conn
+            .execute(
+                &format!(
+                    "UPDATE {t} SET expire_at = {now} + $2
+                     WHERE key = $1 AND value IS NULL AND expire_at > {now}",
+                    t = self.table,
+                    now = NOW_MS
+                ),
+                &[&key, &(ttl as i64)],
+            )
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(n == 1)
+    }
+
+    async fn del(&self, key: &str) -> Result<(), String> {
+        let conn = self.conn().await?;
+        conn.execute(
+            &format!("DELETE FROM {} WHERE key = $1", self.table),
+            &[&key],
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let conn = self.conn().await?;
+        let rows = conn
+            .query(
+                &format!(
+                    "SELECT key FROM {} WHERE left(key, char_length($1)) = $1 AND expire_at > {}",
+                    self.table, NOW_MS
+                ),
+                &[&prefix],
+            )
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.get_raw(key).await?.flatten())
+    }
+
+    // NOTIFY payloads are text of less than 8000 bytes, messages are base64 encoded.
+    async fn publish(&self, channel: &str, msg: Vec<u8>) -> Result<(), String> {
+        let conn = self.conn().await?;
+        conn.execute(
+            "SELECT pg_notify($1, $2)",
+            &[&channel, &general_purpose::STANDARD.encode(msg)],
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    // A subscription holds a dedicated connection, it is re-established after failures.
+    async fn subscribe(&self, channel: &str) -> Result<mpsc::Receiver<Vec<u8>>, String> {
+        let (tx, rx) = mpsc::channel(64);
+        let url = self.url.clone();
+        let channel = channel.to_string();
+        let listen = format!("LISTEN \"{}\"", channel.replace('"', "\"\""));
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                let (client, mut connection) = match tokio_postgres::connect(&url, NoTls).await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        log::error!(target: "postgres", "subscribe {}: {}", channel, err);
+                        sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                // the connection must be polled for the LISTEN command to complete
+                let (ntx, mut nrx) = mpsc::unbounded_channel();
+                let ch = channel.clone();
+                tokio::spawn(async move {
+                    let mut messages =
+                        futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+                    while let Some(msg) = messages.next().await {
+                        match msg {
+                            Ok(AsyncMessage::Notification(n)) => {
+                                if ntx.send(n.payload().to_string()).is_err() {
+                                    return;
+                                }
+                            }
+                            Ok(_) => {}
+                            Err(err) => {
+                                log::error!(target: "postgres", "subscription {}: {}", ch, err);
+                                return;
+                            }
+                        }
+                    }
+                });
+                if let Err(err) = client.batch_execute(&listen).await {
+                    log::error!(target: "postgres", "subscribe {}: {}", channel, err);
+                    sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+
+                while let Some(payload) = nrx.recv().await {
+                    match general_purpose::STANDARD.decode(payload) {
+                        Ok(msg) => {
+                            if tx.send(msg).await.is_err() {
+                                return;
+                            }
+                        }
+                        Err(err) => {
+                            log::warn!(target: "postgres", "invalid notification on {}: {}", channel, err)
+                        }
+                    }
+                }
+                sleep(Duration::from_secs(1)).await;
+            }
+        });
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_invalid_table() {
+        for table in ["", "cache; DROP TABLE users", "\"cache\""] {
+            assert!(PostgresClient::new("postgres://localhost/proxy", table, 60)
+                .await
+                .is_err());
+        }
+    }
+}
@@ -1,15 +1,47 @@
 use async_trait::async_trait;
-use idempotent_proxy_types::err_string;
+use futures::StreamExt;
 use rustis::bb8::{CustomizeConnection, ErrorSink, Pool};
-use rustis::client::PooledClientManager;
-use rustis::commands::{GenericCommands, SetCondition, SetExpiration, StringCommands};
+use rustis::client::{Client, PooledClientManager};
+use rustis::commands::{
+    CallBuilder, GenericCommands, PubSubCommands, ScanOptions, ScriptingCommands, SetCondition,
+    SetExpiration, StringCommands,
+};
 use rustis::resp::BulkString;
-use tokio::time::{sleep, Duration};
+use std::{
+    borrow::Cow,
+    future::Future,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tokio::{
+    sync::mpsc,
+    time::{sleep, Duration},
+};
 
-use super::Cacher;
+use super::{Storage, POLLING_TIMEOUT};
 
 pub struct RedisClient {
+    url: String,
+    cluster: bool, // the keys are hash-tagged, see slot_key
     pool: Pool<PooledClientManager>,
+    max_retries: u32,
+    retry_interval: u64, // in milliseconds, doubled after every attempt
+    max_retrying: usize, // operations waiting for the storage to come back
+    retrying: AtomicUsize,
+}
+
+enum OpError {
+    // no connection, the command was not sent
+    Connect(String),
+    Command(String),
+}
+
+impl From<OpError> for String {
+    fn from(err: OpError) -> Self {
+        match err {
+            OpError::Connect(err) => format!("storage unavailable: {}", err),
+            OpError::Command(err) => err,
+        }
+    }
 }
 
 impl RedisClient {
@@ -25,10 +57,150 @@ impl RedisClient {
             .connection_customizer(Box::new(RedisMonitor {}))
             .build(manager)
             .await?;
-        Ok(RedisClient { pool })
+        Ok(RedisClient {
+            url: url.to_string(),
+            cluster: url.contains("+cluster://"),
+            pool,
+            max_retries: 3,
+            retry_interval: 100,
+            max_retrying: 1000,
+            retrying: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn with_retry(
+        mut self,
+        max_retries: u32,
+        retry_interval: u64,
+        max_retrying: usize,
+    ) -> Self {
+        self.max_retries = max_retries;
+        self.retry_interval = retry_interval;
+        self.max_retrying = max_retrying;
+        self
+    }
+
+    // Runs a storage operation, retrying failures with exponential backoff while the pool
+    // reconnects. Idempotent operations are retried after any failure, the others only when
+    // the command was not sent, so a lock is never obtained twice. At most `max_retrying`
+    // operations wait at a time, the others fail immediately during an outage.
+    async fn run<T, F, Fut>(&self, idempotent: bool, f: F) -> Result<T, String>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, OpError>>,
+    {
+        let mut interval = self.retry_interval;
+        let mut attempt = 0;
+        let mut waiting = false;
+        let res = loop {
+            match f().await {
+                Ok(v) => break Ok(v),
+                Err(err) => {
+                    let retryable = idempotent || matches!(err, OpError::Connect(_));
+                    if !retryable || attempt >= self.max_retries {
+                        break Err(err.into());
+                    }
+                    if !waiting {
+                        if self.retrying.fetch_add(1, Ordering::SeqCst) >= self.max_retrying {
+                            self.retrying.fetch_sub(1, Ordering::SeqCst);
+                            break Err(err.into());
+                        }
+                        waiting = true;
+                    }
+                    attempt += 1;
+                    sleep(Duration::from_millis(interval)).await;
+                    interval = interval.saturating_mul(2);
+                }
+            }
+        };
+        if waiting {
+            self.retrying.fetch_sub(1, Ordering::SeqCst);
+        }
+        res
+    }
+
+    // In a cluster a response and its chunks hash to the same slot: the key without the
+    // chunk suffix is the hash tag.
+    fn slot_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        if !self.cluster {
+            return Cow::Borrowed(key);
+        }
+        match key.find(":chunk:") {
+            Some(i) => Cow::Owned(format!("{{{}}}{}", &key[..i], &key[i..])),
+            None => Cow::Owned(format!("{{{}}}", key)),
+        }
+    }
+
+    fn unslot_key(&self, key: String) -> String {
+        if !self.cluster {
+            return key;
+        }
+        key.strip_prefix('{')
+            .and_then(|k| k.split_once('}'))
+            .map(|(base, rest)| format!("{}{}", base, rest))
+            .unwrap_or(key)
+    }
+
+    async fn get_raw(&self, key: &str) -> Result<Option<BulkString>, String> {
+        let key = self.slot_key(key);
+        let key = key.as_ref();
+        self.run(true, || async move {
+            let conn = self.pool.get().await.map_err(connect_error)?;
+            conn.get(key).await.map_err(command_error)
+        })
+        .await
+    }
+}
+
+// The connection URL of REDIS_URL: a standalone server, or with REDIS_MODE a cluster or the
+// primary of REDIS_SENTINEL_SERVICE found by sentinels, from a comma-separated node list.
+pub fn redis_url(vars: impl Iterator<Item = (String, String)>) -> Result<String, String> {
+    let (mut url, mut mode, mut service) = (String::new(), String::new(), String::new());
+    for (k, v) in vars {
+        match k.as_str() {
+            "REDIS_URL" => url = v.trim().to_string(),
+            "REDIS_MODE" => mode = v.trim().to_ascii_lowercase(),
+            "REDIS_SENTINEL_SERVICE" => service = v.trim().to_string(),
+            _ => {}
+        }
+    }
+    let (scheme, rest) = match url.split_once("://") {
+        Some((scheme, rest)) => (scheme.to_string(), rest.to_string()),
+        None => ("redis".to_string(), url.clone()),
+    };
+    if scheme.contains('+') {
+        return Ok(url); // a rustis cluster or sentinel URL
+    }
+    if scheme != "redis" && scheme != "rediss" {
+        return Err(format!("invalid REDIS_URL scheme: {}", scheme));
+    }
+    match mode.as_str() {
+        "" | "standalone" => Ok(url),
+        "cluster" => Ok(format!("{}+cluster://{}", scheme, rest)),
+        "sentinel" => {
+            if service.is_empty() {
+                return Err("REDIS_MODE=sentinel needs REDIS_SENTINEL_SERVICE".to_string());
+            }
+            // the database, if any, follows the service name
+            let (nodes, db) = match rest.split_once('/') {
+                Some((nodes, db)) if !db.is_empty() => (nodes, format!("/{}", db)),
+                Some((nodes, _)) => (nodes, String::new()),
+                None => (rest.as_str(), String::new()),
+            };
+            Ok(format!("{}+sentinel://{}/{}{}", scheme, nodes, service, db))
+        }
+        v => Err(format!("invalid REDIS_MODE: {}", v)),
     }
 }
 
+fn connect_error(err: impl std::fmt::Display) -> OpError {
+    OpError::Connect(err.to_string())
+}
+
+fn command_error(err: impl std::fmt::Display) -> OpError {
+    OpError::Command(err.to_string())
+}
+
 #[derive(Debug, Clone, Copy)]
 struct RedisMonitor;
 
@@ -51,11 +223,13 @@ impl<C: Send + 'static, E: 'static> CustomizeConnection<C, E> for RedisMonitor {
 }
 
 #[async_trait]
-impl Cacher for RedisClient {
+impl Storage for RedisClient {
     async fn obtain(&self, key: &str, ttl: u64) -> Result<bool, String> {
-        let conn = self.pool.get().await.map_err(err_string)?;
-        let res = conn
-            .set_with_options(
+        let key = self.slot_key(key);
+        let key = key.as_ref();
+        self.run(false, || async move {
+            let conn = self.pool.get().await.map_err(connect_error)?;
+            conn.set_with_options(
                 key,
                 BulkString::from(vec![0]),
                 SetCondition::NX,
@@ -63,21 +237,18 @@ impl Cacher for RedisClient {
                 false,
             )
             .await
-            .map_err(err_string)?;
-        Ok(res)
+            .map_err(command_error)
+        })
+        .await
     }
 
-    async fn polling_get(
+    async fn polling_get_with(
         &self,
         key: &str,
-        poll_interval: u64,
-        counter: u64,
+        delays: &mut (dyn Iterator<Item = u64> + Send),
     ) -> Result<Vec<u8>, String> {
-        let conn = self.pool.get().await.map_err(err_string)?;
-        let mut counter = counter;
-        while counter > 0 {
-            let res: Option<BulkString> = conn.get(key).await.map_err(err_string)?;
-            match res {
+        loop {
+            match self.get_raw(key).await? {
                 None => return Err("not obtained".to_string()),
                 Some(bs) => {
                     if bs.len() > 1 {
@@ -86,31 +257,205 @@ impl Cacher for RedisClient {
                 }
             }
 
-            counter -= 1;
-            sleep(Duration::from_millis(poll_interval)).await;
+            match delays.next() {
+                Some(delay) => sleep(Duration::from_millis(delay)).await,
+                None => return Err(POLLING_TIMEOUT.to_string()),
+            }
         }
-
-        Err(("polling get cache timeout").to_string())
     }
 
     async fn set(&self, key: &str, val: Vec<u8>, ttl: u64) -> Result<bool, String> {
-        let conn = self.pool.get().await.map_err(err_string)?;
-        let res = conn
-            .set_with_options(
+        // setting the same value again is harmless
+        let key = self.slot_key(key);
+        let key = key.as_ref();
+        let val = &val;
+        self.run(true, || async move {
+            let conn = self.pool.get().await.map_err(connect_error)?;
+            conn.set_with_options(
                 key,
-                BulkString::from(val),
+                BulkString::from(val.clone()),
                 SetCondition::XX,
                 SetExpiration::Px(ttl),
                 false,
             )
             .await
-            .map_err(err_string)?;
-        Ok(res)
+            .map_err(command_error)
+        })
+        .await
+    }
+
+    async fn renew(&self, key: &str, ttl: u64) -> Result<bool, String> {
+        let key = self.slot_key(key);
+        let key = key.as_ref();
+        // only a lock, the value written by `obtain`, is renewed
+        const RENEW_SCRIPT: &str = "if redis.call('GET', KEYS[1]) == '\\0' then \
+            return redis.call('PEXPIRE', KEYS[1], ARGV[1]) end return 0";
+        self.run(true, || async move {
+            let conn = self.pool.get().await.map_err(connect_error)?;
+            let n: i64 = conn
+                .eval(CallBuilder::script(RENEW_SCRIPT).keys(key).args(ttl))
+                .await
+                .map_err(command_error)?;
+            Ok(n == 1)
+        })
+        .await
     }
 
     async fn del(&self, key: &str) -> Result<(), String> {
-        let conn = self.pool.get().await.map_err(err_string)?;
-        let _ = conn.del(key).await.map_err(err_string)?;
-        Ok(())
+        let key = self.slot_key(key);
+        let key = key.as_ref();
+        self.run(true, || async move {
+            let conn = self.pool.get().await.map_err(connect_error)?;
+            let _: usize = conn.del(key).await.map_err(command_error)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let pattern = if self.cluster {
+            format!("{{{}*", prefix)
+        } else {
+            format!("{}*", prefix)
+        };
+        let pattern = pattern.as_str();
+        let keys = self
+            .run(true, || async move {
+                let conn = self.pool.get().await.map_err(connect_error)?;
+                if self.cluster {
+                    // SCAN walks a single node, KEYS is sent to all the primaries
+                    let keys: Vec<String> = conn.keys(pattern).await.map_err(command_error)?;
+                    return Ok(keys);
+                }
+                let mut keys: Vec<String> = Vec::new();
+                let mut cursor = 0u64;
+                loop {
+                    let (next, res): (u64, Vec<String>) = conn
+                        .scan(
+                            cursor,
+                            ScanOptions::default().match_pattern(pattern).count(1000),
+                        )
+                        .await
+                        .map_err(command_error)?;
+                    keys.extend(res);
+                    if next == 0 {
+                        return Ok(keys);
+                    }
+                    cursor = next;
+                }
+            })
+            .await?;
+        Ok(keys.into_iter().map(|k| self.unslot_key(k)).collect())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let res = self.get_raw(key).await?;
+        // a lock is a single zero byte
+        Ok(res.filter(|bs| bs.len() > 1).map(|bs| bs.into()))
+    }
+
+    async fn publish(&self, channel: &str, msg: Vec<u8>) -> Result<(), String> {
+        // a subscriber may see an event twice, events are idempotent
+        let msg = &msg;
+        self.run(true, || async move {
+            let conn = self.pool.get().await.map_err(connect_error)?;
+            let _: usize = conn
+                .publish(channel, BulkString::from(msg.clone()))
+                .await
+                .map_err(command_error)?;
+            Ok(())
+        })
+        .await
+    }
+
+    // A subscription holds a dedicated connection, it is re-established after failures.
+    async fn subscribe(&self, channel: &str) -> Result<mpsc::Receiver<Vec<u8>>, String> {
+        let (tx, rx) = mpsc::channel(64);
+        let url = self.url.clone();
+        let channel = channel.to_string();
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                let mut stream = match Client::connect(url.as_str()).await {
+                    Ok(client) => match client.subscribe(channel.as_str()).await {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            log::error!(target: "redis", "subscribe {}: {}", channel, err);
+                            sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                    },
+                    Err(err) => {
+                        log::error!(target: "redis", "subscribe {}: {}", channel, err);
+                        sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                while let Some(msg) = stream.next().await {
+                    match msg {
+                        Ok(msg) => {
+                            if tx.send(msg.payload).await.is_err() {
+                                return;
+                            }
+                        }
+                        Err(err) => {
+                            log::error!(target: "redis", "subscription {}: {}", channel, err);
+                            break;
+                        }
+                    }
+                }
+                sleep(Duration::from_secs(1)).await;
+            }
+        });
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_vars;
+
+    #[test]
+    fn test_redis_url() {
+        assert_eq!(
+            redis_url(test_vars(vec![("REDIS_URL", "127.0.0.1:6379")])).unwrap(),
+            "127.0.0.1:6379"
+        );
+        assert_eq!(
+            redis_url(test_vars(vec![
+                ("REDIS_URL", "redis://:secret@10.0.0.1:6379,10.0.0.2:6379"),
+                ("REDIS_MODE", "cluster"),
+            ]))
+            .unwrap(),
+            "redis+cluster://:secret@10.0.0.1:6379,10.0.0.2:6379"
+        );
+        assert_eq!(
+            redis_url(test_vars(vec![
+                ("REDIS_URL", "rediss://10.0.0.1:26379,10.0.0.2:26379/2"),
+                ("REDIS_MODE", "Sentinel"),
+                ("REDIS_SENTINEL_SERVICE", "proxy"),
+            ]))
+            .unwrap(),
+            "rediss+sentinel://10.0.0.1:26379,10.0.0.2:26379/proxy/2"
+        );
+        assert_eq!(
+            redis_url(test_vars(vec![
+                ("REDIS_URL", "redis+cluster://10.0.0.1:6379"),
+                ("REDIS_MODE", "sentinel"),
+            ]))
+            .unwrap(),
+            "redis+cluster://10.0.0.1:6379"
+        );
+        assert!(redis_url(test_vars(vec![
+            ("REDIS_URL", "10.0.0.1:26379"),
+            ("REDIS_MODE", "sentinel")
+        ]))
+        .is_err());
+        assert!(redis_url(test_vars(vec![
+            ("REDIS_URL", "10.0.0.1:6379"),
+            ("REDIS_MODE", "replica")
+        ]))
+        .is_err());
+        assert!(redis_url(test_vars(vec![("REDIS_URL", "http://10.0.0.1:6379")])).is_err());
     }
 }
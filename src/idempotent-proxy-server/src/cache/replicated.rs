@@ -0,0 +1,275 @@
+use async_trait::async_trait;
+use ciborium::{from_reader, into_writer};
+use idempotent_proxy_types::{err_string, unix_ms};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::{str::FromStr, sync::Arc};
+use tokio::sync::mpsc;
+
+use super::{HybridCacher, RedisClient, Storage};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplicationMode {
+    // the locks and responses are written to the global store, the regional store is a
+    // read cache: a duplicate in any region waits for the first request
+    WriteThrough,
+    // the regional store is authoritative, cached responses and deletions are sent to the
+    // other regions through the global store: a duplicate sent to another region before
+    // the response arrived there is forwarded again
+    PubSub,
+}
+
+impl FromStr for ReplicationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "write_through" => Ok(ReplicationMode::WriteThrough),
+            "pubsub" => Ok(ReplicationMode::PubSub),
+            v => Err(format!("invalid replication mode: {}", v)),
+        }
+    }
+}
+
+// A change of a regional store sent to the other regions.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Replica {
+    pub region: String,
+    pub key: String,
+    pub expire_at: u64,         // unix timestamp in milliseconds
+    pub value: Option<ByteBuf>, // None deletes the key
+}
+
+// Replicates the regional store of a proxy instance between regions through a global
+// Redis store, see ReplicationMode.
+pub struct Replicated {
+    mode: ReplicationMode,
+    region: String,
+    channel: String,
+    local: Arc<HybridCacher>,
+    global: RedisClient,
+}
+
+impl Replicated {
+    // Starts applying the replicas of the other regions in pubsub mode.
+    pub async fn new(
+        mode: ReplicationMode,
+        region: &str,
+        channel: &str,
+        local: HybridCacher,
+        global: RedisClient,
+    ) -> Result<Self, String> {
+        let replicated = Replicated {
+            mode,
+            region: region.to_string(),
+            channel: channel.to_string(),
+            local: Arc::new(local),
+            global,
+        };
+        if mode == ReplicationMode::PubSub {
+            let (local, region) = (replicated.local.clone(), replicated.region.clone());
+            let rx = replicated.global.subscribe(channel).await?;
+            tokio::spawn(async move { apply_replicas(local, &region, rx).await });
+        }
+        Ok(replicated)
+    }
+
+    pub fn local(&self) -> &HybridCacher {
+        &self.local
+    }
+
+    async fn replicate(&self, key: &str, expire_at: u64, value: Option<Vec<u8>>) {
+        let replica = Replica {
+            region: self.region.clone(),
+            key: key.to_string(),
+            expire_at,
+            value: value.map(ByteBuf::from),
+        };
+        let mut msg = Vec::new();
+        let res = match into_writer(&replica, &mut msg) {
+            Ok(()) => self.global.publish(&self.channel, msg).await,
+            Err(err) => Err(err_string(err)),
+        };
+        // the other regions miss the response, their duplicates are forwarded again
+        if let Err(err) = res {
+            log::error!(target: "replication", key = key; "replicate failed: {}", err);
+        }
+    }
+}
+
+async fn apply_replicas(local: Arc<HybridCacher>, region: &str, mut rx: mpsc::Receiver<Vec<u8>>) {
+    while let Some(msg) = rx.recv().await {
+        let replica: Replica = match from_reader(&msg[..]) {
+            Ok(replica) => replica,
+            Err(err) => {
+                log::error!(target: "replication", "invalid replica: {}", err);
+                continue;
+            }
+        };
+        if replica.region == region {
+            continue;
+        }
+        if let Err(err) = apply_replica(&local, replica).await {
+            log::error!(target: "replication", "apply replica failed: {}", err);
+        }
+    }
+}
+
+pub async fn apply_replica(local: &HybridCacher, replica: Replica) -> Result<(), String> {
+    let value = match replica.value {
+        Some(value) => value.into_vec(),
+        None => return local.del(&replica.key).await,
+    };
+    let ttl = replica.expire_at.saturating_sub(unix_ms());
+    if ttl == 0 {
+        return Ok(());
+    }
+    // a response cached in this region in the meantime is kept
+    if !local.obtain(&replica.key, ttl).await? && local.get(&replica.key).await?.is_some() {
+        return Ok(());
+    }
+    local.set(&replica.key, value, ttl).await.map(|_| ())
+}
+
+#[async_trait]
+impl Storage for Replicated {
+    async fn obtain(&self, key: &str, ttl: u64) -> Result<bool, String> {
+        match self.mode {
+            ReplicationMode::WriteThrough => self.global.obtain(key, ttl).await,
+            ReplicationMode::PubSub => self.local.obtain(key, ttl).await,
+        }
+    }
+
+    async fn polling_get_with(
+        &self,
+        key: &str,
+        delays: &mut (dyn Iterator<Item = u64> + Send),
+    ) -> Result<Vec<u8>, String> {
+        match self.mode {
+            ReplicationMode::WriteThrough => {
+                if let Ok(Some(val)) = self.local.get(key).await {
+                    return Ok(val);
+                }
+                self.global.polling_get_with(key, delays).await
+            }
+            ReplicationMode::PubSub => self.local.polling_get_with(key, delays).await,
+        }
+    }
+
+    async fn set(&self, key: &str, val: Vec<u8>, ttl: u64) -> Result<bool, String> {
+        let expire_at = unix_ms() + ttl;
+        match self.mode {
+            ReplicationMode::WriteThrough => {
+                let res = self.global.set(key, val.clone(), ttl).await?;
+                if res {
+                    // the read cache of this region, best effort
+                    let _ = self.local.obtain(key, ttl).await;
+                    let _ = self.local.set(key, val, ttl).await;
+                }
+                Ok(res)
+            }
+            ReplicationMode::PubSub => {
+                let res = self.local.set(key, val.clone(), ttl).await?;
+                if res {
+                    self.replicate(key, expire_at, Some(val)).await;
+                }
+                Ok(res)
+            }
+        }
+    }
+
+    async fn renew(&self, key: &str, ttl: u64) -> Result<bool, String> {
+        match self.mode {
+            ReplicationMode::WriteThrough => self.global.renew(key, ttl).await,
+            ReplicationMode::PubSub => self.local.renew(key, ttl).await,
+        }
+    }
+
+    async fn del(&self, key: &str) -> Result<(), String> {
+        match self.mode {
+            ReplicationMode::WriteThrough => {
+                self.global.del(key).await?;
+                self.local.del(key).await
+            }
+            ReplicationMode::PubSub => {
+                // a deleted lock was never replicated
+                let cached = self.local.get(key).await?.is_some();
+                self.local.del(key).await?;
+                if cached {
+                    self.replicate(key, 0, None).await;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, String> {
+        match self.mode {
+            ReplicationMode::WriteThrough => self.global.keys(prefix).await,
+            ReplicationMode::PubSub => self.local.keys(prefix).await,
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        match self.mode {
+            ReplicationMode::WriteThrough => match self.local.get(key).await {
+                Ok(Some(val)) => Ok(Some(val)),
+                _ => self.global.get(key).await,
+            },
+            ReplicationMode::PubSub => self.local.get(key).await,
+        }
+    }
+
+    // The events and cluster messages stay in the region.
+    async fn publish(&self, channel: &str, msg: Vec<u8>) -> Result<(), String> {
+        self.local.publish(channel, msg).await
+    }
+
+    async fn subscribe(&self, channel: &str) -> Result<mpsc::Receiver<Vec<u8>>, String> {
+        self.local.subscribe(channel).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{CacherEntry, MemoryCacher};
+
+    #[tokio::test]
+    async fn test_apply_replica() {
+        let local = HybridCacher::new(10, 1000, CacherEntry::Memory(MemoryCacher::default()));
+        let replica = |key: &str, expire_at: u64, value: Option<&[u8]>| Replica {
+            region: "eu".to_string(),
+            key: key.to_string(),
+            expire_at,
+            value: value.map(|v| ByteBuf::from(v.to_vec())),
+        };
+
+        let mut msg = Vec::new();
+        into_writer(&replica("k1", unix_ms() + 1000, Some(b"v1")), &mut msg).unwrap();
+        let r: Replica = from_reader(&msg[..]).unwrap();
+        apply_replica(&local, r).await.unwrap();
+        assert_eq!(local.get("k1").await.unwrap(), Some(b"v1".to_vec()));
+
+        // a lock of this region gets the response of the other region
+        assert!(local.obtain("k2", 1000).await.unwrap());
+        apply_replica(&local, replica("k2", unix_ms() + 1000, Some(b"v2")))
+            .await
+            .unwrap();
+        assert_eq!(local.get("k2").await.unwrap(), Some(b"v2".to_vec()));
+        // a cached response is kept
+        apply_replica(&local, replica("k2", unix_ms() + 1000, Some(b"v3")))
+            .await
+            .unwrap();
+        assert_eq!(local.get("k2").await.unwrap(), Some(b"v2".to_vec()));
+
+        apply_replica(&local, replica("k3", unix_ms() - 1, Some(b"v3")))
+            .await
+            .unwrap();
+        assert_eq!(local.get("k3").await.unwrap(), None);
+
+        apply_replica(&local, replica("k1", 0, None)).await.unwrap();
+        assert_eq!(local.get("k1").await.unwrap(), None);
+        assert!("local".parse::<ReplicationMode>().is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use idempotent_proxy_types::{err_string, unix_ms};
+use rusqlite::{params, Connection};
+use std::{
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    time::Duration,
+};
+
+// expired rows are deleted at most once per interval, in milliseconds
+const CLEANUP_INTERVAL: u64 = 60 * 1000;
+
+// The rows loaded on open: key, expiry in unix milliseconds and value.
+type Rows = Vec<(String, u64, Vec<u8>)>;
+
+enum Op {
+    Put(String, u64, Vec<u8>),
+    Delete(String),
+}
+
+// Persists the cached responses of the in-memory storage to a SQLite file so they
+// survive restarts. Writes are queued to a dedicated thread and never block requests.
+#[derive(Clone)]
+pub struct SqliteStore {
+    tx: Sender<Op>,
+}
+
+impl SqliteStore {
+    // Opens or creates the database, returns the store and the rows that have not expired.
+    pub fn open(path: &str) -> Result<(Self, Rows), String> {
+        let conn = Connection::open(path).map_err(err_string)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = NORMAL;
+             CREATE TABLE IF NOT EXISTS cache (key TEXT PRIMARY KEY, value BLOB NOT NULL, expire_at INTEGER NOT NULL);",
+        )
+        .map_err(err_string)?;
+
+        let rows = {
+            let mut stmt = conn
+                .prepare("SELECT key, expire_at, value FROM cache WHERE expire_at > ?1")
+                .map_err(err_string)?;
+            let rows = stmt
+                .query_map(params![unix_ms() as i64], |row| {
+                    Ok((row.get(0)?, row.get::<_, i64>(1)? as u64, row.get(2)?))
+                })
+                .map_err(err_string)?;
+            rows.collect::<Result<Vec<_>, _>>().map_err(err_string)?
+        };
+
+        let (tx, rx) = channel();
+        std::thread::spawn(move || {
+            let mut cleaned_at = unix_ms();
+            loop {
+                let res = match rx.recv_timeout(Duration::from_millis(CLEANUP_INTERVAL)) {
+                    Ok(Op::Put(key, expire_at, value)) => conn.execute(
+                        "INSERT INTO cache (key, value, expire_at) VALUES (?1, ?2, ?3)
+                         ON CONFLICT (key) DO UPDATE SET value = excluded.value, expire_at = excluded.expire_at",
+                        params![key, value, expire_at as i64],
+                    ),
+                    Ok(Op::Delete(key)) => {
+                        conn.execute("DELETE FROM cache WHERE key = ?1", params![key])
+                    }
+                    Err(RecvTimeoutError::Timeout) => Ok(0),
+                    Err(RecvTimeoutError::Disconnected) => return,
+                };
+                if let Err(err) = res {
+                    log::error!(target: "sqlite", "{}", err);
+                }
+
+                let now = unix_ms();
+                if now >= cleaned_at + CLEANUP_INTERVAL {
+                    cleaned_at = now;
+                    if let Err(err) = conn.execute(
+                        "DELETE FROM cache WHERE expire_at <= ?1",
+                        params![now as i64],
+                    ) {
+                        log::error!(target: "sqlite", "cleanup: {}", err);
+                    }
+                }
+            }
+        });
+        Ok((SqliteStore { tx }, rows))
+    }
+
+    pub fn put(&self, key: &str, expire_at: u64, value: Vec<u8>) {
+        let _ = self.tx.send(Op::Put(key.to_string(), expire_at, value));
+    }
+
+    pub fn delete(&self, key: &str) {
+        let _ = self.tx.send(Op::Delete(key.to_string()));
+    }
+}
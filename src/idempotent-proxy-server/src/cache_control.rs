@@ -0,0 +1,149 @@
+use http::HeaderMap;
+use idempotent_proxy_types::unix_ms;
+
+use crate::metadata::parse_utc_time;
+
+// Derives the cache TTL (in milliseconds) from the upstream's Cache-Control or Expires headers,
+// bounded by `min` and `max`. `no-store`, `no-cache` and `private` responses get the `min` TTL,
+// it is still required to dedupe the request. Returns None if the response has no freshness info.
+pub fn ttl_from_headers(headers: &HeaderMap, min: u64, max: u64) -> Option<u64> {
+    let ttl = max_age(headers).or_else(|| expires(headers))?;
+    Some(ttl.clamp(min, max.max(min)))
+}
+
+fn max_age(headers: &HeaderMap) -> Option<u64> {
+    let mut max_age: Option<u64> = None;
+    let mut s_maxage: Option<u64> = None;
+    for value in headers.get_all(http::header::CACHE_CONTROL) {
+        for directive in value.to_str().ok()?.split(',') {
+            let directive = directive.trim().to_ascii_lowercase();
+            let (name, arg) = match directive.split_once('=') {
+                Some((name, arg)) => (name.trim(), Some(arg.trim().trim_matches('"'))),
+                None => (directive.as_str(), None),
+            };
+            match (name, arg) {
+                ("no-store" | "no-cache" | "private", _) => return Some(0),
+                ("s-maxage", Some(v)) => s_maxage = v.parse().ok(),
+                ("max-age", Some(v)) => max_age = v.parse().ok(),
+                _ => {}
+            }
+        }
+    }
+
+    let secs = s_maxage.or(max_age)?;
+    let age = headers
+        .get(http::header::AGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+    Some(secs.saturating_sub(age) * 1000)
+}
+
+fn expires(headers: &HeaderMap) -> Option<u64> {
+    let expires = headers.get(http::header::EXPIRES)?.to_str().ok()?;
+    // an invalid date such as "0" means already expired
+    let expires = parse_http_date(expires).unwrap_or(0);
+    let now = headers
+        .get(http::header::DATE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_http_date)
+        .unwrap_or_else(unix_ms);
+    Some(expires.saturating_sub(now))
+}
+
+// Parses an IMF-fixdate, e.g. `Wed, 22 May 2024 11:11:17 GMT`, to a unix timestamp in milliseconds.
+pub fn parse_http_date(s: &str) -> Option<u64> {
+    let mut parts = s.split_whitespace();
+    let _weekday = parts.next()?;
+    let day: u32 = parts.next()?.parse().ok()?;
+    let month = match parts.next()? {
+        "Jan" => 1,
+        "Feb" => 2,
+        "Mar" => 3,
+        "Apr" => 4,
+        "May" => 5,
+        "Jun" => 6,
+        "Jul" => 7,
+        "Aug" => 8,
+        "Sep" => 9,
+        "Oct" => 10,
+        "Nov" => 11,
+        "Dec" => 12,
+        _ => return None,
+    };
+    let year: u32 = parts.next()?.parse().ok()?;
+    let time = parts.next()?;
+    if parts.next()? != "GMT" {
+        return None;
+    }
+    parse_utc_time(&format!("{:04}-{:02}-{:02}T{}Z", year, month, day, time))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(list: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (k, v) in list {
+            headers.append(*k, v.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        assert_eq!(
+            parse_http_date("Sun, 30 Aug 2015 12:36:00 GMT"),
+            Some(1440938160000)
+        );
+        assert_eq!(parse_http_date("Sun, 30 Aug 2015 12:36:00 UTC"), None);
+        assert_eq!(parse_http_date("0"), None);
+    }
+
+    #[test]
+    fn test_ttl_from_headers() {
+        let min = 1000;
+        let max = 3600 * 1000;
+        assert_eq!(ttl_from_headers(&headers(&[]), min, max), None);
+        assert_eq!(
+            ttl_from_headers(
+                &headers(&[("cache-control", "public, max-age=60")]),
+                min,
+                max
+            ),
+            Some(60 * 1000)
+        );
+        assert_eq!(
+            ttl_from_headers(
+                &headers(&[("cache-control", "max-age=60, s-maxage=120"), ("age", "20")]),
+                min,
+                max
+            ),
+            Some(100 * 1000)
+        );
+        assert_eq!(
+            ttl_from_headers(&headers(&[("cache-control", "max-age=86400")]), min, max),
+            Some(max)
+        );
+        assert_eq!(
+            ttl_from_headers(&headers(&[("cache-control", "no-store")]), min, max),
+            Some(min)
+        );
+        assert_eq!(
+            ttl_from_headers(
+                &headers(&[
+                    ("date", "Sun, 30 Aug 2015 12:36:00 GMT"),
+                    ("expires", "Sun, 30 Aug 2015 12:46:00 GMT")
+                ]),
+                min,
+                max
+            ),
+            Some(600 * 1000)
+        );
+        assert_eq!(
+            ttl_from_headers(&headers(&[("expires", "0")]), min, max),
+            Some(min)
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use reqwest::Url;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::mirror::{replace_origin, sampled};
+
+#[derive(Debug, Default)]
+pub struct VariantStats {
+    pub requests: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+// Splits the traffic of a route between the primary upstream and a canary upstream,
+// sticky per idempotency key.
+#[derive(Clone, Debug)]
+pub struct Canary {
+    pub origin: Url,
+    pub primary: Arc<VariantStats>,
+    pub canary: Arc<VariantStats>,
+}
+
+impl PartialEq for Canary {
+    fn eq(&self, other: &Self) -> bool {
+        self.origin == other.origin
+    }
+}
+
+impl Canary {
+    pub fn new(origin: &str) -> Result<Self, String> {
+        let origin =
+            Url::parse(origin).map_err(|err| format!("invalid canary url {}: {}", origin, err))?;
+        if origin.host().is_none() {
+            return Err(format!("invalid canary url: {}", origin));
+        }
+        Ok(Self {
+            origin,
+            primary: Arc::new(VariantStats::default()),
+            canary: Arc::new(VariantStats::default()),
+        })
+    }
+
+    // Returns the upstream url for the key, and whether it is the canary variant.
+    pub fn route(&self, url: &Url, idempotency_key: &str, percent: u8) -> (Url, bool) {
+        if sampled(idempotency_key, percent) {
+            self.canary.requests.fetch_add(1, Ordering::Relaxed);
+            (replace_origin(url, &self.origin), true)
+        } else {
+            self.primary.requests.fetch_add(1, Ordering::Relaxed);
+            (url.clone(), false)
+        }
+    }
+
+    pub fn record_error(&self, is_canary: bool) {
+        let stats = if is_canary {
+            &self.canary
+        } else {
+            &self.primary
+        };
+        stats.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_canary() {
+        let canary = Canary::new("https://rpc.ankr.com").unwrap();
+        let url = Url::parse("https://cloudflare-eth.com/v1/mainnet").unwrap();
+
+        let (u, is_canary) = canary.route(&url, "alice:POST:key_001", 100);
+        assert!(is_canary);
+        assert_eq!(u.as_str(), "https://rpc.ankr.com/v1/mainnet");
+        let (u, is_canary) = canary.route(&url, "alice:POST:key_001", 0);
+        assert!(!is_canary);
+        assert_eq!(u, url);
+
+        // sticky per key
+        let first = canary.route(&url, "alice:POST:key_002", 30);
+        for _ in 0..10 {
+            assert_eq!(canary.route(&url, "alice:POST:key_002", 30), first);
+        }
+
+        canary.record_error(true);
+        assert_eq!(
+            canary.canary.requests.load(Ordering::Relaxed)
+                + canary.primary.requests.load(Ordering::Relaxed),
+            13
+        );
+        assert_eq!(canary.canary.errors.load(Ordering::Relaxed), 1);
+        assert_eq!(canary.primary.errors.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,115 @@
+use http::HeaderMap;
+
+// Headers of the IC HTTP gateway response verification v2.
+pub const IC_CERTIFICATE: &str = "ic-certificate";
+pub const IC_CERTIFICATE_EXPRESSION: &str = "ic-certificateexpression";
+
+// Response headers covered by a certificate expression.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CertifiedHeaders {
+    Only(Vec<String>),
+    AllExcept(Vec<String>),
+}
+
+// Parses the certified response headers from an `IC-CertificateExpression` value, e.g.
+// `default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},
+// response_certification:ResponseCertification{certified_response_headers:ResponseHeaderList{headers:["content-type"]}}}})`
+pub fn certified_headers(expression: &str) -> CertifiedHeaders {
+    let expression: String = expression.split_whitespace().collect();
+    let headers = |tag: &str| -> Option<Vec<String>> {
+        let rest = &expression[expression.find(tag)? + tag.len()..];
+        let rest = &rest[rest.find("headers:[")? + 9..];
+        let list = &rest[..rest.find(']')?];
+        Some(
+            list.split(',')
+                .map(|h| h.trim_matches('"').to_ascii_lowercase())
+                .filter(|h| !h.is_empty())
+                .collect(),
+        )
+    };
+
+    if let Some(list) = headers("response_header_exclusions:") {
+        return CertifiedHeaders::AllExcept(list);
+    }
+    CertifiedHeaders::Only(headers("certified_response_headers:").unwrap_or_default())
+}
+
+// Returns the response headers filtering that keeps the certification headers and the certified headers,
+// an empty filtering keeps all headers.
+pub fn response_headers_filtering(headers: &HeaderMap, filtering: &str) -> Result<String, String> {
+    if !headers.contains_key(IC_CERTIFICATE) {
+        return Err("missing ic-certificate header in upstream response".to_string());
+    }
+    if filtering.trim().is_empty() {
+        return Ok("".to_string());
+    }
+
+    let expression = headers
+        .get(IC_CERTIFICATE_EXPRESSION)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let mut keep: Vec<String> = vec![
+        IC_CERTIFICATE.to_string(),
+        IC_CERTIFICATE_EXPRESSION.to_string(),
+    ];
+    match certified_headers(expression) {
+        CertifiedHeaders::Only(list) => keep.extend(list),
+        // all headers are certified except the excluded ones, they must be kept as is.
+        CertifiedHeaders::AllExcept(_) => return Ok("".to_string()),
+    }
+    keep.extend(filtering.split(',').map(|h| h.trim().to_ascii_lowercase()));
+    keep.retain(|h| !h.is_empty());
+    keep.sort();
+    keep.dedup();
+    Ok(keep.join(","))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_certified_headers() {
+        assert_eq!(
+            certified_headers(
+                r#"default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},response_certification:ResponseCertification{certified_response_headers:ResponseHeaderList{headers:["Content-Type","cache-control"]}}}})"#
+            ),
+            CertifiedHeaders::Only(vec![
+                "content-type".to_string(),
+                "cache-control".to_string()
+            ])
+        );
+        assert_eq!(
+            certified_headers(
+                r#"default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},response_certification:ResponseCertification{response_header_exclusions:ResponseHeaderList{headers:["date"]}}}})"#
+            ),
+            CertifiedHeaders::AllExcept(vec!["date".to_string()])
+        );
+        assert_eq!(
+            certified_headers("default_certification(ValidationArgs{no_certification:Empty{}})"),
+            CertifiedHeaders::Only(vec![])
+        );
+    }
+
+    #[test]
+    fn test_response_headers_filtering() {
+        let mut headers = HeaderMap::new();
+        assert!(response_headers_filtering(&headers, "").is_err());
+
+        headers.insert(
+            IC_CERTIFICATE,
+            "certificate=:AA==:, tree=:AA==:".parse().unwrap(),
+        );
+        headers.insert(
+            IC_CERTIFICATE_EXPRESSION,
+            r#"default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},response_certification:ResponseCertification{certified_response_headers:ResponseHeaderList{headers:["cache-control"]}}}})"#
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(response_headers_filtering(&headers, "").unwrap(), "");
+        assert_eq!(
+            response_headers_filtering(&headers, "Date").unwrap(),
+            "cache-control,date,ic-certificate,ic-certificateexpression"
+        );
+    }
+}
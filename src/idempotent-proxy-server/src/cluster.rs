@@ -0,0 +1,399 @@
+use base64::{engine::general_purpose, Engine};
+use ciborium::{from_reader, into_writer};
+use idempotent_proxy_types::{auth, unix_ms};
+use k256::ecdsa;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, RwLock},
+};
+use tokio::time::{sleep, Duration};
+
+use crate::cache::{HybridCacher, Storage};
+use crate::handler::AppState;
+
+const CONFIG_KEY: &str = "cluster:config";
+const CONFIG_LOCK_KEY: &str = "cluster:config:lock";
+const CONFIG_TTL: u64 = 10 * 365 * 24 * 3600 * 1000; // in milliseconds, kept until deleted
+const CHANNEL: &str = "cluster:events";
+const PERMITTED_DRIFT: u64 = 10; // seconds, as the token verification
+
+// Configuration shared by the instances through the storage backend,
+// changed by the admin control plane of any instance.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SharedConfig {
+    pub version: u64,
+    pub ed25519_pub_keys: BTreeSet<String>, // base64url
+    pub ecdsa_pub_keys: BTreeSet<String>,   // base64url, SEC1
+    pub revoked_agents: BTreeSet<String>,
+    // sha256 of a revoked token (base64url) -> its expire_at in seconds
+    #[serde(default)]
+    pub revoked_tokens: BTreeMap<String, u64>,
+    // agent -> the tokens of the agent that expire at or before are revoked
+    #[serde(default)]
+    pub revoked_before: BTreeMap<String, u64>,
+}
+
+impl SharedConfig {
+    // Drops the revocations of tokens that have expired anyway.
+    pub fn prune_revocations(&mut self, now: u64) {
+        self.revoked_tokens
+            .retain(|_, expire_at| *expire_at + PERMITTED_DRIFT >= now);
+        self.revoked_before
+            .retain(|_, expire_at| *expire_at + PERMITTED_DRIFT >= now);
+    }
+}
+
+// A token to revoke before its expiration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Revocation {
+    Token { hash: String, expire_at: u64 },
+    Agent { agent: String, expire_before: u64 },
+}
+
+// The revocation id of a token, the sha256 of the token as sent without the `Bearer ` prefix.
+pub fn token_hash(token: &str) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ClusterEvent {
+    ConfigChanged(u64),
+    Purge(String),
+    Reload,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct Envelope {
+    instance: String,
+    event: ClusterEvent,
+}
+
+#[derive(Debug, Default)]
+struct Keys {
+    ecdsa: Vec<ecdsa::VerifyingKey>,
+    ed25519: Vec<ed25519_dalek::VerifyingKey>,
+}
+
+impl Keys {
+    fn from_config(cfg: &SharedConfig) -> Result<Self, String> {
+        Ok(Keys {
+            ecdsa: cfg
+                .ecdsa_pub_keys
+                .iter()
+                .map(|v| parse_ecdsa_key(v))
+                .collect::<Result<_, _>>()?,
+            ed25519: cfg
+                .ed25519_pub_keys
+                .iter()
+                .map(|v| parse_ed25519_key(v))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Cluster {
+    pub enabled: bool,
+    pub instance: String,
+    config: RwLock<Arc<SharedConfig>>,
+    keys: RwLock<Arc<Keys>>,
+}
+
+impl Cluster {
+    pub fn new(enabled: bool, instance: String) -> Self {
+        Cluster {
+            enabled,
+            instance,
+            ..Default::default()
+        }
+    }
+
+    pub fn config(&self) -> Arc<SharedConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn has_keys(&self) -> bool {
+        let keys = self.keys.read().unwrap();
+        !keys.ecdsa.is_empty() || !keys.ed25519.is_empty()
+    }
+
+    // Returns the agent of a token signed by one of the shared keys.
+    pub fn verify(&self, token: &[u8]) -> Option<String> {
+        let keys = self.keys.read().unwrap().clone();
+        if !keys.ecdsa.is_empty() {
+            if let Ok(t) = auth::ecdsa_verify(&keys.ecdsa, token) {
+                return Some(t.1);
+            }
+        }
+        if !keys.ed25519.is_empty() {
+            if let Ok(t) = auth::ed25519_verify(&keys.ed25519, token) {
+                return Some(t.1);
+            }
+        }
+        None
+    }
+
+    pub fn is_revoked(&self, agent: &str) -> bool {
+        self.config.read().unwrap().revoked_agents.contains(agent)
+    }
+
+    // Whether the token of the agent, with its hash and expire_at, is revoked.
+    pub fn is_token_revoked(&self, agent: &str, hash: &str, expire_at: u64) -> bool {
+        let cfg = self.config.read().unwrap();
+        cfg.revoked_tokens.contains_key(hash)
+            || cfg
+                .revoked_before
+                .get(agent)
+                .is_some_and(|before| expire_at <= *before)
+    }
+
+    // Adds the revocation to the shared config, the expired ones are pruned on the way.
+    pub async fn revoke(
+        &self,
+        cacher: &HybridCacher,
+        revocation: Revocation,
+    ) -> Result<Arc<SharedConfig>, String> {
+        self.update(cacher, |cfg| {
+            cfg.prune_revocations(unix_ms() / 1000);
+            match revocation {
+                Revocation::Token { hash, expire_at } => {
+                    cfg.revoked_tokens.insert(hash, expire_at);
+                }
+                Revocation::Agent {
+                    agent,
+                    expire_before,
+                } => {
+                    let before = cfg.revoked_before.entry(agent).or_default();
+                    *before = expire_before.max(*before);
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    fn apply(&self, cfg: SharedConfig, keys: Keys) {
+        *self.keys.write().unwrap() = Arc::new(keys);
+        *self.config.write().unwrap() = Arc::new(cfg);
+    }
+
+    pub async fn load(&self, cacher: &HybridCacher) -> Result<(), String> {
+        if !self.enabled {
+            return Ok(());
+        }
+        match cacher.get(CONFIG_KEY).await? {
+            Some(data) => {
+                let cfg: SharedConfig = from_reader(&data[..]).map_err(|err| err.to_string())?;
+                let keys = Keys::from_config(&cfg)?;
+                self.apply(cfg, keys);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Read-modify-write of the shared config under a storage lock, the other instances
+    // are notified with the new version.
+    pub async fn update(
+        &self,
+        cacher: &HybridCacher,
+        f: impl FnOnce(&mut SharedConfig) -> Result<(), String>,
+    ) -> Result<Arc<SharedConfig>, String> {
+        if !self.enabled {
+            return Err("cluster config sharing is disabled".to_string());
+        }
+        let mut attempts = 0;
+        while !cacher.obtain(CONFIG_LOCK_KEY, 5000).await? {
+            attempts += 1;
+            if attempts > 50 {
+                return Err("cluster config is locked".to_string());
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        let res = async {
+            let mut cfg: SharedConfig = match cacher.get(CONFIG_KEY).await? {
+                Some(data) => from_reader(&data[..]).map_err(|err| err.to_string())?,
+                None => SharedConfig::default(),
+            };
+            f(&mut cfg)?;
+            // invalid keys are rejected before they reach the other instances
+            let keys = Keys::from_config(&cfg)?;
+            cfg.version = (cfg.version + 1).max(unix_ms());
+            let mut data = Vec::new();
+            into_writer(&cfg, &mut data).map_err(|err| err.to_string())?;
+            cacher.obtain(CONFIG_KEY, CONFIG_TTL).await?;
+            cacher.set(CONFIG_KEY, data, CONFIG_TTL).await?;
+            self.apply(cfg.clone(), keys);
+            Ok::<_, String>(cfg)
+        }
+        .await;
+        let _ = cacher.del(CONFIG_LOCK_KEY).await;
+
+        let cfg = res?;
+        self.publish(cacher, ClusterEvent::ConfigChanged(cfg.version))
+            .await?;
+        Ok(Arc::new(cfg))
+    }
+
+    pub async fn publish(&self, cacher: &HybridCacher, event: ClusterEvent) -> Result<(), String> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let mut data = Vec::new();
+        into_writer(
+            &Envelope {
+                instance: self.instance.clone(),
+                event,
+            },
+            &mut data,
+        )
+        .map_err(|err| err.to_string())?;
+        cacher.publish(CHANNEL, data).await
+    }
+}
+
+// Applies the events published by the other instances.
+pub async fn run(app: AppState) {
+    if !app.cluster.enabled {
+        return;
+    }
+    if let Err(err) = app.cluster.load(&app.cacher).await {
+        log::error!(target: "cluster", "failed to load the shared config: {}", err);
+    }
+    let mut rx = match app.cacher.subscribe(CHANNEL).await {
+        Ok(rx) => rx,
+        Err(err) => {
+            log::error!(target: "cluster", "failed to subscribe: {}", err);
+            return;
+        }
+    };
+    while let Some(data) = rx.recv().await {
+        let envelope: Envelope = match from_reader(&data[..]) {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                log::warn!(target: "cluster", "invalid event: {}", err);
+                continue;
+            }
+        };
+        if envelope.instance == app.cluster.instance {
+            continue;
+        }
+        let res = match &envelope.event {
+            ClusterEvent::ConfigChanged(version) if *version > app.cluster.config().version => {
+                app.cluster.load(&app.cacher).await
+            }
+            ClusterEvent::ConfigChanged(_) => Ok(()),
+            ClusterEvent::Purge(key) => app.cacher.del(key).await,
+            ClusterEvent::Reload => app.reload(),
+        };
+        match res {
+            Ok(_) => log::info!(target: "cluster",
+                from = envelope.instance;
+                "applied {:?}", envelope.event),
+            Err(err) => log::error!(target: "cluster",
+                from = envelope.instance;
+                "failed to apply {:?}: {}", envelope.event, err),
+        }
+    }
+}
+
+pub fn parse_ecdsa_key(v: &str) -> Result<ecdsa::VerifyingKey, String> {
+    let v = general_purpose::URL_SAFE_NO_PAD
+        .decode(v)
+        .map_err(|_| format!("invalid base64 key: {}", v))?;
+    ecdsa::VerifyingKey::from_sec1_bytes(&v).map_err(|_| "invalid ecdsa key".to_string())
+}
+
+pub fn parse_ed25519_key(v: &str) -> Result<ed25519_dalek::VerifyingKey, String> {
+    let v = general_purpose::URL_SAFE_NO_PAD
+        .decode(v)
+        .map_err(|_| format!("invalid base64 key: {}", v))?;
+    let key: [u8; 32] = v
+        .try_into()
+        .map_err(|_| "invalid ed25519 key".to_string())?;
+    ed25519_dalek::VerifyingKey::from_bytes(&key).map_err(|_| "invalid ed25519 key".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{CacherEntry, MemoryCacher};
+
+    #[tokio::test]
+    async fn test_shared_config() {
+        let cacher = HybridCacher::new(10, 1000, CacherEntry::Memory(MemoryCacher::default()));
+        let mut rx = cacher.subscribe(CHANNEL).await.unwrap();
+        let a = Cluster::new(true, "a".to_string());
+        let b = Cluster::new(true, "b".to_string());
+
+        let key = ed25519_dalek::SigningKey::from_bytes(&[9u8; 32]);
+        let pub_key = general_purpose::URL_SAFE_NO_PAD.encode(key.verifying_key().to_bytes());
+        let cfg = a
+            .update(&cacher, |cfg| {
+                cfg.ed25519_pub_keys.insert(pub_key.clone());
+                cfg.revoked_agents.insert("mallory".to_string());
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert!(cfg.version > 0);
+
+        let envelope: Envelope = from_reader(&rx.recv().await.unwrap()[..]).unwrap();
+        assert_eq!(envelope.instance, "a");
+        assert_eq!(envelope.event, ClusterEvent::ConfigChanged(cfg.version));
+
+        assert!(!b.has_keys());
+        b.load(&cacher).await.unwrap();
+        assert_eq!(b.config(), cfg);
+        assert!(b.is_revoked("mallory"));
+        let token = auth::ed25519_sign(&key, unix_ms() / 1000 + 60, "web".to_string());
+        assert_eq!(b.verify(&token), Some("web".to_string()));
+
+        assert!(a
+            .update(&cacher, |cfg| {
+                cfg.ecdsa_pub_keys.insert("invalid".to_string());
+                Ok(())
+            })
+            .await
+            .is_err());
+        assert_eq!(a.config(), cfg);
+
+        let now = unix_ms() / 1000;
+        let cfg = b
+            .revoke(
+                &cacher,
+                Revocation::Token {
+                    hash: token_hash("leaked"),
+                    expire_at: now + 60,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(cfg.revoked_tokens.len(), 1);
+        b.revoke(
+            &cacher,
+            Revocation::Agent {
+                agent: "web".to_string(),
+                expire_before: now + 3600,
+            },
+        )
+        .await
+        .unwrap();
+        a.load(&cacher).await.unwrap();
+        assert!(a.is_token_revoked("other", &token_hash("leaked"), now + 60));
+        assert!(!a.is_token_revoked("other", &token_hash("fresh"), now + 60));
+        assert!(a.is_token_revoked("web", &token_hash("fresh"), now + 3600));
+        assert!(!a.is_token_revoked("web", &token_hash("fresh"), now + 3601));
+
+        let mut cfg = (*a.config()).clone();
+        cfg.prune_revocations(now + 120);
+        assert!(cfg.revoked_tokens.is_empty());
+        assert_eq!(cfg.revoked_before.len(), 1);
+
+        let disabled = Cluster::default();
+        assert!(disabled.update(&cacher, |_| Ok(())).await.is_err());
+    }
+}
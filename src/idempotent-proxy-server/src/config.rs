@@ -0,0 +1,562 @@
+use base64::{engine::general_purpose, Engine};
+use http::HeaderValue;
+use idempotent_proxy_types::auth;
+use k256::ecdsa;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+
+use crate::cache::Storage;
+use crate::handler::AppState;
+use crate::keyring::KeyRing;
+use crate::{
+    access_log, admin, admin_ui, agent_limits, agent_methods, agents, alerts, analytics, breaker,
+    cache, cluster, config_file, dns, events, grpc, grpc_proxy, handler, ip_filter, journal, jwt,
+    keying, leader, metrics, otel, policy, pool, redact, resources, routes, scheduler, secrets,
+    shards, tenants, usage, waiters, webhook,
+};
+
+impl AppState {
+    // Builds the state from the environment variables documented in .env,
+    // panics on invalid values like the server does at startup.
+    pub async fn from_env() -> Self {
+        Self::build(None).await
+    }
+
+    // Like from_env, but stores the locks and responses in the given backend
+    // instead of Redis or memory.
+    pub async fn from_env_with_storage(storage: Box<dyn cache::Storage>) -> Self {
+        Self::build(Some(storage)).await
+    }
+
+    async fn build(storage: Option<Box<dyn cache::Storage>>) -> Self {
+        let req_timeout: u64 = std::env::var("REQUEST_TIMEOUT")
+            .map(|n| n.parse().unwrap())
+            .unwrap_or(10000u64)
+            .max(1000u64);
+        let poll_interval: u64 = std::env::var("POLL_INTERVAL")
+            .map(|n| n.parse().unwrap())
+            .unwrap_or(100u64)
+            .max(10u64);
+
+        redact::init(redact::Redactor::from_vars(std::env::vars()));
+        otel::init(std::env::vars()).expect("invalid OpenTelemetry config");
+
+        let dns_config = dns::DnsConfig::from_vars(std::env::vars()).expect("invalid DNS config");
+        if !dns_config.is_empty() {
+            dns::init(dns::CachingResolver::new(dns_config).unwrap());
+        }
+        let http_client = pool::client_builder(req_timeout).build().unwrap();
+
+        let postgres_url = std::env::var("POSTGRES_URL").ok();
+        let dynamodb_table = std::env::var("DYNAMODB_TABLE").ok();
+        let backends = ["REDIS_URL", "POSTGRES_URL", "DYNAMODB_TABLE"]
+            .iter()
+            .filter(|k| std::env::var(k).is_ok())
+            .count();
+        if storage.is_none() && backends > 1 {
+            panic!("only one of REDIS_URL, POSTGRES_URL and DYNAMODB_TABLE can be set");
+        }
+        let cacher_entry = match (storage, std::env::var("REDIS_URL")) {
+            (Some(storage), _) => cache::CacherEntry::Custom(storage),
+            (None, Ok(_)) => {
+                let url = cache::redis_url(std::env::vars()).expect("invalid Redis config");
+                let redis_client = cache::RedisClient::new(&url).await.unwrap().with_retry(
+                    std::env::var("STORAGE_MAX_RETRIES")
+                        .map(|n| n.parse().unwrap())
+                        .unwrap_or(3u32),
+                    std::env::var("STORAGE_RETRY_INTERVAL")
+                        .map(|n| n.parse().unwrap())
+                        .unwrap_or(100u64)
+                        .max(10u64),
+                    std::env::var("STORAGE_MAX_RETRYING")
+                        .map(|n| n.parse().unwrap())
+                        .unwrap_or(1000usize),
+                );
+                cache::CacherEntry::Redis(redis_client)
+            }
+            (None, Err(_)) if postgres_url.is_some() => {
+                let client = cache::PostgresClient::new(
+                    postgres_url.as_deref().unwrap(),
+                    &std::env::var("POSTGRES_TABLE")
+                        .unwrap_or("idempotent_proxy_cache".to_string()),
+                    std::env::var("POSTGRES_CLEANUP_INTERVAL")
+                        .map(|n| n.parse().unwrap())
+                        .unwrap_or(60u64),
+                )
+                .await
+                .expect("failed to connect to Postgres");
+                cache::CacherEntry::Postgres(client)
+            }
+            #[cfg(not(feature = "dynamodb"))]
+            (None, Err(_)) if dynamodb_table.is_some() => {
+                panic!("DYNAMODB_TABLE needs a build with the dynamodb feature")
+            }
+            #[cfg(feature = "dynamodb")]
+            (None, Err(_)) if dynamodb_table.is_some() => cache::CacherEntry::DynamoDb(
+                cache::DynamoClient::new(
+                    dynamodb_table.as_deref().unwrap(),
+                    std::env::var("DYNAMODB_ENDPOINT_URL").ok().as_deref(),
+                )
+                .await,
+            ),
+            (None, Err(_)) => {
+                let cacher = cache::MemoryCacher::new(
+                    std::env::var("CACHE_SHARDS")
+                        .map(|n| n.parse().unwrap())
+                        .unwrap_or(shards::DEFAULT_SHARDS),
+                )
+                .with_max_entries(
+                    std::env::var("CACHE_MAX_ENTRIES")
+                        .map(|n| n.parse().unwrap())
+                        .unwrap_or(0usize),
+                );
+                cache::CacherEntry::Memory(match std::env::var("CACHE_SQLITE_PATH") {
+                    Ok(path) => cacher
+                        .with_sqlite(&path)
+                        .await
+                        .expect("failed to open CACHE_SQLITE_PATH"),
+                    Err(_) => cacher,
+                })
+            }
+        };
+        let cacher_entry = match std::env::var("REPLICATION_MODE") {
+            Ok(mode) if !mode.is_empty() => {
+                let mode: cache::ReplicationMode = mode.parse().unwrap();
+                let region = std::env::var("REPLICATION_REGION")
+                    .expect("REPLICATION_MODE needs REPLICATION_REGION");
+                let url = std::env::var("REPLICATION_REDIS_URL")
+                    .expect("REPLICATION_MODE needs REPLICATION_REDIS_URL");
+                let global = cache::RedisClient::new(&url)
+                    .await
+                    .expect("failed to connect to REPLICATION_REDIS_URL");
+                let local = cache::HybridCacher::new(poll_interval, req_timeout, cacher_entry);
+                let replicated = cache::Replicated::new(
+                    mode,
+                    &region,
+                    &std::env::var("REPLICATION_CHANNEL")
+                        .unwrap_or("idempotent-proxy:replication".to_string()),
+                    local,
+                    global,
+                )
+                .await
+                .expect("failed to start the replication");
+                cache::CacherEntry::Replicated(Box::new(replicated))
+            }
+            _ => cacher_entry,
+        };
+
+        let agents: agents::AgentSet = std::env::var("ALLOW_AGENTS")
+            .unwrap_or_default()
+            .parse()
+            .expect("invalid ALLOW_AGENTS");
+
+        let nonce_agents: agents::AgentSet = std::env::var("NONCE_AGENTS")
+            .unwrap_or_default()
+            .parse()
+            .expect("invalid NONCE_AGENTS");
+
+        let admin_agents: agents::AgentSet = std::env::var("ADMIN_AGENTS")
+            .unwrap_or_default()
+            .parse()
+            .expect("invalid ADMIN_AGENTS");
+
+        let live = LiveConfig::from_vars(std::env::vars()).expect("invalid config");
+        let routes = routes::Routes::from_vars(std::env::vars()).expect("invalid route config");
+        let tenants = tenants::Tenants::from_vars(std::env::vars()).expect("invalid tenant config");
+
+        let idempotency_mode: handler::IdempotencyMode = std::env::var("IDEMPOTENCY_MODE")
+            .unwrap_or_default()
+            .parse()
+            .unwrap();
+
+        let events = match std::env::var("NATS_URL") {
+            Ok(url) => {
+                let prefix = std::env::var("NATS_SUBJECT_PREFIX")
+                    .unwrap_or_else(|_| "idempotent-proxy".to_string());
+                events::EventPublisher::connect(&url, &prefix)
+                    .await
+                    .unwrap()
+            }
+            Err(_) => events::EventPublisher::default(),
+        };
+
+        let jwt = jwt::JwtVerifier::from_vars(std::env::vars()).expect("invalid JWT config");
+        if let Some(jwt) = &jwt {
+            // tokens are rejected until the keys are fetched, the refresh job retries
+            if let Err(err) = jwt.refresh_keys(&http_client).await {
+                log::error!(target: "jwt", "{}", err);
+            }
+        }
+
+        let signing_key: Option<ed25519_dalek::SigningKey> =
+            std::env::var("PROXY_SIGNING_KEY").ok().map(|v| {
+                let v = general_purpose::URL_SAFE_NO_PAD
+                    .decode(v)
+                    .expect("invalid base64");
+                let key: [u8; 32] = v.try_into().expect("invalid ed25519 signing key");
+                ed25519_dalek::SigningKey::from_bytes(&key)
+            });
+
+        let webhook = webhook::WebhookSender {
+            http_client: http_client.clone(),
+            signing_key: signing_key.clone(),
+            max_retries: std::env::var("CALLBACK_MAX_RETRIES")
+                .map(|n| n.parse().unwrap())
+                .unwrap_or(5u32),
+            retry_interval: std::env::var("CALLBACK_RETRY_INTERVAL")
+                .map(|n| n.parse().unwrap())
+                .unwrap_or(1000u64)
+                .max(100u64),
+        };
+
+        let scheduler = scheduler::Scheduler {
+            poll_interval: std::env::var("SCHEDULE_POLL_INTERVAL")
+                .map(|n| n.parse().unwrap())
+                .unwrap_or(1000u64)
+                .max(100u64),
+            max_delay: std::env::var("SCHEDULE_MAX_DELAY")
+                .map(|n| n.parse::<u64>().unwrap())
+                .unwrap_or(86400u64)
+                * 1000,
+        };
+
+        let instance_id = std::env::var("INSTANCE_ID").unwrap_or_else(|_| {
+            format!(
+                "{}-{}",
+                std::env::var("HOSTNAME").unwrap_or_default(),
+                idempotent_proxy_types::unix_ms()
+            )
+        });
+
+        AppState {
+            http_client: Arc::new(http_client.clone()),
+            cacher: Arc::new(cache::HybridCacher::new(
+                poll_interval,
+                req_timeout,
+                cacher_entry,
+            )),
+            agents: Arc::new(agents),
+            nonce_agents: Arc::new(nonce_agents),
+            agent_methods: Arc::new(
+                agent_methods::AgentMethods::from_vars(std::env::vars())
+                    .expect("invalid AGENT_METHODS"),
+            ),
+            agent_limits: Arc::new(
+                agent_limits::AgentLimits::from_vars(std::env::vars())
+                    .expect("invalid AGENT_LIMITS"),
+            ),
+            policies: Arc::new(
+                policy::Policies::from_vars(std::env::vars()).expect("invalid POLICY config"),
+            ),
+            live: Arc::new(RwLock::new(Arc::new(live))),
+            jwt: jwt.map(Arc::new),
+            events: Arc::new(events),
+            webhook: Arc::new(webhook),
+            alerts: Arc::new(
+                alerts::Alerts::from_vars(std::env::vars(), http_client.clone()).unwrap(),
+            ),
+            signing_key: signing_key.map(Arc::new),
+            tenants: Arc::new(tenants),
+            ip_filter: Arc::new(ip_filter::IpFilter::from_vars(std::env::vars()).unwrap()),
+            cluster: Arc::new(cluster::Cluster::new(
+                std::env::var("CLUSTER_CONFIG_SHARING")
+                    .map(|v| v == "true")
+                    .unwrap_or(false),
+                instance_id.clone(),
+            )),
+            leader: Arc::new(leader::Leader::new(
+                std::env::var("LEADER_ELECTION")
+                    .map(|v| v == "true")
+                    .unwrap_or(false),
+                "background",
+                &instance_id,
+                std::env::var("LEADER_LEASE_TTL")
+                    .map(|n| n.parse::<u64>().unwrap())
+                    .unwrap_or(10u64)
+                    .max(1u64)
+                    * 1000,
+            )),
+            journal: Arc::new(journal::Journal::new(
+                std::env::var("JOURNAL_SIZE")
+                    .map(|n| n.parse().unwrap())
+                    .unwrap_or(10000usize),
+            )),
+            access_log: Arc::new(
+                access_log::AccessLog::from_vars(std::env::vars())
+                    .expect("invalid access log config"),
+            ),
+            usage: Arc::new(
+                usage::Accounting::from_vars(std::env::vars(), &instance_id)
+                    .expect("invalid usage accounting config"),
+            ),
+            analytics: Arc::new(analytics::Analytics::new(poll_interval)),
+            resources: Arc::new(resources::Resources::default()),
+            breakers: Arc::new(
+                breaker::Breakers::from_vars(std::env::vars())
+                    .expect("invalid circuit breaker config"),
+            ),
+            waiters: Arc::new(waiters::Waiters::new(
+                std::env::var("MAX_WAITERS_PER_KEY")
+                    .map(|n| n.parse().unwrap())
+                    .unwrap_or(0usize),
+            )),
+            scheduler: Arc::new(scheduler),
+            grpc_channels: Arc::new(grpc_proxy::Channels::new(req_timeout)),
+            idempotency_mode,
+            // the x-idempotency-ttl header can't exceed it, REQUEST_TIMEOUT by default
+            idempotency_ttl_max: std::env::var("IDEMPOTENCY_TTL_MAX")
+                .map(|n| n.parse::<u64>().unwrap() * 1000)
+                .unwrap_or(req_timeout),
+            check_fingerprint: std::env::var("IDEMPOTENCY_FINGERPRINT")
+                .map(|v| v.parse().expect("invalid IDEMPOTENCY_FINGERPRINT"))
+                .unwrap_or(true),
+            storage_failure: std::env::var("STORAGE_FAILURE_POLICY")
+                .unwrap_or_default()
+                .parse()
+                .unwrap(),
+            wait_policy: waiters::WaitPolicy::from_vars(
+                std::env::vars(),
+                poll_interval,
+                req_timeout,
+            )
+            .expect("invalid wait config"),
+            keying: keying::Keying::from_vars(std::env::vars())
+                .expect("invalid idempotency key config"),
+            // in milliseconds, a third of the lock TTL by default
+            lock_renew_interval: std::env::var("LOCK_RENEW_INTERVAL")
+                .map(|v| v.parse().expect("invalid LOCK_RENEW_INTERVAL"))
+                .unwrap_or(req_timeout / 3),
+            admin: Arc::new(admin::AdminState::new(admin_agents, routes)),
+        }
+    }
+
+    // Starts the background jobs and the admin listeners configured by the environment.
+    pub fn spawn_services(&self) {
+        tokio::spawn(scheduler::run(self.clone()));
+        tokio::spawn(cluster::run(self.clone()));
+        tokio::spawn(self.leader.clone().run(self.cacher.clone()));
+        tokio::spawn(config_file::watch(self.clone()));
+        tokio::spawn(secrets::run(self.clone()));
+        tokio::spawn(usage::run(self.clone()));
+        if let Some(jwt) = &self.jwt {
+            tokio::spawn(jwt.clone().run(self.http_client.clone()));
+        }
+        if let Ok(addr) = std::env::var("GRPC_ADDR") {
+            let addr: SocketAddr = addr.parse().expect("invalid GRPC_ADDR");
+            tokio::spawn(grpc::serve(addr, self.clone()));
+        }
+        if let Ok(addr) = std::env::var("ADMIN_UI_ADDR") {
+            let addr: SocketAddr = addr.parse().expect("invalid ADMIN_UI_ADDR");
+            tokio::spawn(admin_ui::serve(addr, self.clone()));
+        }
+        if let Ok(addr) = std::env::var("METRICS_ADDR") {
+            let addr: SocketAddr = addr.parse().expect("invalid METRICS_ADDR");
+            tokio::spawn(metrics::serve(addr, self.clone()));
+        }
+    }
+
+    // Called once the listeners stopped: releases the locks of the requests that did not
+    // finish draining, so their callers can retry on another instance instead of waiting
+    // for the lock TTL. Returns the number of released locks.
+    pub async fn shutdown(&self) -> usize {
+        let mut released = 0;
+        for (key, inflight) in self.admin.inflight() {
+            // the response may have been cached after the request left the in-flight list
+            match self.cacher.get(&key).await {
+                Ok(None) => {}
+                Ok(Some(_)) => continue,
+                Err(err) => {
+                    log::error!(target: "server", idempotency_key = key; "release lock failed: {}", err);
+                    continue;
+                }
+            }
+            match self.cacher.del(&key).await {
+                Ok(()) => {
+                    log::warn!(target: "server",
+                        action = "release",
+                        agent = inflight.agent,
+                        method = inflight.method,
+                        idempotency_key = key;
+                        "lock released on shutdown");
+                    self.admin.finish(&key);
+                    released += 1;
+                }
+                Err(err) => {
+                    log::error!(target: "server", idempotency_key = key; "release lock failed: {}", err);
+                }
+            }
+        }
+        if let Err(err) = self.usage.flush(&self.cacher).await {
+            log::error!(target: "usage", "flush failed: {}", err);
+        }
+        self.leader.resign(&self.cacher).await;
+        released
+    }
+
+    pub fn live(&self) -> Arc<LiveConfig> {
+        self.live.read().unwrap().clone()
+    }
+
+    // Reloads the .env file and CONFIG_FILE: the routes, the URL_ and HEADER_ constants and
+    // the verification keys. Requests in flight finish with the config they started with,
+    // the other settings, e.g. the storage backend, need a restart.
+    pub fn reload(&self) -> Result<(), String> {
+        if let Err(err) = dotenvy::dotenv_override() {
+            if !err.not_found() {
+                return Err(format!("reload .env failed: {}", err));
+            }
+        }
+        config_file::apply_from_env()?;
+        secrets::apply_cached();
+        let routes = routes::Routes::from_vars(std::env::vars())?;
+        let live = LiveConfig::from_vars(std::env::vars())?;
+        self.admin.set_routes(routes);
+        *self.live.write().unwrap() = Arc::new(live);
+        Ok(())
+    }
+}
+
+// The constants and verification keys replaced together on reload.
+#[derive(Debug, Default)]
+pub struct LiveConfig {
+    pub url_vars: HashMap<String, String>,
+    pub header_vars: HashMap<String, HeaderValue>,
+    pub ecdsa_pub_keys: KeyRing<ecdsa::VerifyingKey>,
+    pub ed25519_pub_keys: KeyRing<ed25519_dalek::VerifyingKey>,
+    pub p256_pub_keys: KeyRing<p256::ecdsa::VerifyingKey>,
+    pub bls_pub_keys: KeyRing<[u8; 96]>,
+    pub hmac_keys: Vec<auth::HmacKey>,
+}
+
+impl LiveConfig {
+    pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Self, String> {
+        let vars: Vec<(String, String)> = vars.collect();
+        // the placeholders are resolved when forwarding, an unknown variable fails early
+        let lookup = |name: &str| vars.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone());
+        for (k, v) in &vars {
+            if k.starts_with("URL_") || k.starts_with("HEADER_") {
+                secrets::expand_with(v, lookup).map_err(|err| format!("{}: {}", k, err))?;
+            }
+        }
+        let url_vars = vars
+            .iter()
+            .filter(|(k, _)| k.starts_with("URL_"))
+            .cloned()
+            .collect();
+        let header_vars = vars
+            .iter()
+            .filter(|(k, _)| k.starts_with("HEADER_"))
+            .map(|(k, v)| {
+                v.parse()
+                    .map(|v| (k.clone(), v))
+                    .map_err(|_| format!("invalid header value of {}", k))
+            })
+            .collect::<Result<_, _>>()?;
+
+        // <ALG>_PUB_KEY_<ID>, generations of keys with an optional activation and retirement time
+        let ecdsa_pub_keys = KeyRing::from_vars("ECDSA_PUB_KEY", vars.iter().cloned(), |v| {
+            ecdsa::VerifyingKey::from_sec1_bytes(&v).ok()
+        })?;
+        let ed25519_pub_keys = KeyRing::from_vars("ED25519_PUB_KEY", vars.iter().cloned(), |v| {
+            ed25519_dalek::VerifyingKey::from_bytes(&v.try_into().ok()?).ok()
+        })?;
+        let p256_pub_keys = KeyRing::from_vars("P256_PUB_KEY", vars.iter().cloned(), |v| {
+            p256::ecdsa::VerifyingKey::from_sec1_bytes(&v).ok()
+        })?;
+        let bls_pub_keys =
+            KeyRing::from_vars("BLS_PUB_KEY", vars.iter().cloned(), |v| v.try_into().ok())?;
+
+        // HMAC_KEY_<ID>, the lowercase id is the key id of the tokens, empty for HMAC_KEY
+        let mut hmac_keys = Vec::new();
+        for (k, v) in &vars {
+            let id = match k.strip_prefix("HMAC_KEY") {
+                Some("") => "",
+                Some(id) => match id.strip_prefix('_') {
+                    Some(id) => id,
+                    None => continue,
+                },
+                None => continue,
+            };
+            let secret = general_purpose::URL_SAFE_NO_PAD
+                .decode(v)
+                .map_err(|_| format!("invalid base64 in {}", k))?;
+            if secret.len() < 16 {
+                return Err(format!("HMAC key {} is shorter than 16 bytes", k));
+            }
+            hmac_keys.push(auth::HmacKey {
+                id: id.to_ascii_lowercase(),
+                secret,
+            });
+        }
+
+        Ok(LiveConfig {
+            url_vars,
+            header_vars,
+            ecdsa_pub_keys,
+            ed25519_pub_keys,
+            p256_pub_keys,
+            bls_pub_keys,
+            hmac_keys,
+        })
+    }
+
+    pub fn has_keys(&self) -> bool {
+        !self.ecdsa_pub_keys.is_empty()
+            || !self.ed25519_pub_keys.is_empty()
+            || !self.p256_pub_keys.is_empty()
+            || !self.bls_pub_keys.is_empty()
+            || !self.hmac_keys.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_vars;
+
+    #[test]
+    fn test_live_config() {
+        let live = LiveConfig::from_vars(test_vars(vec![
+            ("URL_HTTPBIN", "https://httpbin.org/get"),
+            ("HEADER_API_KEY", "abc123"),
+            (
+                "ECDSA_PUB_KEY_2024B",
+                "A6t1U8kc10AbLJ3-V1avU4rYvmAsYjXuzY0kPublttot;retire=1725148800",
+            ),
+            ("HMAC_KEY_K1", "c2hhcmVkIHNlY3JldCBrZXk"),
+            ("HMAC_KEYS", "ignored"),
+        ]))
+        .unwrap();
+        assert_eq!(live.url_vars.len(), 1);
+        assert_eq!(live.header_vars["HEADER_API_KEY"], "abc123");
+        assert_eq!(live.ecdsa_pub_keys.generations()[0].id, "2024b");
+        assert_eq!(live.hmac_keys.len(), 1);
+        assert_eq!(live.hmac_keys[0].id, "k1");
+        assert!(live.has_keys());
+        assert!(!LiveConfig::default().has_keys());
+
+        assert!(LiveConfig::from_vars(test_vars(vec![("HMAC_KEY", "c2hvcnQ")])).is_err());
+        assert!(LiveConfig::from_vars(test_vars(vec![("HEADER_X", "a\nb")])).is_err());
+        let live = LiveConfig::from_vars(test_vars(vec![
+            (
+                "URL_COINBASE",
+                "https://api.coinbase.com/v2?key=${COINBASE_KEY}",
+            ),
+            ("HEADER_COINBASE", "Bearer ${COINBASE_KEY}"),
+            ("COINBASE_KEY", "s3cr3t"),
+        ]))
+        .unwrap();
+        // kept as templates until forwarded
+        assert_eq!(
+            live.url_vars["URL_COINBASE"],
+            "https://api.coinbase.com/v2?key=${COINBASE_KEY}"
+        );
+        assert!(
+            LiveConfig::from_vars(test_vars(vec![("URL_X", "https://x.io/${MISSING}")])).is_err()
+        );
+        assert!(LiveConfig::from_vars(test_vars(vec![("ED25519_PUB_KEY", "AQID")])).is_err());
+    }
+}
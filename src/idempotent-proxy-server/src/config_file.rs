@@ -0,0 +1,197 @@
+use std::{collections::BTreeSet, path::Path, sync::Mutex, time::SystemTime};
+use tokio::time::{sleep, Duration};
+
+use crate::handler::AppState;
+
+// The variables set by the last load of CONFIG_FILE.
+static APPLIED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+// A TOML config file as environment variables: the top level keys are variable names and
+// the keys of a table are prefixed with its name, so `[url] httpbin = "..."` is URL_HTTPBIN
+// and `[ecdsa_pub_key] 2024b = "..."` is ECDSA_PUB_KEY_2024B. Arrays are joined with commas.
+pub fn parse(data: &str) -> Result<Vec<(String, String)>, String> {
+    let table: toml::Table = data
+        .parse()
+        .map_err(|err| format!("invalid config file: {}", err))?;
+    let mut vars = Vec::new();
+    flatten("", &table, &mut vars)?;
+    Ok(vars)
+}
+
+fn flatten(
+    prefix: &str,
+    table: &toml::Table,
+    vars: &mut Vec<(String, String)>,
+) -> Result<(), String> {
+    for (k, v) in table {
+        let name = match prefix {
+            "" => k.to_ascii_uppercase(),
+            prefix => format!("{}_{}", prefix, k.to_ascii_uppercase()),
+        };
+        match v {
+            toml::Value::Table(t) => flatten(&name, t, vars)?,
+            toml::Value::Array(items) => {
+                let items = items
+                    .iter()
+                    .map(|item| scalar(item).ok_or_else(|| format!("invalid value of {}", name)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                vars.push((name, items.join(",")));
+            }
+            v => vars.push((name, scalar(v).unwrap_or_default())),
+        }
+    }
+    Ok(())
+}
+
+fn scalar(v: &toml::Value) -> Option<String> {
+    match v {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(n) => Some(n.to_string()),
+        toml::Value::Float(n) => Some(n.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        toml::Value::Datetime(d) => Some(d.to_string()),
+        _ => None,
+    }
+}
+
+// Sets the variables of CONFIG_FILE, they override the environment and the .env file.
+// Variables removed from the file since the last load are removed from the environment.
+pub fn apply_from_env() -> Result<usize, String> {
+    let path = match std::env::var("CONFIG_FILE") {
+        Ok(path) if !path.trim().is_empty() => path,
+        _ => return Ok(0),
+    };
+    let data = std::fs::read_to_string(path.trim())
+        .map_err(|err| format!("read {} failed: {}", path, err))?;
+    let vars = parse(&data)?;
+    set_vars(&APPLIED, &vars);
+    Ok(vars.len())
+}
+
+// Sets the variables in the environment, and removes the ones `applied` by the last call
+// that are gone.
+pub fn set_vars(applied: &Mutex<BTreeSet<String>>, vars: &[(String, String)]) {
+    let mut applied = applied.lock().unwrap();
+    let names: BTreeSet<String> = vars.iter().map(|(k, _)| k.clone()).collect();
+    for name in applied.difference(&names) {
+        std::env::remove_var(name);
+    }
+    for (k, v) in vars {
+        std::env::set_var(k, v);
+    }
+    *applied = names;
+}
+
+// Resolves a path relative to the directory of CONFIG_FILE, or to the working directory
+// without CONFIG_FILE.
+pub fn resolve(path: &str) -> String {
+    let config = std::env::var("CONFIG_FILE").unwrap_or_default();
+    match Path::new(config.trim()).parent() {
+        Some(dir) if Path::new(path).is_relative() => dir.join(path).to_string_lossy().to_string(),
+        _ => path.to_string(),
+    }
+}
+
+fn modified_at(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// The modification times of CONFIG_FILE and of the route scripts.
+fn modified_all(app: &AppState, path: &str) -> Vec<Option<SystemTime>> {
+    std::iter::once(path.to_string())
+        .filter(|path| !path.is_empty())
+        .chain(app.admin.routes().script_paths())
+        .map(|path| modified_at(&path))
+        .collect()
+}
+
+// Reloads the config on SIGHUP, and when CONFIG_FILE or a route script changes if
+// CONFIG_FILE_WATCH is set to the seconds between the checks.
+pub async fn watch(app: AppState) {
+    let path = std::env::var("CONFIG_FILE").unwrap_or_default();
+    let path = path.trim();
+    let interval: u64 = std::env::var("CONFIG_FILE_WATCH")
+        .map(|n| n.parse().expect("invalid CONFIG_FILE_WATCH"))
+        .unwrap_or(0);
+    let mut modified = modified_all(&app, path);
+
+    #[cfg(unix)]
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            log::error!(target: "config", "failed to install SIGHUP handler: {}", err);
+            return;
+        }
+    };
+    loop {
+        #[cfg(unix)]
+        let signaled = hangup.recv();
+        #[cfg(not(unix))]
+        let signaled = std::future::pending::<Option<()>>();
+
+        let trigger = tokio::select! {
+            _ = signaled => "SIGHUP",
+            _ = sleep(Duration::from_secs(interval)), if interval > 0 => {
+                let m = modified_all(&app, path);
+                if m == modified {
+                    continue;
+                }
+                "CONFIG_FILE or script change"
+            }
+        };
+        let reloaded = app.reload();
+        // the scripts of the reloaded routes may differ
+        modified = modified_all(&app, path);
+        match reloaded {
+            Ok(_) => log::warn!(target: "config", "reloaded on {}", trigger),
+            Err(err) => log::error!(target: "config", "reload on {} failed: {}", trigger, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let vars = parse(
+            r#"
+redis_url = "redis://127.0.0.1:6379"
+request_timeout = 10000
+hmac_key = "c2hhcmVkIHNlY3JldCBrZXk"
+
+[url]
+httpbin = "https://httpbin.org/get"
+
+[ecdsa_pub_key]
+2024b = "A6t1U8kc10AbLJ3-V1avU4rYvmAsYjXuzY0kPublttot;active=1717200000"
+
+[route.httpbin]
+cache_ttl = 60
+cors_origins = ["https://app.example.com", "https://admin.example.com"]
+"#,
+        )
+        .unwrap();
+        let get = |name: &str| {
+            vars.iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(get("REDIS_URL"), Some("redis://127.0.0.1:6379"));
+        assert_eq!(get("REQUEST_TIMEOUT"), Some("10000"));
+        assert_eq!(get("URL_HTTPBIN"), Some("https://httpbin.org/get"));
+        assert_eq!(
+            get("ECDSA_PUB_KEY_2024B"),
+            Some("A6t1U8kc10AbLJ3-V1avU4rYvmAsYjXuzY0kPublttot;active=1717200000")
+        );
+        assert_eq!(get("ROUTE_HTTPBIN_CACHE_TTL"), Some("60"));
+        assert_eq!(
+            get("ROUTE_HTTPBIN_CORS_ORIGINS"),
+            Some("https://app.example.com,https://admin.example.com")
+        );
+
+        assert!(parse("url = [{ a = 1 }]").is_err());
+        assert!(parse("[url").is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use axum::{body::Body, response::Response};
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+
+use crate::routes::split_list;
+
+// CORS preflight answered by the proxy, OPTIONS requests of a route with
+// allowed origins never reach the upstream and are never cached.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Cors {
+    pub allow_origins: Vec<String>, // "*" for any origin
+    pub allow_methods: Option<String>,
+    pub allow_headers: Option<String>,
+    pub max_age: Option<u64>, // in seconds
+}
+
+impl Cors {
+    pub fn set(&mut self, option: &str, value: &str) -> Result<(), String> {
+        match option {
+            "CORS_ALLOW_ORIGINS" => self.allow_origins = split_list(value),
+            "CORS_ALLOW_METHODS" => self.allow_methods = Some(value.trim().to_string()),
+            "CORS_ALLOW_HEADERS" => self.allow_headers = Some(value.trim().to_string()),
+            "CORS_MAX_AGE" => {
+                self.max_age = Some(
+                    value
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("invalid CORS_MAX_AGE value: {}", value))?,
+                )
+            }
+            _ => return Err(format!("unknown route option: {}", option)),
+        }
+        Ok(())
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        if self.allow_origins.is_empty() {
+            return Err("CORS_ALLOW_ORIGINS is required".to_string());
+        }
+        Ok(())
+    }
+
+    // Answers a preflight request, an origin that is not allowed gets no CORS headers
+    // so the browser blocks the actual request.
+    pub fn preflight(&self, headers: &HeaderMap) -> Response {
+        let mut res = Response::new(Body::empty());
+        *res.status_mut() = StatusCode::NO_CONTENT;
+        let origin = headers
+            .get(header::ORIGIN)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let allow_origin = if self.allow_origins.iter().any(|o| o == "*") {
+            HeaderValue::from_static("*")
+        } else if !origin.is_empty() && self.allow_origins.iter().any(|o| o == origin) {
+            res.headers_mut()
+                .insert(header::VARY, HeaderValue::from_static("Origin"));
+            match HeaderValue::from_str(origin) {
+                Ok(v) => v,
+                Err(_) => return res,
+            }
+        } else {
+            return res;
+        };
+
+        let h = res.headers_mut();
+        h.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        let methods = self
+            .allow_methods
+            .as_deref()
+            .unwrap_or("GET, HEAD, POST, PUT, PATCH, DELETE");
+        if let Ok(v) = HeaderValue::from_str(methods) {
+            h.insert(header::ACCESS_CONTROL_ALLOW_METHODS, v);
+        }
+        // the requested headers are allowed by default
+        let allow_headers = match &self.allow_headers {
+            Some(v) => HeaderValue::from_str(v).ok(),
+            None => headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS).cloned(),
+        };
+        if let Some(v) = allow_headers {
+            h.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, v);
+        }
+        if let Some(max_age) = self.max_age {
+            h.insert(header::ACCESS_CONTROL_MAX_AGE, max_age.into());
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_preflight() {
+        let mut cors = Cors::default();
+        cors.set("CORS_ALLOW_ORIGINS", "https://app.example.com")
+            .unwrap();
+        cors.set("CORS_MAX_AGE", "600").unwrap();
+        assert!(cors.check().is_ok());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ORIGIN,
+            HeaderValue::from_static("https://app.example.com"),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            HeaderValue::from_static("idempotency-key, proxy-authorization"),
+        );
+        let res = cors.preflight(&headers);
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "idempotency-key, proxy-authorization"
+        );
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_MAX_AGE], "600");
+        assert_eq!(res.headers()[header::VARY], "Origin");
+
+        headers.insert(
+            header::ORIGIN,
+            HeaderValue::from_static("https://evil.example.com"),
+        );
+        let res = cors.preflight(&headers);
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(res
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        assert!(Cors::default().check().is_err());
+        assert!(Cors::default().set("CORS_MAX_AGE", "x").is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use hickory_resolver::{
+    config::{
+        LookupIpStrategy, NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig,
+        ResolverOpts,
+    },
+    system_conf::read_system_conf,
+    TokioAsyncResolver,
+};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+static RESOLVER: OnceLock<Arc<CachingResolver>> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IpStrategy {
+    // A and AAAA records are resolved together, addresses are interleaved (IPv6 first)
+    // so the connector races the families as in RFC 8305 (Happy Eyeballs v2).
+    #[default]
+    Dual,
+    Ipv4,
+    Ipv6,
+}
+
+impl FromStr for IpStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "dual" => Ok(IpStrategy::Dual),
+            "ipv4" => Ok(IpStrategy::Ipv4),
+            "ipv6" => Ok(IpStrategy::Ipv6),
+            v => Err(format!("invalid DNS ip strategy: {}", v)),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DnsConfig {
+    pub nameservers: Vec<SocketAddr>,
+    pub min_ttl: Option<u64>, // in seconds
+    pub max_ttl: Option<u64>, // in seconds
+    pub cache_size: Option<usize>,
+    pub overrides: HashMap<String, Vec<IpAddr>>,
+    pub ip_strategy: Option<IpStrategy>,
+}
+
+impl DnsConfig {
+    pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Self, String> {
+        let mut cfg = DnsConfig::default();
+        for (k, v) in vars {
+            match k.as_str() {
+                "DNS_NAMESERVERS" => cfg.nameservers = parse_nameservers(&v)?,
+                "DNS_MIN_TTL" => cfg.min_ttl = Some(parse_number(&k, &v)?),
+                "DNS_MAX_TTL" => cfg.max_ttl = Some(parse_number(&k, &v)?),
+                "DNS_CACHE_SIZE" => cfg.cache_size = Some(parse_number(&k, &v)? as usize),
+                "DNS_OVERRIDES" => cfg.overrides = parse_overrides(&v)?,
+                "DNS_IP_STRATEGY" => cfg.ip_strategy = Some(v.parse()?),
+                _ => {}
+            }
+        }
+        Ok(cfg)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &DnsConfig::default()
+    }
+}
+
+// A caching resolver for upstream hosts with custom nameservers, TTL clamping and static overrides.
+pub struct CachingResolver {
+    resolver: TokioAsyncResolver,
+    overrides: HashMap<String, Vec<IpAddr>>,
+    ip_strategy: IpStrategy,
+}
+
+impl CachingResolver {
+    pub fn new(cfg: DnsConfig) -> Result<Self, String> {
+        let (config, mut opts) = if cfg.nameservers.is_empty() {
+            read_system_conf().map_err(|err| format!("read system DNS config failed: {}", err))?
+        } else {
+            let mut group = Vec::with_capacity(cfg.nameservers.len() * 2);
+            for addr in &cfg.nameservers {
+                group.push(NameServerConfig::new(*addr, Protocol::Udp));
+                group.push(NameServerConfig::new(*addr, Protocol::Tcp));
+            }
+            (
+                ResolverConfig::from_parts(None, vec![], NameServerConfigGroup::from(group)),
+                ResolverOpts::default(),
+            )
+        };
+        if let Some(secs) = cfg.min_ttl {
+            opts.positive_min_ttl = Some(Duration::from_secs(secs));
+        }
+        if let Some(secs) = cfg.max_ttl {
+            opts.positive_max_ttl = Some(Duration::from_secs(secs));
+        }
+        if let Some(size) = cfg.cache_size {
+            opts.cache_size = size;
+        }
+        let ip_strategy = cfg.ip_strategy.unwrap_or_default();
+        opts.ip_strategy = match ip_strategy {
+            IpStrategy::Dual => LookupIpStrategy::Ipv4AndIpv6,
+            IpStrategy::Ipv4 => LookupIpStrategy::Ipv4Only,
+            IpStrategy::Ipv6 => LookupIpStrategy::Ipv6Only,
+        };
+
+        Ok(Self {
+            resolver: TokioAsyncResolver::tokio(config, opts),
+            overrides: cfg.overrides,
+            ip_strategy,
+        })
+    }
+}
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_ascii_lowercase();
+        if let Some(ips) = self.overrides.get(&host) {
+            let addrs = to_addrs(ips.clone(), self.ip_strategy);
+            return Box::pin(async move { Ok(addrs) });
+        }
+
+        let resolver = self.resolver.clone();
+        let ip_strategy = self.ip_strategy;
+        Box::pin(async move {
+            let lookup = resolver.lookup_ip(host).await?;
+            Ok(to_addrs(lookup.iter().collect(), ip_strategy))
+        })
+    }
+}
+
+fn to_addrs(ips: Vec<IpAddr>, ip_strategy: IpStrategy) -> Addrs {
+    let ips = match ip_strategy {
+        IpStrategy::Dual => interleave(ips),
+        _ => ips,
+    };
+    Box::new(ips.into_iter().map(|ip| SocketAddr::new(ip, 0)))
+}
+
+// Sorts addresses as IPv6, IPv4, IPv6, IPv4..., the connector tries the first family and
+// starts the other family in parallel after a short delay (RFC 8305 section 4).
+fn interleave(ips: Vec<IpAddr>) -> Vec<IpAddr> {
+    let (v6, v4): (Vec<IpAddr>, Vec<IpAddr>) = ips.into_iter().partition(|ip| ip.is_ipv6());
+    let mut out = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => out.extend(a.into_iter().chain(b)),
+        }
+    }
+    out
+}
+
+pub fn init(resolver: CachingResolver) {
+    let _ = RESOLVER.set(Arc::new(resolver));
+}
+
+// The process wide resolver, None for the system resolver.
+pub fn resolver() -> Option<Arc<CachingResolver>> {
+    RESOLVER.get().cloned()
+}
+
+fn parse_number(key: &str, value: &str) -> Result<u64, String> {
+    value
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| format!("invalid {} value: {}", key, value))
+}
+
+// "1.1.1.1,[2606:4700:4700::1111]:53,8.8.8.8:5353", the port is 53 by default.
+fn parse_nameservers(value: &str) -> Result<Vec<SocketAddr>, String> {
+    value
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse::<SocketAddr>()
+                .or_else(|_| s.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                .map_err(|_| format!("invalid nameserver: {}", s))
+        })
+        .collect()
+}
+
+// "api.example.com=10.0.0.1|10.0.0.2,rpc.example.com=10.0.0.3"
+fn parse_overrides(value: &str) -> Result<HashMap<String, Vec<IpAddr>>, String> {
+    let mut overrides = HashMap::new();
+    for item in value.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        let (host, ips) = item
+            .split_once('=')
+            .ok_or_else(|| format!("invalid DNS override: {}", item))?;
+        let ips = ips
+            .split('|')
+            .map(|ip| {
+                ip.trim()
+                    .parse::<IpAddr>()
+                    .map_err(|_| format!("invalid DNS override: {}", item))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        overrides.insert(host.trim().to_ascii_lowercase(), ips);
+    }
+    Ok(overrides)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_interleave() {
+        let ips: Vec<IpAddr> = vec![
+            "10.0.0.1".parse().unwrap(),
+            "10.0.0.2".parse().unwrap(),
+            "10.0.0.3".parse().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+            "2001:db8::2".parse().unwrap(),
+        ];
+        let out: Vec<String> = interleave(ips).iter().map(|ip| ip.to_string()).collect();
+        assert_eq!(
+            out,
+            vec![
+                "2001:db8::1",
+                "10.0.0.1",
+                "2001:db8::2",
+                "10.0.0.2",
+                "10.0.0.3"
+            ]
+        );
+        assert!(interleave(vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_dns_config() {
+        let cfg = DnsConfig::from_vars(
+            vec![
+                (
+                    "DNS_NAMESERVERS".to_string(),
+                    "1.1.1.1, [2606:4700:4700::1111]:53,8.8.8.8:5353".to_string(),
+                ),
+                ("DNS_MIN_TTL".to_string(), "30".to_string()),
+                (
+                    "DNS_OVERRIDES".to_string(),
+                    "API.example.com=10.0.0.1|10.0.0.2, rpc.example.com=::1".to_string(),
+                ),
+                ("URL_HTTPBIN".to_string(), "https://httpbin.org".to_string()),
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+        assert_eq!(
+            cfg.nameservers,
+            vec![
+                "1.1.1.1:53".parse::<SocketAddr>().unwrap(),
+                "[2606:4700:4700::1111]:53".parse().unwrap(),
+                "8.8.8.8:5353".parse().unwrap(),
+            ]
+        );
+        assert_eq!(cfg.min_ttl, Some(30));
+        assert_eq!(cfg.max_ttl, None);
+        assert_eq!(
+            cfg.overrides.get("api.example.com").unwrap(),
+            &vec![
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "10.0.0.2".parse().unwrap()
+            ]
+        );
+        assert_eq!(cfg.overrides.len(), 2);
+        assert!(!cfg.is_empty());
+        assert!(DnsConfig::from_vars(std::iter::empty()).unwrap().is_empty());
+
+        assert_eq!(cfg.ip_strategy, None);
+        assert!(DnsConfig::from_vars(
+            vec![("DNS_IP_STRATEGY".to_string(), "ipv5".to_string())].into_iter()
+        )
+        .is_err());
+
+        assert!(parse_nameservers("dns.google").is_err());
+        assert!(parse_overrides("api.example.com").is_err());
+        assert!(parse_overrides("api.example.com=localhost").is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use axum::body::Bytes;
+use idempotent_proxy_types::unix_ms;
+use serde::Serialize;
+
+// Request lifecycle events published to NATS subjects: `{prefix}.{kind}`,
+// e.g. `idempotent-proxy.received`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Received,
+    LockAcquired,
+    UpstreamDone,
+    Cached,
+    Replayed,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Received => "received",
+            EventKind::LockAcquired => "lock_acquired",
+            EventKind::UpstreamDone => "upstream_done",
+            EventKind::Cached => "cached",
+            EventKind::Replayed => "replayed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event<'a> {
+    pub event: &'static str,
+    pub agent: &'a str,
+    pub method: &'a str,
+    pub url: &'a str,
+    pub idempotency_key: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    pub timestamp: u64, // in milliseconds
+}
+
+#[derive(Clone, Default)]
+pub struct EventPublisher {
+    client: Option<async_nats::Client>,
+    prefix: String,
+}
+
+impl EventPublisher {
+    pub async fn connect(url: &str, prefix: &str) -> Result<Self, String> {
+        let client = async_nats::connect(url)
+            .await
+            .map_err(|err| format!("connect to nats failed: {}", err))?;
+        Ok(Self {
+            client: Some(client),
+            prefix: prefix.trim_end_matches('.').to_string(),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.client.is_some()
+    }
+
+    pub fn subject(&self, kind: EventKind) -> String {
+        format!("{}.{}", self.prefix, kind.as_str())
+    }
+
+    // Publishing never blocks the request path, errors are only logged.
+    pub fn publish(
+        &self,
+        kind: EventKind,
+        agent: &str,
+        method: &str,
+        url: &str,
+        idempotency_key: &str,
+        status: Option<u16>,
+    ) {
+        let client = match &self.client {
+            Some(client) => client.clone(),
+            None => return,
+        };
+
+        let event = Event {
+            event: kind.as_str(),
+            agent,
+            method,
+            url,
+            idempotency_key,
+            status,
+            timestamp: unix_ms(),
+        };
+        let payload = match serde_json::to_vec(&event) {
+            Ok(payload) => Bytes::from(payload),
+            Err(err) => {
+                log::error!(target: "events", "failed to encode event: {}", err);
+                return;
+            }
+        };
+
+        let subject = self.subject(kind);
+        tokio::spawn(async move {
+            if let Err(err) = client.publish(subject.clone(), payload).await {
+                log::warn!(target: "events", "failed to publish {}: {}", subject, err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_event() {
+        let publisher = EventPublisher {
+            client: None,
+            prefix: "idempotent-proxy".to_string(),
+        };
+        assert!(!publisher.is_enabled());
+        assert_eq!(
+            publisher.subject(EventKind::LockAcquired),
+            "idempotent-proxy.lock_acquired"
+        );
+
+        let event = Event {
+            event: EventKind::Cached.as_str(),
+            agent: "alice",
+            method: "POST",
+            url: "https://httpbin.org/post",
+            idempotency_key: "alice:POST:key_001",
+            status: Some(200),
+            timestamp: 1716376993000,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"cached","agent":"alice","method":"POST","url":"https://httpbin.org/post","idempotency_key":"alice:POST:key_001","status":200,"timestamp":1716376993000}"#
+        );
+    }
+}
@@ -0,0 +1,303 @@
+use base64::{engine::general_purpose, Engine};
+use idempotent_proxy_types::{auth::sha3_256, err_string};
+use serde::Deserialize;
+use serde_json::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperationType {
+    Query,
+    Mutation,
+    Subscription,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawBody {
+    query: String,
+    #[serde(default)]
+    operation_name: Option<String>,
+    #[serde(default)]
+    variables: Option<Value>,
+}
+
+// A GraphQL POST body: the executed operation and a fingerprint of the normalized query
+// and the variables, so requests differing only in formatting share the cached response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GraphQLBody {
+    pub operation_type: OperationType,
+    pub operation_name: String,
+    pub fingerprint: String,
+}
+
+impl GraphQLBody {
+    pub fn parse(body: &[u8]) -> Result<Self, String> {
+        let raw: RawBody = serde_json::from_slice(body).map_err(err_string)?;
+        let operations = parse_operations(&raw.query)?;
+        let (operation_type, operation_name) = match raw.operation_name.as_deref() {
+            Some(name) if !name.is_empty() => operations
+                .into_iter()
+                .find(|(_, n)| n == name)
+                .ok_or_else(|| format!("operation {} not found", name))?,
+            _ => {
+                if operations.len() != 1 {
+                    return Err("operationName is required for multiple operations".to_string());
+                }
+                operations.into_iter().next().unwrap()
+            }
+        };
+
+        // serde_json::Map is ordered by keys, so variables are encoded canonically.
+        let variables = raw.variables.unwrap_or(Value::Null);
+        let query = normalize_query(&raw.query);
+        let data =
+            serde_json::to_vec(&(&query, &operation_name, &variables)).map_err(err_string)?;
+        Ok(GraphQLBody {
+            operation_type,
+            operation_name,
+            fingerprint: general_purpose::URL_SAFE_NO_PAD.encode(sha3_256(&data)),
+        })
+    }
+}
+
+// Removes the comments and the insignificant whitespace and commas of a GraphQL document,
+// a space is kept only between two names or numbers. Strings are kept as they are.
+pub fn normalize_query(query: &str) -> String {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut out = String::with_capacity(query.len());
+    let mut separated = false;
+    let mut chars = query.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                separated = true;
+            }
+            c if c.is_whitespace() || c == ',' || c == '\u{feff}' => separated = true,
+            '"' => {
+                separated = false;
+                out.push('"');
+                let mut block = false;
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    out.push('"');
+                    if chars.peek() != Some(&'"') {
+                        continue; // an empty string
+                    }
+                    chars.next();
+                    out.push('"');
+                    block = true;
+                }
+                // a block string ends with an unescaped triple quote
+                let mut quotes = 0;
+                while let Some(c) = chars.next() {
+                    out.push(c);
+                    match c {
+                        '\\' if block => {
+                            while quotes < 3 && chars.peek() == Some(&'"') {
+                                out.push('"');
+                                chars.next();
+                                quotes += 1;
+                            }
+                            quotes = 0;
+                        }
+                        '\\' => {
+                            if let Some(c) = chars.next() {
+                                out.push(c);
+                            }
+                        }
+                        '"' => {
+                            quotes += 1;
+                            if quotes == if block { 3 } else { 1 } {
+                                break;
+                            }
+                        }
+                        _ => quotes = 0,
+                    }
+                }
+            }
+            c => {
+                if separated && is_word(c) && out.ends_with(is_word) {
+                    out.push(' ');
+                }
+                separated = false;
+                out.push(c);
+            }
+        }
+    }
+    out
+}
+
+// Returns the operations (type, name) defined in a GraphQL document, fragments are skipped.
+fn parse_operations(query: &str) -> Result<Vec<(OperationType, String)>, String> {
+    let mut operations = Vec::new();
+    let mut depth = 0usize;
+    let mut pending: Option<(OperationType, String)> = None;
+    let mut fragment = false;
+    let mut chars = query.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '"' => {
+                let mut escaped = false;
+                for c in chars.by_ref() {
+                    match c {
+                        '\\' if !escaped => escaped = true,
+                        '"' if !escaped => break,
+                        _ => escaped = false,
+                    }
+                }
+            }
+            '{' | '(' | '[' => {
+                if depth == 0 && c == '{' {
+                    if !fragment {
+                        operations.push(
+                            pending
+                                .take()
+                                .unwrap_or((OperationType::Query, "".to_string())),
+                        );
+                    }
+                    pending = None;
+                    fragment = false;
+                }
+                depth += 1;
+            }
+            '}' | ')' | ']' => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| "unbalanced GraphQL document".to_string())?;
+            }
+            c if depth == 0 && (c.is_alphabetic() || c == '_') => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' {
+                        word.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let op = match word.as_str() {
+                    "query" => Some(OperationType::Query),
+                    "mutation" => Some(OperationType::Mutation),
+                    "subscription" => Some(OperationType::Subscription),
+                    _ => None,
+                };
+                match op {
+                    Some(op) if pending.is_none() && !fragment => {
+                        pending = Some((op, "".to_string()));
+                    }
+                    _ if word == "fragment" && pending.is_none() => fragment = true,
+                    _ => {
+                        if let Some((_, name)) = pending.as_mut() {
+                            if name.is_empty() {
+                                *name = word;
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if depth != 0 {
+        return Err("unbalanced GraphQL document".to_string());
+    }
+    if operations.is_empty() {
+        return Err("no GraphQL operation found".to_string());
+    }
+    Ok(operations)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_operations() {
+        assert_eq!(
+            parse_operations("{ user(id: 1) { name } }").unwrap(),
+            vec![(OperationType::Query, "".to_string())]
+        );
+        assert_eq!(
+            parse_operations(
+                r#"
+                # a comment with mutation { }
+                query GetUser($id: ID = "}") { user(id: $id) { ...F } }
+                fragment F on User { name }
+                mutation AddUser($input: UserInput = {name: "x"}) { addUser(input: $input) { id } }
+                "#
+            )
+            .unwrap(),
+            vec![
+                (OperationType::Query, "GetUser".to_string()),
+                (OperationType::Mutation, "AddUser".to_string())
+            ]
+        );
+        assert!(parse_operations("query { a ").is_err());
+        assert!(parse_operations("fragment F on User { name }").is_err());
+    }
+
+    #[test]
+    fn test_normalize_query() {
+        assert_eq!(
+            normalize_query(
+                r#"
+                # the user
+                query GetUser($id: ID = "a  b", $n: Int = 1) {
+                    user(id: $id, first: 10 , n: $n) {
+                        ... on User { name }
+                        bio(format: """  x "" """)
+                    }
+                }
+                "#
+            ),
+            r#"query GetUser($id:ID="a  b"$n:Int=1){user(id:$id first:10 n:$n){...on User{name}bio(format:"""  x "" """)}}"#
+        );
+        assert_eq!(normalize_query("{a(s:\"\\\" ,\")}"), "{a(s:\"\\\" ,\")}");
+        assert_eq!(normalize_query("{ a(s: \"\") b }"), "{a(s:\"\")b}");
+    }
+
+    #[test]
+    fn test_graphql_body() {
+        let a = GraphQLBody::parse(
+            br#"{"query":"query A { a } mutation B($v: Int) { b(v: $v) }","operationName":"B","variables":{"v":1,"w":2}}"#,
+        )
+        .unwrap();
+        assert_eq!(a.operation_type, OperationType::Mutation);
+        assert_eq!(a.operation_name, "B");
+
+        let b = GraphQLBody::parse(
+            br#"{"operationName":"B","variables":{"w":2,"v":1},"query":"query A { a } mutation B($v: Int) { b(v: $v) }"}"#,
+        )
+        .unwrap();
+        assert_eq!(a, b);
+
+        let c = GraphQLBody::parse(
+            br#"{"query":"query A { a } mutation B($v: Int) { b(v: $v) }","operationName":"A"}"#,
+        )
+        .unwrap();
+        assert_eq!(c.operation_type, OperationType::Query);
+        assert_ne!(a.fingerprint, c.fingerprint);
+
+        // formatting does not change the fingerprint
+        let d = GraphQLBody::parse(
+            br#"{"query":"query A { a }\n# comment\nmutation B($v: Int) {\n  b(v: $v)\n}","operationName":"B","variables":{"v":1,"w":2}}"#,
+        )
+        .unwrap();
+        assert_eq!(a, d);
+
+        assert!(GraphQLBody::parse(br#"{"query":"query A { a } query B { b }"}"#).is_err());
+        assert!(GraphQLBody::parse(br#"{"query":"{ a }","operationName":"C"}"#).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use http::StatusCode;
+use std::net::SocketAddr;
+use tonic::{Request, Response, Status};
+
+use crate::cluster::SharedConfig;
+use crate::handler::AppState;
+
+pub mod pb {
+    tonic::include_proto!("idempotent_proxy.admin.v1");
+}
+
+use pb::admin_server::{Admin, AdminServer};
+
+pub struct AdminService {
+    app: AppState,
+}
+
+impl AdminService {
+    // Checks the `authorization: Bearer <token>` metadata, the agent must be an admin agent.
+    #[allow(clippy::result_large_err)]
+    fn authorize<T>(&self, req: &Request<T>) -> Result<String, Status> {
+        let token = req
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        self.app
+            .authorize_admin(token)
+            .map_err(|(status, msg)| match status {
+                StatusCode::FORBIDDEN => Status::permission_denied(msg),
+                _ => Status::unauthenticated(msg),
+            })
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn purge_key(
+        &self,
+        req: Request<pb::PurgeKeyRequest>,
+    ) -> Result<Response<pb::PurgeKeyResponse>, Status> {
+        let agent = self.authorize(&req)?;
+        let key = req.into_inner().key;
+        if key.is_empty() {
+            return Err(Status::invalid_argument("missing key"));
+        }
+        self.app
+            .purge_key(&agent, &key)
+            .await
+            .map_err(Status::unavailable)?;
+        Ok(Response::new(pb::PurgeKeyResponse {}))
+    }
+
+    async fn list_inflight(
+        &self,
+        req: Request<pb::ListInflightRequest>,
+    ) -> Result<Response<pb::ListInflightResponse>, Status> {
+        self.authorize(&req)?;
+        let requests = self
+            .app
+            .admin
+            .inflight()
+            .into_iter()
+            .map(|(key, r)| pb::InflightRequest {
+                key,
+                agent: r.agent,
+                method: r.method,
+                url: r.url,
+                started_at: r.started_at,
+            })
+            .collect();
+        Ok(Response::new(pb::ListInflightResponse { requests }))
+    }
+
+    async fn reload_config(
+        &self,
+        req: Request<pb::ReloadConfigRequest>,
+    ) -> Result<Response<pb::ReloadConfigResponse>, Status> {
+        let agent = self.authorize(&req)?;
+        self.app
+            .reload_config(&agent)
+            .await
+            .map_err(Status::failed_precondition)?;
+        Ok(Response::new(pb::ReloadConfigResponse {}))
+    }
+
+    async fn agent_stats(
+        &self,
+        req: Request<pb::AgentStatsRequest>,
+    ) -> Result<Response<pb::AgentStatsResponse>, Status> {
+        self.authorize(&req)?;
+        let agents = self
+            .app
+            .admin
+            .stats(&req.into_inner().agent)
+            .into_iter()
+            .map(|(agent, s)| pb::AgentStats {
+                agent,
+                requests: s.requests,
+                cache_hits: s.cache_hits,
+                errors: s.errors,
+            })
+            .collect();
+        Ok(Response::new(pb::AgentStatsResponse { agents }))
+    }
+
+    async fn get_shared_config(
+        &self,
+        req: Request<pb::GetSharedConfigRequest>,
+    ) -> Result<Response<pb::SharedConfig>, Status> {
+        self.authorize(&req)?;
+        Ok(Response::new(shared_config(&self.app.cluster.config())))
+    }
+
+    async fn update_shared_config(
+        &self,
+        req: Request<pb::UpdateSharedConfigRequest>,
+    ) -> Result<Response<pb::SharedConfig>, Status> {
+        let agent = self.authorize(&req)?;
+        let u = req.into_inner();
+        let cfg = self
+            .app
+            .cluster
+            .update(&self.app.cacher, |cfg| {
+                cfg.ed25519_pub_keys.extend(u.add_ed25519_pub_keys);
+                for k in &u.remove_ed25519_pub_keys {
+                    cfg.ed25519_pub_keys.remove(k);
+                }
+                cfg.ecdsa_pub_keys.extend(u.add_ecdsa_pub_keys);
+                for k in &u.remove_ecdsa_pub_keys {
+                    cfg.ecdsa_pub_keys.remove(k);
+                }
+                cfg.revoked_agents.extend(u.revoke_agents);
+                for a in &u.unrevoke_agents {
+                    cfg.revoked_agents.remove(a);
+                }
+                Ok(())
+            })
+            .await
+            .map_err(Status::failed_precondition)?;
+        log::warn!(target: "admin",
+            action = "update_shared_config",
+            agent = agent,
+            version = cfg.version;
+            "");
+        Ok(Response::new(shared_config(&cfg)))
+    }
+}
+
+fn shared_config(cfg: &SharedConfig) -> pb::SharedConfig {
+    pb::SharedConfig {
+        version: cfg.version,
+        ed25519_pub_keys: cfg.ed25519_pub_keys.iter().cloned().collect(),
+        ecdsa_pub_keys: cfg.ecdsa_pub_keys.iter().cloned().collect(),
+        revoked_agents: cfg.revoked_agents.iter().cloned().collect(),
+    }
+}
+
+pub async fn serve(addr: SocketAddr, app: AppState) {
+    log::warn!(target: "server", "admin gRPC service listening on {:?}", addr);
+    if let Err(err) = tonic::transport::Server::builder()
+        .add_service(AdminServer::new(AdminService { app }))
+        .serve(addr)
+        .await
+    {
+        log::error!(target: "server", "admin gRPC service failed: {}", err);
+    }
+}
@@ -0,0 +1,392 @@
+use axum::{body::Body, response::Response};
+use bytes::{Bytes, BytesMut};
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use http_body_util::{BodyExt, Full};
+use idempotent_proxy_types::err_string;
+use reqwest::Url;
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tower::ServiceExt;
+
+// The codes of failures that may not happen again, such responses are not cached:
+// CANCELLED, UNKNOWN, DEADLINE_EXCEEDED, RESOURCE_EXHAUSTED, ABORTED, INTERNAL,
+// UNAVAILABLE and DATA_LOSS.
+const TRANSIENT_CODES: &[u32] = &[1, 2, 4, 8, 10, 13, 14, 15];
+
+// The HTTP statuses standing for gRPC codes in the proxy's error paths, in both directions.
+const CODE_STATUSES: &[(u32, StatusCode)] = &[
+    (2, StatusCode::BAD_GATEWAY),
+    (3, StatusCode::BAD_REQUEST),
+    (4, StatusCode::GATEWAY_TIMEOUT),
+    (5, StatusCode::NOT_FOUND),
+    (7, StatusCode::FORBIDDEN),
+    (8, StatusCode::TOO_MANY_REQUESTS),
+    (9, StatusCode::UNPROCESSABLE_ENTITY),
+    (10, StatusCode::CONFLICT),
+    (11, StatusCode::RANGE_NOT_SATISFIABLE),
+    (12, StatusCode::NOT_IMPLEMENTED),
+    (13, StatusCode::INTERNAL_SERVER_ERROR),
+    (14, StatusCode::SERVICE_UNAVAILABLE),
+    (16, StatusCode::UNAUTHORIZED),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Grpc,
+    GrpcWeb, // the trailers are sent in the body, grpc-web-text is not supported
+}
+
+impl Protocol {
+    pub fn of(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)?
+            .to_str()
+            .ok()?
+            .to_ascii_lowercase();
+        if content_type.starts_with("application/grpc-web-text") {
+            None
+        } else if content_type.starts_with("application/grpc-web") {
+            Some(Protocol::GrpcWeb)
+        } else if content_type.starts_with("application/grpc") {
+            Some(Protocol::Grpc)
+        } else {
+            None
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Protocol::Grpc => "application/grpc",
+            Protocol::GrpcWeb => "application/grpc-web",
+        }
+    }
+}
+
+// A unary call sends one length-prefixed message: a compressed flag and a big-endian length.
+pub fn check_unary(body: &[u8]) -> Result<(), String> {
+    if body.len() < 5 || body[0] > 1 {
+        return Err("invalid gRPC message".to_string());
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    if body.len() != 5 + len {
+        return Err("only unary gRPC calls are supported".to_string());
+    }
+    Ok(())
+}
+
+// The metadata of a gRPC or gRPC-web call forwarded as a native gRPC call over HTTP/2.
+pub fn upstream_headers(headers: &mut HeaderMap) {
+    if let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            v.to_ascii_lowercase()
+                .replace("application/grpc-web", "application/grpc")
+        })
+    {
+        // from a valid header value
+        headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+    }
+    for name in [
+        header::CONNECTION,
+        header::CONTENT_LENGTH,
+        header::TRANSFER_ENCODING,
+        header::UPGRADE,
+    ] {
+        headers.remove(name);
+    }
+    headers.remove("keep-alive");
+    headers.remove("x-grpc-web");
+    headers.insert(header::TE, HeaderValue::from_static("trailers"));
+}
+
+pub fn is_transient(code: u32) -> bool {
+    TRANSIENT_CODES.contains(&code)
+}
+
+pub fn code_status(code: u32) -> StatusCode {
+    CODE_STATUSES
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map(|(_, status)| *status)
+        .unwrap_or(StatusCode::BAD_GATEWAY)
+}
+
+pub fn status_code(status: StatusCode) -> u32 {
+    match status {
+        StatusCode::PROXY_AUTHENTICATION_REQUIRED => 16,
+        StatusCode::PAYLOAD_TOO_LARGE => 8,
+        StatusCode::REQUEST_TIMEOUT => 4,
+        status if status.as_u16() == 425 => 14, // Too Early
+        status => CODE_STATUSES
+            .iter()
+            .find(|(_, s)| *s == status)
+            .map(|(code, _)| *code)
+            .unwrap_or(2),
+    }
+}
+
+// The grpc-status and grpc-message of a response, in its trailers or in the headers of
+// a trailers-only response.
+pub fn status_of(headers: &HeaderMap, trailers: &HeaderMap) -> Option<(u32, String)> {
+    let map = if trailers.contains_key("grpc-status") {
+        trailers
+    } else {
+        headers
+    };
+    let code = map.get("grpc-status")?.to_str().ok()?.trim().parse().ok()?;
+    let message = map
+        .get("grpc-message")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    Some((code, percent_decode(message)))
+}
+
+// A trailers-only response with the error of the proxy or of a transient upstream failure.
+pub fn error_response(protocol: Protocol, status: StatusCode, message: &str) -> Response {
+    let mut res = Response::new(Body::empty());
+    let headers = res.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(protocol.content_type()),
+    );
+    headers.insert("grpc-status", status_code(status).into());
+    // percent-encoded, a valid header value
+    headers.insert("grpc-message", percent_encode(message).parse().unwrap());
+    res
+}
+
+// Replies to a gRPC call: errors and non-200 responses become gRPC statuses, the trailers
+// of a gRPC-web reply are encoded in its body.
+pub async fn reply(protocol: Protocol, res: Result<Response, (StatusCode, String)>) -> Response {
+    let res = match res {
+        Ok(res) => res,
+        Err((status, msg)) => return error_response(protocol, status, &msg),
+    };
+    if res.status() != StatusCode::OK {
+        let status = res.status();
+        let body = axum::body::to_bytes(res.into_body(), 4096)
+            .await
+            .unwrap_or_default();
+        return error_response(protocol, status, &String::from_utf8_lossy(&body));
+    }
+    if protocol == Protocol::Grpc {
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    let collected = match body.collect().await {
+        Ok(collected) => collected,
+        Err(err) => return error_response(protocol, StatusCode::BAD_GATEWAY, &err.to_string()),
+    };
+    let trailers = collected.trailers().cloned().unwrap_or_default();
+    let mut body = BytesMut::from(&collected.to_bytes()[..]);
+    if !trailers.is_empty() {
+        body.extend_from_slice(&web_trailers(&trailers));
+    }
+    if let Some(content_type) = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.replace("application/grpc", "application/grpc-web"))
+    {
+        parts
+            .headers
+            .insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+    }
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body.freeze()))
+}
+
+// The trailers frame of a gRPC-web body: the 0x80 flag, the length and HTTP/1 style lines.
+fn web_trailers(trailers: &HeaderMap) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in trailers {
+        block.extend_from_slice(name.as_str().as_bytes());
+        block.extend_from_slice(b": ");
+        block.extend_from_slice(value.as_bytes());
+        block.extend_from_slice(b"\r\n");
+    }
+    let mut frame = Vec::with_capacity(block.len() + 5);
+    frame.push(0x80);
+    frame.extend_from_slice(&(block.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&block);
+    frame
+}
+
+// grpc-message is percent-encoded outside of printable ASCII.
+fn percent_encode(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    for b in message.bytes() {
+        if (0x20..0x7f).contains(&b) && b != b'%' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+fn percent_decode(message: &str) -> String {
+    let bytes = message.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+// HTTP/2 channels to the gRPC upstreams, one per origin, connected on first use.
+pub struct Channels {
+    timeout: Duration,
+    channels: Mutex<HashMap<String, Channel>>,
+}
+
+impl Channels {
+    pub fn new(timeout: u64) -> Self {
+        Channels {
+            timeout: Duration::from_millis(timeout),
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, url: &Url) -> Result<Channel, String> {
+        let origin = url.origin().ascii_serialization();
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(channel) = channels.get(&origin) {
+            return Ok(channel.clone());
+        }
+        let mut endpoint = Endpoint::from_shared(origin.clone())
+            .map_err(err_string)?
+            .timeout(self.timeout)
+            .tcp_nodelay(true);
+        if url.scheme() == "https" {
+            endpoint = endpoint
+                .tls_config(ClientTlsConfig::new().with_webpki_roots())
+                .map_err(err_string)?;
+        }
+        let channel = endpoint.connect_lazy();
+        channels.insert(origin, channel.clone());
+        Ok(channel)
+    }
+}
+
+// Sends a unary call built as an upstream request of the route, and reads the response
+// with its trailers.
+pub async fn call(
+    channel: Channel,
+    rreq: reqwest::Request,
+) -> Result<(http::response::Parts, Bytes, HeaderMap), String> {
+    let body = rreq
+        .body()
+        .and_then(|b| b.as_bytes())
+        .map(Bytes::copy_from_slice)
+        .unwrap_or_default();
+    let mut req = http::Request::new(tonic::body::boxed(Full::new(body)));
+    *req.method_mut() = rreq.method().clone();
+    *req.uri_mut() = rreq.url().as_str().parse().map_err(err_string)?;
+    *req.headers_mut() = rreq.headers().clone();
+    let res = channel.oneshot(req).await.map_err(err_string)?;
+    let (parts, body) = res.into_parts();
+    let collected = body.collect().await.map_err(err_string)?;
+    let trailers = collected.trailers().cloned().unwrap_or_default();
+    Ok((parts, collected.to_bytes(), trailers))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_grpc_proxy() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/grpc-web+proto"),
+        );
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("12"));
+        assert_eq!(Protocol::of(&headers), Some(Protocol::GrpcWeb));
+        upstream_headers(&mut headers);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/grpc+proto");
+        assert_eq!(headers[header::TE], "trailers");
+        assert!(!headers.contains_key(header::CONTENT_LENGTH));
+        assert_eq!(Protocol::of(&headers), Some(Protocol::Grpc));
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/grpc-web-text"),
+        );
+        assert_eq!(Protocol::of(&headers), None);
+
+        assert!(check_unary(&[0, 0, 0, 0, 2, 8, 1]).is_ok());
+        assert!(check_unary(&[0, 0, 0, 0, 0]).is_ok());
+        // two messages of a streaming call
+        assert!(check_unary(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(check_unary(&[2, 0, 0, 0, 0]).is_err());
+        assert!(check_unary(&[0, 0]).is_err());
+
+        for code in [2, 4, 8, 10, 13, 14] {
+            assert!(is_transient(code));
+            assert_eq!(status_code(code_status(code)), code);
+        }
+        assert!(!is_transient(0) && !is_transient(5));
+        assert_eq!(status_code(StatusCode::PROXY_AUTHENTICATION_REQUIRED), 16);
+        assert_eq!(status_code(StatusCode::IM_A_TEAPOT), 2);
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("5"));
+        trailers.insert(
+            "grpc-message",
+            HeaderValue::from_static("not%20found%E2%9C%93"),
+        );
+        assert_eq!(
+            status_of(&HeaderMap::new(), &trailers),
+            Some((5, "not found✓".to_string()))
+        );
+        assert_eq!(status_of(&HeaderMap::new(), &HeaderMap::new()), None);
+        assert_eq!(percent_encode("50% ✓"), "50%25 %E2%9C%93");
+
+        let res = reply(
+            Protocol::GrpcWeb,
+            Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "storage unavailable".to_string(),
+            )),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["grpc-status"], "14");
+        assert_eq!(res.headers()["grpc-message"], "storage unavailable");
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/grpc-web");
+
+        // the trailers are appended to a gRPC-web body
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        trailers.remove("grpc-message");
+        let body = Full::new(Bytes::from_static(&[0, 0, 0, 0, 1, 7]))
+            .with_trailers(async move { Some(Ok(trailers)) });
+        let mut res = Response::new(Body::new(body));
+        res.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/grpc+proto"),
+        );
+        let res = reply(Protocol::GrpcWeb, Ok(res)).await;
+        assert_eq!(
+            res.headers()[header::CONTENT_TYPE],
+            "application/grpc-web+proto"
+        );
+        let body = axum::body::to_bytes(res.into_body(), 1024).await.unwrap();
+        assert_eq!(
+            &body[..],
+            b"\x00\x00\x00\x00\x01\x07\x80\x00\x00\x00\x10grpc-status: 0\r\n"
+        );
+    }
+}
@@ -1,103 +1,590 @@
 use axum::{
-    body::to_bytes,
-    extract::{Request, State},
-    response::IntoResponse,
+    body::{to_bytes, Bytes},
+    extract::{ConnectInfo, Request, State},
+    response::{IntoResponse, Response},
 };
 use base64::{engine::general_purpose, Engine};
-use http::{header::AsHeaderName, HeaderMap, HeaderValue, StatusCode};
+use http::{header::AsHeaderName, HeaderMap, HeaderValue, Method, StatusCode};
+use http_body_util::LengthLimitError;
 use idempotent_proxy_types::*;
-use k256::ecdsa;
 use reqwest::Client;
 use std::{
-    collections::{BTreeSet, HashMap},
-    sync::Arc,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, RwLock},
 };
 
-use crate::cache::{Cacher, HybridCacher, ResponseData};
+use crate::access_log::AccessLog;
+use crate::admin::{AdminState, ErrorRecord};
+use crate::agent_limits::AgentLimits;
+use crate::agent_methods::AgentMethods;
+use crate::agents::AgentSet;
+use crate::alerts::{AlertKind, Alerts};
+use crate::analytics::Analytics;
+use crate::breaker::{self, Breakers};
+use crate::cache::{self, ChunkWriter, HybridCacher, ResponseData, Storage};
+use crate::certification;
+use crate::cluster::{self, Cluster};
+use crate::config::LiveConfig;
+use crate::events::{EventKind, EventPublisher};
+use crate::graphql::{GraphQLBody, OperationType};
+use crate::grpc_proxy::{self, Protocol};
+use crate::ip_filter::IpFilter;
+use crate::journal::{Journal, JournalRecord, Outcome};
+use crate::json_mask::JsonMask;
+use crate::jsonrpc::JsonRpcBody;
+use crate::jwt::JwtVerifier;
+use crate::keying::{KeyScheme, Keying};
+use crate::leader::Leader;
+use crate::lease::{self, KeyStatus};
+use crate::mtls::ClientIdentity;
+use crate::otel::Trace;
+use crate::policy::Policies;
+use crate::redact;
+use crate::resources::Resources;
+use crate::routes::RouteConfig;
+use crate::scheduler::{self, ScheduledJob, Scheduler};
+use crate::scripts::Verdict;
+use crate::secrets;
+use crate::tenants::{Tenant, Tenants};
+use crate::usage::Accounting;
+use crate::waiters::{WaitMode, WaitPolicy, Waiters};
+use crate::webhook::WebhookSender;
+use crate::websocket;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdempotencyMode {
+    #[default]
+    Default,
+    // the fingerprint of JSON-RPC method+params is part of the idempotency key
+    JsonRpc,
+    // the GraphQL operation name and the fingerprint of query+variables are part of the idempotency key
+    GraphQL,
+}
+
+impl FromStr for IdempotencyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "default" => Ok(IdempotencyMode::Default),
+            "jsonrpc" => Ok(IdempotencyMode::JsonRpc),
+            "graphql" => Ok(IdempotencyMode::GraphQL),
+            v => Err(format!("invalid idempotency mode: {}", v)),
+        }
+    }
+}
+
+// What to do with requests while the storage is unavailable: refuse them with 503 (closed),
+// or forward them without the idempotency guarantee (open).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailurePolicy {
+    #[default]
+    Closed,
+    Open,
+}
+
+impl FromStr for FailurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "closed" => Ok(FailurePolicy::Closed),
+            "open" => Ok(FailurePolicy::Open),
+            v => Err(format!("invalid storage failure policy: {}", v)),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct AppState {
     pub http_client: Arc<Client>,
     pub cacher: Arc<HybridCacher>,
-    pub agents: Arc<BTreeSet<String>>,
-    pub url_vars: Arc<HashMap<String, String>>,
-    pub header_vars: Arc<HashMap<String, HeaderValue>>,
-    pub ecdsa_pub_keys: Arc<Vec<ecdsa::VerifyingKey>>,
-    pub ed25519_pub_keys: Arc<Vec<ed25519_dalek::VerifyingKey>>,
+    pub agents: Arc<AgentSet>,
+    pub nonce_agents: Arc<AgentSet>, // agents that must use single-use tokens
+    pub agent_methods: Arc<AgentMethods>,
+    pub agent_limits: Arc<AgentLimits>,
+    pub policies: Arc<Policies>,
+    pub live: Arc<RwLock<Arc<LiveConfig>>>, // URL_ and HEADER_ constants and keys, see reload
+    pub jwt: Option<Arc<JwtVerifier>>,
+    pub events: Arc<EventPublisher>,
+    pub webhook: Arc<WebhookSender>,
+    pub alerts: Arc<Alerts>,
+    pub signing_key: Option<Arc<ed25519_dalek::SigningKey>>,
+    pub tenants: Arc<Tenants>,
+    pub ip_filter: Arc<IpFilter>,
+    pub waiters: Arc<Waiters>,
+    pub journal: Arc<Journal>,
+    pub access_log: Arc<AccessLog>,
+    pub usage: Arc<Accounting>,
+    pub analytics: Arc<Analytics>,
+    pub resources: Arc<Resources>,
+    pub breakers: Arc<Breakers>,
+    pub cluster: Arc<Cluster>,
+    pub leader: Arc<Leader>,
+    pub scheduler: Arc<Scheduler>,
+    pub grpc_channels: Arc<grpc_proxy::Channels>,
+    pub idempotency_mode: IdempotencyMode,
+    pub idempotency_ttl_max: u64, // in milliseconds
+    pub check_fingerprint: bool,
+    pub storage_failure: FailurePolicy,
+    pub wait_policy: WaitPolicy,
+    pub keying: Keying,
+    pub lock_renew_interval: u64, // in milliseconds, 0 disables the lease renewal
+    pub admin: Arc<AdminState>,
 }
 
 impl AppState {
-    pub fn alter_headers(&self, headers: &mut HeaderMap) {
+    // The HEADER_ constants' placeholders are resolved here, see secrets::expand.
+    pub fn alter_headers(&self, headers: &mut HeaderMap) -> Result<(), String> {
         headers.remove(&http::header::HOST);
         headers.remove(&http::header::FORWARDED);
         headers.remove(&HEADER_PROXY_AUTHORIZATION);
         headers.remove(&HEADER_X_FORWARDED_FOR);
         headers.remove(&HEADER_X_FORWARDED_HOST);
         headers.remove(&HEADER_X_FORWARDED_PROTO);
+        // the proxy negotiates the upstream encoding itself, see RouteConfig::accept_encoding
+        headers.remove(http::header::ACCEPT_ENCODING);
 
-        if !self.header_vars.is_empty() {
+        let live = self.live();
+        if !live.header_vars.is_empty() {
             for val in headers.values_mut() {
                 if let Ok(s) = val.to_str() {
-                    if let Some(v) = self.header_vars.get(s) {
-                        *val = v.clone();
+                    if let Some(v) = live.header_vars.get(s) {
+                        *val = secrets::expand_header(v)
+                            .map_err(|err| format!("{}: {}", s, err))?
+                            .into_owned();
                     }
                 }
             }
         }
+        Ok(())
     }
 
-    // TODO: support JWT and CWT
+    // TODO: support CWT
     pub fn verify_token(&self, access_token: &str) -> Result<String, String> {
-        if !access_token.starts_with("Bearer ") {
-            return Err("invalid proxy-authorization header".to_string());
-        }
-        let token = general_purpose::URL_SAFE_NO_PAD
-            .decode(access_token.strip_prefix("Bearer ").unwrap().as_bytes())
-            .map_err(|err| err.to_string())?;
-        if !self.ecdsa_pub_keys.is_empty() {
-            return auth::ecdsa_verify(&self.ecdsa_pub_keys, &token)
+        if let Some(res) = self.verify_jwt(access_token) {
+            return res;
+        }
+        with_token(access_token, |token| self.verify_global_token(token))?
+    }
+
+    // Resolves the tenant and the agent of a proxy token, tokens signed by the global keys have no tenant.
+    pub fn authenticate(&self, access_token: &str) -> Result<(Option<&Tenant>, String), String> {
+        if let Some(res) = self.verify_jwt(access_token) {
+            return res.map(|agent| (None, agent));
+        }
+        with_token(access_token, |token| {
+            if let Some((tenant, agent)) = self.tenants.verify(token) {
+                return Ok((Some(tenant), agent));
+            }
+            self.verify_global_token(token).map(|agent| (None, agent))
+        })?
+    }
+
+    // Whether a verified token of the agent was revoked before its expiration.
+    pub fn is_token_revoked(&self, access_token: &str, agent: &str) -> bool {
+        match (
+            access_token.strip_prefix("Bearer "),
+            token_expire_at(access_token),
+        ) {
+            (Some(token), Some(expire_at)) => {
+                self.cluster
+                    .is_token_revoked(agent, &cluster::token_hash(token), expire_at)
+            }
+            _ => false,
+        }
+    }
+
+    // Records the nonce of a single-use token until the token expires, false if it was used before.
+    pub async fn use_nonce(
+        &self,
+        agent: &str,
+        nonce: &str,
+        expire_at: u64,
+    ) -> Result<bool, String> {
+        let ttl = (expire_at + 10) * 1000; // with the permitted drift of the verification
+        let ttl = ttl.saturating_sub(unix_ms()).max(1000);
+        self.cacher
+            .obtain(&format!("nonce:{}:{}", agent, nonce), ttl)
+            .await
+    }
+
+    // JWTs have no tenant, None if the token is left to the CBOR verification.
+    fn verify_jwt(&self, access_token: &str) -> Option<Result<String, String>> {
+        let jwt = self.jwt.as_ref()?;
+        match access_token.strip_prefix("Bearer ") {
+            Some(token) if JwtVerifier::is_jwt(token) => Some(jwt.verify(token)),
+            _ if jwt.only => Some(Err("proxy authentication requires a JWT".to_string())),
+            _ => None,
+        }
+    }
+
+    // Verifies with the keys of the environment, then with the keys shared by the cluster.
+    // The key id of the token selects the key generation.
+    fn verify_global_token(&self, token: &[u8]) -> Result<String, String> {
+        let key_id = auth::Token::key_id(token);
+        let (key_id, now) = (key_id.as_deref(), unix_ms() / 1000);
+        let live = self.live();
+        let mut res = Err("proxy authentication verify failed".to_string());
+        if !live.ecdsa_pub_keys.is_empty() {
+            res = auth::ecdsa_verify(&live.ecdsa_pub_keys.select(key_id, now), token)
+                .map(|t| t.1)
+                .map_err(|err| format!("proxy authentication verify failed: {}", err));
+        } else if !live.ed25519_pub_keys.is_empty() {
+            res = auth::ed25519_verify(&live.ed25519_pub_keys.select(key_id, now), token)
+                .map(|t| t.1)
+                .map_err(|err| format!("proxy authentication verify failed: {}", err));
+        }
+        if res.is_err() && !live.p256_pub_keys.is_empty() {
+            res = auth::p256_verify(&live.p256_pub_keys.select(key_id, now), token)
                 .map(|t| t.1)
                 .map_err(|err| format!("proxy authentication verify failed: {}", err));
         }
-        if !self.ed25519_pub_keys.is_empty() {
-            return auth::ed25519_verify(&self.ed25519_pub_keys, &token)
+        if res.is_err() && !live.bls_pub_keys.is_empty() {
+            res = auth::bls_verify(&live.bls_pub_keys.select(key_id, now), token)
                 .map(|t| t.1)
                 .map_err(|err| format!("proxy authentication verify failed: {}", err));
         }
+        if res.is_err() && !live.hmac_keys.is_empty() {
+            res = auth::hmac_verify(&live.hmac_keys, token)
+                .map(|t| t.1)
+                .map_err(|err| format!("proxy authentication verify failed: {}", err));
+        }
+        if res.is_err() {
+            if let Some(agent) = self.cluster.verify(token) {
+                return Ok(agent);
+            }
+        }
+        res
+    }
+}
+
+// The scope of a version 2 token, it is signed with the token and read once verified.
+fn token_scope(access_token: &str) -> Option<auth::Scope> {
+    with_token(access_token, |token| {
+        ciborium::from_reader::<auth::Token, _>(token)
+            .ok()
+            .and_then(|t| t.3)
+    })
+    .ok()
+    .flatten()
+}
 
-        Err("proxy authentication verify failed".to_string())
+// The expire_at in seconds of a proxy token or JWT, read once verified.
+pub fn token_expire_at(access_token: &str) -> Option<u64> {
+    match access_token.strip_prefix("Bearer ") {
+        Some(token) if JwtVerifier::is_jwt(token) => {
+            let claims = general_purpose::URL_SAFE_NO_PAD
+                .decode(token.split('.').nth(1)?)
+                .ok()?;
+            serde_json::from_slice::<serde_json::Value>(&claims).ok()?["exp"].as_u64()
+        }
+        _ => with_token(access_token, |token| {
+            ciborium::from_reader::<auth::Token, _>(token)
+                .ok()
+                .map(|t| t.0)
+        })
+        .ok()
+        .flatten(),
     }
 }
 
+// Decodes the base64url token of a `Bearer <token>` proxy-authorization header for `f`.
+fn with_token<R>(access_token: &str, f: impl FnOnce(&[u8]) -> R) -> Result<R, String> {
+    let token = access_token
+        .strip_prefix("Bearer ")
+        .ok_or_else(|| "invalid proxy-authorization header".to_string())?
+        .as_bytes();
+    // tokens are small, decode them on the stack
+    let mut buf = [0u8; 256];
+    match general_purpose::URL_SAFE_NO_PAD.decode_slice(token, &mut buf) {
+        Ok(n) => Ok(f(&buf[..n])),
+        Err(_) => {
+            let heap = general_purpose::URL_SAFE_NO_PAD
+                .decode(token)
+                .map_err(|err| err.to_string())?;
+            Ok(f(&heap))
+        }
+    }
+}
+
+pub struct ProxyRequest {
+    pub agent: String,
+    pub route: String, // the URL_<NAME> constant, empty for x-forwarded-host requests
+    pub method: Method,
+    pub url: reqwest::Url,
+    pub headers: HeaderMap,
+    pub body: Option<Bytes>,
+    pub idempotency_key: String,
+    pub legacy_key: Option<String>, // with IDEMPOTENCY_KEY_SCHEME=migrate
+    pub json_mask: String,
+    pub response_headers: String,
+    pub trace: Trace,           // the server span of the request
+    pub cache_ttl: Option<u64>, // in milliseconds, from the x-idempotency-ttl header
+    pub fingerprint: String,    // of method, URL and body, empty if not checked
+}
+
+// `HEAD /status/<key>?method=POST&url=URL_NAME` tells whether the idempotency key of the
+// agent is pending, cached or absent. The URL (a URL_ constant or a full URL) is required
+// by the composite key schemes.
+async fn key_status(
+    app: &AppState,
+    agent: &str,
+    key_prefix: &str,
+    uri: &http::Uri,
+) -> Result<KeyStatus, (StatusCode, String)> {
+    let key = uri.path().strip_prefix("/status/").unwrap_or_default();
+    if key.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "missing idempotency key".to_string(),
+        ));
+    }
+    let path_query = uri.path_and_query().map(|v| v.as_str()).unwrap_or_default();
+    let query = reqwest::Url::parse(&format!("http://localhost{}", path_query))
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    let mut method = Method::POST;
+    let mut url = String::new();
+    for (k, v) in query.query_pairs() {
+        match k.as_ref() {
+            "method" => {
+                method = Method::from_str(&v.to_ascii_uppercase())
+                    .map_err(|_| (StatusCode::BAD_REQUEST, format!("invalid method: {}", v)))?
+            }
+            "url" if v.starts_with("URL_") => {
+                url = app
+                    .live()
+                    .url_vars
+                    .get(v.as_ref())
+                    .cloned()
+                    .unwrap_or_default()
+            }
+            "url" => url = v.to_string(),
+            _ => {}
+        }
+    }
+    if method == Method::HEAD {
+        method = Method::GET;
+    }
+    if url.is_empty() && app.keying.scheme != KeyScheme::Legacy {
+        return Err((StatusCode::BAD_REQUEST, "missing query: url".to_string()));
+    }
+    // the legacy scheme ignores the URL
+    let url = reqwest::Url::parse(if url.is_empty() {
+        "http://localhost"
+    } else {
+        &url
+    })
+    .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    let (key, legacy) = app
+        .keying
+        .storage_key(key_prefix, agent, &method, &url, key);
+    let unavailable = |err: String| (StatusCode::SERVICE_UNAVAILABLE, err);
+    let status = KeyStatus::of(&app.cacher, &key)
+        .await
+        .map_err(unavailable)?;
+    match legacy {
+        Some(legacy) if status == KeyStatus::Absent => KeyStatus::of(&app.cacher, &legacy)
+            .await
+            .map_err(unavailable),
+        _ => Ok(status),
+    }
+}
+
+// GET /healthz, answered before authentication, agent limits and the upstream,
+// so liveness probes neither need a token nor use up an agent's quota.
+pub async fn healthz() -> impl IntoResponse {
+    (StatusCode::OK, "OK")
+}
+
 pub async fn proxy(
     State(app): State<AppState>,
     req: Request,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    // Access control
-    let agent = if !app.ecdsa_pub_keys.is_empty() || !app.ed25519_pub_keys.is_empty() {
+) -> Result<Response, (StatusCode, String)> {
+    // gRPC clients get the errors as gRPC statuses
+    match Protocol::of(req.headers()) {
+        Some(protocol) => Ok(grpc_proxy::reply(protocol, proxy_request(app, req).await).await),
+        None => proxy_request(app, req).await,
+    }
+}
+
+async fn proxy_request(app: AppState, mut req: Request) -> Result<Response, (StatusCode, String)> {
+    let resources = app.resources.clone();
+    let _active = resources.request();
+    let trace = Trace::server(req.headers(), "proxy");
+    trace.set_attribute("http.request.method", req.method().to_string());
+    let client_ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| app.ip_filter.client_ip(addr.ip(), req.headers()));
+    // IP and GeoIP restrictions apply to every request, preflights included
+    if !app.ip_filter.is_empty() {
+        let ip = client_ip
+            .ok_or_else(|| (StatusCode::FORBIDDEN, "unknown client address".to_string()))?;
+        app.ip_filter
+            .check(ip)
+            .map_err(|err| (StatusCode::FORBIDDEN, err))?;
+    }
+
+    // CORS preflight requests carry no credentials, they are answered before access control
+    if req.method() == Method::OPTIONS {
+        let route = req
+            .uri()
+            .path()
+            .strip_prefix('/')
+            .filter(|p| p.starts_with("URL_"))
+            .unwrap_or_default();
+        if let Some(cors) = &app.admin.routes().get(route).cors {
+            return Ok(cors.preflight(req.headers()));
+        }
+    }
+
+    // Access control, a verified client certificate takes the place of the proxy token
+    let identity = req.extensions().get::<ClientIdentity>().cloned();
+    let mut scope: Option<auth::Scope> = None;
+    let mut verified_token: Option<String> = None;
+    let (agent, key_prefix, is_tenant) = if let Some(ClientIdentity(agent)) = identity {
+        (agent, String::new(), false)
+    } else if !app.tenants.is_empty()
+        || app.live().has_keys()
+        || app.jwt.is_some()
+        || app.cluster.has_keys()
+    {
         let token = extract_header(req.headers(), &HEADER_PROXY_AUTHORIZATION, || {
             "".to_string()
         });
 
-        match app.verify_token(&token) {
-            Err(err) => return Err((StatusCode::PROXY_AUTHENTICATION_REQUIRED, err)),
-            Ok(agent) => agent,
+        let auth_span = trace.span("auth");
+        let auth = app.authenticate(&token);
+        if let Err(err) = &auth {
+            auth_span.set_error(err);
+        }
+        drop(auth_span);
+        if auth.is_ok() {
+            scope = token_scope(&token);
+            verified_token = Some(token);
+        }
+        match auth {
+            Err(err) => {
+                if let Some(ip) = client_ip {
+                    app.alerts.auth_failed(ip);
+                }
+                return Err((StatusCode::PROXY_AUTHENTICATION_REQUIRED, err));
+            }
+            Ok((Some(tenant), agent)) => {
+                if !tenant.allows_agent(&agent) {
+                    return Err((
+                        StatusCode::FORBIDDEN,
+                        format!("agent {} is not allowed", tenant.agent_namespace(&agent)),
+                    ));
+                }
+                if let Some(limiter) = &tenant.limiter {
+                    if !limiter.try_acquire() {
+                        return Err((
+                            StatusCode::TOO_MANY_REQUESTS,
+                            format!("tenant {} rate limit exceeded", tenant.name),
+                        ));
+                    }
+                }
+                (
+                    tenant.agent_namespace(&agent),
+                    tenant.key_prefix.clone(),
+                    true,
+                )
+            }
+            Ok((None, agent)) => (agent, String::new(), false),
         }
     } else {
-        "ANON".to_string()
+        ("ANON".to_string(), String::new(), false)
     };
 
-    if !app.agents.is_empty() && !app.agents.contains(&agent) {
+    if app.cluster.is_revoked(&agent) {
+        return Err((
+            StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+            format!("agent {} is revoked", agent),
+        ));
+    }
+    if let Some(token) = &verified_token {
+        if app.is_token_revoked(token, &agent) {
+            return Err((
+                StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+                format!("token of agent {} is revoked", agent),
+            ));
+        }
+        // a single-use token is rejected on its second use, storage errors fail closed
+        match scope.as_ref().and_then(|s| s.nonce.as_deref()) {
+            Some(nonce) => {
+                let expire_at = token_expire_at(token).unwrap_or_default();
+                let fresh = app
+                    .use_nonce(&agent, nonce, expire_at)
+                    .await
+                    .map_err(|err| (StatusCode::SERVICE_UNAVAILABLE, err))?;
+                if !fresh {
+                    return Err((
+                        StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+                        format!("single-use token of agent {} was used", agent),
+                    ));
+                }
+            }
+            None if app.nonce_agents.contains(&agent) => {
+                return Err((
+                    StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+                    format!("agent {} requires single-use tokens", agent),
+                ));
+            }
+            None => {}
+        }
+    }
+
+    // ALLOW_AGENTS applies to the agents of the global keys, tenants have their own lists
+    if !is_tenant && !app.agents.is_empty() && !app.agents.contains(&agent) {
         return Err((
             StatusCode::FORBIDDEN,
             format!("agent {} is not allowed", agent),
         ));
     }
 
-    let method = req.method().to_string();
+    if req.method() == Method::HEAD
+        && req.uri().path().starts_with("/status/")
+        && !req.headers().contains_key(&HEADER_X_FORWARDED_HOST)
+    {
+        return key_status(&app, &agent, &key_prefix, req.uri())
+            .await
+            .map(IntoResponse::into_response);
+    }
+
+    trace.set_attribute("proxy.agent", agent.clone());
+    let method = req.method().clone();
+    if !app.agent_methods.allows(&agent, &method) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("agent {} may not use method {}", agent, method),
+        ));
+    }
+    if let Err((msg, retry_after)) = app.agent_limits.check(&agent) {
+        log::warn!(target: "handler",
+            action = "limit",
+            agent = agent.as_str(),
+            status = 429u16;
+            "{}", msg);
+        return Ok((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(http::header::RETRY_AFTER, retry_after.to_string())],
+            msg,
+        )
+            .into_response());
+    }
+
     let path = req.uri().path();
+    let route = if path.starts_with("/URL_") {
+        path.strip_prefix('/').unwrap().to_string()
+    } else {
+        "".to_string()
+    };
     let url = if path.starts_with("/URL_") {
         let url = app
+            .live()
             .url_vars
             .get(path.strip_prefix('/').unwrap())
             .map(|s| s.to_string())
@@ -124,120 +611,1484 @@ pub async fn proxy(
         format!("https://{}{}", host, path_query)
     };
 
-    let url =
+    let mut url =
         reqwest::Url::parse(&url).map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
-    let idempotency_key = extract_header(req.headers(), &HEADER_IDEMPOTENCY_KEY, || "".to_string());
-    if idempotency_key.is_empty() {
+    // the policies and the idempotency key apply to the request as the script rewrote it
+    if let Some(script) = &app.admin.routes().get(&route).script {
+        let verdict = script
+            .on_request(method.as_str(), &mut url, req.headers_mut())
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+        if let Verdict::Veto(reason) = verdict {
+            return Err((StatusCode::FORBIDDEN, reason));
+        }
+    }
+    if !app.policies.allows(&agent, &method, &url) {
+        log::warn!(target: "handler",
+            action = "policy",
+            method = method.as_str(),
+            url = redact::url(&url),
+            agent = agent.as_str();
+            "forwarding denied");
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("agent {} may not forward to {}", agent, redact::url(&url)),
+        ));
+    }
+    // a scoped token narrows the policies further
+    if let Some(scope) = &scope {
+        let host = url.host_str().unwrap_or_default();
+        if !scope.allows(method.as_str(), host, url.path()) {
+            log::warn!(target: "handler",
+                action = "scope",
+                method = method.as_str(),
+                url = redact::url(&url),
+                agent = agent.as_str();
+                "forwarding denied");
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!(
+                    "token scope of agent {} does not allow {} {}",
+                    agent,
+                    method,
+                    redact::url(&url)
+                ),
+            ));
+        }
+    }
+    if websocket::is_upgrade(req.headers()) {
+        // frames are relayed without caching, the idempotency-key header is not required
+        if !app.admin.routes().get(&route).websocket {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "WebSocket is not enabled for the route".to_string(),
+            ));
+        }
+        return websocket::proxy(app, req, url, agent, route).await;
+    }
+    let grpc = Protocol::of(req.headers());
+    match (grpc, app.admin.routes().get(&route).grpc) {
+        (Some(_), false) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "gRPC is not enabled for the route".to_string(),
+            ));
+        }
+        (None, true) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "the route only accepts gRPC calls".to_string(),
+            ));
+        }
+        (Some(_), true) if method != Method::POST => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "gRPC calls must use POST".to_string(),
+            ));
+        }
+        _ => {}
+    }
+    let idempotency_mode =
+        match extract_header(req.headers(), &HEADER_X_IDEMPOTENCY_MODE, || "".to_string()).as_str()
+        {
+            "" => app.idempotency_mode,
+            v => {
+                let mode =
+                    IdempotencyMode::from_str(v).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+                // other modes make the idempotency-key header optional, routes opt in to them
+                if mode != app.idempotency_mode
+                    && !app
+                        .admin
+                        .routes()
+                        .get(&route)
+                        .idempotency_modes
+                        .contains(&mode)
+                {
+                    return Err((
+                        StatusCode::FORBIDDEN,
+                        format!("idempotency mode {} is not allowed for the route", v),
+                    ));
+                }
+                mode
+            }
+        };
+    let idempotency_key = app.keying.extract(req.headers());
+    if idempotency_key.is_empty()
+        && idempotency_mode == IdempotencyMode::Default
+        && method != Method::OPTIONS
+    {
         return Err((
             StatusCode::BAD_REQUEST,
-            "missing header: idempotency-key".to_string(),
+            format!("missing header: {}", app.keying.header()),
         ));
     }
 
-    let idempotency_key = format!("{}:{}:{}", agent, method, idempotency_key);
+    let cache_ttl = extract_header(req.headers(), &HEADER_X_IDEMPOTENCY_TTL, || "".to_string());
+    let cache_ttl = if cache_ttl.is_empty() {
+        None
+    } else {
+        Some(
+            parse_idempotency_ttl(&cache_ttl, app.idempotency_ttl_max)
+                .map_err(|err| (StatusCode::BAD_REQUEST, err))?,
+        )
+    };
 
-    let lock = app
-        .cacher
-        .obtain(&idempotency_key, app.cacher.cache_ttl)
-        .await
-        .map_err(bad_gateway)?;
-    if !lock {
-        let data = app
-            .cacher
-            .polling_get(
-                &idempotency_key,
-                app.cacher.poll_interval,
-                app.cacher.cache_ttl / app.cacher.poll_interval,
+    let callback_url = extract_header(req.headers(), &HEADER_X_CALLBACK_URL, || "".to_string());
+    let callback_url = if callback_url.is_empty() {
+        None
+    } else {
+        Some(reqwest::Url::parse(&callback_url).map_err(|err| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("invalid callback url: {}", err),
             )
-            .await
-            .map_err(bad_gateway)?;
+        })?)
+    };
 
-        let res = ResponseData::try_from(&data[..]).map_err(bad_gateway)?;
-        log::info!(target: "handler",
-                    action = "cachehit",
+    let execute_after = extract_header(req.headers(), &HEADER_X_EXECUTE_AFTER, || "".to_string());
+    let execute_at = if execute_after.is_empty() {
+        None
+    } else {
+        Some(
+            app.scheduler
+                .parse_execute_at(&execute_after)
+                .map_err(|err| (StatusCode::BAD_REQUEST, err))?,
+        )
+    };
+
+    let mut json_mask = extract_header(req.headers(), &HEADER_X_JSON_MASK, || "".to_string());
+    JsonMask::parse(&json_mask).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    if !json_mask.is_empty() && app.admin.routes().get(&route).ic_certification {
+        // The certified body must be returned byte for byte.
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "x-json-mask is not supported for IC certified routes".to_string(),
+        ));
+    }
+    let range = extract_header(req.headers(), http::header::RANGE, || "".to_string());
+    if !range.is_empty() && !json_mask.is_empty() {
+        // a partial body is not a valid JSON document
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "x-json-mask is not supported for range requests".to_string(),
+        ));
+    }
+    if json_mask.is_empty() && range.is_empty() {
+        let routes = app.admin.routes();
+        let config = routes.get(&route);
+        if !config.ic_certification {
+            json_mask = config.json_mask.clone();
+        }
+    }
+    let response_headers =
+        extract_header(req.headers(), &HEADER_RESPONSE_HEADERS, || "".to_string());
+
+    let mut headers = req.headers().clone();
+    headers.remove(&HEADER_X_CALLBACK_URL);
+    headers.remove(&HEADER_X_EXECUTE_AFTER);
+    headers.remove(&HEADER_X_IDEMPOTENCY_MODE);
+    headers.remove(&HEADER_X_IDEMPOTENCY_TTL);
+    app.alter_headers(&mut headers)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+    app.admin
+        .routes()
+        .get(&route)
+        .filter_request_headers(&mut headers);
+    if let Some(v) = &app.admin.routes().get(&route).accept_encoding {
+        // validated by the route config
+        headers.insert(http::header::ACCEPT_ENCODING, v.parse().unwrap());
+    }
+    if grpc.is_some() {
+        grpc_proxy::upstream_headers(&mut headers);
+    }
+
+    let mut body = if !method.is_safe() {
+        let limit = app
+            .agent_limits
+            .max_body_size(&agent)
+            .unwrap_or_else(|| app.admin.routes().get(&route).max_body_size());
+        let content_length = extract_header(req.headers(), http::header::CONTENT_LENGTH, || {
+            "0".to_string()
+        });
+        if content_length.trim().parse::<usize>().unwrap_or(0) > limit {
+            return Err(payload_too_large(limit));
+        }
+        // chunked bodies are counted while they are streamed in
+        let body = to_bytes(req.into_body(), limit).await.map_err(|err| {
+            match err.into_inner().downcast_ref::<LengthLimitError>() {
+                Some(_) => payload_too_large(limit),
+                None => (StatusCode::BAD_REQUEST, "failed to read body".to_string()),
+            }
+        })?;
+        Some(body)
+    } else {
+        None
+    };
+    if grpc.is_some() {
+        // the idempotency key comes from the metadata, the response of the call is cached
+        grpc_proxy::check_unary(body.as_deref().unwrap_or_default())
+            .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    }
+
+    // the JSON-RPC and GraphQL keys already include the fingerprint of the payload
+    let fingerprint = if app.check_fingerprint && idempotency_mode == IdempotencyMode::Default {
+        request_fingerprint(&method, &url, body.as_deref())
+    } else {
+        "".to_string()
+    };
+
+    let mut jsonrpc: Option<JsonRpcBody> = None;
+    let idempotency_key = match idempotency_mode {
+        IdempotencyMode::Default => idempotency_key,
+        IdempotencyMode::JsonRpc => {
+            // The fingerprint of method+params is part of the key, calls that differ only in ids
+            // share the cached response. The idempotency-key header is optional in this mode.
+            let jr = JsonRpcBody::parse(body.as_deref().unwrap_or_default()).map_err(|err| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid JSON-RPC body: {}", err),
+                )
+            })?;
+            body = Some(Bytes::from(jr.upstream_body()));
+            headers.remove(http::header::CONTENT_LENGTH);
+            let fingerprint = jr.fingerprint();
+            jsonrpc = Some(jr);
+            if idempotency_key.is_empty() {
+                fingerprint
+            } else {
+                format!("{}:{}", idempotency_key, fingerprint)
+            }
+        }
+        IdempotencyMode::GraphQL => {
+            // Queries are safe to dedupe by fingerprint, the idempotency-key header is optional.
+            let gq = GraphQLBody::parse(body.as_deref().unwrap_or_default()).map_err(|err| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid GraphQL body: {}", err),
+                )
+            })?;
+            match gq.operation_type {
+                OperationType::Subscription => {
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        "GraphQL subscription is not supported".to_string(),
+                    ));
+                }
+                OperationType::Mutation
+                    if !app.admin.routes().get(&route).graphql_allow_mutations =>
+                {
+                    return Err((
+                        StatusCode::FORBIDDEN,
+                        "GraphQL mutation is not allowed for the route".to_string(),
+                    ));
+                }
+                OperationType::Mutation
+                    if idempotency_key.is_empty()
+                        && app.admin.routes().get(&route).graphql_require_mutation_key =>
+                {
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        format!(
+                            "missing header: {} for GraphQL mutation",
+                            app.keying.header()
+                        ),
+                    ));
+                }
+                _ => {}
+            }
+            if idempotency_key.is_empty() {
+                format!("{}:{}", gq.operation_name, gq.fingerprint)
+            } else {
+                format!(
+                    "{}:{}:{}",
+                    idempotency_key, gq.operation_name, gq.fingerprint
+                )
+            }
+        }
+    };
+    // HEAD shares the cached response of GET
+    let key_method = if method == Method::HEAD {
+        &Method::GET
+    } else {
+        &method
+    };
+    let (mut idempotency_key, mut legacy_key) =
+        app.keying
+            .storage_key(&key_prefix, &agent, key_method, &url, &idempotency_key);
+    let range_passthrough = !range.is_empty() && app.admin.routes().get(&route).range_passthrough;
+    if !range.is_empty() {
+        // partial content is cached per range, it never overwrites the full response
+        idempotency_key = format!("{}:range:{}", idempotency_key, range.trim());
+        legacy_key = legacy_key.map(|key| format!("{}:range:{}", key, range.trim()));
+    }
+
+    let preq = ProxyRequest {
+        agent,
+        route,
+        method,
+        url,
+        headers,
+        body,
+        idempotency_key,
+        legacy_key,
+        json_mask,
+        response_headers,
+        trace,
+        cache_ttl,
+        fingerprint,
+    };
+
+    if preq.method == Method::HEAD || preq.method == Method::OPTIONS || range_passthrough {
+        return app.passthrough(&preq).await;
+    }
+
+    if let Some(execute_at) = execute_at {
+        // Delayed mode: persist the request, the scheduler executes it at `execute_at`.
+        // The result is cached under the idempotency key and delivered to the callback url if any.
+        let job = ScheduledJob::new(execute_at, &preq, callback_url.as_ref());
+        scheduler::schedule(&app, &job).await?;
+        return Ok((StatusCode::ACCEPTED, preq.idempotency_key).into_response());
+    }
+
+    match callback_url {
+        None => {
+            let res = app.handle(&preq).await;
+            // the JSON-RPC response id is restored in the body
+            let res = if jsonrpc.is_some() {
+                app.load_body(&preq.idempotency_key, res).await?
+            } else {
+                res?
+            };
+            let res = restore_jsonrpc(jsonrpc.as_ref(), res);
+            let mut res = app.response(&preq.idempotency_key, res);
+            // not cached, so a config reload takes effect for cached responses too
+            app.admin
+                .routes()
+                .get(&preq.route)
+                .response_headers
+                .apply(res.headers_mut())
+                .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+            Ok(res)
+        }
+        Some(callback_url) => {
+            // Async mode: reply 202 immediately, deliver the result to the callback url later.
+            let idempotency_key = preq.idempotency_key.clone();
+            tokio::spawn(async move {
+                let res = app.handle(&preq).await;
+                let res = app
+                    .load_body(&preq.idempotency_key, res)
+                    .await
+                    .map(|res| restore_jsonrpc(jsonrpc.as_ref(), res));
+                app.webhook
+                    .deliver(&callback_url, &preq.idempotency_key, res)
+                    .await;
+            });
+            Ok((StatusCode::ACCEPTED, idempotency_key).into_response())
+        }
+    }
+}
+
+impl AppState {
+    pub async fn handle(&self, preq: &ProxyRequest) -> Result<ResponseData, (StatusCode, String)> {
+        let agent = preq.agent.as_str();
+        let method = preq.method.as_str();
+        let url = redact::url(&preq.url);
+        let url = url.as_str();
+        let idempotency_key = preq.idempotency_key.as_str();
+        let started_at = unix_ms();
+        let journal = |status: u16, outcome: Outcome, bytes: u64| {
+            let elapsed = unix_ms().saturating_sub(started_at);
+            self.analytics
+                .record(agent, idempotency_key, outcome, elapsed);
+            let record = JournalRecord {
+                at: started_at,
+                agent: agent.to_string(),
+                method: method.to_string(),
+                url: url.to_string(),
+                idempotency_key: idempotency_key.to_string(),
+                status,
+                outcome,
+                elapsed,
+            };
+            self.access_log.log(&record, bytes);
+            self.usage
+                .record(agent, outcome, bytes, started_at + elapsed);
+            self.journal.record(record)
+        };
+        self.events.publish(
+            EventKind::Received,
+            agent,
+            method,
+            url,
+            idempotency_key,
+            None,
+        );
+
+        self.admin.record(agent, |s| s.requests += 1);
+
+        if self.breakers.is_enabled() {
+            let routes = self.admin.routes();
+            let upstream = routes
+                .get(&preq.route)
+                .replica_url(&preq.url, idempotency_key);
+            let host = upstream.host_str().unwrap_or_default();
+            if let Err(retry_after) = self.breakers.check(host, started_at) {
+                log::warn!(target: "handler",
+                    action = "breaker",
                     method = method,
-                    url = url.to_string(),
-                    status = res.status,
+                    url = url,
+                    status = 503u16,
+                    agent = agent,
+                    idempotency_key = idempotency_key;
+                    "circuit of {} is open", host);
+                let rd = breaker::open_circuit(host, retry_after);
+                journal(rd.status, Outcome::Unavailable, rd.body_size());
+                return Ok(rd);
+            }
+        }
+
+        let lock_span = preq.trace.span("lock");
+        let obtained = self
+            .cacher
+            .obtain(idempotency_key, self.cacher.cache_ttl)
+            .await;
+        lock_span.record(&obtained);
+        if let Ok(lock) = obtained {
+            lock_span.set_attribute("proxy.lock_acquired", lock);
+        }
+        drop(lock_span);
+        let lock = match obtained {
+            Ok(lock) => lock,
+            Err(err) => {
+                log::error!(target: "handler",
+                    action = "obtain",
+                    method = method,
+                    url = url,
+                    agent = agent,
+                    idempotency_key = idempotency_key,
+                    policy = format!("{:?}", self.storage_failure);
+                    "storage unavailable: {}", err);
+                self.alerts.fire(
+                    AlertKind::StorageUnavailable,
+                    "storage",
+                    format!(
+                        "storage unavailable ({:?} policy): {}",
+                        self.storage_failure, err
+                    ),
+                );
+                if self.storage_failure == FailurePolicy::Open {
+                    // no lock: duplicates in the meantime are forwarded too
+                    let res = self.forward(preq).await;
+                    match &res {
+                        Ok(rd) => journal(rd.status, Outcome::Proxied, rd.body_size()),
+                        Err((status, _)) => journal(status.as_u16(), Outcome::Failed, 0),
+                    }
+                    return res;
+                }
+                let mut rd = ResponseData::new(StatusCode::SERVICE_UNAVAILABLE.as_u16());
+                rd.headers
+                    .push((http::header::RETRY_AFTER.to_string(), "1".to_string()));
+                rd.body = Bytes::from_static(b"idempotency storage unavailable");
+                journal(rd.status, Outcome::Unavailable, rd.body_size());
+                return Ok(rd);
+            }
+        };
+        if !lock {
+            if self.wait_policy.mode == WaitMode::Reject {
+                // wait timeouts and the waiter cap are load, not conflicts, and don't alert
+                self.alerts.fire(
+                    AlertKind::KeyConflict,
+                    agent,
+                    format!(
+                        "{} {} rejected while {} is in flight",
+                        method, url, idempotency_key
+                    ),
+                );
+                let rd = self.wait_policy.too_early();
+                journal(rd.status, Outcome::Rejected, rd.body_size());
+                return Ok(rd);
+            }
+            let _waiter = match self.waiters.acquire(idempotency_key) {
+                Some(guard) => guard,
+                None => {
+                    self.admin.record(agent, |s| s.errors += 1);
+                    log::warn!(target: "handler",
+                        action = "waiting",
+                        method = method,
+                        url = url,
+                        status = 429u16,
+                        agent = agent,
+                        idempotency_key = idempotency_key;
+                        "too many waiters");
+                    // not an error: the request may be retried after the in-flight one completes
+                    let mut rd = ResponseData::new(StatusCode::TOO_MANY_REQUESTS.as_u16());
+                    rd.headers
+                        .push((http::header::RETRY_AFTER.to_string(), "1".to_string()));
+                    rd.body =
+                        Bytes::from_static(b"too many requests waiting for the idempotency key");
+                    rd.mime = "text/plain".to_string();
+                    journal(rd.status, Outcome::Rejected, rd.body_size());
+                    return Ok(rd);
+                }
+            };
+            let lookup_span = preq.trace.span("cache_lookup");
+            let polled = self
+                .cacher
+                .polling_get_with(idempotency_key, &mut self.wait_policy.delays())
+                .await;
+            lookup_span.record(&polled);
+            drop(lookup_span);
+            let data = match polled {
+                Ok(data) => data,
+                Err(err) if err == cache::POLLING_TIMEOUT => {
+                    log::warn!(target: "handler",
+                        action = "waiting",
+                        method = method,
+                        url = url,
+                        status = self.wait_policy.timeout_status.as_u16(),
+                        agent = agent,
+                        idempotency_key = idempotency_key;
+                        "wait timeout");
+                    let rd = self.wait_policy.timed_out();
+                    journal(rd.status, Outcome::Rejected, rd.body_size());
+                    return Ok(rd);
+                }
+                Err(err) => return Err(bad_gateway(err)),
+            };
+
+            let res = ResponseData::try_from(&data[..]).map_err(bad_gateway)?;
+            if !preq.fingerprint.is_empty()
+                && !res.fingerprint.is_empty()
+                && res.fingerprint != preq.fingerprint
+            {
+                log::warn!(target: "handler",
+                    action = "fingerprint",
+                    method = method,
+                    url = url,
+                    status = 422u16,
+                    agent = agent,
+                    idempotency_key = idempotency_key;
+                    "idempotency key reused with a different payload");
+                journal(422, Outcome::Failed, 0);
+                return Err((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "idempotency-key was used with a different request payload".to_string(),
+                ));
+            }
+            self.admin.record(agent, |s| s.cache_hits += 1);
+            journal(res.status, Outcome::Replayed, res.body_size());
+            self.events.publish(
+                EventKind::Replayed,
+                agent,
+                method,
+                url,
+                idempotency_key,
+                Some(res.status),
+            );
+            log::info!(target: "handler",
+                        action = "cachehit",
+                        method = method,
+                        url = url,
+                        status = res.status,
+                        agent = agent,
+                        idempotency_key = idempotency_key;
+                        "");
+            return Ok(res);
+        }
+
+        self.events.publish(
+            EventKind::LockAcquired,
+            agent,
+            method,
+            url,
+            idempotency_key,
+            None,
+        );
+
+        if let Some(rd) = self.migrated_response(preq).await {
+            journal(rd.status, Outcome::Replayed, rd.body_size());
+            return Ok(rd);
+        }
+
+        self.admin.start(idempotency_key, agent, method, url);
+        let res = lease::with_lease(
+            &self.cacher,
+            idempotency_key,
+            self.lock_renew_interval,
+            self.forward(preq),
+        )
+        .await;
+        self.admin.finish(idempotency_key);
+        match res {
+            Ok(res) => {
+                log::info!(target: "handler",
+                    action = "proxying",
+                    method = method,
+                    url = url,
+                    status = 200u16,
                     agent = agent,
                     idempotency_key = idempotency_key;
                     "");
-        return Ok(res);
+                journal(res.status, Outcome::Proxied, res.body_size());
+                Ok(res)
+            }
+            Err((status, msg)) => {
+                let _ = self.cacher.del(idempotency_key).await;
+                self.admin.record(agent, |s| s.errors += 1);
+                journal(status.as_u16(), Outcome::Failed, 0);
+                let message = redact::message(&msg, &preq.headers);
+                log::warn!(target: "handler",
+                    action = "proxying",
+                    method = method,
+                    url = url,
+                    status = status.as_u16(),
+                    agent = agent,
+                    idempotency_key = idempotency_key;
+                    "{}", message);
+                self.admin.record_error(ErrorRecord {
+                    agent: agent.to_string(),
+                    method: method.to_string(),
+                    url: url.to_string(),
+                    status: status.as_u16(),
+                    message,
+                    at: unix_ms(),
+                });
+                Err((status, msg))
+            }
+        }
     }
 
-    let res = {
-        let method = req.method();
-        let json_mask = extract_header(req.headers(), &HEADER_X_JSON_MASK, || "".to_string());
-        let response_headers =
-            extract_header(req.headers(), &HEADER_RESPONSE_HEADERS, || "".to_string());
+    // A response cached under the legacy key before IDEMPOTENCY_KEY_SCHEME=migrate, it is
+    // stored under the new key (releasing its lock) so duplicates find it there.
+    async fn migrated_response(&self, preq: &ProxyRequest) -> Option<ResponseData> {
+        let legacy_key = preq.legacy_key.as_deref()?;
+        let data = self.cacher.get(legacy_key).await.ok()??;
+        let rd = ResponseData::try_from(&data[..]).ok()?;
+        // the chunks of a streamed body belong to the legacy key
+        let rd = self.load_body(legacy_key, Ok(rd)).await.ok()?;
+        let data = rd.to_bytes().ok()?;
+        if let Err(err) = self
+            .cacher
+            .set(&preq.idempotency_key, data, self.cacher.cache_ttl)
+            .await
+        {
+            log::warn!(target: "handler",
+                action = "migrate_key",
+                agent = preq.agent,
+                idempotency_key = preq.idempotency_key;
+                "storage unavailable: {}", err);
+            let _ = self.cacher.del(&preq.idempotency_key).await;
+        }
+        Some(rd)
+    }
 
-        let mut headers = req.headers().clone();
-        app.alter_headers(&mut headers);
+    async fn forward(&self, preq: &ProxyRequest) -> Result<ResponseData, (StatusCode, String)> {
+        let routes = self.admin.routes();
+        let route = routes.get(&preq.route);
+        if route.grpc {
+            return self.forward_grpc(preq, route).await;
+        }
+        let span = preq.trace.client("upstream");
+        let http_client = self.route_client(route)?;
+        let mut attempt = 1;
+        // the upstream guard is held until the response body is read or stored
+        let (rres, is_canary, _upstream) = loop {
+            // rebuilt for every attempt: signatures and tokens may be time bound
+            let (rreq, is_canary) = self.upstream_request(preq, route, &span).await?;
+            let host = rreq.url().host_str().unwrap_or_default().to_string();
+            if let Err(retry_after) = self.breakers.allow(&host, unix_ms()) {
+                return Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!(
+                        "circuit breaker of upstream {} is open, retry after {}s",
+                        host, retry_after
+                    ),
+                ));
+            }
+            let upstream = self.resources.upstream(&host);
+            if let (Some(retry), 1) = (&route.retry, attempt) {
+                retry.record_request(&host);
+            }
+            let executed = http_client.execute(rreq).await;
+            self.breakers.record(
+                &host,
+                executed
+                    .as_ref()
+                    .is_ok_and(|rres| !rres.status().is_server_error()),
+                unix_ms(),
+            );
+            let (delay, reason) = match executed {
+                Ok(rres) => {
+                    let status = rres.status();
+                    let delay = route
+                        .retry
+                        .as_ref()
+                        .filter(|retry| retry.retries_status(status))
+                        .and_then(|retry| {
+                            retry.next_delay(
+                                &host,
+                                &preq.idempotency_key,
+                                attempt,
+                                Some(rres.headers()),
+                            )
+                        });
+                    match delay {
+                        Some(delay) => (delay, status.to_string()),
+                        None => break (rres, is_canary, upstream),
+                    }
+                }
+                Err(err) => {
+                    let delay = route
+                        .retry
+                        .as_ref()
+                        .filter(|retry| retry.retries_error(&err))
+                        .and_then(|retry| {
+                            retry.next_delay(&host, &preq.idempotency_key, attempt, None)
+                        });
+                    if let Some(canary) = &route.canary {
+                        canary.record_error(is_canary);
+                    }
+                    match delay {
+                        Some(delay) => (delay, err.without_url().to_string()),
+                        None => {
+                            let err = err.without_url();
+                            span.set_error(&err.to_string());
+                            return self.upstream_failure(preq, route, err).await;
+                        }
+                    }
+                }
+            };
+            log::warn!(target: "handler",
+                action = "retry",
+                url = redact::url(&preq.url),
+                attempt = attempt,
+                agent = preq.agent,
+                idempotency_key = preq.idempotency_key;
+                "{}, retrying in {}ms", reason, delay.as_millis());
+            drop(upstream);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        };
+        if attempt > 1 {
+            span.set_attribute("proxy.retries", (attempt - 1) as i64);
+        }
+        let status = rres.status();
+        span.set_attribute("http.response.status_code", status.as_u16() as i64);
+        if status == StatusCode::UNAUTHORIZED {
+            if let Some(client) = &route.oauth2 {
+                // the token may be revoked before it expires
+                client.invalidate().await;
+            }
+        }
+        // If the HTTP status code is 500 or below, it's considered a server response and should be cached; any exceptions should be handled by the client. Otherwise, it's considered a non-response from the server and should not be cached.
+        let cacheable = status >= StatusCode::OK && status <= StatusCode::INTERNAL_SERVER_ERROR;
+        // the limit of the agent applies to any response, the one of the route to cached ones
+        let max_size = self
+            .agent_limits
+            .max_response_size(&preq.agent)
+            .or_else(|| route.max_cacheable_size().filter(|_| cacheable));
+        if let Some(limit) = max_size {
+            if rres.content_length().is_some_and(|len| len > limit as u64) {
+                return Err(response_too_large(limit));
+            }
+        }
+        if let Some(chunk_size) = route
+            .stream_chunk_size(&preq.json_mask)
+            .filter(|_| cacheable)
+        {
+            return self
+                .forward_chunked(preq, route, rres, chunk_size, is_canary)
+                .await;
+        }
 
-        let mut rreq = reqwest::Request::new(method.clone(), url.clone());
-        *rreq.headers_mut() = headers;
+        let headers = rres.headers().to_owned();
+        let res_body = match max_size {
+            Some(limit) => read_limited(rres, limit).await,
+            None => rres.bytes().await.map(Some),
+        };
+        let res_body = match res_body {
+            Ok(Some(body)) => body,
+            Ok(None) => return Err(response_too_large(max_size.unwrap_or_default())),
+            Err(err) => return self.upstream_failure(preq, route, err).await,
+        };
+        drop(span);
+        self.upstream_done(preq, route, status, is_canary);
 
-        if !method.is_safe() {
-            let body = to_bytes(req.into_body(), 1024 * 1024)
-                .await
-                .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
-            *rreq.body_mut() = Some(reqwest::Body::from(body));
+        if let Some(mirror) = &route.mirror {
+            mirror.spawn(
+                route.mirror_percent.unwrap_or(100),
+                self.http_client.as_ref().clone(),
+                preq,
+                status,
+                res_body.clone(),
+            );
         }
 
-        let rres = app.http_client.execute(rreq).await.map_err(bad_gateway)?;
+        if cacheable {
+            let mut rd = self.response_head(preq, route, status, &headers)?;
+            if let Some(schema) = &route.response_schema {
+                if status.is_success() {
+                    // A malformed response must not be frozen into the cache.
+                    schema.validate(&res_body).map_err(|err| {
+                        (
+                            StatusCode::BAD_GATEWAY,
+                            format!("upstream response schema validation failed: {}", err),
+                        )
+                    })?;
+                }
+            }
+
+            let res_body = match &route.response_plugin {
+                Some(plugin) => {
+                    plugin
+                        .transform(status.as_u16(), res_body)
+                        .await
+                        .map_err(|err| {
+                            (
+                                StatusCode::BAD_GATEWAY,
+                                format!("upstream response transformation failed: {}", err),
+                            )
+                        })?
+                }
+                None => res_body,
+            };
+            rd.with_body(&res_body, &preq.json_mask)
+                .map_err(bad_gateway)?;
+            if let Some(preset) = route
+                .preset
+                .filter(|_| !route.ic_certification && status != StatusCode::PARTIAL_CONTENT)
+            {
+                preset
+                    .apply(&mut rd, &route.volatile_fields)
+                    .map_err(bad_gateway)?;
+            }
+            let data = rd.to_bytes().map_err(bad_gateway)?;
+
+            let ttl = route.failure_ttl_of(status).unwrap_or_else(|| {
+                preq.cache_ttl
+                    .unwrap_or_else(|| route.cache_ttl(&headers, self.cacher.cache_ttl))
+            });
+            self.store(preq, rd, data, ttl).await
+        } else if route.failure_ttl_of(status).is_some() {
+            // the error page of a gateway in front of the upstream is cached as it is
+            let mut rd = ResponseData::new(status.as_u16());
+            rd.fingerprint = preq.fingerprint.clone();
+            rd.with_headers(&headers, &preq.response_headers);
+            rd.body = res_body;
+            self.negative_cache(preq, route, rd).await
+        } else {
+            Err((status, String::from_utf8_lossy(&res_body).to_string()))
+        }
+    }
+
+    // Caches the response under the idempotency key, which releases its lock.
+    async fn store(
+        &self,
+        preq: &ProxyRequest,
+        rd: ResponseData,
+        data: Vec<u8>,
+        ttl: u64,
+    ) -> Result<ResponseData, (StatusCode, String)> {
+        let store_span = preq.trace.span("store");
+        let stored = self.cacher.set(&preq.idempotency_key, data, ttl).await;
+        store_span.record(&stored);
+        drop(store_span);
+        if let Err(err) = stored {
+            if self.storage_failure == FailurePolicy::Closed {
+                return Err((StatusCode::SERVICE_UNAVAILABLE, err));
+            }
+            // the upstream did the work, the caller gets the response even if duplicates won't
+            log::warn!(target: "handler",
+                action = "cache",
+                url = redact::url(&preq.url),
+                agent = preq.agent,
+                idempotency_key = preq.idempotency_key;
+                "storage unavailable: {}", err);
+            return Ok(rd);
+        }
+        self.events.publish(
+            EventKind::Cached,
+            &preq.agent,
+            preq.method.as_str(),
+            &redact::url(&preq.url),
+            &preq.idempotency_key,
+            Some(rd.status),
+        );
+        Ok(rd)
+    }
+
+    // Forwards a unary gRPC call over HTTP/2, built like the other upstream requests of
+    // the route. The response is cached with its trailers unless its status is a transient
+    // failure, which releases the lock like a 5xx response.
+    async fn forward_grpc(
+        &self,
+        preq: &ProxyRequest,
+        route: &RouteConfig,
+    ) -> Result<ResponseData, (StatusCode, String)> {
+        let span = preq.trace.client("upstream");
+        let (rreq, is_canary) = self.upstream_request(preq, route, &span).await?;
+        let host = rreq.url().host_str().unwrap_or_default().to_string();
+        if let Err(retry_after) = self.breakers.allow(&host, unix_ms()) {
+            return Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!(
+                    "circuit breaker of upstream {} is open, retry after {}s",
+                    host, retry_after
+                ),
+            ));
+        }
+        let channel = self.grpc_channels.get(rreq.url()).map_err(bad_gateway)?;
+        let _upstream = self.resources.upstream(&host);
+        let called = grpc_proxy::call(channel, rreq).await;
+        let (parts, body, trailers) = match called {
+            Ok(res) => res,
+            Err(err) => {
+                self.breakers.record(&host, false, unix_ms());
+                span.set_error(&err);
+                return Err(bad_gateway(err));
+            }
+        };
+        span.set_attribute("http.response.status_code", parts.status.as_u16() as i64);
+        let (code, message) = match grpc_proxy::status_of(&parts.headers, &trailers) {
+            Some(status) if parts.status == StatusCode::OK => status,
+            _ => {
+                self.breakers.record(&host, false, unix_ms());
+                return Err(bad_gateway(format!(
+                    "invalid upstream gRPC response, HTTP status {}",
+                    parts.status
+                )));
+            }
+        };
+        span.set_attribute("rpc.grpc.status_code", code as i64);
+        drop(span);
+        self.breakers
+            .record(&host, !grpc_proxy::is_transient(code), unix_ms());
+        self.upstream_done(preq, route, parts.status, is_canary);
+        if grpc_proxy::is_transient(code) {
+            return Err((grpc_proxy::code_status(code), message));
+        }
+
+        let max_size = self
+            .agent_limits
+            .max_response_size(&preq.agent)
+            .or_else(|| route.max_cacheable_size());
+        if let Some(limit) = max_size.filter(|limit| body.len() > *limit) {
+            return Err(response_too_large(limit));
+        }
+        let mut rd = self.response_head(preq, route, parts.status, &parts.headers)?;
+        rd.body = body;
+        rd.trailers = trailers
+            .iter()
+            .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+            .collect();
+        let data = rd.to_bytes().map_err(bad_gateway)?;
+        let ttl = preq.cache_ttl.unwrap_or(self.cacher.cache_ttl);
+        self.store(preq, rd, data, ttl).await
+    }
+
+    // Streams a cacheable response body into the storage in chunks, so the memory held
+    // per request is bounded by one chunk. The chunks written so far are deleted if the
+    // upstream stream or a storage write fails.
+    async fn forward_chunked(
+        &self,
+        preq: &ProxyRequest,
+        route: &RouteConfig,
+        mut rres: reqwest::Response,
+        chunk_size: usize,
+        is_canary: bool,
+    ) -> Result<ResponseData, (StatusCode, String)> {
         let status = rres.status();
         let headers = rres.headers().to_owned();
-        let res_body = rres.bytes().await.map_err(bad_gateway)?;
+        let mut rd = self.response_head(preq, route, status, &headers)?;
+        let ttl = route.failure_ttl_of(status).unwrap_or_else(|| {
+            preq.cache_ttl
+                .unwrap_or_else(|| route.cache_ttl(&headers, self.cacher.cache_ttl))
+        });
+        // the chunks must outlive the response that refers to them
+        let mut writer = ChunkWriter::new(
+            &self.cacher,
+            &preq.idempotency_key,
+            ttl + self.cacher.cache_ttl,
+            chunk_size,
+        );
+        let max_size = self
+            .agent_limits
+            .max_response_size(&preq.agent)
+            .or_else(|| route.max_cacheable_size());
+        let mut size = 0;
+        loop {
+            match rres.chunk().await {
+                Ok(Some(data)) => {
+                    size += data.len();
+                    if let Some(limit) = max_size.filter(|&limit| size > limit) {
+                        writer.abort().await;
+                        return Err(response_too_large(limit));
+                    }
+                    if let Err(err) = writer.write(&data).await {
+                        writer.abort().await;
+                        return Err(bad_gateway(err));
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    writer.abort().await;
+                    return self.upstream_failure(preq, route, err).await;
+                }
+            }
+        }
+        match writer.inline() {
+            Some(body) => rd.body = body,
+            None => match writer.finish().await {
+                Ok(chunks) => rd.chunks = Some(chunks),
+                Err(err) => {
+                    writer.abort().await;
+                    return Err(bad_gateway(err));
+                }
+            },
+        }
+        self.upstream_done(preq, route, status, is_canary);
 
-        // If the HTTP status code is 500 or below, it's considered a server response and should be cached; any exceptions should be handled by the client. Otherwise, it's considered a non-response from the server and should not be cached.
-        if status >= StatusCode::OK && status <= StatusCode::INTERNAL_SERVER_ERROR {
-            let mut rd = ResponseData::new(status.as_u16());
-            rd.with_headers(&headers, &response_headers);
-            rd.with_body(&res_body, &json_mask).map_err(bad_gateway)?;
-            let data = rd.to_bytes().map_err(bad_gateway)?;
+        let data = rd.to_bytes().map_err(bad_gateway)?;
+        let store_span = preq.trace.span("store");
+        let stored = self.cacher.set(&preq.idempotency_key, data, ttl).await;
+        store_span.record(&stored);
+        drop(store_span);
+        if let Err(err) = stored {
+            writer.abort().await;
+            return Err(bad_gateway(err));
+        }
+        self.events.publish(
+            EventKind::Cached,
+            &preq.agent,
+            preq.method.as_str(),
+            &redact::url(&preq.url),
+            &preq.idempotency_key,
+            Some(rd.status),
+        );
+        Ok(rd)
+    }
 
-            let _ = app
-                .cacher
-                .set(&idempotency_key, data, app.cacher.cache_ttl)
+    // Checks the content-type of a cacheable response and keeps the response headers to cache.
+    fn response_head(
+        &self,
+        preq: &ProxyRequest,
+        route: &RouteConfig,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> Result<ResponseData, (StatusCode, String)> {
+        let content_type = headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        if !route.allows_content_type(content_type) {
+            // e.g. an HTML error page from a CDN in front of the API
+            return Err((
+                StatusCode::BAD_GATEWAY,
+                format!("unexpected upstream content-type: {}", content_type),
+            ));
+        }
+        let content_encoding = headers
+            .get(http::header::CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        if !route.allows_content_encoding(content_encoding) {
+            // the upstream ignored the negotiated encoding, callers could get different bytes
+            return Err((
+                StatusCode::BAD_GATEWAY,
+                format!("unexpected upstream content-encoding: {}", content_encoding),
+            ));
+        }
+
+        let mut rd = ResponseData::new(status.as_u16());
+        rd.fingerprint = preq.fingerprint.clone();
+        if route.ic_certification && status.is_success() {
+            // Keep the IC response verification headers and the headers they certify.
+            let filtering =
+                certification::response_headers_filtering(headers, &preq.response_headers)
+                    .map_err(bad_gateway)?;
+            rd.with_headers(headers, &filtering);
+        } else {
+            rd.with_headers(headers, &preq.response_headers);
+        }
+        if status == StatusCode::PARTIAL_CONTENT {
+            // partial content is meaningless without its range
+            keep_header(&mut rd, headers, http::header::CONTENT_RANGE);
+        }
+        // session cookies must not be frozen in the cache and replayed to other callers
+        strip_cookies(&mut rd, route);
+        route.deterministic_headers(&mut rd.headers);
+        if let Some(script) = &route.script {
+            if let Verdict::Veto(reason) = script.on_response(&mut rd).map_err(bad_gateway)? {
+                return Err((StatusCode::BAD_GATEWAY, reason));
+            }
+        }
+        Ok(rd)
+    }
+
+    fn upstream_done(
+        &self,
+        preq: &ProxyRequest,
+        route: &RouteConfig,
+        status: StatusCode,
+        is_canary: bool,
+    ) {
+        self.events.publish(
+            EventKind::UpstreamDone,
+            &preq.agent,
+            preq.method.as_str(),
+            &redact::url(&preq.url),
+            &preq.idempotency_key,
+            Some(status.as_u16()),
+        );
+        if let Some(canary) = &route.canary {
+            if status.is_server_error() {
+                canary.record_error(is_canary);
+            }
+            log::info!(target: "canary",
+                url = redact::url(&preq.url),
+                status = status.as_u16(),
+                variant = if is_canary { "canary" } else { "primary" },
+                idempotency_key = preq.idempotency_key;
+                "");
+        }
+    }
+
+    // Streams a body stored in chunks from the storage.
+    pub fn response(&self, idempotency_key: &str, rd: ResponseData) -> Response {
+        match rd.chunks {
+            None => rd.into_response(),
+            Some(chunks) => {
+                let mut res = rd.into_response();
+                *res.body_mut() =
+                    cache::chunked_body(self.cacher.clone(), idempotency_key.to_string(), chunks);
+                res.headers_mut()
+                    .insert(http::header::CONTENT_LENGTH, chunks.size.into());
+                res
+            }
+        }
+    }
+
+    // Reads a body stored in chunks into memory, for consumers that need the whole body.
+    pub async fn load_body(
+        &self,
+        idempotency_key: &str,
+        res: Result<ResponseData, (StatusCode, String)>,
+    ) -> Result<ResponseData, (StatusCode, String)> {
+        let mut rd = res?;
+        if let Some(chunks) = rd.chunks.take() {
+            rd.body = cache::read_chunks(&self.cacher, idempotency_key, chunks)
                 .await
                 .map_err(bad_gateway)?;
+        }
+        Ok(rd)
+    }
+}
 
-            Ok(rd)
+impl AppState {
+    // Connection failures and timeouts, and the 5xx responses of FAILURE_STATUSES, are cached
+    // for a short TTL when the route enables it, so duplicates of the request during an
+    // upstream outage do not hit the upstream again.
+    async fn upstream_failure(
+        &self,
+        preq: &ProxyRequest,
+        route: &RouteConfig,
+        err: reqwest::Error,
+    ) -> Result<ResponseData, (StatusCode, String)> {
+        let status = if err.is_timeout() {
+            StatusCode::GATEWAY_TIMEOUT
         } else {
-            Err((status, String::from_utf8_lossy(&res_body).to_string()))
+            StatusCode::BAD_GATEWAY
+        };
+        // The upstream URL may carry credentials, so it is never part of the error message.
+        let msg = err.without_url().to_string();
+        if route.failure_ttl == 0 {
+            return Err((status, msg));
         }
+
+        let mut rd = ResponseData::new(status.as_u16());
+        rd.body = Bytes::from(msg.into_bytes());
+        self.negative_cache(preq, route, rd).await
+    }
+
+    // Stores an upstream failure for the FAILURE_TTL of the route, the caller gets the
+    // failure as an error if it can't be stored.
+    async fn negative_cache(
+        &self,
+        preq: &ProxyRequest,
+        route: &RouteConfig,
+        rd: ResponseData,
+    ) -> Result<ResponseData, (StatusCode, String)> {
+        let status = StatusCode::from_u16(rd.status).unwrap_or(StatusCode::BAD_GATEWAY);
+        let data = rd.to_bytes().map_err(bad_gateway)?;
+        if self
+            .cacher
+            .set(&preq.idempotency_key, data, route.failure_ttl * 1000)
+            .await
+            .is_err()
+        {
+            return Err((status, String::from_utf8_lossy(&rd.body).to_string()));
+        }
+        log::warn!(target: "handler",
+            action = "negative_cache",
+            url = redact::url(&preq.url),
+            status = status.as_u16(),
+            agent = preq.agent,
+            idempotency_key = preq.idempotency_key;
+            "{}", String::from_utf8_lossy(&rd.body));
+        Ok(rd)
+    }
+}
+
+// A proxy token `[expire_at, "<METHOD> <path?query>", signature]` (base64url encoded CBOR)
+// signed with the proxy's key, upstreams verify it with `auth::ed25519_verify` and compare
+// the subject with the request they received, so stale or redirected replays are rejected.
+fn signed_timestamp(
+    key: &ed25519_dalek::SigningKey,
+    ttl: u64,
+    method: &Method,
+    url: &reqwest::Url,
+) -> HeaderValue {
+    let subject = match url.query() {
+        Some(query) => format!("{} {}?{}", method, url.path(), query),
+        None => format!("{} {}", method, url.path()),
     };
+    let token = auth::ed25519_sign(key, unix_ms() / 1000 + ttl, subject);
+    HeaderValue::from_str(&general_purpose::URL_SAFE_NO_PAD.encode(token))
+        .expect("base64url is a valid header value")
+}
 
-    match res {
-        Ok(res) => {
-            log::info!(target: "handler",
-                action = "proxying",
-                method = method,
-                url = url.to_string(),
-                status = 200u16,
-                agent = agent,
-                idempotency_key = idempotency_key;
-                "");
-            Ok(res)
+impl AppState {
+    // Builds the upstream request of a route: replica and canary selection, static headers,
+    // signed timestamp, authorization and signing. Returns whether the canary was selected.
+    async fn upstream_request(
+        &self,
+        preq: &ProxyRequest,
+        route: &RouteConfig,
+        span: &Trace,
+    ) -> Result<(reqwest::Request, bool), (StatusCode, String)> {
+        let url = route.replica_url(&preq.url, &preq.idempotency_key);
+        let (url, is_canary) = match &route.canary {
+            Some(canary) => canary.route(&url, &preq.idempotency_key, route.canary_percent),
+            None => (url, false),
+        };
+        let url = secrets::expand_url(&url)
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?
+            .into_owned();
+        let mut rreq = reqwest::Request::new(preq.method.clone(), url);
+        *rreq.headers_mut() = preq.headers.clone();
+        if let Some(body) = &preq.body {
+            *rreq.body_mut() = Some(reqwest::Body::from(body.clone()));
         }
-        Err((status, msg)) => {
-            let _ = app.cacher.del(&idempotency_key).await;
-            log::warn!(target: "handler",
-                action = "proxying",
-                method = method,
-                url = url.to_string(),
-                status = status.as_u16(),
-                agent = agent,
-                idempotency_key = idempotency_key;
-                "{}", msg);
-            Err((status, msg))
+        // before the authorization and signing steps so that static headers are signed too
+        route
+            .request_headers
+            .apply(rreq.headers_mut())
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+        span.inject(rreq.headers_mut());
+        if route.signed_timestamp_ttl > 0 {
+            let key = self.signing_key.as_ref().ok_or_else(|| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "PROXY_SIGNING_KEY is required by SIGNED_TIMESTAMP_TTL".to_string(),
+                )
+            })?;
+            let value =
+                signed_timestamp(key, route.signed_timestamp_ttl, rreq.method(), rreq.url());
+            rreq.headers_mut().insert(&HEADER_X_PROXY_TIMESTAMP, value);
+        }
+        if let Some(client) = &route.oauth2 {
+            let authorization = client
+                .authorization(&self.http_client)
+                .await
+                .map_err(bad_gateway)?;
+            rreq.headers_mut()
+                .insert(http::header::AUTHORIZATION, authorization);
+        }
+        if let Some(token) = &route.metadata_token {
+            let authorization = token
+                .authorization(&self.http_client)
+                .await
+                .map_err(bad_gateway)?;
+            rreq.headers_mut()
+                .insert(http::header::AUTHORIZATION, authorization);
+        }
+        if let Some(signer) = &route.aws_sigv4 {
+            signer
+                .sign_request(&self.http_client, &mut rreq, preq.body.as_ref())
+                .await
+                .map_err(|err| {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("AWS SigV4 signing failed: {}", err),
+                    )
+                })?;
+        }
+
+        Ok((rreq, is_canary))
+    }
+
+    fn route_client(&self, route: &RouteConfig) -> Result<Client, (StatusCode, String)> {
+        match &route.pool {
+            // cache_ttl is the request timeout
+            Some(pool) => pool
+                .client(self.cacher.cache_ttl)
+                .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err)),
+            None => Ok(self.http_client.as_ref().clone()),
+        }
+    }
+
+    // HEAD replays the status and headers of the cached GET response with the same
+    // idempotency key, HEAD and OPTIONS are forwarded without caching otherwise,
+    // so that their empty bodies never pollute the cache.
+    pub async fn passthrough(&self, preq: &ProxyRequest) -> Result<Response, (StatusCode, String)> {
+        if preq.method == Method::HEAD {
+            if let Ok(data) = self
+                .cacher
+                .polling_get_with(&preq.idempotency_key, &mut self.wait_policy.delays())
+                .await
+            {
+                let mut rd = ResponseData::try_from(&data[..]).map_err(bad_gateway)?;
+                let len = match rd.chunks.take() {
+                    Some(chunks) => chunks.size as usize,
+                    None => rd.body.len(),
+                };
+                rd.body = Bytes::new();
+                let mut res = rd.into_response();
+                res.headers_mut()
+                    .insert(http::header::CONTENT_LENGTH, len.into());
+                return Ok(res);
+            }
+        }
+
+        let routes = self.admin.routes();
+        let route = routes.get(&preq.route);
+        let span = preq.trace.client("upstream");
+        let (rreq, _) = self.upstream_request(preq, route, &span).await?;
+        let _upstream = self
+            .resources
+            .upstream(rreq.url().host_str().unwrap_or_default());
+        let rres = self
+            .route_client(route)?
+            .execute(rreq)
+            .await
+            .map_err(|err| bad_gateway(err.without_url()))?;
+        let status = rres.status();
+        span.set_attribute("http.response.status_code", status.as_u16() as i64);
+        let headers = rres.headers().to_owned();
+        let mut rd = ResponseData::new(status.as_u16());
+        rd.with_headers(&headers, &preq.response_headers);
+        keep_header(&mut rd, &headers, http::header::CONTENT_RANGE);
+        strip_cookies(&mut rd, route);
+        let body = rres
+            .bytes()
+            .await
+            .map_err(|err| bad_gateway(err.without_url()))?;
+        rd.body = body;
+        let mut res = rd.into_response();
+        if preq.method == Method::HEAD {
+            if let Some(len) = headers.get(http::header::CONTENT_LENGTH) {
+                res.headers_mut()
+                    .insert(http::header::CONTENT_LENGTH, len.clone());
+            }
         }
+        route
+            .response_headers
+            .apply(res.headers_mut())
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+        Ok(res)
+    }
+}
+
+// Keeps a response header even if the response-headers filtering drops it.
+fn keep_header(rd: &mut ResponseData, headers: &HeaderMap, name: http::header::HeaderName) {
+    if rd.headers.iter().any(|(k, _)| k == name.as_str()) {
+        return;
+    }
+    if let Some(v) = headers.get(&name).and_then(|v| v.to_str().ok()) {
+        rd.headers.push((name.to_string(), v.to_string()));
     }
 }
 
+// Drops the upstream Set-Cookie headers unless the route keeps them.
+fn strip_cookies(rd: &mut ResponseData, route: &RouteConfig) {
+    if !route.keep_cookies {
+        rd.headers.retain(|(k, _)| k != "set-cookie");
+    }
+}
+
+// Maps a normalized JSON-RPC response back to the caller's request ids.
+fn restore_jsonrpc(jsonrpc: Option<&JsonRpcBody>, mut res: ResponseData) -> ResponseData {
+    if let Some(jr) = jsonrpc {
+        if (200..300).contains(&res.status) {
+            if let Ok(body) = jr.restore_response(&res.body) {
+                res.body = Bytes::from(body);
+            }
+        }
+    }
+    res
+}
+
+fn payload_too_large(limit: usize) -> (StatusCode, String) {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("request body exceeds the limit of {} bytes", limit),
+    )
+}
+
 fn bad_gateway(err: impl std::fmt::Display) -> (StatusCode, String) {
     (StatusCode::BAD_GATEWAY, err.to_string())
 }
 
+fn response_too_large(limit: usize) -> (StatusCode, String) {
+    (
+        StatusCode::BAD_GATEWAY,
+        format!("upstream response exceeds the limit of {} bytes", limit),
+    )
+}
+
+// Reads a response body up to the limit, None if it is larger.
+async fn read_limited(
+    mut rres: reqwest::Response,
+    limit: usize,
+) -> Result<Option<Bytes>, reqwest::Error> {
+    let mut body = Vec::new();
+    while let Some(chunk) = rres.chunk().await? {
+        if body.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Some(Bytes::from(body)))
+}
+
+// The IETF Idempotency-Key fingerprint of a request, stored with its cached response.
+fn request_fingerprint(method: &Method, url: &reqwest::Url, body: Option<&[u8]>) -> String {
+    let mut data = Vec::new();
+    data.extend_from_slice(method.as_str().as_bytes());
+    data.push(b'\n');
+    data.extend_from_slice(url.as_str().as_bytes());
+    data.push(b'\n');
+    data.extend_from_slice(body.unwrap_or_default());
+    general_purpose::URL_SAFE_NO_PAD.encode(auth::sha3_256(&data))
+}
+
+// Parses the x-idempotency-ttl header (in seconds), returns the TTL in milliseconds.
+fn parse_idempotency_ttl(value: &str, max: u64) -> Result<u64, String> {
+    let ttl = value
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .filter(|&secs| secs > 0)
+        .ok_or_else(|| format!("invalid x-idempotency-ttl: {}", value))?
+        .saturating_mul(1000);
+    if ttl > max {
+        return Err(format!(
+            "x-idempotency-ttl exceeds the max TTL of {} seconds",
+            max / 1000
+        ));
+    }
+    Ok(ttl)
+}
+
 fn extract_header<K>(hm: &HeaderMap, key: K, or: impl FnOnce() -> String) -> String
 where
     K: AsHeaderName,
@@ -253,7 +2104,117 @@ where
 
 #[cfg(test)]
 mod test {
+    use super::*;
 
     #[test]
     fn test_challenge() {}
+
+    #[test]
+    fn test_request_fingerprint() {
+        let url = reqwest::Url::parse("https://httpbin.org/post?a=1").unwrap();
+        let a = request_fingerprint(&Method::POST, &url, Some(b"{}"));
+        assert_eq!(a, request_fingerprint(&Method::POST, &url, Some(b"{}")));
+        assert_ne!(a, request_fingerprint(&Method::POST, &url, Some(b"{ }")));
+        assert_ne!(a, request_fingerprint(&Method::PUT, &url, Some(b"{}")));
+        let url2 = reqwest::Url::parse("https://httpbin.org/post?a=2").unwrap();
+        assert_ne!(a, request_fingerprint(&Method::POST, &url2, Some(b"{}")));
+        assert_eq!(
+            request_fingerprint(&Method::GET, &url, None),
+            request_fingerprint(&Method::GET, &url, Some(b""))
+        );
+    }
+
+    #[test]
+    fn test_token_scope() {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[1u8; 32]);
+        let scope = auth::Scope {
+            hosts: vec!["prices.example.com".to_string()],
+            paths: vec![],
+            methods: vec!["GET".to_string()],
+            nonce: None,
+        };
+        let bearer =
+            |token: Vec<u8>| format!("Bearer {}", general_purpose::URL_SAFE_NO_PAD.encode(token));
+        let token =
+            auth::ed25519_sign_scoped(&key, 4102444800, "price-feed".to_string(), scope.clone());
+        assert_eq!(token_scope(&bearer(token)), Some(scope));
+        let token = auth::ed25519_sign(&key, 4102444800, "price-feed".to_string());
+        assert_eq!(token_scope(&bearer(token)), None);
+        assert_eq!(token_scope("Bearer !!"), None);
+    }
+
+    #[test]
+    fn test_token_expire_at() {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[1u8; 32]);
+        let token = auth::ed25519_sign(&key, 4102444800, "price-feed".to_string());
+        let token = format!("Bearer {}", general_purpose::URL_SAFE_NO_PAD.encode(token));
+        assert_eq!(token_expire_at(&token), Some(4102444800));
+        // the signature of a JWT is not checked here
+        let jwt = format!(
+            "Bearer eyJhbGciOiJFUzI1NiJ9.{}.c2ln",
+            general_purpose::URL_SAFE_NO_PAD.encode(r#"{"sub":"alice","exp":1700000000}"#)
+        );
+        assert_eq!(token_expire_at(&jwt), Some(1700000000));
+        assert_eq!(token_expire_at("Bearer a.!!.b"), None);
+        assert_eq!(token_expire_at("Bearer !!"), None);
+        assert_eq!(token_expire_at("token"), None);
+    }
+
+    #[test]
+    fn test_parse_idempotency_ttl() {
+        assert_eq!(parse_idempotency_ttl("60", 300 * 1000), Ok(60 * 1000));
+        assert_eq!(parse_idempotency_ttl(" 300 ", 300 * 1000), Ok(300 * 1000));
+        assert!(parse_idempotency_ttl("301", 300 * 1000).is_err());
+        assert!(parse_idempotency_ttl("0", 300 * 1000).is_err());
+        assert!(parse_idempotency_ttl("-1", 300 * 1000).is_err());
+        assert!(parse_idempotency_ttl("1.5", 300 * 1000).is_err());
+    }
+
+    #[test]
+    fn test_keep_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::CONTENT_RANGE,
+            HeaderValue::from_static("bytes 0-1023/4096"),
+        );
+        headers.insert("x-request-id", HeaderValue::from_static("abc"));
+        let mut rd = ResponseData::new(206);
+        rd.with_headers(&headers, "x-request-id");
+        assert_eq!(rd.headers.len(), 1);
+        keep_header(&mut rd, &headers, http::header::CONTENT_RANGE);
+        keep_header(&mut rd, &headers, http::header::CONTENT_RANGE);
+        assert_eq!(
+            rd.headers,
+            vec![
+                ("x-request-id".to_string(), "abc".to_string()),
+                ("content-range".to_string(), "bytes 0-1023/4096".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failure_policy() {
+        assert_eq!("".parse::<FailurePolicy>().unwrap(), FailurePolicy::Closed);
+        assert_eq!(
+            "Open".parse::<FailurePolicy>().unwrap(),
+            FailurePolicy::Open
+        );
+        assert!("ignore".parse::<FailurePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_signed_timestamp() {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]);
+        let url = reqwest::Url::parse("https://api.example.com/v1/orders?id=1").unwrap();
+        let value = signed_timestamp(&key, 5, &Method::POST, &url);
+        let data = general_purpose::URL_SAFE_NO_PAD
+            .decode(value.as_bytes())
+            .unwrap();
+        let token = auth::ed25519_verify(&[key.verifying_key()], &data).unwrap();
+        assert_eq!(token.1, "POST /v1/orders?id=1");
+        assert!(token.0 >= unix_ms() / 1000);
+
+        let other = ed25519_dalek::SigningKey::from_bytes(&[8u8; 32]);
+        assert!(auth::ed25519_verify(&[other.verifying_key()], &data).is_err());
+    }
 }
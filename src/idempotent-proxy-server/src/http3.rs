@@ -0,0 +1,219 @@
+// HTTP/3 (QUIC) listener, available in builds with the `http3` feature. It serves the
+// same router as the TCP listener with the same certificate, clients discover it by
+// the Alt-Svc header of the HTTP/1.1 and HTTP/2 responses.
+#[cfg(feature = "http3")]
+mod enabled {
+    use axum::{body::Body, extract::ConnectInfo, Router};
+    use bytes::{Buf, Bytes};
+    use http::{header, HeaderValue, Request, Response};
+    use http_body_util::BodyExt;
+    use std::{fs::File, io::BufReader, net::SocketAddr, sync::Arc};
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+
+    type SendStream = h3::server::RequestStream<h3_quinn::SendStream<Bytes>, Bytes>;
+    type RecvStream = h3::server::RequestStream<h3_quinn::RecvStream, Bytes>;
+
+    // Connection specific headers are not allowed in HTTP/3.
+    const CONNECTION_HEADERS: [&str; 5] = [
+        "connection",
+        "keep-alive",
+        "proxy-connection",
+        "transfer-encoding",
+        "upgrade",
+    ];
+
+    // Stops accepting connections when `stopped` turns true and returns once the open ones
+    // have finished their requests.
+    pub async fn serve(
+        app: Router,
+        addr: SocketAddr,
+        cert_file: &str,
+        key_file: &str,
+        stopped: watch::Receiver<bool>,
+    ) -> Result<(), String> {
+        let endpoint = quinn::Endpoint::server(server_config(cert_file, key_file)?, addr)
+            .map_err(|err| format!("bind {} failed: {}", addr, err))?;
+        let mut stopping = stopped.clone();
+        loop {
+            let incoming = tokio::select! {
+                incoming = endpoint.accept() => incoming,
+                _ = stopping.wait_for(|v| *v) => None,
+            };
+            let Some(incoming) = incoming else { break };
+            let app = app.clone();
+            let stopped = stopped.clone();
+            tokio::spawn(async move {
+                if let Err(err) = serve_connection(app, incoming, stopped).await {
+                    log::debug!(target: "http3", "connection closed: {}", err);
+                }
+            });
+        }
+        endpoint.set_server_config(None);
+        endpoint.wait_idle().await;
+        Ok(())
+    }
+
+    fn server_config(cert_file: &str, key_file: &str) -> Result<quinn::ServerConfig, String> {
+        let open = |path: &str| {
+            File::open(path)
+                .map(BufReader::new)
+                .map_err(|err| format!("read {} failed: {}", path, err))
+        };
+        let certs = rustls_pemfile::certs(&mut open(cert_file)?)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("invalid certificate {}: {}", cert_file, err))?;
+        let key = rustls_pemfile::private_key(&mut open(key_file)?)
+            .map_err(|err| format!("invalid key {}: {}", key_file, err))?
+            .ok_or_else(|| format!("no private key in {}", key_file))?;
+
+        let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|err| err.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| err.to_string())?;
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)
+            .map_err(|err| err.to_string())?;
+        Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+    }
+
+    async fn serve_connection(
+        app: Router,
+        incoming: quinn::Incoming,
+        mut stopped: watch::Receiver<bool>,
+    ) -> Result<(), String> {
+        let conn = incoming.await.map_err(|err| err.to_string())?;
+        let remote = conn.remote_address();
+        let mut conn = h3::server::Connection::new(h3_quinn::Connection::new(conn))
+            .await
+            .map_err(|err| err.to_string())?;
+        let mut draining = false;
+        loop {
+            let accepted = tokio::select! {
+                accepted = conn.accept() => Some(accepted),
+                _ = stopped.wait_for(|v| *v), if !draining => None,
+            };
+            let Some(accepted) = accepted else {
+                // GOAWAY, the requests already received are still served
+                draining = true;
+                conn.shutdown(0).await.map_err(|err| err.to_string())?;
+                continue;
+            };
+            match accepted {
+                Ok(Some((req, stream))) => {
+                    let app = app.clone();
+                    tokio::spawn(async move {
+                        let (send, recv) = stream.split();
+                        if let Err(err) = serve_request(app, remote, req, send, recv).await {
+                            log::debug!(target: "http3", "request failed: {}", err);
+                        }
+                    });
+                }
+                Ok(None) => return Ok(()),
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+    }
+
+    async fn serve_request(
+        app: Router,
+        remote: SocketAddr,
+        req: Request<()>,
+        mut send: SendStream,
+        recv: RecvStream,
+    ) -> Result<(), String> {
+        // the request body is streamed, so the handler's body limit applies as usual
+        let body = futures::stream::unfold(Some(recv), |recv| async move {
+            let mut recv = recv?;
+            match recv.recv_data().await {
+                Ok(Some(mut data)) => Some((Ok(data.copy_to_bytes(data.remaining())), Some(recv))),
+                Ok(None) => None,
+                Err(err) => Some((Err(err), None)),
+            }
+        });
+        let (mut parts, _) = req.into_parts();
+        parts.extensions.insert(ConnectInfo(remote));
+        // HTTP/3 carries the host in the :authority pseudo header
+        if !parts.headers.contains_key(header::HOST) {
+            if let Some(host) = parts
+                .uri
+                .authority()
+                .and_then(|a| HeaderValue::from_str(a.as_str()).ok())
+            {
+                parts.headers.insert(header::HOST, host);
+            }
+        }
+
+        let res = match app
+            .oneshot(Request::from_parts(parts, Body::from_stream(body)))
+            .await
+        {
+            Ok(res) => res,
+            Err(err) => match err {},
+        };
+        let (mut parts, mut body) = res.into_parts();
+        for name in CONNECTION_HEADERS {
+            parts.headers.remove(name);
+        }
+        send.send_response(Response::from_parts(parts, ()))
+            .await
+            .map_err(|err| err.to_string())?;
+        while let Some(frame) = body.frame().await {
+            match frame.map_err(|err| err.to_string())?.into_data() {
+                Ok(data) => send.send_data(data).await.map_err(|err| err.to_string())?,
+                Err(frame) => {
+                    if let Ok(trailers) = frame.into_trailers() {
+                        send.send_trailers(trailers)
+                            .await
+                            .map_err(|err| err.to_string())?;
+                    }
+                }
+            }
+        }
+        send.finish().await.map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(feature = "http3")]
+pub use enabled::*;
+
+#[cfg(not(feature = "http3"))]
+mod disabled {
+    use axum::Router;
+    use std::net::SocketAddr;
+    use tokio::sync::watch;
+
+    pub async fn serve(
+        _app: Router,
+        _addr: SocketAddr,
+        _cert_file: &str,
+        _key_file: &str,
+        _stopped: watch::Receiver<bool>,
+    ) -> Result<(), String> {
+        Err("built without the http3 feature".to_string())
+    }
+}
+
+#[cfg(not(feature = "http3"))]
+pub use disabled::*;
+
+pub const ENABLED: bool = cfg!(feature = "http3");
+
+// The Alt-Svc value that advertises the HTTP/3 listener on the port.
+pub fn alt_svc(port: u16) -> String {
+    format!("h3=\":{}\"; ma=86400", port)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_alt_svc() {
+        assert_eq!(alt_svc(8443), r#"h3=":8443"; ma=86400"#);
+    }
+}
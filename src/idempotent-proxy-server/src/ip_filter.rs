@@ -0,0 +1,303 @@
+use http::HeaderMap;
+use idempotent_proxy_types::HEADER_X_FORWARDED_FOR;
+use maxminddb::{geoip2, Reader};
+use std::{collections::BTreeSet, net::IpAddr, str::FromStr};
+
+// An IP network in CIDR notation, a single address is a /32 or /128 network.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid IP network: {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid IP network: {}", s))?,
+            None => max,
+        };
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn parse_nets(value: &str) -> Result<Vec<IpNet>, String> {
+    value
+        .split(',')
+        .filter(|s| !s.trim().is_empty())
+        .map(|s| s.parse())
+        .collect()
+}
+
+fn parse_set(value: &str) -> BTreeSet<String> {
+    value
+        .split(',')
+        .map(|s| s.trim().to_ascii_uppercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+// Access restrictions on the client address: IP allow and deny lists, and countries and
+// autonomous systems from MaxMind GeoIP2/GeoLite2 databases. Deny rules win, a non empty
+// allow rule rejects clients it does not match, including clients the database does not know.
+#[derive(Default)]
+pub struct IpFilter {
+    allow_ips: Vec<IpNet>,
+    deny_ips: Vec<IpNet>,
+    trusted_proxies: Vec<IpNet>,
+    country_db: Option<Reader<Vec<u8>>>,
+    asn_db: Option<Reader<Vec<u8>>>,
+    allow_countries: BTreeSet<String>, // ISO 3166-1 alpha-2 codes
+    deny_countries: BTreeSet<String>,
+    allow_asns: BTreeSet<String>,
+    deny_asns: BTreeSet<String>,
+}
+
+impl IpFilter {
+    pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Self, String> {
+        let mut f = IpFilter::default();
+        for (k, v) in vars {
+            match k.as_str() {
+                "ALLOW_IPS" => f.allow_ips = parse_nets(&v)?,
+                "DENY_IPS" => f.deny_ips = parse_nets(&v)?,
+                "TRUSTED_PROXIES" => f.trusted_proxies = parse_nets(&v)?,
+                "GEOIP_COUNTRY_DB" => {
+                    f.country_db = Some(
+                        Reader::open_readfile(v.trim())
+                            .map_err(|err| format!("open GEOIP_COUNTRY_DB failed: {}", err))?,
+                    )
+                }
+                "GEOIP_ASN_DB" => {
+                    f.asn_db = Some(
+                        Reader::open_readfile(v.trim())
+                            .map_err(|err| format!("open GEOIP_ASN_DB failed: {}", err))?,
+                    )
+                }
+                "ALLOW_COUNTRIES" => f.allow_countries = parse_set(&v),
+                "DENY_COUNTRIES" => f.deny_countries = parse_set(&v),
+                "ALLOW_ASNS" => f.allow_asns = parse_set(&v),
+                "DENY_ASNS" => f.deny_asns = parse_set(&v),
+                _ => {}
+            }
+        }
+        if f.country_db.is_none() && !(f.allow_countries.is_empty() && f.deny_countries.is_empty())
+        {
+            return Err(
+                "GEOIP_COUNTRY_DB is required by ALLOW_COUNTRIES and DENY_COUNTRIES".to_string(),
+            );
+        }
+        if f.asn_db.is_none() && !(f.allow_asns.is_empty() && f.deny_asns.is_empty()) {
+            return Err("GEOIP_ASN_DB is required by ALLOW_ASNS and DENY_ASNS".to_string());
+        }
+        Ok(f)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow_ips.is_empty()
+            && self.deny_ips.is_empty()
+            && self.allow_countries.is_empty()
+            && self.deny_countries.is_empty()
+            && self.allow_asns.is_empty()
+            && self.deny_asns.is_empty()
+    }
+
+    // The client address: the peer, or the last x-forwarded-for address that is not one
+    // of our trusted proxies when the peer is one.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted(&peer) {
+            return peer;
+        }
+        let mut client = peer;
+        for v in headers.get_all(&HEADER_X_FORWARDED_FOR) {
+            let Ok(v) = v.to_str() else { return client };
+            for ip in v.split(',').rev() {
+                match ip.trim().parse::<IpAddr>() {
+                    Ok(ip) if self.is_trusted(&ip) => client = ip,
+                    Ok(ip) => return ip,
+                    Err(_) => return client,
+                }
+            }
+        }
+        client
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|n| n.contains(ip))
+    }
+
+    pub fn check(&self, ip: IpAddr) -> Result<(), String> {
+        if self.deny_ips.iter().any(|n| n.contains(&ip)) {
+            return Err(format!("client {} is denied", ip));
+        }
+        if !self.allow_ips.is_empty() && !self.allow_ips.iter().any(|n| n.contains(&ip)) {
+            return Err(format!("client {} is not allowed", ip));
+        }
+
+        let country = self.country_db.as_ref().and_then(|db| {
+            db.lookup::<geoip2::Country>(ip)
+                .ok()
+                .and_then(|c| c.country)
+                .and_then(|c| c.iso_code)
+                .map(|c| c.to_string())
+        });
+        let asn = self.asn_db.as_ref().and_then(|db| {
+            db.lookup::<geoip2::Asn>(ip)
+                .ok()
+                .and_then(|a| a.autonomous_system_number)
+        });
+        self.check_geo(ip, country.as_deref(), asn)
+    }
+
+    fn check_geo(&self, ip: IpAddr, country: Option<&str>, asn: Option<u32>) -> Result<(), String> {
+        let country = country.map(|c| c.to_ascii_uppercase());
+        if let Some(c) = &country {
+            if self.deny_countries.contains(c) {
+                return Err(format!("client {} from {} is denied", ip, c));
+            }
+        }
+        if !self.allow_countries.is_empty()
+            && !country
+                .as_ref()
+                .is_some_and(|c| self.allow_countries.contains(c))
+        {
+            return Err(format!(
+                "client {} from {} is not allowed",
+                ip,
+                country.as_deref().unwrap_or("an unknown country")
+            ));
+        }
+
+        let asn = asn.map(|n| n.to_string());
+        if let Some(n) = &asn {
+            if self.deny_asns.contains(n) {
+                return Err(format!("client {} from AS{} is denied", ip, n));
+            }
+        }
+        if !self.allow_asns.is_empty() && !asn.as_ref().is_some_and(|n| self.allow_asns.contains(n))
+        {
+            return Err(format!(
+                "client {} from AS{} is not allowed",
+                ip,
+                asn.as_deref().unwrap_or("?")
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::HeaderValue;
+
+    fn vars(list: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        list.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_ip_net() {
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(!net.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(!net.contains(&"::1".parse().unwrap()));
+        let net: IpNet = "192.168.1.7".parse().unwrap();
+        assert!(net.contains(&"192.168.1.7".parse().unwrap()));
+        assert!(!net.contains(&"192.168.1.8".parse().unwrap()));
+        let net: IpNet = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(&"2001:db8::1".parse().unwrap()));
+        let net: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(net.contains(&"8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("example.com".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_ip_filter() {
+        let f = IpFilter::from_vars(vars(&[
+            ("ALLOW_IPS", "10.0.0.0/8, 192.168.0.0/16"),
+            ("DENY_IPS", "10.0.0.13"),
+            ("TRUSTED_PROXIES", "192.168.0.0/16"),
+        ]))
+        .unwrap();
+        assert!(!f.is_empty());
+        assert!(f.check("10.0.0.1".parse().unwrap()).is_ok());
+        assert!(f.check("10.0.0.13".parse().unwrap()).is_err());
+        assert!(f.check("8.8.8.8".parse().unwrap()).is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            &HEADER_X_FORWARDED_FOR,
+            HeaderValue::from_static("8.8.8.8, 10.0.0.1, 192.168.0.2"),
+        );
+        // spoofed entries left of the first untrusted address are ignored
+        assert_eq!(
+            f.client_ip("192.168.0.1".parse().unwrap(), &headers),
+            "10.0.0.1".parse::<IpAddr>().unwrap()
+        );
+        // x-forwarded-for from an untrusted peer is ignored
+        assert_eq!(
+            f.client_ip("10.0.0.2".parse().unwrap(), &headers),
+            "10.0.0.2".parse::<IpAddr>().unwrap()
+        );
+
+        assert!(IpFilter::from_vars(vars(&[("DENY_COUNTRIES", "KP")])).is_err());
+        assert!(IpFilter::from_vars(vars(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_check_geo() {
+        let f = IpFilter {
+            allow_countries: parse_set("us, de"),
+            deny_asns: parse_set("64496"),
+            ..Default::default()
+        };
+        let ip: IpAddr = "203.0.113.1".parse().unwrap();
+        assert!(f.check_geo(ip, Some("US"), Some(64500)).is_ok());
+        assert!(f.check_geo(ip, Some("de"), None).is_ok());
+        assert!(f.check_geo(ip, Some("KP"), None).is_err());
+        assert!(f.check_geo(ip, None, None).is_err());
+        assert!(f.check_geo(ip, Some("US"), Some(64496)).is_err());
+
+        let f = IpFilter {
+            deny_countries: parse_set("KP,IR"),
+            allow_asns: parse_set("64500"),
+            ..Default::default()
+        };
+        assert!(f.check_geo(ip, Some("ir"), Some(64500)).is_err());
+        assert!(f.check_geo(ip, None, Some(64500)).is_ok());
+        assert!(f.check_geo(ip, None, Some(64501)).is_err());
+    }
+}
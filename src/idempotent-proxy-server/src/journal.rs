@@ -0,0 +1,147 @@
+use serde::Serialize;
+use std::{collections::VecDeque, sync::Mutex};
+
+use crate::agents::AgentPattern;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Proxied,
+    Replayed,
+    Rejected,
+    Failed,
+    Unavailable, // the storage was unavailable
+}
+
+// An audit record of a proxied request, URLs are redacted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct JournalRecord {
+    pub at: u64, // unix timestamp in milliseconds
+    pub agent: String,
+    pub method: String,
+    pub url: String,
+    pub idempotency_key: String,
+    pub status: u16,
+    pub outcome: Outcome,
+    pub elapsed: u64, // in milliseconds
+}
+
+#[derive(Debug, Default)]
+pub struct JournalFilter {
+    pub from: Option<u64>, // inclusive
+    pub to: Option<u64>,   // exclusive
+    pub agent: Option<AgentPattern>,
+}
+
+impl JournalFilter {
+    fn matches(&self, r: &JournalRecord) -> bool {
+        self.from.is_none_or(|from| r.at >= from)
+            && self.to.is_none_or(|to| r.at < to)
+            && self.agent.as_ref().is_none_or(|p| p.matches(&r.agent))
+    }
+}
+
+// The recent requests of this instance in a bounded ring, the oldest records are dropped first.
+#[derive(Debug, Default)]
+pub struct Journal {
+    capacity: usize, // 0 to disable
+    records: Mutex<VecDeque<JournalRecord>>,
+}
+
+impl Journal {
+    pub fn new(capacity: usize) -> Self {
+        Journal {
+            capacity,
+            records: Mutex::new(VecDeque::with_capacity(capacity.min(1024))),
+        }
+    }
+
+    pub fn record(&self, record: JournalRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.records.lock().unwrap();
+        if records.len() >= self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    // The records and their approximate size in bytes.
+    pub fn usage(&self) -> (usize, usize) {
+        let records = self.records.lock().unwrap();
+        let bytes = records
+            .iter()
+            .map(|r| {
+                std::mem::size_of::<JournalRecord>()
+                    + r.agent.len()
+                    + r.method.len()
+                    + r.url.len()
+                    + r.idempotency_key.len()
+            })
+            .sum();
+        (records.len(), bytes)
+    }
+
+    // Returns the matching records in time order.
+    pub fn query(&self, filter: &JournalFilter) -> Vec<JournalRecord> {
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| filter.matches(r))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(at: u64, agent: &str) -> JournalRecord {
+        JournalRecord {
+            at,
+            agent: agent.to_string(),
+            method: "POST".to_string(),
+            url: "https://httpbin.org/post".to_string(),
+            idempotency_key: format!("{}:POST:key_{}", agent, at),
+            status: 200,
+            outcome: Outcome::Proxied,
+            elapsed: 10,
+        }
+    }
+
+    #[test]
+    fn test_journal() {
+        let journal = Journal::new(3);
+        for (at, agent) in [(1, "alice"), (2, "bob"), (3, "worker-1"), (4, "worker-2")] {
+            journal.record(record(at, agent));
+        }
+        let all = journal.query(&JournalFilter::default());
+        assert_eq!(all.iter().map(|r| r.at).collect::<Vec<_>>(), vec![2, 3, 4]);
+        assert_eq!(journal.usage().0, 3);
+
+        let res = journal.query(&JournalFilter {
+            from: Some(3),
+            to: None,
+            agent: Some("worker-*".parse().unwrap()),
+        });
+        assert_eq!(res.len(), 2);
+        let res = journal.query(&JournalFilter {
+            from: Some(2),
+            to: Some(4),
+            agent: Some("worker-*".parse().unwrap()),
+        });
+        assert_eq!(res, vec![record(3, "worker-1")]);
+
+        assert_eq!(
+            serde_json::to_string(&record(1, "alice")).unwrap(),
+            r#"{"at":1,"agent":"alice","method":"POST","url":"https://httpbin.org/post","idempotency_key":"alice:POST:key_1","status":200,"outcome":"proxied","elapsed":10}"#
+        );
+
+        let disabled = Journal::new(0);
+        disabled.record(record(1, "alice"));
+        assert!(disabled.query(&JournalFilter::default()).is_empty());
+    }
+}
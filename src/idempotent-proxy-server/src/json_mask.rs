@@ -0,0 +1,226 @@
+use serde_json::{Map, Value};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+    All, // every element of an array or every field of an object
+}
+
+// The fields of a JSON response to keep, as a comma separated list of paths, e.g.
+// "id, result.number, result.transactions[*].hash, $['a.b']". A path of top level names
+// keeps those fields as before. Objects of the result have their keys sorted, so the
+// masked body is the same bytes whatever the order of the upstream fields.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct JsonMask(Vec<Vec<Segment>>);
+
+impl JsonMask {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut paths = Vec::new();
+        for path in split_paths(s) {
+            let path = path.trim();
+            if !path.is_empty() {
+                paths.push(parse_path(path).ok_or_else(|| format!("invalid JSON path: {}", path))?);
+            }
+        }
+        Ok(JsonMask(paths))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn apply(&self, value: &Value) -> Value {
+        let paths: Vec<&[Segment]> = self.0.iter().map(|p| p.as_slice()).collect();
+        select(value, &paths).unwrap_or_else(|| Value::Object(Map::new()))
+    }
+}
+
+// Splits on the commas outside of brackets, a quoted key may contain commas.
+fn split_paths(s: &str) -> Vec<&str> {
+    let mut paths = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in s.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ',' if depth == 0 => {
+                paths.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    paths.push(&s[start..]);
+    paths
+}
+
+fn parse_path(path: &str) -> Option<Vec<Segment>> {
+    let mut rest = path.strip_prefix('$').unwrap_or(path);
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix('[') {
+            let end = r.find(']')?;
+            let inner = r[..end].trim();
+            segments.push(match inner {
+                "*" => Segment::All,
+                _ => match inner
+                    .strip_prefix('\'')
+                    .and_then(|v| v.strip_suffix('\''))
+                    .or_else(|| inner.strip_prefix('"').and_then(|v| v.strip_suffix('"')))
+                {
+                    Some(key) => Segment::Key(key.to_string()),
+                    None => Segment::Index(inner.parse().ok()?),
+                },
+            });
+            rest = &r[end + 1..];
+        } else {
+            let r = rest.strip_prefix('.').unwrap_or(rest);
+            if r.starts_with('[') {
+                rest = r;
+                continue;
+            }
+            let end = r.find(['.', '[']).unwrap_or(r.len());
+            let key = r[..end].trim();
+            if key.is_empty() {
+                return None;
+            }
+            segments.push(match key {
+                "*" => Segment::All,
+                _ => Segment::Key(key.to_string()),
+            });
+            rest = &r[end..];
+        }
+    }
+    (!segments.is_empty()).then_some(segments)
+}
+
+// The parts of the value the paths select, None if they select nothing.
+fn select(value: &Value, paths: &[&[Segment]]) -> Option<Value> {
+    if paths.iter().any(|p| p.is_empty()) {
+        return Some(sorted(value));
+    }
+    match value {
+        Value::Object(obj) => {
+            let mut keys: Vec<&String> = obj.keys().collect();
+            keys.sort();
+            let mut out = Map::new();
+            for key in keys {
+                let rest: Vec<&[Segment]> = paths
+                    .iter()
+                    .filter(|p| match &p[0] {
+                        Segment::Key(k) => k == key,
+                        Segment::All => true,
+                        Segment::Index(_) => false,
+                    })
+                    .map(|p| &p[1..])
+                    .collect();
+                if !rest.is_empty() {
+                    if let Some(v) = select(&obj[key], &rest) {
+                        out.insert(key.clone(), v);
+                    }
+                }
+            }
+            (!out.is_empty()).then_some(Value::Object(out))
+        }
+        Value::Array(arr) => {
+            let mut out = Vec::new();
+            for (i, item) in arr.iter().enumerate() {
+                let rest: Vec<&[Segment]> = paths
+                    .iter()
+                    .filter(|p| match &p[0] {
+                        Segment::Index(n) => *n == i,
+                        Segment::All => true,
+                        Segment::Key(_) => false,
+                    })
+                    .map(|p| &p[1..])
+                    .collect();
+                if !rest.is_empty() {
+                    if let Some(v) = select(item, &rest) {
+                        out.push(v);
+                    }
+                }
+            }
+            (!out.is_empty()).then_some(Value::Array(out))
+        }
+        _ => None,
+    }
+}
+
+// Inserts the keys in order, serde_json may be built with `preserve_order`.
+fn sorted(value: &Value) -> Value {
+    match value {
+        Value::Object(obj) => {
+            let mut keys: Vec<&String> = obj.keys().collect();
+            keys.sort();
+            Value::Object(
+                keys.into_iter()
+                    .map(|k| (k.clone(), sorted(&obj[k])))
+                    .collect(),
+            )
+        }
+        Value::Array(arr) => Value::Array(arr.iter().map(sorted).collect()),
+        v => v.clone(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            JsonMask::parse(" id, $.result.txs[*].hash, a[2]['x.y'], b.*").unwrap(),
+            JsonMask(vec![
+                vec![Segment::Key("id".to_string())],
+                vec![
+                    Segment::Key("result".to_string()),
+                    Segment::Key("txs".to_string()),
+                    Segment::All,
+                    Segment::Key("hash".to_string()),
+                ],
+                vec![
+                    Segment::Key("a".to_string()),
+                    Segment::Index(2),
+                    Segment::Key("x.y".to_string()),
+                ],
+                vec![Segment::Key("b".to_string()), Segment::All],
+            ])
+        );
+        assert!(JsonMask::parse("").unwrap().is_empty());
+        for s in ["a..b", "a[x]", "a[1", "$", "a."] {
+            assert!(JsonMask::parse(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let value = json!({
+            "url": "https://httpbin.org/get",
+            "args": {"z": 1, "a": 2},
+            "result": {
+                "number": "0x10",
+                "hash": "0xabc",
+                "txs": [{"hash": "0x1", "gas": 1}, {"hash": "0x2", "gas": 2}, {"gas": 3}],
+            },
+        });
+
+        let mask = JsonMask::parse("args,url").unwrap();
+        assert_eq!(
+            serde_json::to_string(&mask.apply(&value)).unwrap(),
+            r#"{"args":{"a":2,"z":1},"url":"https://httpbin.org/get"}"#
+        );
+
+        let mask = JsonMask::parse("result.number, result.txs[*].hash, result.txs[1].gas").unwrap();
+        assert_eq!(
+            mask.apply(&value),
+            json!({"result": {"number": "0x10", "txs": [{"hash": "0x1"}, {"gas": 2, "hash": "0x2"}]}})
+        );
+
+        let mask = JsonMask::parse("missing, url.x").unwrap();
+        assert_eq!(mask.apply(&value), json!({}));
+        assert_eq!(mask.apply(&json!([1, 2])), json!({}));
+    }
+}
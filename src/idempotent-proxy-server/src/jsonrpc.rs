@@ -0,0 +1,193 @@
+use base64::{engine::general_purpose, Engine};
+use idempotent_proxy_types::{auth::sha3_256, err_string};
+use serde_json::{json, Value};
+
+// A JSON-RPC request (single or batch) normalized for idempotency:
+// identical calls (same method and params) are deduplicated and renumbered,
+// so the upstream body and the cached response do not depend on the caller's ids.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonRpcBody {
+    pub batch: bool,
+    // original ids of the entries, `None` for notifications
+    ids: Vec<Option<Value>>,
+    // index into `calls` for every original entry
+    mapping: Vec<usize>,
+    // unique (method, params) pairs
+    calls: Vec<(Value, Value)>,
+}
+
+impl JsonRpcBody {
+    pub fn parse(body: &[u8]) -> Result<Self, String> {
+        let value: Value = serde_json::from_slice(body).map_err(err_string)?;
+        let (batch, entries) = match value {
+            Value::Array(entries) => {
+                if entries.is_empty() {
+                    return Err("empty JSON-RPC batch".to_string());
+                }
+                (true, entries)
+            }
+            v @ Value::Object(_) => (false, vec![v]),
+            _ => return Err("invalid JSON-RPC request".to_string()),
+        };
+
+        let mut rb = JsonRpcBody {
+            batch,
+            ids: Vec::with_capacity(entries.len()),
+            mapping: Vec::with_capacity(entries.len()),
+            calls: Vec::with_capacity(entries.len()),
+        };
+        for entry in entries {
+            let mut entry = match entry {
+                Value::Object(entry) => entry,
+                _ => return Err("invalid JSON-RPC request".to_string()),
+            };
+            let method = match entry.remove("method") {
+                Some(v @ Value::String(_)) => v,
+                _ => return Err("invalid JSON-RPC method".to_string()),
+            };
+            let params = entry.remove("params").unwrap_or(Value::Null);
+            let call = (method, params);
+            let idx = match rb.calls.iter().position(|c| c == &call) {
+                Some(idx) => idx,
+                None => {
+                    rb.calls.push(call);
+                    rb.calls.len() - 1
+                }
+            };
+            rb.ids.push(entry.remove("id"));
+            rb.mapping.push(idx);
+        }
+        Ok(rb)
+    }
+
+    // Fingerprint of the unique calls, independent of the caller's ids and of duplicates.
+    pub fn fingerprint(&self) -> String {
+        let data = serde_json::to_vec(&self.calls).expect("failed to encode JSON-RPC calls");
+        general_purpose::URL_SAFE_NO_PAD.encode(sha3_256(&data))
+    }
+
+    // The body sent upstream, unique calls with ids from 0.
+    pub fn upstream_body(&self) -> Vec<u8> {
+        let calls: Vec<Value> = self
+            .calls
+            .iter()
+            .enumerate()
+            .map(|(i, (method, params))| {
+                let mut call = json!({"jsonrpc": "2.0", "id": i, "method": method});
+                if !params.is_null() {
+                    call["params"] = params.clone();
+                }
+                call
+            })
+            .collect();
+        let body = if self.batch || calls.len() > 1 {
+            Value::Array(calls)
+        } else {
+            calls.into_iter().next().unwrap()
+        };
+        serde_json::to_vec(&body).expect("failed to encode JSON-RPC body")
+    }
+
+    // Maps the upstream response back to the caller's request: restores original ids,
+    // fans out deduplicated results and drops responses for notifications.
+    pub fn restore_response(&self, body: &[u8]) -> Result<Vec<u8>, String> {
+        let value: Value = serde_json::from_slice(body).map_err(err_string)?;
+        let mut results: Vec<Option<Value>> = vec![None; self.calls.len()];
+        let entries = match value {
+            Value::Array(entries) => entries,
+            v => vec![v],
+        };
+        for entry in entries {
+            match entry.get("id").and_then(|v| v.as_u64()) {
+                Some(i) if (i as usize) < results.len() => results[i as usize] = Some(entry),
+                // errors without id (e.g. parse error) apply to all calls
+                _ => results.iter_mut().for_each(|r| {
+                    if r.is_none() {
+                        *r = Some(entry.clone());
+                    }
+                }),
+            }
+        }
+
+        let mut output: Vec<Value> = Vec::with_capacity(self.ids.len());
+        for (id, idx) in self.ids.iter().zip(self.mapping.iter()) {
+            if let Some(id) = id {
+                let mut res = results[*idx]
+                    .clone()
+                    .ok_or_else(|| format!("missing JSON-RPC response for entry {}", idx))?;
+                if let Value::Object(ref mut obj) = res {
+                    obj.insert("id".to_string(), id.clone());
+                }
+                output.push(res);
+            }
+        }
+
+        let body = if self.batch {
+            Value::Array(output)
+        } else {
+            output.into_iter().next().unwrap_or(Value::Null)
+        };
+        serde_json::to_vec(&body).map_err(err_string)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_jsonrpc_single() {
+        let a = JsonRpcBody::parse(
+            br#"{"jsonrpc":"2.0","id":1,"method":"eth_sendRawTransaction","params":["0xf86c"]}"#,
+        )
+        .unwrap();
+        let b = JsonRpcBody::parse(
+            br#"{"id":"abc","jsonrpc":"2.0","params":["0xf86c"],"method":"eth_sendRawTransaction"}"#,
+        )
+        .unwrap();
+        assert_eq!(a.fingerprint(), b.fingerprint());
+        assert_eq!(a.upstream_body(), b.upstream_body());
+        assert_eq!(
+            a.upstream_body(),
+            br#"{"id":0,"jsonrpc":"2.0","method":"eth_sendRawTransaction","params":["0xf86c"]}"#
+        );
+
+        let res = br#"{"jsonrpc":"2.0","id":0,"result":"0xabcd"}"#;
+        assert_eq!(
+            a.restore_response(res).unwrap(),
+            br#"{"id":1,"jsonrpc":"2.0","result":"0xabcd"}"#
+        );
+        assert_eq!(
+            b.restore_response(res).unwrap(),
+            br#"{"id":"abc","jsonrpc":"2.0","result":"0xabcd"}"#
+        );
+
+        let c = JsonRpcBody::parse(
+            br#"{"jsonrpc":"2.0","id":1,"method":"eth_sendRawTransaction","params":["0xf86d"]}"#,
+        )
+        .unwrap();
+        assert_ne!(a.fingerprint(), c.fingerprint());
+    }
+
+    #[test]
+    fn test_jsonrpc_batch() {
+        let a = JsonRpcBody::parse(
+            br#"[{"jsonrpc":"2.0","id":1,"method":"eth_blockNumber"},{"jsonrpc":"2.0","id":2,"method":"eth_chainId"},{"jsonrpc":"2.0","id":3,"method":"eth_blockNumber"}]"#,
+        )
+        .unwrap();
+        assert_eq!(a.calls.len(), 2);
+        assert_eq!(
+            a.upstream_body(),
+            br#"[{"id":0,"jsonrpc":"2.0","method":"eth_blockNumber"},{"id":1,"jsonrpc":"2.0","method":"eth_chainId"}]"#
+        );
+
+        let res = br#"[{"jsonrpc":"2.0","id":1,"result":"0x1"},{"jsonrpc":"2.0","id":0,"result":"0x10"}]"#;
+        assert_eq!(
+            a.restore_response(res).unwrap(),
+            br#"[{"id":1,"jsonrpc":"2.0","result":"0x10"},{"id":2,"jsonrpc":"2.0","result":"0x1"},{"id":3,"jsonrpc":"2.0","result":"0x10"}]"#
+        );
+
+        assert!(JsonRpcBody::parse(b"[]").is_err());
+        assert!(JsonRpcBody::parse(br#"{"id":1}"#).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use idempotent_proxy_types::{
+    auth::{jwks_parse, jwt_verify, Jwk, JwtValidation},
+    unix_ms,
+};
+use reqwest::Client;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+};
+use tokio::{sync::Notify, time::Duration};
+
+use crate::routes::parse_bool;
+
+// An unknown kid triggers a refresh of the keys, at most once a minute.
+const MIN_REFRESH_INTERVAL: u64 = 60 * 1000;
+
+// JWTs of an OIDC provider as proxy tokens, next to the CBOR tokens: JWT_JWKS_URL,
+// optionally JWT_ISSUER, JWT_AUDIENCE and JWT_JWKS_REFRESH (seconds, 3600 by default).
+// The `sub` claim is the agent, JWT_ONLY=true rejects the CBOR tokens.
+pub struct JwtVerifier {
+    pub jwks_url: String,
+    pub validation: JwtValidation,
+    pub refresh: Duration,
+    pub only: bool,
+    keys: RwLock<Arc<Vec<Jwk>>>,
+    refreshed_at: AtomicU64,
+    stale: Notify,
+}
+
+impl JwtVerifier {
+    // None if no JWKS url is configured.
+    pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Option<Self>, String> {
+        let mut verifier = JwtVerifier {
+            jwks_url: String::new(),
+            validation: JwtValidation::default(),
+            refresh: Duration::from_secs(3600),
+            only: false,
+            keys: RwLock::new(Arc::new(Vec::new())),
+            refreshed_at: AtomicU64::new(0),
+            stale: Notify::new(),
+        };
+        for (k, v) in vars {
+            let v = v.trim();
+            match k.as_str() {
+                "JWT_JWKS_URL" if !v.is_empty() => {
+                    reqwest::Url::parse(v)
+                        .map_err(|err| format!("invalid JWT_JWKS_URL {}: {}", v, err))?;
+                    verifier.jwks_url = v.to_string();
+                }
+                "JWT_ISSUER" if !v.is_empty() => verifier.validation.issuer = Some(v.to_string()),
+                "JWT_AUDIENCE" if !v.is_empty() => {
+                    verifier.validation.audience = Some(v.to_string())
+                }
+                "JWT_JWKS_REFRESH" => {
+                    let secs: u64 = v
+                        .parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| format!("invalid JWT_JWKS_REFRESH value: {}", v))?;
+                    verifier.refresh = Duration::from_secs(secs);
+                }
+                "JWT_ONLY" => verifier.only = parse_bool(v)?,
+                _ => {}
+            }
+        }
+        Ok((!verifier.jwks_url.is_empty()).then_some(verifier))
+    }
+
+    // JWTs are three base64url parts joined by dots, the CBOR tokens have no dot.
+    pub fn is_jwt(token: &str) -> bool {
+        token.contains('.')
+    }
+
+    pub fn verify(&self, jwt: &str) -> Result<String, String> {
+        let keys = self.keys.read().unwrap().clone();
+        jwt_verify(&keys, &self.validation, jwt)
+            .map(|token| token.1)
+            .map_err(|err| {
+                // the provider may have rotated its keys
+                if err.starts_with("failed to verify") {
+                    self.stale.notify_one();
+                }
+                format!("proxy authentication verify failed: {}", err)
+            })
+    }
+
+    pub async fn refresh_keys(&self, http_client: &Client) -> Result<usize, String> {
+        self.refreshed_at.store(unix_ms(), Ordering::Relaxed);
+        let res = http_client
+            .get(&self.jwks_url)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|err| format!("fetch JWKS failed: {}", err))?;
+        let data = res
+            .bytes()
+            .await
+            .map_err(|err| format!("fetch JWKS failed: {}", err))?;
+        let keys = jwks_parse(&data)?;
+        let n = keys.len();
+        *self.keys.write().unwrap() = Arc::new(keys);
+        Ok(n)
+    }
+
+    // Refreshes the keys periodically, or early when a token fails to verify.
+    pub async fn run(self: Arc<Self>, http_client: Arc<Client>) {
+        loop {
+            let _ = tokio::time::timeout(self.refresh, self.stale.notified()).await;
+            let elapsed = unix_ms().saturating_sub(self.refreshed_at.load(Ordering::Relaxed));
+            if elapsed < MIN_REFRESH_INTERVAL {
+                tokio::time::sleep(Duration::from_millis(MIN_REFRESH_INTERVAL - elapsed)).await;
+            }
+            match self.refresh_keys(&http_client).await {
+                Ok(n) => log::info!(target: "jwt", "refreshed {} keys from JWKS", n),
+                Err(err) => log::error!(target: "jwt", "{}", err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_vars;
+
+    #[test]
+    fn test_jwt_verifier() {
+        assert!(JwtVerifier::from_vars(test_vars(vec![(
+            "JWT_ISSUER",
+            "https://auth.example.com"
+        )]))
+        .unwrap()
+        .is_none());
+
+        let verifier = JwtVerifier::from_vars(test_vars(vec![
+            (
+                "JWT_JWKS_URL",
+                "https://auth.example.com/.well-known/jwks.json",
+            ),
+            ("JWT_ISSUER", "https://auth.example.com"),
+            ("JWT_AUDIENCE", "idempotent-proxy"),
+            ("JWT_JWKS_REFRESH", "600"),
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            verifier.validation,
+            JwtValidation {
+                issuer: Some("https://auth.example.com".to_string()),
+                audience: Some("idempotent-proxy".to_string()),
+            }
+        );
+        assert_eq!(verifier.refresh, Duration::from_secs(600));
+        assert!(!verifier.only);
+        // no keys fetched yet
+        assert!(verifier.verify("eyJhbGciOiJFUzI1NiJ9.e30.c2ln").is_err());
+
+        assert!(JwtVerifier::is_jwt("eyJhbGciOiJFUzI1NiJ9.e30.c2ln"));
+        assert!(!JwtVerifier::is_jwt("gxpmZDmJaklDUGFuZGFEQU9Y"));
+        assert!(JwtVerifier::from_vars(test_vars(vec![("JWT_JWKS_URL", "jwks.json")])).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use base64::{engine::general_purpose, Engine};
+use http::{HeaderMap, HeaderName, Method};
+use idempotent_proxy_types::{auth::sha3_256, HEADER_IDEMPOTENCY_KEY};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyScheme {
+    #[default]
+    Legacy, // <agent>:<method>:<key>
+    Composite, // <agent>:<method>:<url hash>:<key>
+    // composite keys, the responses cached under legacy keys are still replayed
+    Migrate,
+}
+
+impl FromStr for KeyScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "legacy" => Ok(KeyScheme::Legacy),
+            "composite" => Ok(KeyScheme::Composite),
+            "migrate" => Ok(KeyScheme::Migrate),
+            v => Err(format!("invalid idempotency key scheme: {}", v)),
+        }
+    }
+}
+
+// Where the idempotency key of a request comes from and how its storage key is composed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Keying {
+    pub headers: Vec<HeaderName>, // the first one present is used
+    pub scheme: KeyScheme,
+}
+
+impl Default for Keying {
+    fn default() -> Self {
+        Keying {
+            headers: vec![HEADER_IDEMPOTENCY_KEY.clone()],
+            scheme: KeyScheme::Legacy,
+        }
+    }
+}
+
+impl Keying {
+    pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Self, String> {
+        let mut keying = Keying::default();
+        for (k, v) in vars {
+            match k.as_str() {
+                "IDEMPOTENCY_KEY_HEADERS" => {
+                    keying.headers = v
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|v| !v.is_empty())
+                        .map(|v| {
+                            HeaderName::from_str(v)
+                                .map_err(|_| format!("invalid IDEMPOTENCY_KEY_HEADERS item: {}", v))
+                        })
+                        .collect::<Result<_, _>>()?;
+                    if keying.headers.is_empty() {
+                        return Err("IDEMPOTENCY_KEY_HEADERS is empty".to_string());
+                    }
+                }
+                "IDEMPOTENCY_KEY_SCHEME" => keying.scheme = v.parse()?,
+                _ => {}
+            }
+        }
+        Ok(keying)
+    }
+
+    // The idempotency key of the request, empty if none of the headers is present.
+    pub fn extract(&self, headers: &HeaderMap) -> String {
+        self.headers
+            .iter()
+            .filter_map(|name| headers.get(name))
+            .filter_map(|v| v.to_str().ok())
+            .map(str::trim)
+            .find(|v| !v.is_empty())
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    // The name of the first configured header, for error messages.
+    pub fn header(&self) -> &str {
+        self.headers[0].as_str()
+    }
+
+    // The storage key of a request and, while migrating, its legacy key.
+    // `prefix` is the tenant's key prefix, `key` the idempotency key with the
+    // fingerprints of the idempotency mode.
+    pub fn storage_key(
+        &self,
+        prefix: &str,
+        agent: &str,
+        method: &Method,
+        url: &reqwest::Url,
+        key: &str,
+    ) -> (String, Option<String>) {
+        let legacy = format!("{}{}:{}:{}", prefix, agent, method, key);
+        if self.scheme == KeyScheme::Legacy {
+            return (legacy, None);
+        }
+        // the URL may carry credentials and be long, only its hash is part of the key
+        let hash =
+            general_purpose::URL_SAFE_NO_PAD.encode(&sha3_256(url.as_str().as_bytes())[..16]);
+        let composite = format!("{}{}:{}:{}:{}", prefix, agent, method, hash, key);
+        match self.scheme {
+            KeyScheme::Migrate => (composite, Some(legacy)),
+            _ => (composite, None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_vars;
+
+    #[test]
+    fn test_keying() {
+        let url = reqwest::Url::parse("https://httpbin.org/post?a=1").unwrap();
+        let keying = Keying::from_vars(test_vars(vec![])).unwrap();
+        assert_eq!(keying, Keying::default());
+        assert_eq!(
+            keying.storage_key("acme:", "alice", &Method::POST, &url, "k1"),
+            ("acme:alice:POST:k1".to_string(), None)
+        );
+
+        let keying = Keying::from_vars(test_vars(vec![
+            ("IDEMPOTENCY_KEY_HEADERS", "X-Request-Id, Idempotency-Key"),
+            ("IDEMPOTENCY_KEY_SCHEME", "migrate"),
+        ]))
+        .unwrap();
+        assert_eq!(keying.header(), "x-request-id");
+        let mut headers = HeaderMap::new();
+        assert_eq!(keying.extract(&headers), "");
+        headers.insert("idempotency-key", "k1".parse().unwrap());
+        assert_eq!(keying.extract(&headers), "k1");
+        headers.insert("x-request-id", " r1 ".parse().unwrap());
+        assert_eq!(keying.extract(&headers), "r1");
+
+        let (key, legacy) = keying.storage_key("", "alice", &Method::POST, &url, "k1");
+        assert!(key.starts_with("alice:POST:") && key.ends_with(":k1"));
+        assert_eq!(key.len(), "alice:POST::k1".len() + 22);
+        assert_eq!(legacy.as_deref(), Some("alice:POST:k1"));
+        let other = reqwest::Url::parse("https://httpbin.org/post?a=2").unwrap();
+        assert_ne!(
+            keying
+                .storage_key("", "alice", &Method::POST, &other, "k1")
+                .0,
+            key
+        );
+
+        assert!(Keying::from_vars(test_vars(vec![("IDEMPOTENCY_KEY_HEADERS", " ,")])).is_err());
+        assert!(
+            Keying::from_vars(test_vars(vec![("IDEMPOTENCY_KEY_HEADERS", "bad header")])).is_err()
+        );
+        assert!(Keying::from_vars(test_vars(vec![("IDEMPOTENCY_KEY_SCHEME", "url")])).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use base64::{engine::general_purpose, Engine};
+use std::borrow::Cow;
+
+// A verification key of a key generation, the id is the lowercase suffix of its
+// variable, e.g. `2024b` for ECDSA_PUB_KEY_2024B, and empty for ECDSA_PUB_KEY.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyGen<K> {
+    pub id: String,
+    pub key: K,
+    pub active_at: u64, // unix timestamp in seconds, 0 if active from the start
+    pub retire_at: Option<u64>, // tokens are rejected from then on
+}
+
+impl<K> KeyGen<K> {
+    pub fn is_active(&self, now: u64) -> bool {
+        now >= self.active_at && self.retire_at.is_none_or(|at| now < at)
+    }
+}
+
+// The verification keys of an algorithm. Generations overlap while the signers rotate,
+// a token with a key id is verified with the keys of that id only:
+// ECDSA_PUB_KEY_2024B="<base64url key>;active=1717200000;retire=1725148800".
+#[derive(Clone, Debug)]
+pub struct KeyRing<K> {
+    gens: Vec<KeyGen<K>>,
+    keys: Vec<K>,  // of all generations
+    windows: bool, // whether any generation has an activation or retirement time
+}
+
+impl<K> Default for KeyRing<K> {
+    fn default() -> Self {
+        KeyRing {
+            gens: Vec::new(),
+            keys: Vec::new(),
+            windows: false,
+        }
+    }
+}
+
+impl<K: Clone> KeyRing<K> {
+    pub fn new(mut gens: Vec<KeyGen<K>>) -> Self {
+        gens.sort_by(|a, b| a.id.cmp(&b.id));
+        KeyRing {
+            keys: gens.iter().map(|g| g.key.clone()).collect(),
+            windows: gens
+                .iter()
+                .any(|g| g.active_at > 0 || g.retire_at.is_some()),
+            gens,
+        }
+    }
+
+    // Loads the keys of the `<prefix>` and `<prefix>_<ID>` variables, `parse` decodes a key.
+    pub fn from_vars(
+        prefix: &str,
+        vars: impl Iterator<Item = (String, String)>,
+        parse: impl Fn(Vec<u8>) -> Option<K>,
+    ) -> Result<Self, String> {
+        let mut gens = Vec::new();
+        for (k, v) in vars {
+            let id = match k.strip_prefix(prefix) {
+                Some("") => "",
+                Some(id) => match id.strip_prefix('_') {
+                    Some(id) => id,
+                    None => continue,
+                },
+                None => continue,
+            };
+            let mut parts = v.split(';');
+            let key = general_purpose::URL_SAFE_NO_PAD
+                .decode(parts.next().unwrap_or_default().trim())
+                .ok()
+                .and_then(&parse)
+                .ok_or_else(|| format!("invalid key in {}", k))?;
+            let mut gen = KeyGen {
+                id: id.to_ascii_lowercase(),
+                key,
+                active_at: 0,
+                retire_at: None,
+            };
+            for part in parts {
+                let (name, value) = part
+                    .split_once('=')
+                    .ok_or_else(|| format!("invalid {} option: {}", k, part))?;
+                let at: u64 = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid {} timestamp: {}", k, value))?;
+                match name.trim() {
+                    "active" => gen.active_at = at,
+                    "retire" => gen.retire_at = Some(at),
+                    _ => return Err(format!("invalid {} option: {}", k, part)),
+                }
+            }
+            gens.push(gen);
+        }
+        Ok(KeyRing::new(gens))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gens.is_empty()
+    }
+
+    pub fn generations(&self) -> &[KeyGen<K>] {
+        &self.gens
+    }
+
+    // The keys that verify a token with the key id at `now`, tokens without key id
+    // are verified with the keys of every active generation.
+    pub fn select(&self, key_id: Option<&str>, now: u64) -> Cow<'_, [K]> {
+        match key_id {
+            None if !self.windows => Cow::Borrowed(&self.keys),
+            _ => Cow::Owned(
+                self.gens
+                    .iter()
+                    .filter(|g| g.is_active(now) && key_id.is_none_or(|id| g.id == id))
+                    .map(|g| g.key.clone())
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_vars;
+
+    #[test]
+    fn test_key_ring() {
+        let parse = |v: Vec<u8>| (v.len() == 3).then_some(v);
+        let ring = KeyRing::from_vars(
+            "ECDSA_PUB_KEY",
+            test_vars(vec![
+                ("ECDSA_PUB_KEY_2024B", "AQID;active=2000;retire=3000"),
+                ("ECDSA_PUB_KEY_2024A", "BAUG;retire=2500"),
+                ("ECDSA_PUB_KEYS", "BwgJ"),
+                ("ED25519_PUB_KEY", "BwgJ"),
+            ]),
+            parse,
+        )
+        .unwrap();
+        assert_eq!(ring.generations().len(), 2);
+        assert_eq!(ring.generations()[0].id, "2024a");
+        assert_eq!(ring.generations()[1].retire_at, Some(3000));
+
+        // both generations verify while they overlap
+        assert_eq!(ring.select(None, 1000), vec![vec![4, 5, 6]]);
+        assert_eq!(ring.select(None, 2200), vec![vec![4, 5, 6], vec![1, 2, 3]]);
+        assert_eq!(ring.select(Some("2024b"), 2200), vec![vec![1, 2, 3]]);
+        assert_eq!(ring.select(Some("2024a"), 2600).len(), 0);
+        assert_eq!(ring.select(None, 3000).len(), 0);
+
+        let ring = KeyRing::from_vars(
+            "ECDSA_PUB_KEY",
+            test_vars(vec![("ECDSA_PUB_KEY", "AQID"), ("ECDSA_PUB_KEY_1", "BAUG")]),
+            parse,
+        )
+        .unwrap();
+        assert!(matches!(ring.select(None, 0), Cow::Borrowed(_)));
+        assert_eq!(ring.select(Some(""), 0), vec![vec![1, 2, 3]]);
+
+        for v in ["AQ", "AQID;retire", "AQID;retire=soon", "AQID;expire=1"] {
+            assert!(KeyRing::from_vars(
+                "ECDSA_PUB_KEY",
+                test_vars(vec![("ECDSA_PUB_KEY_1", v)]),
+                parse
+            )
+            .is_err());
+        }
+    }
+}
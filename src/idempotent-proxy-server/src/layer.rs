@@ -0,0 +1,132 @@
+use axum::{
+    extract::{Request, State},
+    response::{IntoResponse, Response},
+};
+use futures::future::BoxFuture;
+use http::Uri;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+use crate::handler::{self, AppState};
+
+// Embeds the proxy in an existing axum or tower application: requests under the prefix
+// are proxied with the prefix stripped, e.g. `/proxy/URL_API` as `/URL_API`, the others
+// go to the wrapped service. Serve with `into_make_service_with_connect_info::<SocketAddr>()`
+// for the IP restrictions and alerts to see client addresses.
+#[derive(Clone)]
+pub struct ProxyLayer {
+    app: AppState,
+    prefix: String,
+}
+
+impl ProxyLayer {
+    // Proxies every request until a prefix is set.
+    pub fn new(app: AppState) -> Self {
+        ProxyLayer {
+            app,
+            prefix: String::new(),
+        }
+    }
+
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.trim_end_matches('/').to_string();
+        self
+    }
+}
+
+impl<S> Layer<S> for ProxyLayer {
+    type Service = ProxyService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ProxyService {
+            app: self.app.clone(),
+            prefix: self.prefix.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ProxyService<S> {
+    app: AppState,
+    prefix: String,
+    inner: S,
+}
+
+impl<S> Service<Request> for ProxyService<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        match strip_prefix(&self.prefix, req.uri()) {
+            Some(uri) => {
+                let app = self.app.clone();
+                let (mut parts, body) = req.into_parts();
+                parts.uri = uri;
+                let req = Request::from_parts(parts, body);
+                Box::pin(async move { Ok(handler::proxy(State(app), req).await.into_response()) })
+            }
+            None => {
+                // the clone is not ready yet, keep the ready service for this call
+                let clone = self.inner.clone();
+                let mut inner = std::mem::replace(&mut self.inner, clone);
+                Box::pin(inner.call(req))
+            }
+        }
+    }
+}
+
+// Returns the URI without the prefix if its path is under the prefix.
+fn strip_prefix(prefix: &str, uri: &Uri) -> Option<Uri> {
+    if prefix.is_empty() {
+        return Some(uri.clone());
+    }
+    let rest = uri.path().strip_prefix(prefix)?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    let path = if rest.is_empty() { "/" } else { rest };
+    let path_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_query.parse().ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strip_prefix() {
+        let uri: Uri = "/proxy/URL_API/v1?a=1".parse().unwrap();
+        assert_eq!(
+            strip_prefix("/proxy", &uri).unwrap(),
+            "/URL_API/v1?a=1".parse::<Uri>().unwrap()
+        );
+        assert_eq!(strip_prefix("", &uri).unwrap(), uri);
+        assert_eq!(
+            strip_prefix("/proxy", &"/proxy".parse().unwrap()).unwrap(),
+            "/".parse::<Uri>().unwrap()
+        );
+        assert!(strip_prefix("/proxy", &"/proxyx/URL_API".parse().unwrap()).is_none());
+        assert!(strip_prefix("/proxy", &"/health".parse().unwrap()).is_none());
+
+        let uri: Uri = "http://localhost:8080/proxy/URL_API".parse().unwrap();
+        assert_eq!(
+            strip_prefix("/proxy", &uri).unwrap(),
+            "http://localhost:8080/URL_API".parse::<Uri>().unwrap()
+        );
+    }
+}
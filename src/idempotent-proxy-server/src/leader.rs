@@ -0,0 +1,112 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::time::{sleep, Duration};
+
+use crate::cache::{HybridCacher, Storage};
+
+const LEADER_PREFIX: &str = "leader:";
+
+// Leader election with a lease in the storage backend, so that singleton background
+// jobs (e.g. scanning the scheduled jobs) run on one instance only.
+// Every instance is the leader when the election is disabled.
+#[derive(Debug)]
+pub struct Leader {
+    pub enabled: bool,
+    key: String,
+    instance: String,
+    lease_ttl: u64, // in milliseconds
+    is_leader: AtomicBool,
+}
+
+impl Leader {
+    pub fn new(enabled: bool, name: &str, instance: &str, lease_ttl: u64) -> Self {
+        Leader {
+            enabled,
+            key: format!("{}{}", LEADER_PREFIX, name),
+            instance: instance.to_string(),
+            lease_ttl,
+            is_leader: AtomicBool::new(!enabled),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.is_leader.load(Ordering::Relaxed)
+    }
+
+    // Acquires the lease if it is free, renews it if it is ours.
+    async fn campaign(&self, cacher: &HybridCacher) -> Result<bool, String> {
+        if cacher.obtain(&self.key, self.lease_ttl).await? {
+            cacher
+                .set(&self.key, self.instance.as_bytes().to_vec(), self.lease_ttl)
+                .await?;
+            return Ok(true);
+        }
+        match cacher.get(&self.key).await? {
+            Some(v) if v == self.instance.as_bytes() => {
+                cacher.set(&self.key, v, self.lease_ttl).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // Releases the lease so that another instance takes over without waiting for its expiry.
+    pub async fn resign(&self, cacher: &HybridCacher) {
+        if self.enabled && self.is_leader.swap(false, Ordering::Relaxed) {
+            let _ = cacher.del(&self.key).await;
+        }
+    }
+
+    pub async fn run(self: Arc<Self>, cacher: Arc<HybridCacher>) {
+        if !self.enabled {
+            return;
+        }
+        loop {
+            let is_leader = match self.campaign(&cacher).await {
+                Ok(v) => v,
+                Err(err) => {
+                    // a stale leader must step down when the storage is unreachable
+                    log::error!(target: "leader", "campaign failed: {}", err);
+                    false
+                }
+            };
+            if self.is_leader.swap(is_leader, Ordering::Relaxed) != is_leader {
+                log::warn!(target: "leader",
+                    key = self.key,
+                    instance = self.instance;
+                    "{}", if is_leader { "elected" } else { "stepped down" });
+            }
+            // renew well before the lease expires
+            sleep(Duration::from_millis(self.lease_ttl / 3)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{CacherEntry, MemoryCacher};
+
+    #[tokio::test]
+    async fn test_leader() {
+        let cacher = HybridCacher::new(10, 1000, CacherEntry::Memory(MemoryCacher::default()));
+        let a = Leader::new(true, "jobs", "a", 1000);
+        let b = Leader::new(true, "jobs", "b", 1000);
+        assert!(!a.is_leader());
+
+        assert!(a.campaign(&cacher).await.unwrap());
+        assert!(!b.campaign(&cacher).await.unwrap());
+        // renewal
+        assert!(a.campaign(&cacher).await.unwrap());
+
+        a.is_leader.store(true, Ordering::Relaxed);
+        a.resign(&cacher).await;
+        assert!(!a.is_leader());
+        assert!(b.campaign(&cacher).await.unwrap());
+        assert!(!a.campaign(&cacher).await.unwrap());
+
+        assert!(Leader::new(false, "jobs", "c", 1000).is_leader());
+    }
+}
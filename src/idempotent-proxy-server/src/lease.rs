@@ -0,0 +1,138 @@
+use axum::response::{IntoResponse, Response};
+use http::StatusCode;
+use idempotent_proxy_types::HEADER_X_IDEMPOTENCY_STATUS;
+use std::future::Future;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+use crate::cache::{HybridCacher, Storage, POLLING_TIMEOUT};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyStatus {
+    Pending, // locked, the first request is in flight
+    Cached,
+    Absent,
+}
+
+impl KeyStatus {
+    pub async fn of(cacher: &HybridCacher, key: &str) -> Result<Self, String> {
+        // a poll without delays answers at once
+        match cacher.polling_get_with(key, &mut std::iter::empty()).await {
+            Ok(_) => Ok(KeyStatus::Cached),
+            Err(err) if err == POLLING_TIMEOUT => Ok(KeyStatus::Pending),
+            Err(err) if err == "not obtained" => Ok(KeyStatus::Absent),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyStatus::Pending => "pending",
+            KeyStatus::Cached => "cached",
+            KeyStatus::Absent => "absent",
+        }
+    }
+}
+
+// The answer of `HEAD /status/<key>`: 200 cached, 202 pending or 404 absent, with the
+// status in the x-idempotency-status header.
+impl IntoResponse for KeyStatus {
+    fn into_response(self) -> Response {
+        let status = match self {
+            KeyStatus::Cached => StatusCode::OK,
+            KeyStatus::Pending => StatusCode::ACCEPTED,
+            KeyStatus::Absent => StatusCode::NOT_FOUND,
+        };
+        (status, [(&HEADER_X_IDEMPOTENCY_STATUS, self.as_str())]).into_response()
+    }
+}
+
+// Renews the lock of the key every `every` milliseconds while the future runs, so an
+// upstream call longer than the lock TTL is not duplicated by another instance. The
+// renewal stops once the key is not a lock anymore.
+pub async fn with_lease<T>(
+    cacher: &HybridCacher,
+    key: &str,
+    every: u64,
+    fut: impl Future<Output = T>,
+) -> T {
+    if every == 0 {
+        return fut.await;
+    }
+    tokio::pin!(fut);
+    let mut ticks = interval(Duration::from_millis(every));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks.tick().await; // the first tick is immediate
+    loop {
+        tokio::select! {
+            res = &mut fut => return res,
+            _ = ticks.tick() => {
+                match cacher.renew(key, cacher.cache_ttl).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        log::warn!(target: "handler",
+                            action = "renew",
+                            idempotency_key = key;
+                            "the lock is not renewable");
+                        return fut.await;
+                    }
+                    Err(err) => {
+                        log::warn!(target: "handler",
+                            action = "renew",
+                            idempotency_key = key;
+                            "storage unavailable: {}", err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{CacherEntry, MemoryCacher};
+
+    #[tokio::test]
+    async fn test_lease() {
+        let cacher = HybridCacher::new(10, 300, CacherEntry::Memory(MemoryCacher::default()));
+        assert_eq!(
+            KeyStatus::of(&cacher, "k").await.unwrap(),
+            KeyStatus::Absent
+        );
+        assert!(cacher.obtain("k", cacher.cache_ttl).await.unwrap());
+        assert_eq!(
+            KeyStatus::of(&cacher, "k").await.unwrap(),
+            KeyStatus::Pending
+        );
+
+        // the lock outlives its TTL while the call is in flight
+        with_lease(
+            &cacher,
+            "k",
+            100,
+            tokio::time::sleep(Duration::from_millis(700)),
+        )
+        .await;
+        assert_eq!(
+            KeyStatus::of(&cacher, "k").await.unwrap(),
+            KeyStatus::Pending
+        );
+        assert!(cacher.set("k", b"v".to_vec(), 1000).await.unwrap());
+        assert_eq!(
+            KeyStatus::of(&cacher, "k").await.unwrap(),
+            KeyStatus::Cached
+        );
+        // a cached response is not a lock
+        assert!(!cacher.renew("k", 1000).await.unwrap());
+
+        assert!(!cacher.renew("other", 1000).await.unwrap());
+        // an expired lock is not renewed
+        assert!(cacher.obtain("other", 100).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!cacher.renew("other", 1000).await.unwrap());
+
+        let res = KeyStatus::Pending.into_response();
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert_eq!(res.headers()["x-idempotency-status"], "pending");
+    }
+}
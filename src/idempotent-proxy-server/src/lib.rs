@@ -0,0 +1,77 @@
+// The idempotent proxy as a library: build an `AppState` (e.g. with `AppState::from_env`)
+// and mount `handler::proxy` as a route, or wrap an existing service with `ProxyLayer`.
+pub mod access_log;
+pub mod acme;
+pub mod admin;
+pub mod admin_ui;
+pub mod agent_limits;
+pub mod agent_methods;
+pub mod agents;
+pub mod alerts;
+pub mod analytics;
+pub mod breaker;
+pub mod cache;
+pub mod cache_control;
+pub mod canary;
+pub mod certification;
+pub mod cluster;
+pub mod config;
+pub mod config_file;
+pub mod cors;
+pub mod dns;
+pub mod events;
+pub mod graphql;
+pub mod grpc;
+pub mod grpc_proxy;
+pub mod handler;
+pub mod http3;
+pub mod ip_filter;
+pub mod journal;
+pub mod json_mask;
+pub mod jsonrpc;
+pub mod jwt;
+pub mod keying;
+pub mod keyring;
+pub mod layer;
+pub mod leader;
+pub mod lease;
+pub mod metadata;
+pub mod metrics;
+pub mod mirror;
+pub mod mtls;
+pub mod oauth2;
+pub mod otel;
+pub mod plugin;
+pub mod policy;
+pub mod pool;
+pub mod presets;
+pub mod profiling;
+pub mod redact;
+pub mod resources;
+pub mod retry;
+pub mod routes;
+pub mod scheduler;
+pub mod schema;
+pub mod scripts;
+pub mod secrets;
+pub mod shards;
+pub mod sigv4;
+pub mod static_headers;
+pub mod tenants;
+pub mod usage;
+pub mod waiters;
+pub mod webhook;
+pub mod websocket;
+
+// Environment variables for the `from_vars` tests.
+#[cfg(test)]
+pub(crate) fn test_vars(vars: Vec<(&str, &str)>) -> std::vec::IntoIter<(String, String)> {
+    vars.into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<Vec<_>>()
+        .into_iter()
+}
+
+pub use cache::Storage;
+pub use handler::AppState;
+pub use layer::{ProxyLayer, ProxyService};
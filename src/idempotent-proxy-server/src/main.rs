@@ -1,21 +1,23 @@
-use axum::{routing, Router};
+use axum::{middleware, response::Response, routing, Router};
 use axum_server::tls_rustls::RustlsConfig;
-use base64::{engine::general_purpose, Engine};
 use dotenvy::dotenv;
-use http::HeaderValue;
-use k256::ecdsa;
-use reqwest::ClientBuilder;
-use std::{
-    collections::{BTreeSet, HashMap},
-    net::SocketAddr,
-    sync::Arc,
-    time::Duration,
+use http::{header, HeaderValue};
+use idempotent_proxy_server::{
+    acme::Acme, config_file, handler, http3, mtls::ClientAuth, otel, secrets, AppState,
 };
+use std::{net::SocketAddr, time::Duration};
 use structured_logger::{async_json::new_writer, get_env_level, Builder};
-use tokio::signal;
+use tokio::{signal, sync::watch};
 
-mod cache;
-mod handler;
+#[cfg(feature = "profiling")]
+#[global_allocator]
+static ALLOC: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+// samples an allocation every 512KiB for the heap profiles
+#[cfg(feature = "profiling")]
+#[allow(non_upper_case_globals)]
+#[export_name = "malloc_conf"]
+pub static malloc_conf: &[u8] = b"prof:true,prof_active:true,lg_prof_sample:19\0";
 
 const APP_NAME: &str = env!("CARGO_PKG_NAME");
 const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -23,101 +25,32 @@ const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 #[tokio::main]
 async fn main() {
     dotenv().expect(".env file not found");
+    config_file::apply_from_env().expect("invalid CONFIG_FILE");
+    secrets::apply_from_env()
+        .await
+        .expect("load secrets failed");
 
     Builder::with_level(&get_env_level().to_string())
         .with_target_writer("*", new_writer(tokio::io::stdout()))
         .init();
 
-    let req_timeout: u64 = std::env::var("REQUEST_TIMEOUT")
-        .map(|n| n.parse().unwrap())
-        .unwrap_or(10000u64)
-        .max(1000u64);
-    let poll_interval: u64 = std::env::var("POLL_INTERVAL")
-        .map(|n| n.parse().unwrap())
-        .unwrap_or(100u64)
-        .max(10u64);
-
-    let http_client = ClientBuilder::new()
-        .http2_keep_alive_interval(Some(Duration::from_secs(25)))
-        .http2_keep_alive_timeout(Duration::from_secs(15))
-        .http2_keep_alive_while_idle(true)
-        .connect_timeout(Duration::from_secs(10))
-        .timeout(Duration::from_millis(req_timeout))
-        .gzip(true)
-        .build()
-        .unwrap();
-
-    let cacher_entry = match std::env::var("REDIS_URL") {
-        Ok(url) => {
-            let redis_client = cache::RedisClient::new(&url).await.unwrap();
-            cache::CacherEntry::Redis(redis_client)
-        }
-        Err(_) => cache::CacherEntry::Memory(cache::MemoryCacher::default()),
-    };
-
-    let agents: BTreeSet<String> = std::env::var("ALLOW_AGENTS")
-        .unwrap_or_default()
-        .split(',')
-        .filter_map(|s| {
-            let s = s.trim();
-            if s.is_empty() {
-                None
-            } else {
-                Some(s.to_string())
-            }
-        })
-        .collect();
-
-    let url_vars: HashMap<String, String> = std::env::vars()
-        .filter(|(k, _)| k.starts_with("URL_"))
-        .collect();
-
-    let header_vars: HashMap<String, HeaderValue> = std::env::vars()
-        .filter(|(k, _)| k.starts_with("HEADER_"))
-        .map(|(k, v)| (k, v.parse().expect("invalid header value")))
-        .collect();
-
-    let ecdsa_pub_keys: Vec<ecdsa::VerifyingKey> = std::env::vars()
-        .filter(|(k, _)| k.starts_with("ECDSA_PUB_KEY"))
-        .map(|(_, v)| {
-            let v = general_purpose::URL_SAFE_NO_PAD
-                .decode(v)
-                .expect("invalid base64");
-            ecdsa::VerifyingKey::from_sec1_bytes(&v).expect("invalid ecdsa key")
-        })
-        .collect();
-
-    let ed25519_pub_keys: Vec<ed25519_dalek::VerifyingKey> = std::env::vars()
-        .filter(|(k, _)| k.starts_with("ED25519_PUB_KEY"))
-        .map(|(_, v)| {
-            let v = general_purpose::URL_SAFE_NO_PAD
-                .decode(v)
-                .expect("invalid base64");
-            if v.len() != 32 {
-                panic!("invalid eddsa key");
-            }
-            let mut key = [0u8; 32];
-            key.copy_from_slice(&v);
-            ed25519_dalek::VerifyingKey::from_bytes(&key).expect("invalid ecdsa key")
-        })
-        .collect();
-
     let handle = axum_server::Handle::new();
-    let app = Router::new()
+    // how long in-flight requests may take to finish after a termination signal
+    let drain_timeout = Duration::from_secs(
+        std::env::var("SHUTDOWN_TIMEOUT")
+            .map(|n| n.parse().expect("invalid SHUTDOWN_TIMEOUT"))
+            .unwrap_or(10u64),
+    );
+    let (stopping, stopped) = watch::channel(false);
+    tokio::spawn(shutdown_signal(handle.clone(), drain_timeout, stopping));
+    let app_state = AppState::from_env().await;
+    app_state.spawn_services();
+    let state = app_state.clone();
+
+    let mut app = Router::new()
+        .route("/healthz", routing::get(handler::healthz))
         .route("/*any", routing::any(handler::proxy))
-        .with_state(handler::AppState {
-            http_client: Arc::new(http_client),
-            cacher: Arc::new(cache::HybridCacher::new(
-                poll_interval,
-                req_timeout,
-                cacher_entry,
-            )),
-            agents: Arc::new(agents),
-            url_vars: Arc::new(url_vars),
-            header_vars: Arc::new(header_vars),
-            ecdsa_pub_keys: Arc::new(ecdsa_pub_keys),
-            ed25519_pub_keys: Arc::new(ed25519_pub_keys),
-        });
+        .with_state(app_state);
 
     let addr: SocketAddr = std::env::var("SERVER_ADDR")
         .unwrap_or("127.0.0.1:8080".to_string())
@@ -126,30 +59,134 @@ async fn main() {
 
     let cert_file = std::env::var("TLS_CERT_FILE").unwrap_or_default();
     let key_file = std::env::var("TLS_KEY_FILE").unwrap_or_default();
-    match key_file.is_empty() {
-        true => {
+    let tls_pem = secrets::tls_pem();
+    let has_tls = !key_file.is_empty() || tls_pem.is_some();
+    let acme = Acme::from_vars(std::env::vars()).unwrap();
+    let client_auth = ClientAuth::from_vars(std::env::vars()).unwrap();
+    if client_auth.is_some() && key_file.is_empty() {
+        panic!("TLS_CLIENT_CA_FILE needs TLS_CERT_FILE and TLS_KEY_FILE");
+    }
+
+    // HTTP/3 shares the certificate, the TCP listener advertises it with Alt-Svc
+    let http3_addr = std::env::var("HTTP3_ADDR").unwrap_or_default();
+    let mut h3_server = None;
+    if !http3_addr.is_empty() {
+        let h3_addr: SocketAddr = http3_addr.parse().unwrap();
+        if !http3::ENABLED {
+            panic!("HTTP3_ADDR needs a build with the http3 feature");
+        }
+        if key_file.is_empty() {
+            panic!("HTTP3_ADDR needs TLS_CERT_FILE and TLS_KEY_FILE");
+        }
+        let alt_svc = HeaderValue::from_str(&http3::alt_svc(h3_addr.port())).unwrap();
+        let (h3_app, cert_file, key_file) = (app.clone(), cert_file.clone(), key_file.clone());
+        let h3_stopped = stopped.clone();
+        h3_server = Some(tokio::spawn(async move {
+            log::warn!(target: "server", "{}@{} listening on {:?} with http3", APP_NAME, APP_VERSION, h3_addr);
+            if let Err(err) = http3::serve(h3_app, h3_addr, &cert_file, &key_file, h3_stopped).await
+            {
+                log::error!(target: "server", "http3 listener failed: {}", err);
+            }
+        }));
+        app = app.layer(middleware::map_response(move |mut res: Response| {
+            let alt_svc = alt_svc.clone();
+            async move {
+                res.headers_mut().insert(header::ALT_SVC, alt_svc);
+                res
+            }
+        }));
+    }
+    match (acme, !has_tls) {
+        (Some(acme), _) => {
+            log::warn!(target: "server", "{}@{} listening on {:?} with tls for {:?}", APP_NAME, APP_VERSION, addr, acme.domains);
+            axum_server::bind(addr)
+                .acceptor(acme.acceptor())
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
+        }
+        (None, true) => {
             let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
             log::warn!(target: "server", "{}@{} listening on {:?}", APP_NAME, APP_VERSION, addr);
-            axum::serve(listener, app)
-                .with_graceful_shutdown(shutdown_signal(handle))
+            let server = axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(signaled(stopped.clone()));
+            tokio::select! {
+                res = async { server.await } => res.unwrap(),
+                _ = async {
+                    signaled(stopped).await;
+                    tokio::time::sleep(drain_timeout).await;
+                } => {
+                    log::warn!(target: "server", "drain timeout, forcing shutdown");
+                }
+            }
+        }
+        (None, false) if client_auth.is_some() => {
+            let acceptor = client_auth
+                .unwrap()
+                .acceptor(&cert_file, &key_file)
+                .unwrap_or_else(|err| panic!("client auth config failed: {}", err));
+            log::warn!(target: "server", "{}@{} listening on {:?} with mtls", APP_NAME, APP_VERSION, addr);
+            axum_server::bind(addr)
+                .acceptor(acceptor)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
                 .await
                 .unwrap();
         }
-        false => {
-            let config = RustlsConfig::from_pem_file(&cert_file, &key_file)
-                .await
-                .unwrap_or_else(|_| panic!("read tls file failed: {}, {}", cert_file, key_file));
+        (None, false) => {
+            let config = match tls_pem {
+                Some((cert, key)) => {
+                    let config = RustlsConfig::from_pem(cert.into_bytes(), key.into_bytes())
+                        .await
+                        .unwrap_or_else(|err| {
+                            panic!("invalid TLS_CERT_PEM or TLS_KEY_PEM: {}", err)
+                        });
+                    tokio::spawn(secrets::reload_tls(config.clone()));
+                    config
+                }
+                None => RustlsConfig::from_pem_file(&cert_file, &key_file)
+                    .await
+                    .unwrap_or_else(|_| {
+                        panic!("read tls file failed: {}, {}", cert_file, key_file)
+                    }),
+            };
             log::warn!(target: "server", "{}@{} listening on {:?} with tls", APP_NAME, APP_VERSION,addr);
             axum_server::bind_rustls(addr, config)
                 .handle(handle)
-                .serve(app.into_make_service())
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
                 .await
                 .unwrap();
         }
     }
+    if let Some(h3_server) = h3_server {
+        // the QUIC connections drain within the same timeout
+        if tokio::time::timeout(drain_timeout, h3_server)
+            .await
+            .is_err()
+        {
+            log::warn!(target: "server", "http3 drain timeout, forcing shutdown");
+        }
+    }
+    let released = state.shutdown().await;
+    log::warn!(target: "server", released = released; "shutdown completed");
+    otel::shutdown();
+}
+
+async fn signaled(mut stopped: watch::Receiver<bool>) {
+    let _ = stopped.wait_for(|v| *v).await;
 }
 
-async fn shutdown_signal(handle: axum_server::Handle) {
+// Stops accepting connections on a termination signal and lets the in-flight requests
+// finish within the drain timeout, the locks of the others are released by AppState::shutdown.
+async fn shutdown_signal(
+    handle: axum_server::Handle,
+    drain_timeout: Duration,
+    stopping: watch::Sender<bool>,
+) {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -173,6 +210,6 @@ async fn shutdown_signal(handle: axum_server::Handle) {
     }
 
     log::warn!(target: "server", "received termination signal, starting graceful shutdown");
-    // 10 secs is how long server will wait to force shutdown
-    handle.graceful_shutdown(Some(Duration::from_secs(10)));
+    let _ = stopping.send(true);
+    handle.graceful_shutdown(Some(drain_timeout));
 }
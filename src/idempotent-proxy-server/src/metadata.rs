@@ -0,0 +1,308 @@
+use base64::{engine::general_purpose, Engine};
+use http::HeaderValue;
+use idempotent_proxy_types::{err_string, unix_ms};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{str::FromStr, sync::Arc};
+use tokio::sync::RwLock;
+
+use crate::oauth2::refresh_at;
+use crate::sigv4::Credentials;
+
+const GCP_METADATA_HOST: &str = "metadata.google.internal";
+const AWS_METADATA_ENDPOINT: &str = "http://169.254.169.254";
+
+// Tokens of the VM's service account from the GCP metadata server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataTokenKind {
+    GcpIdentity,    // OIDC identity token for the audience, e.g. Cloud Run or IAP
+    GcpAccessToken, // OAuth2 access token for Google APIs
+}
+
+impl FromStr for MetadataTokenKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "gcp-identity" => Ok(MetadataTokenKind::GcpIdentity),
+            "gcp-access-token" => Ok(MetadataTokenKind::GcpAccessToken),
+            v => Err(format!("invalid metadata token: {}", v)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Token {
+    token: String,
+    refresh_at: u64, // unix timestamp in milliseconds
+}
+
+#[derive(Deserialize)]
+struct AccessTokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Clone)]
+pub struct MetadataToken {
+    pub kind: MetadataTokenKind,
+    pub audience: String,
+    token: Arc<RwLock<Option<Token>>>,
+}
+
+impl std::fmt::Debug for MetadataToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MetadataToken")
+            .field("kind", &self.kind)
+            .field("audience", &self.audience)
+            .finish()
+    }
+}
+
+impl PartialEq for MetadataToken {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind && self.audience == other.audience
+    }
+}
+
+impl MetadataToken {
+    pub fn new(kind: MetadataTokenKind) -> Self {
+        Self {
+            kind,
+            audience: "".to_string(),
+            token: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    pub fn with_audience(mut self, audience: &str) -> Self {
+        self.audience = audience.trim().to_string();
+        self.token = Arc::new(RwLock::new(None));
+        self
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        if self.kind == MetadataTokenKind::GcpIdentity && self.audience.is_empty() {
+            return Err("METADATA_AUDIENCE is required for gcp-identity tokens".to_string());
+        }
+        Ok(())
+    }
+
+    pub async fn authorization(&self, http_client: &Client) -> Result<HeaderValue, String> {
+        if let Some(t) = self.token.read().await.as_ref() {
+            if t.refresh_at > unix_ms() {
+                return HeaderValue::from_str(&format!("Bearer {}", t.token)).map_err(err_string);
+            }
+        }
+
+        let mut token = self.token.write().await;
+        let t = match token.as_ref() {
+            Some(t) if t.refresh_at > unix_ms() => t.clone(),
+            _ => {
+                let t = self.fetch(http_client).await?;
+                *token = Some(t.clone());
+                t
+            }
+        };
+        HeaderValue::from_str(&format!("Bearer {}", t.token)).map_err(err_string)
+    }
+
+    async fn fetch(&self, http_client: &Client) -> Result<Token, String> {
+        let host = std::env::var("GCE_METADATA_HOST").unwrap_or(GCP_METADATA_HOST.to_string());
+        let base = format!(
+            "http://{}/computeMetadata/v1/instance/service-accounts/default",
+            host
+        );
+        let now = unix_ms();
+        match self.kind {
+            MetadataTokenKind::GcpIdentity => {
+                let url = reqwest::Url::parse_with_params(
+                    &format!("{}/identity", base),
+                    &[("audience", self.audience.as_str()), ("format", "full")],
+                )
+                .map_err(err_string)?;
+                let token = gcp_get(http_client, url.as_str()).await?;
+                let token = String::from_utf8(token).map_err(err_string)?;
+                let exp = jwt_exp(&token)?;
+                Ok(Token {
+                    refresh_at: refresh_at(now, Some((exp * 1000).saturating_sub(now) / 1000)),
+                    token,
+                })
+            }
+            MetadataTokenKind::GcpAccessToken => {
+                let data = gcp_get(http_client, &format!("{}/token", base)).await?;
+                let tr: AccessTokenResponse = serde_json::from_slice(&data).map_err(err_string)?;
+                Ok(Token {
+                    token: tr.access_token,
+                    refresh_at: refresh_at(now, Some(tr.expires_in)),
+                })
+            }
+        }
+    }
+}
+
+async fn gcp_get(http_client: &Client, url: &str) -> Result<Vec<u8>, String> {
+    let res = http_client
+        .get(url)
+        .header("metadata-flavor", "Google")
+        .send()
+        .await
+        .map_err(err_string)?;
+    let status = res.status();
+    let data = res.bytes().await.map_err(err_string)?;
+    if !status.is_success() {
+        return Err(format!(
+            "GCP metadata request failed, status: {}, body: {}",
+            status,
+            String::from_utf8_lossy(&data)
+        ));
+    }
+    Ok(data.to_vec())
+}
+
+// Fetches the instance role credentials from the EC2 instance metadata service (IMDSv2).
+pub async fn aws_credentials(http_client: &Client) -> Result<Credentials, String> {
+    let endpoint = std::env::var("AWS_EC2_METADATA_SERVICE_ENDPOINT")
+        .unwrap_or(AWS_METADATA_ENDPOINT.to_string());
+    let endpoint = endpoint.trim_end_matches('/');
+    let token = http_client
+        .put(format!("{}/latest/api/token", endpoint))
+        .header("x-aws-ec2-metadata-token-ttl-seconds", "21600")
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|err| format!("fetch IMDSv2 token failed: {}", err))?
+        .text()
+        .await
+        .map_err(err_string)?;
+
+    let aws_get = |path: String| {
+        let req = http_client
+            .get(format!("{}{}", endpoint, path))
+            .header("x-aws-ec2-metadata-token", &token);
+        async move {
+            req.send()
+                .await
+                .and_then(|res| res.error_for_status())
+                .map_err(|err| format!("EC2 metadata request failed: {}", err))?
+                .bytes()
+                .await
+                .map_err(err_string)
+        }
+    };
+
+    let roles = aws_get("/latest/meta-data/iam/security-credentials/".to_string()).await?;
+    let role = String::from_utf8_lossy(&roles)
+        .lines()
+        .next()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| "no IAM role attached to the instance".to_string())?;
+    let data = aws_get(format!(
+        "/latest/meta-data/iam/security-credentials/{}",
+        role
+    ))
+    .await?;
+    let obj: Value = serde_json::from_slice(&data).map_err(err_string)?;
+    let field = |name: &str| -> Result<String, String> {
+        obj[name]
+            .as_str()
+            .map(|s| s.to_string())
+            .ok_or_else(|| format!("invalid EC2 metadata credentials, missing {}", name))
+    };
+    Ok(Credentials {
+        access_key_id: field("AccessKeyId")?,
+        secret_access_key: field("SecretAccessKey")?,
+        session_token: Some(field("Token")?),
+        expires_at: parse_utc_time(&field("Expiration")?)
+            .ok_or_else(|| "invalid EC2 metadata credentials expiration".to_string())?,
+    })
+}
+
+// Returns the `exp` claim (unix timestamp in seconds) of a JWT, the signature is not verified.
+fn jwt_exp(token: &str) -> Result<u64, String> {
+    let payload = token
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| "invalid JWT".to_string())?;
+    let payload = general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .map_err(err_string)?;
+    let claims: Value = serde_json::from_slice(&payload).map_err(err_string)?;
+    claims["exp"]
+        .as_u64()
+        .ok_or_else(|| "missing exp claim in JWT".to_string())
+}
+
+// Parses `YYYY-MM-DDTHH:MM:SSZ` to a unix timestamp in milliseconds.
+pub fn parse_utc_time(s: &str) -> Option<u64> {
+    let s = s.trim().strip_suffix('Z')?;
+    let (date, time) = s.split_once('T')?;
+    let mut date = date.split('-').map(|v| v.parse::<i64>().ok());
+    let (y, m, d) = (date.next()??, date.next()??, date.next()??);
+    let mut time = time.split(':');
+    let (hh, mm) = (
+        time.next()?.parse::<u64>().ok()?,
+        time.next()?.parse::<u64>().ok()?,
+    );
+    // fractional seconds are ignored
+    let ss = time.next()?.split('.').next()?.parse::<u64>().ok()?;
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return None;
+    }
+
+    // days from civil, http://howardhinnant.github.io/date_algorithms.html
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if m > 2 { m - 3 } else { m + 9 };
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    if days < 0 {
+        return None;
+    }
+    Some(((days as u64) * 86400 + hh * 3600 + mm * 60 + ss) * 1000)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_utc_time() {
+        assert_eq!(parse_utc_time("2015-08-30T12:36:00Z"), Some(1440938160000));
+        assert_eq!(
+            parse_utc_time("2000-02-29T00:00:00.123Z"),
+            Some(951782400000)
+        );
+        assert_eq!(parse_utc_time("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_utc_time("2015-08-30T12:36:00"), None);
+        assert_eq!(parse_utc_time("2015-13-30T12:36:00Z"), None);
+    }
+
+    #[test]
+    fn test_jwt_exp() {
+        let payload = general_purpose::URL_SAFE_NO_PAD
+            .encode(br#"{"aud":"https://api.example.com","exp":1716376993}"#);
+        assert_eq!(
+            jwt_exp(&format!("eyJhbGciOiJSUzI1NiJ9.{}.sig", payload)).unwrap(),
+            1716376993
+        );
+        assert!(jwt_exp("invalid").is_err());
+    }
+
+    #[test]
+    fn test_metadata_token() {
+        let kind: MetadataTokenKind = "gcp-identity".parse().unwrap();
+        assert!(MetadataToken::new(kind).check().is_err());
+        assert!(MetadataToken::new(kind)
+            .with_audience("https://api.example.com")
+            .check()
+            .is_ok());
+        assert!(MetadataToken::new("gcp-access-token".parse().unwrap())
+            .check()
+            .is_ok());
+        assert!("azure".parse::<MetadataTokenKind>().is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use axum::{extract::State, response::IntoResponse, routing, Router};
+use http::header;
+use std::{fmt::Display, fmt::Write, net::SocketAddr};
+
+use crate::analytics::Counters;
+use crate::breaker::CircuitState;
+use crate::handler::AppState;
+use crate::resources::LATENCY_BUCKETS;
+
+// A per-agent counter: metric name, help text and how to read it.
+type AgentCounter = (&'static str, &'static str, fn(&Counters) -> u64);
+
+const PREFIX: &str = "idempotent_proxy_";
+
+pub fn router(app: AppState) -> Router {
+    Router::new()
+        .route("/metrics", routing::get(metrics))
+        .with_state(app)
+}
+
+// A separate listener, scrapers are not authenticated.
+pub async fn serve(addr: SocketAddr, app: AppState) {
+    let listener = match tokio::net::TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!(target: "server", "metrics bind failed: {}", err);
+            return;
+        }
+    };
+    log::warn!(target: "server", "metrics listening on {:?}", addr);
+    if let Err(err) = axum::serve(listener, router(app)).await {
+        log::error!(target: "server", "metrics failed: {}", err);
+    }
+}
+
+async fn metrics(State(app): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render(&app),
+    )
+}
+
+// Renders the counters in the Prometheus text exposition format.
+pub fn render(app: &AppState) -> String {
+    let mut w = Writer::default();
+    let snapshot = app.analytics.snapshot();
+    let t = &snapshot.totals;
+
+    w.counter("requests_total", "Idempotent requests served", t.requests);
+    w.counter(
+        "cache_hits_total",
+        "Duplicates answered with the cached response",
+        t.hits,
+    );
+    w.counter(
+        "cache_misses_total",
+        "First requests forwarded to the upstream",
+        t.misses,
+    );
+    w.counter(
+        "conflicts_total",
+        "Duplicates that arrived while the first request was in flight",
+        t.conflicts,
+    );
+    w.counter(
+        "failures_total",
+        "Forwarded requests that failed and requests refused without storage",
+        t.failures,
+    );
+    w.header("cache_hit_ratio", "gauge", "Cache hits per request");
+    w.sample("cache_hit_ratio", &[], snapshot.hit_rate);
+
+    let mut cumulative = 0;
+    let buckets: Vec<(Option<u64>, u64)> = snapshot
+        .wait_histogram
+        .iter()
+        .map(|b| {
+            cumulative += b.count;
+            (b.le, cumulative)
+        })
+        .collect();
+    w.histogram(
+        "lock_wait_seconds",
+        "Time duplicates waited for the response of the first request",
+        &[],
+        &buckets,
+        t.wait_total,
+        cumulative,
+    );
+
+    let agents: [AgentCounter; 4] = [
+        ("agent_requests_total", "Requests per agent", |c| c.requests),
+        ("agent_cache_hits_total", "Cache hits per agent", |c| c.hits),
+        ("agent_cache_misses_total", "Cache misses per agent", |c| {
+            c.misses
+        }),
+        ("agent_failures_total", "Failures per agent", |c| c.failures),
+    ];
+    for (name, help, value) in agents {
+        w.header(name, "counter", help);
+        for (agent, counters) in &snapshot.agents {
+            w.sample(name, &[("agent", agent)], value(counters));
+        }
+    }
+
+    let upstreams = app.resources.upstreams();
+    w.header("upstream_active", "gauge", "Upstream requests in flight");
+    for (host, usage) in &upstreams {
+        w.sample("upstream_active", &[("host", host)], usage.active);
+    }
+    w.header(
+        "upstream_duration_seconds",
+        "histogram",
+        "Upstream latency until the response is read",
+    );
+    for (host, usage) in &upstreams {
+        let mut cumulative = 0;
+        let buckets: Vec<(Option<u64>, u64)> = usage
+            .latency_buckets
+            .iter()
+            .enumerate()
+            .map(|(i, n)| {
+                cumulative += n;
+                (LATENCY_BUCKETS.get(i).copied(), cumulative)
+            })
+            .collect();
+        w.histogram_samples(
+            "upstream_duration_seconds",
+            &[("host", host)],
+            &buckets,
+            usage.latency_sum,
+            cumulative,
+        );
+    }
+
+    let circuits = app.breakers.circuits();
+    w.header(
+        "circuit_state",
+        "gauge",
+        "Circuit breaker state per upstream host: 0 closed, 1 open, 2 half open",
+    );
+    for (host, circuit) in &circuits {
+        let state = match circuit.state {
+            CircuitState::Closed => 0,
+            CircuitState::Open => 1,
+            CircuitState::HalfOpen => 2,
+        };
+        w.sample("circuit_state", &[("host", host)], state);
+    }
+    w.header(
+        "circuit_trips_total",
+        "counter",
+        "Times the circuit of an upstream host opened",
+    );
+    for (host, circuit) in &circuits {
+        w.sample("circuit_trips_total", &[("host", host)], circuit.trips);
+    }
+    w.header(
+        "circuit_rejected_total",
+        "counter",
+        "Requests failed fast by an open circuit",
+    );
+    for (host, circuit) in &circuits {
+        w.sample(
+            "circuit_rejected_total",
+            &[("host", host)],
+            circuit.rejected,
+        );
+    }
+
+    w.header("active_requests", "gauge", "Requests being served");
+    w.sample("active_requests", &[], app.resources.active_requests());
+    w.counter(
+        "storage_errors_total",
+        "Failed storage operations",
+        app.cacher.errors(),
+    );
+    w.counter(
+        "access_log_dropped_total",
+        "Access log lines dropped because the writer fell behind",
+        app.access_log.dropped(),
+    );
+    w.0
+}
+
+#[derive(Default)]
+struct Writer(String);
+
+impl Writer {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {}{} {}", PREFIX, name, help);
+        let _ = writeln!(self.0, "# TYPE {}{} {}", PREFIX, name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let _ = write!(self.0, "{}{}", PREFIX, name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+
+    fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, "counter", help);
+        self.sample(name, &[], value);
+    }
+
+    fn histogram(
+        &mut self,
+        name: &str,
+        help: &str,
+        labels: &[(&str, &str)],
+        buckets: &[(Option<u64>, u64)],
+        sum_ms: u64,
+        count: u64,
+    ) {
+        self.header(name, "histogram", help);
+        self.histogram_samples(name, labels, buckets, sum_ms, count);
+    }
+
+    // Cumulative buckets with upper bounds in milliseconds, None for +Inf.
+    fn histogram_samples(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        buckets: &[(Option<u64>, u64)],
+        sum_ms: u64,
+        count: u64,
+    ) {
+        let bucket = format!("{}_bucket", name);
+        for (le, n) in buckets {
+            let le = match le {
+                Some(ms) => (*ms as f64 / 1000.0).to_string(),
+                None => "+Inf".to_string(),
+            };
+            let mut labels = labels.to_vec();
+            labels.push(("le", &le));
+            self.sample(&bucket, &labels, n);
+        }
+        self.sample(&format!("{}_sum", name), labels, sum_ms as f64 / 1000.0);
+        self.sample(&format!("{}_count", name), labels, count);
+    }
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_writer() {
+        let mut w = Writer::default();
+        w.counter("requests_total", "Requests", 3);
+        w.header("agent_requests_total", "counter", "Requests per agent");
+        w.sample("agent_requests_total", &[("agent", "a\"b\\c")], 2);
+        w.histogram(
+            "lock_wait_seconds",
+            "Waits",
+            &[],
+            &[(Some(10), 1), (Some(250), 2), (None, 3)],
+            1500,
+            3,
+        );
+        assert_eq!(
+            w.0,
+            r#"# HELP idempotent_proxy_requests_total Requests
+# TYPE idempotent_proxy_requests_total counter
+idempotent_proxy_requests_total 3
+# HELP idempotent_proxy_agent_requests_total Requests per agent
+# TYPE idempotent_proxy_agent_requests_total counter
+idempotent_proxy_agent_requests_total{agent="a\"b\\c"} 2
+# HELP idempotent_proxy_lock_wait_seconds Waits
+# TYPE idempotent_proxy_lock_wait_seconds histogram
+idempotent_proxy_lock_wait_seconds_bucket{le="0.01"} 1
+idempotent_proxy_lock_wait_seconds_bucket{le="0.25"} 2
+idempotent_proxy_lock_wait_seconds_bucket{le="+Inf"} 3
+idempotent_proxy_lock_wait_seconds_sum 1.5
+idempotent_proxy_lock_wait_seconds_count 3
+"#
+        );
+    }
+}
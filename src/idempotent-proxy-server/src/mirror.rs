@@ -0,0 +1,134 @@
+use axum::body::Bytes;
+use http::StatusCode;
+use idempotent_proxy_types::auth::sha3_256;
+use reqwest::{Client, Url};
+
+use crate::handler::ProxyRequest;
+use crate::redact;
+use crate::secrets;
+
+// A shadow upstream receiving a copy of a percentage of the requests,
+// its responses are discarded, differences with the primary upstream are logged.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mirror {
+    pub origin: Url,
+}
+
+impl Mirror {
+    pub fn new(origin: &str) -> Result<Self, String> {
+        let origin =
+            Url::parse(origin).map_err(|err| format!("invalid mirror url {}: {}", origin, err))?;
+        if origin.host().is_none() {
+            return Err(format!("invalid mirror url: {}", origin));
+        }
+        Ok(Self { origin })
+    }
+
+    // Replaces the scheme, host and port of the url with the mirror's.
+    pub fn mirror_url(&self, url: &Url) -> Url {
+        replace_origin(url, &self.origin)
+    }
+
+    pub fn spawn(
+        &self,
+        percent: u8,
+        http_client: Client,
+        preq: &ProxyRequest,
+        status: StatusCode,
+        body: Bytes,
+    ) {
+        if !sampled(&preq.idempotency_key, percent) {
+            return;
+        }
+
+        let url = self.mirror_url(&preq.url);
+        // the logs get the template, without the secrets
+        let resolved = match secrets::expand_url(&url) {
+            Ok(resolved) => resolved.into_owned(),
+            Err(err) => {
+                log::warn!(target: "mirror",
+                    url = redact::url(&url),
+                    idempotency_key = preq.idempotency_key;
+                    "mirror request failed: {}", err);
+                return;
+            }
+        };
+        let mut req = reqwest::Request::new(preq.method.clone(), resolved);
+        *req.headers_mut() = preq.headers.clone();
+        if let Some(body) = &preq.body {
+            *req.body_mut() = Some(reqwest::Body::from(body.clone()));
+        }
+        let idempotency_key = preq.idempotency_key.clone();
+        let url = redact::url(&url);
+
+        tokio::spawn(async move {
+            let res = match http_client.execute(req).await {
+                Ok(res) => res,
+                Err(err) => {
+                    log::warn!(target: "mirror",
+                        url = url,
+                        status = status.as_u16(),
+                        idempotency_key = idempotency_key;
+                        "mirror request failed: {}", err.without_url());
+                    return;
+                }
+            };
+            let mirror_status = res.status();
+            let mirror_body = res.bytes().await.unwrap_or_default();
+            if mirror_status != status || mirror_body != body {
+                log::warn!(target: "mirror",
+                    url = url,
+                    status = status.as_u16(),
+                    mirror_status = mirror_status.as_u16(),
+                    body_size = body.len(),
+                    mirror_body_size = mirror_body.len(),
+                    idempotency_key = idempotency_key;
+                    "mirror response differs");
+            } else {
+                log::info!(target: "mirror",
+                    url = url,
+                    status = status.as_u16(),
+                    idempotency_key = idempotency_key;
+                    "mirror response matches");
+            }
+        });
+    }
+}
+
+// Sampling is deterministic per idempotency key, so retries get the same decision.
+pub fn sampled(idempotency_key: &str, percent: u8) -> bool {
+    let hash = sha3_256(idempotency_key.as_bytes());
+    (u16::from_be_bytes([hash[0], hash[1]]) % 100) < percent as u16
+}
+
+pub fn replace_origin(url: &Url, origin: &Url) -> Url {
+    let mut replaced = origin.clone();
+    replaced.set_path(url.path());
+    replaced.set_query(url.query());
+    replaced
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mirror() {
+        let mirror = Mirror::new("http://127.0.0.1:8081").unwrap();
+        let url = Url::parse("https://httpbin.org/get?api-key=abc123").unwrap();
+        assert_eq!(
+            mirror.mirror_url(&url).as_str(),
+            "http://127.0.0.1:8081/get?api-key=abc123"
+        );
+
+        assert!(sampled("alice:GET:key_001", 100));
+        assert!(!sampled("alice:GET:key_001", 0));
+
+        let sampled = (0..1000)
+            .filter(|i| sampled(&format!("alice:GET:key_{}", i), 50))
+            .count();
+        assert!(sampled > 400 && sampled < 600);
+
+        assert!(Mirror::new("not a url").is_err());
+    }
+}
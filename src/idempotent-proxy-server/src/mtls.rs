@@ -0,0 +1,253 @@
+use axum_server::{
+    accept::Accept,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use futures::future::BoxFuture;
+use rustls::{server::WebPkiClientVerifier, RootCertStore};
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tower::Service;
+use x509_parser::prelude::*;
+
+// The agent of a request authenticated by its client certificate, it takes the
+// place of the proxy token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientIdentity(pub String);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AgentFrom {
+    #[default]
+    Cn, // the common name of the subject
+    San, // the first DNS, email or URI subject alternative name
+}
+
+// Client certificates verified against TLS_CLIENT_CA_FILE on the TLS listener,
+// TLS_CLIENT_AUTH=optional lets clients without one fall back to proxy tokens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientAuth {
+    pub ca_file: String,
+    pub optional: bool,
+    pub agent_from: AgentFrom,
+}
+
+impl ClientAuth {
+    // None if no CA bundle is configured.
+    pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Option<Self>, String> {
+        let mut auth = ClientAuth {
+            ca_file: String::new(),
+            optional: false,
+            agent_from: AgentFrom::Cn,
+        };
+        for (k, v) in vars {
+            match k.as_str() {
+                "TLS_CLIENT_CA_FILE" => auth.ca_file = v.trim().to_string(),
+                "TLS_CLIENT_AUTH" => {
+                    auth.optional = match v.trim().to_ascii_lowercase().as_str() {
+                        "" | "required" => false,
+                        "optional" => true,
+                        _ => return Err(format!("invalid TLS_CLIENT_AUTH value: {}", v)),
+                    }
+                }
+                "TLS_CLIENT_AGENT" => {
+                    auth.agent_from = match v.trim().to_ascii_lowercase().as_str() {
+                        "" | "cn" => AgentFrom::Cn,
+                        "san" => AgentFrom::San,
+                        _ => return Err(format!("invalid TLS_CLIENT_AGENT value: {}", v)),
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok((!auth.ca_file.is_empty()).then_some(auth))
+    }
+
+    // The acceptor for axum_server with the server certificate and key files.
+    pub fn acceptor(&self, cert_file: &str, key_file: &str) -> Result<ClientCertAcceptor, String> {
+        let open = |path: &str| {
+            File::open(path)
+                .map(BufReader::new)
+                .map_err(|err| format!("read {} failed: {}", path, err))
+        };
+        let certs = rustls_pemfile::certs(&mut open(cert_file)?)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("invalid certificate {}: {}", cert_file, err))?;
+        let key = rustls_pemfile::private_key(&mut open(key_file)?)
+            .map_err(|err| format!("invalid key {}: {}", key_file, err))?
+            .ok_or_else(|| format!("no private key in {}", key_file))?;
+        let mut roots = RootCertStore::empty();
+        for ca in rustls_pemfile::certs(&mut open(&self.ca_file)?) {
+            let ca = ca.map_err(|err| format!("invalid CA bundle {}: {}", self.ca_file, err))?;
+            roots.add(ca).map_err(|err| err.to_string())?;
+        }
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+        let verifier = match self.optional {
+            true => verifier.allow_unauthenticated(),
+            false => verifier,
+        }
+        .build()
+        .map_err(|err| err.to_string())?;
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|err| err.to_string())?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)
+            .map_err(|err| err.to_string())?;
+        tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(ClientCertAcceptor {
+            inner: RustlsAcceptor::new(RustlsConfig::from_config(Arc::new(tls))),
+            agent_from: self.agent_from,
+        })
+    }
+}
+
+// The agent name of a DER encoded certificate.
+pub fn agent_name(der: &[u8], from: AgentFrom) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    let name = match from {
+        AgentFrom::Cn => cert
+            .subject()
+            .iter_common_name()
+            .next()?
+            .as_str()
+            .ok()?
+            .to_string(),
+        AgentFrom::San => cert
+            .subject_alternative_name()
+            .ok()??
+            .value
+            .general_names
+            .iter()
+            .find_map(|name| match name {
+                GeneralName::DNSName(v) | GeneralName::RFC822Name(v) | GeneralName::URI(v) => {
+                    Some(v.to_string())
+                }
+                _ => None,
+            })?,
+    };
+    (!name.is_empty()).then_some(name)
+}
+
+// Terminates TLS and adds the ClientIdentity of the verified client certificate
+// to every request of the connection.
+#[derive(Clone)]
+pub struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+    agent_from: AgentFrom,
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = <RustlsAcceptor as Accept<I, S>>::Stream;
+    type Service = WithIdentity<S>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let (inner, agent_from) = (self.inner.clone(), self.agent_from);
+        Box::pin(async move {
+            let (stream, service) = Accept::<I, S>::accept(&inner, stream, service).await?;
+            let identity = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| agent_name(cert, agent_from))
+                .map(ClientIdentity);
+            Ok((
+                stream,
+                WithIdentity {
+                    inner: service,
+                    identity,
+                },
+            ))
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct WithIdentity<S> {
+    inner: S,
+    identity: Option<ClientIdentity>,
+}
+
+impl<S, B> Service<http::Request<B>> for WithIdentity<S>
+where
+    S: Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        if let Some(identity) = &self.identity {
+            req.extensions_mut().insert(identity.clone());
+        }
+        self.inner.call(req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_vars;
+
+    #[test]
+    fn test_agent_name() {
+        let mut params =
+            rcgen::CertificateParams::new(vec!["worker-1.agents.example.com".to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "worker-1");
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+
+        assert_eq!(
+            agent_name(cert.der(), AgentFrom::Cn),
+            Some("worker-1".to_string())
+        );
+        assert_eq!(
+            agent_name(cert.der(), AgentFrom::San),
+            Some("worker-1.agents.example.com".to_string())
+        );
+        assert_eq!(agent_name(b"not a certificate", AgentFrom::Cn), None);
+    }
+
+    #[test]
+    fn test_client_auth() {
+        assert_eq!(
+            ClientAuth::from_vars(test_vars(vec![("TLS_CLIENT_AUTH", "optional")])),
+            Ok(None)
+        );
+        assert_eq!(
+            ClientAuth::from_vars(test_vars(vec![
+                ("TLS_CLIENT_CA_FILE", "/etc/https/agents-ca.pem"),
+                ("TLS_CLIENT_AUTH", "Optional"),
+                ("TLS_CLIENT_AGENT", "san"),
+            ])),
+            Ok(Some(ClientAuth {
+                ca_file: "/etc/https/agents-ca.pem".to_string(),
+                optional: true,
+                agent_from: AgentFrom::San,
+            }))
+        );
+        assert!(ClientAuth::from_vars(test_vars(vec![
+            ("TLS_CLIENT_CA_FILE", "ca.pem"),
+            ("TLS_CLIENT_AGENT", "uid"),
+        ]))
+        .is_err());
+    }
+}
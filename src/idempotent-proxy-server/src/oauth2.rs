@@ -0,0 +1,185 @@
+use http::HeaderValue;
+use idempotent_proxy_types::{err_string, unix_ms};
+use reqwest::Client;
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+// Tokens are refreshed early, when less than 1/5 of their lifetime (at most 5 minutes) is left.
+const MAX_REFRESH_BEFORE: u64 = 5 * 60 * 1000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Token {
+    access_token: String,
+    refresh_at: u64, // unix timestamp in milliseconds
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<u64>, // in seconds
+}
+
+// OAuth2 client credentials grant, the token is injected as a Bearer authorization header.
+#[derive(Clone, Default)]
+pub struct OAuth2Client {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scope: String,
+    pub audience: String,
+    token: Arc<RwLock<Option<Token>>>,
+}
+
+impl std::fmt::Debug for OAuth2Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OAuth2Client")
+            .field("token_url", &self.token_url)
+            .field("client_id", &self.client_id)
+            .field("scope", &self.scope)
+            .field("audience", &self.audience)
+            .finish()
+    }
+}
+
+impl PartialEq for OAuth2Client {
+    fn eq(&self, other: &Self) -> bool {
+        self.token_url == other.token_url
+            && self.client_id == other.client_id
+            && self.client_secret == other.client_secret
+            && self.scope == other.scope
+            && self.audience == other.audience
+    }
+}
+
+impl OAuth2Client {
+    pub fn set(&mut self, option: &str, value: &str) -> Result<(), String> {
+        let value = value.trim().to_string();
+        match option {
+            "OAUTH2_TOKEN_URL" => {
+                reqwest::Url::parse(&value)
+                    .map_err(|err| format!("invalid token url {}: {}", value, err))?;
+                self.token_url = value
+            }
+            "OAUTH2_CLIENT_ID" => self.client_id = value,
+            "OAUTH2_CLIENT_SECRET" => self.client_secret = value,
+            "OAUTH2_SCOPE" => self.scope = value,
+            "OAUTH2_AUDIENCE" => self.audience = value,
+            _ => return Err(format!("unknown route option: {}", option)),
+        }
+        // a route may clone the default client, it must not share the cached token.
+        self.token = Arc::new(RwLock::new(None));
+        Ok(())
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        if self.token_url.is_empty() || self.client_id.is_empty() {
+            return Err("OAUTH2_TOKEN_URL and OAUTH2_CLIENT_ID are required".to_string());
+        }
+        Ok(())
+    }
+
+    pub async fn authorization(&self, http_client: &Client) -> Result<HeaderValue, String> {
+        let token = self.access_token(http_client).await?;
+        HeaderValue::from_str(&format!("Bearer {}", token)).map_err(err_string)
+    }
+
+    // Drops the cached token, e.g. when the upstream rejects it with 401.
+    pub async fn invalidate(&self) {
+        *self.token.write().await = None;
+    }
+
+    async fn access_token(&self, http_client: &Client) -> Result<String, String> {
+        if let Some(t) = self.token.read().await.as_ref() {
+            if t.refresh_at > unix_ms() {
+                return Ok(t.access_token.clone());
+            }
+        }
+
+        // only one request refreshes the token, others wait for it.
+        let mut token = self.token.write().await;
+        if let Some(t) = token.as_ref() {
+            if t.refresh_at > unix_ms() {
+                return Ok(t.access_token.clone());
+            }
+        }
+
+        let t = self.fetch_token(http_client).await?;
+        let access_token = t.access_token.clone();
+        *token = Some(t);
+        Ok(access_token)
+    }
+
+    async fn fetch_token(&self, http_client: &Client) -> Result<Token, String> {
+        let mut params = vec![("grant_type", "client_credentials")];
+        if !self.scope.is_empty() {
+            params.push(("scope", &self.scope));
+        }
+        if !self.audience.is_empty() {
+            params.push(("audience", &self.audience));
+        }
+        let now = unix_ms();
+        let res = http_client
+            .post(&self.token_url)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&params)
+            .send()
+            .await
+            .map_err(err_string)?;
+        let status = res.status();
+        let data = res.bytes().await.map_err(err_string)?;
+        if !status.is_success() {
+            return Err(format!(
+                "fetch OAuth2 token failed, status: {}, body: {}",
+                status,
+                String::from_utf8_lossy(&data)
+            ));
+        }
+
+        let tr: TokenResponse = serde_json::from_slice(&data).map_err(err_string)?;
+        Ok(Token {
+            access_token: tr.access_token,
+            refresh_at: refresh_at(now, tr.expires_in),
+        })
+    }
+}
+
+pub fn refresh_at(now: u64, expires_in: Option<u64>) -> u64 {
+    // tokens without expires_in are refreshed every 5 minutes
+    let lifetime = expires_in.map(|s| s * 1000).unwrap_or(MAX_REFRESH_BEFORE);
+    now + lifetime - (lifetime / 5).min(MAX_REFRESH_BEFORE)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_refresh_at() {
+        assert_eq!(
+            refresh_at(1000, Some(3600)),
+            1000 + 3600 * 1000 - 300 * 1000
+        );
+        assert_eq!(refresh_at(1000, Some(60)), 1000 + 48 * 1000);
+        assert_eq!(refresh_at(1000, None), 1000 + 240 * 1000);
+        assert_eq!(refresh_at(1000, Some(0)), 1000);
+    }
+
+    #[test]
+    fn test_oauth2_client() {
+        let mut client = OAuth2Client::default();
+        assert!(client.check().is_err());
+        client
+            .set("OAUTH2_TOKEN_URL", "https://auth.example.com/oauth/token")
+            .unwrap();
+        client.set("OAUTH2_CLIENT_ID", "proxy").unwrap();
+        assert!(client.check().is_ok());
+        assert!(client.set("OAUTH2_TOKEN_URL", "not a url").is_err());
+
+        let other = client.clone();
+        client.set("OAUTH2_SCOPE", "read").unwrap();
+        assert_ne!(client, other);
+        assert!(!Arc::ptr_eq(&client.token, &other.token));
+    }
+}
@@ -0,0 +1,152 @@
+use http::{HeaderMap, HeaderName, HeaderValue};
+use idempotent_proxy_types::err_string;
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::{SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue, Value,
+};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+
+const TRACER: &str = "idempotent-proxy-server";
+
+// Installs the W3C trace context propagator, and the OTLP (gRPC) exporter when
+// OTEL_EXPORTER_OTLP_ENDPOINT or OTEL_EXPORTER_OTLP_TRACES_ENDPOINT is set. Without an
+// exporter the incoming `traceparent` is still passed on to the upstream.
+pub fn init(vars: impl Iterator<Item = (String, String)>) -> Result<bool, String> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let vars: Vec<(String, String)> = vars.collect();
+    let var = |name: &str| vars.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone());
+    if var("OTEL_EXPORTER_OTLP_ENDPOINT").is_none()
+        && var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").is_none()
+    {
+        return Ok(false);
+    }
+
+    // the exporter reads the endpoint, headers and timeout from the OTEL_EXPORTER_OTLP_* variables
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .build()
+        .map_err(err_string)?;
+    let provider = trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            var("OTEL_SERVICE_NAME").unwrap_or(TRACER.to_string()),
+        )]))
+        .build();
+    global::set_tracer_provider(provider);
+    Ok(true)
+}
+
+// Flushes the spans not exported yet.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+// The span of a request step, it ends when the last clone is dropped.
+#[derive(Clone, Default)]
+pub struct Trace(Context);
+
+impl Trace {
+    // Starts the server span of a request, a child of the incoming `traceparent` if any.
+    pub fn server(headers: &HeaderMap, name: &'static str) -> Self {
+        let parent = global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(headers)));
+        let tracer = global::tracer(TRACER);
+        let span = tracer
+            .span_builder(name)
+            .with_kind(SpanKind::Server)
+            .start_with_context(&tracer, &parent);
+        Trace(parent.with_span(span))
+    }
+
+    pub fn span(&self, name: &'static str) -> Self {
+        self.child(name, SpanKind::Internal)
+    }
+
+    // A span of a call to the upstream, its context goes out with the request.
+    pub fn client(&self, name: &'static str) -> Self {
+        self.child(name, SpanKind::Client)
+    }
+
+    fn child(&self, name: &'static str, kind: SpanKind) -> Self {
+        let tracer = global::tracer(TRACER);
+        let span = tracer
+            .span_builder(name)
+            .with_kind(kind)
+            .start_with_context(&tracer, &self.0);
+        Trace(self.0.with_span(span))
+    }
+
+    pub fn set_attribute(&self, key: &'static str, value: impl Into<Value>) {
+        self.0.span().set_attribute(KeyValue::new(key, value));
+    }
+
+    pub fn set_error(&self, message: &str) {
+        self.0.span().set_status(Status::error(message.to_string()));
+    }
+
+    pub fn record<T>(&self, res: &Result<T, String>) {
+        if let Err(err) = res {
+            self.set_error(err);
+        }
+    }
+
+    // Replaces the trace context headers with the context of this span.
+    pub fn inject(&self, headers: &mut HeaderMap) {
+        global::get_text_map_propagator(|p| {
+            p.inject_context(&self.0, &mut HeaderInjector(headers))
+        });
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_propagation() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let parent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", parent.parse().unwrap());
+        let trace = Trace::server(&headers, "proxy");
+        let upstream = trace.client("upstream");
+        upstream.set_attribute("http.response.status_code", 200i64);
+
+        let mut out = HeaderMap::new();
+        upstream.inject(&mut out);
+        let traceparent = out.get("traceparent").unwrap().to_str().unwrap();
+        // the same trace, without an SDK the span of the proxy keeps the incoming span id
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+
+        let mut out = HeaderMap::new();
+        Trace::default().span("store").inject(&mut out);
+        assert!(out.get("traceparent").is_none());
+    }
+}
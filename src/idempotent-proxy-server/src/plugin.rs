@@ -0,0 +1,158 @@
+use bytes::Bytes;
+use idempotent_proxy_types::err_string;
+use std::sync::Arc;
+use wasmtime::{Config, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+// Bounds of a single transformation, a plugin exceeding them fails the response.
+const MAX_FUEL: u64 = 100_000_000;
+const MAX_MEMORY: usize = 64 * 1024 * 1024;
+
+// WebAssembly module attached to a route, run over upstream response bodies before caching.
+// The module has no imports and exports:
+//   memory
+//   alloc(len: i32) -> i32, a buffer for the body in the memory
+//   transform(status: i32, ptr: i32, len: i32) -> i64, the transformed body as
+//     (ptr << 32 | len), or a negative value to refuse the response, which is not cached.
+// Every call runs in a fresh instance, plugins keep no state between responses.
+pub struct ResponsePlugin {
+    path: String,
+    engine: Engine,
+    module: Module,
+}
+
+impl ResponsePlugin {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|err| format!("read {} failed: {}", path, err))?;
+        Self::new(path, &data)
+    }
+
+    // Accepts a binary module, or a text one in tests.
+    pub fn new(path: &str, wasm: &[u8]) -> Result<Self, String> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(err_string)?;
+        let module = Module::new(&engine, wasm)
+            .map_err(|err| format!("invalid plugin {}: {}", path, err))?;
+        if let Some(import) = module.imports().next() {
+            return Err(format!(
+                "plugin {} may not import {}.{}",
+                path,
+                import.module(),
+                import.name()
+            ));
+        }
+        for export in ["memory", "alloc", "transform"] {
+            if module.get_export(export).is_none() {
+                return Err(format!("plugin {} does not export {}", path, export));
+            }
+        }
+        Ok(Self {
+            path: path.to_string(),
+            engine,
+            module,
+        })
+    }
+
+    // Runs the plugin on the blocking pool, it is CPU bound.
+    pub async fn transform(self: &Arc<Self>, status: u16, body: Bytes) -> Result<Bytes, String> {
+        let plugin = self.clone();
+        tokio::task::spawn_blocking(move || plugin.run(status, &body))
+            .await
+            .map_err(err_string)?
+            .map(Bytes::from)
+    }
+
+    pub fn run(&self, status: u16, body: &[u8]) -> Result<Vec<u8>, String> {
+        let len = i32::try_from(body.len()).map_err(err_string)?;
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits: &mut StoreLimits| limits);
+        store.set_fuel(MAX_FUEL).map_err(err_string)?;
+
+        let instance = Instance::new(&mut store, &self.module, &[]).map_err(err_string)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| "plugin memory not found".to_string())?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "alloc")
+            .map_err(err_string)?;
+        let transform = instance
+            .get_typed_func::<(i32, i32, i32), i64>(&mut store, "transform")
+            .map_err(err_string)?;
+
+        let ptr = alloc.call(&mut store, len).map_err(err_string)?;
+        memory
+            .write(&mut store, ptr as u32 as usize, body)
+            .map_err(err_string)?;
+        let res = transform
+            .call(&mut store, (status as i32, ptr, len))
+            .map_err(|err| format!("plugin {} failed: {}", self.path, err))?;
+        if res < 0 {
+            return Err(format!("plugin {} refused the response", self.path));
+        }
+
+        let mut out = vec![0u8; res as u32 as usize];
+        memory
+            .read(&store, (res >> 32) as u32 as usize, &mut out)
+            .map_err(err_string)?;
+        Ok(out)
+    }
+}
+
+impl std::fmt::Debug for ResponsePlugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponsePlugin")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl PartialEq for ResponsePlugin {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Strips the first and last bytes of successful responses, refuses others.
+    const TRIM: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (global $next (mut i32) (i32.const 1024))
+            (func (export "alloc") (param $len i32) (result i32)
+                (local $ptr i32)
+                (local.set $ptr (global.get $next))
+                (global.set $next (i32.add (global.get $next) (local.get $len)))
+                (local.get $ptr))
+            (func (export "transform") (param $status i32) (param $ptr i32) (param $len i32) (result i64)
+                (if (i32.ne (local.get $status) (i32.const 200))
+                    (then (return (i64.const -1))))
+                (i64.or
+                    (i64.shl (i64.extend_i32_u (i32.add (local.get $ptr) (i32.const 1))) (i64.const 32))
+                    (i64.extend_i32_u (i32.sub (local.get $len) (i32.const 2))))))
+    "#;
+
+    #[test]
+    fn test_response_plugin() {
+        let plugin = ResponsePlugin::new("trim.wat", TRIM.as_bytes()).unwrap();
+        assert_eq!(plugin.run(200, b"[1,2]").unwrap(), b"1,2");
+        assert_eq!(plugin.run(200, b"[]").unwrap(), b"");
+        assert!(plugin.run(404, b"[1,2]").is_err());
+
+        let spin = TRIM.replace("(if (i32.ne", "(loop $spin (br $spin))\n(if (i32.ne");
+        let plugin = ResponsePlugin::new("spin.wat", spin.as_bytes()).unwrap();
+        assert!(plugin.run(200, b"[1,2]").unwrap_err().contains("failed"));
+
+        let env = TRIM.replace(
+            "(memory (export",
+            "(import \"env\" \"now\" (func (result i64)))\n(memory (export",
+        );
+        assert!(ResponsePlugin::new("env.wat", env.as_bytes())
+            .unwrap_err()
+            .contains("may not import"));
+        assert!(ResponsePlugin::new("empty.wat", b"(module)").is_err());
+    }
+}
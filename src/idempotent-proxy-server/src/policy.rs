@@ -0,0 +1,182 @@
+use http::Method;
+use std::collections::BTreeMap;
+
+use crate::agents::{glob_match, AgentSet};
+use crate::routes::split_list;
+
+const PREFIX: &str = "POLICY_";
+
+// Where an agent may forward requests, configured in named groups:
+// POLICY_<NAME>_AGENTS="web,worker-*", POLICY_<NAME>_HOSTS="api.example.com,*.example.org",
+// optionally POLICY_<NAME>_PATHS="/v1/,/health" (path prefixes) and POLICY_<NAME>_METHODS="GET,POST".
+#[derive(Debug, Default)]
+struct Policy {
+    agents: AgentSet,
+    hosts: Vec<String>,   // lowercase globs
+    paths: Vec<String>,   // any path if empty
+    methods: Vec<Method>, // any method if empty
+}
+
+impl Policy {
+    fn allows(&self, method: &Method, url: &reqwest::Url) -> bool {
+        let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+        self.hosts
+            .iter()
+            .any(|h| glob_match(h.as_bytes(), host.as_bytes()))
+            && (self.paths.is_empty() || self.paths.iter().any(|p| url.path().starts_with(p)))
+            && (self.methods.is_empty() || self.methods.contains(method))
+    }
+}
+
+// Agents in some policies may only forward to the URLs one of them allows,
+// agents in no policy are not restricted.
+#[derive(Debug, Default)]
+pub struct Policies(Vec<Policy>);
+
+impl Policies {
+    pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Self, String> {
+        let mut groups: BTreeMap<String, (Option<AgentSet>, Policy)> = BTreeMap::new();
+        for (k, v) in vars {
+            let Some(name_option) = k.strip_prefix(PREFIX) else {
+                continue;
+            };
+            let (name, option) = ["AGENTS", "HOSTS", "PATHS", "METHODS"]
+                .iter()
+                .find_map(|option| {
+                    name_option
+                        .strip_suffix(option)
+                        .and_then(|name| name.strip_suffix('_'))
+                        .filter(|name| !name.is_empty())
+                        .map(|name| (name.to_string(), *option))
+                })
+                .ok_or_else(|| format!("unknown policy option: {}", k))?;
+            let (agents, policy) = groups.entry(name).or_default();
+            match option {
+                "AGENTS" => *agents = Some(v.parse()?),
+                "HOSTS" => {
+                    policy.hosts = split_list(&v)
+                        .iter()
+                        .map(|h| h.to_ascii_lowercase())
+                        .collect()
+                }
+                "PATHS" => {
+                    policy.paths = split_list(&v)
+                        .iter()
+                        .map(|p| {
+                            if p.starts_with('/') {
+                                Ok(p.to_string())
+                            } else {
+                                Err(format!("invalid path prefix {} in {}", p, k))
+                            }
+                        })
+                        .collect::<Result<_, _>>()?
+                }
+                _ => {
+                    policy.methods = split_list(&v)
+                        .iter()
+                        .map(|m| {
+                            Method::from_bytes(m.to_ascii_uppercase().as_bytes())
+                                .map_err(|_| format!("invalid method {} in {}", m, k))
+                        })
+                        .collect::<Result<_, _>>()?
+                }
+            }
+        }
+
+        let mut policies = Vec::with_capacity(groups.len());
+        for (name, group) in groups {
+            match group {
+                (Some(agents), mut policy) if !policy.hosts.is_empty() => {
+                    policy.agents = agents;
+                    policies.push(policy);
+                }
+                _ => return Err(format!("{}{} needs both AGENTS and HOSTS", PREFIX, name)),
+            }
+        }
+        Ok(Policies(policies))
+    }
+
+    pub fn allows(&self, agent: &str, method: &Method, url: &reqwest::Url) -> bool {
+        let mut restricted = false;
+        for policy in self.0.iter().filter(|p| p.agents.contains(agent)) {
+            if policy.allows(method, url) {
+                return true;
+            }
+            restricted = true;
+        }
+        !restricted
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_policies() {
+        let vars = [
+            ("POLICY_PAYMENTS_AGENTS", "billing, worker-*"),
+            ("POLICY_PAYMENTS_HOSTS", "api.stripe.com, *.Example.com"),
+            ("POLICY_PAYMENTS_PATHS", "/v1/"),
+            ("POLICY_READS_AGENTS", "worker-*"),
+            ("POLICY_READS_HOSTS", "httpbin.org"),
+            ("POLICY_READS_METHODS", "get"),
+            ("ALLOW_AGENTS", "billing"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        let p = Policies::from_vars(vars).unwrap();
+        let url = |s: &str| reqwest::Url::parse(s).unwrap();
+
+        assert!(p.allows(
+            "billing",
+            &Method::POST,
+            &url("https://api.stripe.com/v1/charges")
+        ));
+        assert!(p.allows(
+            "billing",
+            &Method::POST,
+            &url("https://EU.example.com/v1/x")
+        ));
+        assert!(!p.allows(
+            "billing",
+            &Method::POST,
+            &url("https://api.stripe.com/v2/charges")
+        ));
+        assert!(!p.allows("billing", &Method::POST, &url("https://example.com/v1/x")));
+        assert!(!p.allows("billing", &Method::GET, &url("http://169.254.169.254/v1/")));
+        // dot segments are resolved before matching
+        assert!(!p.allows(
+            "billing",
+            &Method::GET,
+            &url("https://api.stripe.com/v1/../admin")
+        ));
+        // an agent in several policies gets all of them
+        assert!(p.allows("worker-1", &Method::GET, &url("https://httpbin.org/get")));
+        assert!(!p.allows("worker-1", &Method::POST, &url("https://httpbin.org/post")));
+        assert!(p.allows(
+            "worker-1",
+            &Method::POST,
+            &url("https://api.stripe.com/v1/x")
+        ));
+        // agents in no policy are not restricted
+        assert!(p.allows("other", &Method::GET, &url("http://10.0.0.1/")));
+
+        for vars in [
+            vec![("POLICY_A_AGENTS", "billing")],
+            vec![("POLICY_A_HOSTS", "example.com")],
+            vec![("POLICY_A_AGENTS", "billing"), ("POLICY_A_HOSTS", "")],
+            vec![("POLICY_A_UNKNOWN", "1")],
+            vec![
+                ("POLICY_A_AGENTS", "billing"),
+                ("POLICY_A_HOSTS", "example.com"),
+                ("POLICY_A_PATHS", "v1"),
+            ],
+        ] {
+            let vars = vars
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()));
+            assert!(Policies::from_vars(vars).is_err());
+        }
+    }
+}
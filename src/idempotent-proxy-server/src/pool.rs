@@ -0,0 +1,229 @@
+use idempotent_proxy_types::{err_string, unix_ms};
+use reqwest::{redirect, Client, ClientBuilder};
+use std::{
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use crate::dns;
+
+// The upstream HTTP client settings shared by the global client and the route pools.
+pub fn client_builder(req_timeout: u64) -> ClientBuilder {
+    let builder = ClientBuilder::new()
+        .http2_keep_alive_interval(Some(Duration::from_secs(25)))
+        .http2_keep_alive_timeout(Duration::from_secs(15))
+        .http2_keep_alive_while_idle(true)
+        .connect_timeout(Duration::from_secs(10))
+        .timeout(Duration::from_millis(req_timeout))
+        .gzip(true);
+    match dns::resolver() {
+        Some(resolver) => builder.dns_resolver(resolver),
+        None => builder,
+    }
+}
+
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedirectPolicy {
+    None,            // 3xx responses are returned as is
+    Follow(usize),   // follow up to N redirects
+    SameHost(usize), // follow up to N redirects to the same host, others are returned as is
+}
+
+impl FromStr for RedirectPolicy {
+    type Err = String;
+
+    // "none", "follow", "follow:5", "same-host" or "same-host:3"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        let (name, n) = match s.split_once(':') {
+            Some((name, n)) => (
+                name,
+                n.trim()
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid redirect policy: {}", s))?,
+            ),
+            None => (s.as_str(), DEFAULT_MAX_REDIRECTS),
+        };
+        match name.trim() {
+            "none" => Ok(RedirectPolicy::None),
+            "follow" => Ok(RedirectPolicy::Follow(n)),
+            "same-host" => Ok(RedirectPolicy::SameHost(n)),
+            _ => Err(format!("invalid redirect policy: {}", s)),
+        }
+    }
+}
+
+impl RedirectPolicy {
+    fn to_policy(self) -> redirect::Policy {
+        match self {
+            RedirectPolicy::None => redirect::Policy::none(),
+            RedirectPolicy::Follow(n) => redirect::Policy::limited(n),
+            RedirectPolicy::SameHost(n) => redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() > n {
+                    attempt.error("too many redirects")
+                } else if attempt.url().host_str()
+                    != attempt.previous().first().and_then(|u| u.host_str())
+                {
+                    attempt.stop()
+                } else {
+                    attempt.follow()
+                }
+            }),
+        }
+    }
+}
+
+// A client with its own connection pool and settings for a route,
+// routes without these options use the global client.
+#[derive(Clone, Default)]
+pub struct Pool {
+    pub max_idle_per_host: Option<usize>,
+    pub idle_timeout: Option<u64>,  // in seconds
+    pub max_lifetime: Option<u64>,  // in seconds
+    pub tcp_keepalive: Option<u64>, // in seconds
+    pub redirect: Option<RedirectPolicy>,
+    client: Arc<RwLock<Option<(Client, u64)>>>,
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pool")
+            .field("max_idle_per_host", &self.max_idle_per_host)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_lifetime", &self.max_lifetime)
+            .field("tcp_keepalive", &self.tcp_keepalive)
+            .field("redirect", &self.redirect)
+            .finish()
+    }
+}
+
+impl PartialEq for Pool {
+    fn eq(&self, other: &Self) -> bool {
+        self.max_idle_per_host == other.max_idle_per_host
+            && self.idle_timeout == other.idle_timeout
+            && self.max_lifetime == other.max_lifetime
+            && self.tcp_keepalive == other.tcp_keepalive
+            && self.redirect == other.redirect
+    }
+}
+
+impl Pool {
+    pub fn set(&mut self, option: &str, value: &str) -> Result<(), String> {
+        // a route may clone the default pool, it must not share the client.
+        self.client = Arc::new(RwLock::new(None));
+        if option == "REDIRECT_POLICY" {
+            self.redirect = Some(value.parse()?);
+            return Ok(());
+        }
+
+        let n = value
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| format!("invalid {} value: {}", option, value))?;
+        match option {
+            "POOL_MAX_IDLE_PER_HOST" => self.max_idle_per_host = Some(n as usize),
+            "POOL_IDLE_TIMEOUT" => self.idle_timeout = Some(n),
+            "POOL_MAX_LIFETIME" => self.max_lifetime = Some(n),
+            "TCP_KEEPALIVE" => self.tcp_keepalive = Some(n),
+            _ => return Err(format!("unknown route option: {}", option)),
+        }
+        Ok(())
+    }
+
+    // Returns the pool's client, it is rebuilt with fresh connections after `max_lifetime`,
+    // connections of the old client are closed when in-flight requests finish.
+    pub fn client(&self, req_timeout: u64) -> Result<Client, String> {
+        let now = unix_ms();
+        if let Some((client, created_at)) = self.client.read().unwrap().as_ref() {
+            if !self.expired(*created_at, now) {
+                return Ok(client.clone());
+            }
+        }
+
+        let mut guard = self.client.write().unwrap();
+        if let Some((client, created_at)) = guard.as_ref() {
+            if !self.expired(*created_at, now) {
+                return Ok(client.clone());
+            }
+        }
+        let client = self.builder(req_timeout).build().map_err(err_string)?;
+        *guard = Some((client.clone(), now));
+        Ok(client)
+    }
+
+    fn expired(&self, created_at: u64, now: u64) -> bool {
+        match self.max_lifetime {
+            Some(secs) if secs > 0 => created_at + secs * 1000 <= now,
+            _ => false,
+        }
+    }
+
+    fn builder(&self, req_timeout: u64) -> ClientBuilder {
+        let mut builder = client_builder(req_timeout);
+        if let Some(n) = self.max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(n);
+        }
+        if let Some(secs) = self.idle_timeout {
+            // 0 disables the idle timeout
+            builder = builder.pool_idle_timeout(if secs > 0 {
+                Some(Duration::from_secs(secs))
+            } else {
+                None
+            });
+        }
+        if let Some(policy) = self.redirect {
+            builder = builder.redirect(policy.to_policy());
+        }
+        if let Some(secs) = self.tcp_keepalive {
+            builder = builder.tcp_keepalive(if secs > 0 {
+                Some(Duration::from_secs(secs))
+            } else {
+                None
+            });
+        }
+        builder
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redirect_policy() {
+        assert_eq!("none".parse::<RedirectPolicy>(), Ok(RedirectPolicy::None));
+        assert_eq!(
+            "follow".parse::<RedirectPolicy>(),
+            Ok(RedirectPolicy::Follow(10))
+        );
+        assert_eq!(
+            "Same-Host:3".parse::<RedirectPolicy>(),
+            Ok(RedirectPolicy::SameHost(3))
+        );
+        assert!("follow:x".parse::<RedirectPolicy>().is_err());
+        assert!("always".parse::<RedirectPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_pool() {
+        let mut pool = Pool::default();
+        pool.set("POOL_MAX_IDLE_PER_HOST", "8").unwrap();
+        pool.set("POOL_IDLE_TIMEOUT", "30").unwrap();
+        assert!(pool.set("TCP_KEEPALIVE", "abc").is_err());
+        assert_eq!(pool.max_idle_per_host, Some(8));
+        assert_eq!(pool.idle_timeout, Some(30));
+
+        assert!(!pool.expired(1000, 1000 + 3600 * 1000));
+        pool.set("POOL_MAX_LIFETIME", "60").unwrap();
+        assert!(!pool.expired(1000, 1000 + 59 * 1000));
+        assert!(pool.expired(1000, 1000 + 60 * 1000));
+
+        let other = pool.clone();
+        pool.set("TCP_KEEPALIVE", "15").unwrap();
+        assert_ne!(pool, other);
+        assert!(!Arc::ptr_eq(&pool.client, &other.client));
+    }
+}
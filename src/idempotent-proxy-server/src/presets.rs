@@ -0,0 +1,122 @@
+use axum::body::Bytes;
+use idempotent_proxy_types::err_string;
+use serde_json::Value;
+use std::str::FromStr;
+
+use crate::cache::ResponseData;
+
+// Response normalization presets for common providers, applied before caching
+// so that every IC replica receives byte-identical responses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preset {
+    EthereumJsonRpc,
+    BitcoinRpc,
+    Exchange,
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "ethereum" | "eth" | "ethereum-jsonrpc" => Ok(Preset::EthereumJsonRpc),
+            "bitcoin" | "btc" | "bitcoin-rpc" => Ok(Preset::BitcoinRpc),
+            "exchange" => Ok(Preset::Exchange),
+            v => Err(format!("invalid response preset: {}", v)),
+        }
+    }
+}
+
+impl Preset {
+    // Fields removed from JSON objects at any depth.
+    pub fn volatile_fields(&self) -> &'static [&'static str] {
+        match self {
+            // JSON-RPC responses only echo the request, canonicalization is enough
+            Preset::EthereumJsonRpc | Preset::BitcoinRpc => &[],
+            Preset::Exchange => &[
+                "serverTime",
+                "server_time",
+                "timestamp",
+                "requestId",
+                "request_id",
+                "traceId",
+                "trace_id",
+            ],
+        }
+    }
+
+    pub fn apply(
+        &self,
+        rd: &mut ResponseData,
+        extra_volatile_fields: &[String],
+    ) -> Result<(), String> {
+        // Date, Set-Cookie, request ids and so on differ between replicas.
+        rd.headers.clear();
+
+        if !rd.mime.contains("application/json") || rd.body.is_empty() {
+            return Ok(());
+        }
+
+        let mut obj: Value = serde_json::from_slice(&rd.body).map_err(err_string)?;
+        let fields: Vec<&str> = self
+            .volatile_fields()
+            .iter()
+            .copied()
+            .chain(extra_volatile_fields.iter().map(|s| s.as_str()))
+            .collect();
+        remove_fields(&mut obj, &fields);
+        // serde_json::Map is ordered by keys, so the output is canonical (sorted keys, no whitespace)
+        rd.body = Bytes::from(serde_json::to_vec(&obj).map_err(err_string)?);
+        Ok(())
+    }
+}
+
+fn remove_fields(value: &mut Value, fields: &[&str]) {
+    if fields.is_empty() {
+        return;
+    }
+
+    match value {
+        Value::Object(obj) => {
+            obj.retain(|k, _| !fields.contains(&k.as_str()));
+            obj.values_mut().for_each(|v| remove_fields(v, fields));
+        }
+        Value::Array(arr) => arr.iter_mut().for_each(|v| remove_fields(v, fields)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_preset() {
+        assert_eq!("eth".parse::<Preset>().unwrap(), Preset::EthereumJsonRpc);
+        assert_eq!("Bitcoin".parse::<Preset>().unwrap(), Preset::BitcoinRpc);
+        assert!("unknown".parse::<Preset>().is_err());
+
+        let mut rd = ResponseData::new(200);
+        rd.mime = "application/json; charset=utf-8".to_string();
+        rd.headers.push((
+            "date".to_string(),
+            "Wed, 22 May 2024 11:11:17 GMT".to_string(),
+        ));
+        rd.body = Bytes::from(
+            br#"{ "symbol": "ICPUSDT", "price": "10.5", "serverTime": 1716376277000, "data": [{"timestamp": 1, "v": 2}] }"#
+                .to_vec(),
+        );
+        Preset::Exchange.apply(&mut rd, &["v".to_string()]).unwrap();
+        assert!(rd.headers.is_empty());
+        assert_eq!(
+            &rd.body[..],
+            br#"{"data":[{}],"price":"10.5","symbol":"ICPUSDT"}"#
+        );
+
+        let mut rd = ResponseData::new(200);
+        rd.mime = "application/json".to_string();
+        rd.body = Bytes::from(br#"{"result": 800000, "error": null, "id": 1}"#.to_vec());
+        Preset::BitcoinRpc.apply(&mut rd, &[]).unwrap();
+        assert_eq!(&rd.body[..], br#"{"error":null,"id":1,"result":800000}"#);
+    }
+}
@@ -0,0 +1,94 @@
+// On-demand pprof profiles, available in builds with the `profiling` feature.
+// The CPU profile samples the process for a while, the heap profile dumps the
+// allocations sampled by jemalloc since the start.
+#[cfg(feature = "profiling")]
+mod enabled {
+    use http::StatusCode;
+    use pprof::protos::Message;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // a process can only run one CPU profiler at a time
+    static CPU_PROFILING: AtomicBool = AtomicBool::new(false);
+
+    fn internal_error(err: impl std::fmt::Display) -> (StatusCode, String) {
+        (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+    }
+
+    pub async fn cpu_profile(
+        seconds: u64,
+        frequency: i32,
+    ) -> Result<Vec<u8>, (StatusCode, String)> {
+        if CPU_PROFILING.swap(true, Ordering::AcqRel) {
+            return Err((
+                StatusCode::CONFLICT,
+                "a CPU profile is in progress".to_string(),
+            ));
+        }
+        let res = tokio::task::spawn_blocking(move || {
+            let guard = pprof::ProfilerGuardBuilder::default()
+                .frequency(frequency)
+                .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+                .build()
+                .map_err(internal_error)?;
+            std::thread::sleep(std::time::Duration::from_secs(seconds));
+            let profile = guard
+                .report()
+                .build()
+                .and_then(|report| report.pprof())
+                .map_err(internal_error)?;
+            let mut body = Vec::new();
+            profile.encode(&mut body).map_err(internal_error)?;
+            Ok(body)
+        })
+        .await
+        .map_err(internal_error);
+        CPU_PROFILING.store(false, Ordering::Release);
+        res?
+    }
+
+    pub async fn heap_profile() -> Result<Vec<u8>, (StatusCode, String)> {
+        let ctl = jemalloc_pprof::PROF_CTL.as_ref().ok_or_else(|| {
+            (
+                StatusCode::NOT_IMPLEMENTED,
+                "jemalloc heap profiling is not available".to_string(),
+            )
+        })?;
+        let mut ctl = ctl.lock().await;
+        if !ctl.activated() {
+            return Err((
+                StatusCode::NOT_IMPLEMENTED,
+                "jemalloc heap profiling is not active".to_string(),
+            ));
+        }
+        ctl.dump_pprof().map_err(internal_error)
+    }
+}
+
+#[cfg(feature = "profiling")]
+pub use enabled::*;
+
+#[cfg(not(feature = "profiling"))]
+mod disabled {
+    use http::StatusCode;
+
+    fn not_built() -> (StatusCode, String) {
+        (
+            StatusCode::NOT_IMPLEMENTED,
+            "built without the profiling feature".to_string(),
+        )
+    }
+
+    pub async fn cpu_profile(
+        _seconds: u64,
+        _frequency: i32,
+    ) -> Result<Vec<u8>, (StatusCode, String)> {
+        Err(not_built())
+    }
+
+    pub async fn heap_profile() -> Result<Vec<u8>, (StatusCode, String)> {
+        Err(not_built())
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+pub use disabled::*;
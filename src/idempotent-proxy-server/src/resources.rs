@@ -0,0 +1,181 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+// upper bounds of the upstream latency histogram buckets, in milliseconds
+pub const LATENCY_BUCKETS: [u64; 12] =
+    [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000];
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct UpstreamUsage {
+    pub active: u64, // requests waiting for or reading an upstream response
+    pub max_active: u64,
+    pub total: u64,
+    // requests per LATENCY_BUCKETS bucket and an overflow bucket, until the response is read
+    pub latency_buckets: Vec<u64>,
+    pub latency_sum: u64, // in milliseconds
+}
+
+// Counts the requests being served and the upstream requests per backend host,
+// reqwest does not expose the state of its connection pools.
+#[derive(Debug, Default)]
+pub struct Resources {
+    active_requests: AtomicUsize,
+    upstreams: Mutex<BTreeMap<String, UpstreamUsage>>,
+}
+
+impl Resources {
+    pub fn request(&self) -> RequestGuard<'_> {
+        self.active_requests.fetch_add(1, Ordering::Relaxed);
+        RequestGuard(self)
+    }
+
+    pub fn active_requests(&self) -> usize {
+        self.active_requests.load(Ordering::Relaxed)
+    }
+
+    pub fn upstream(&self, host: &str) -> UpstreamGuard<'_> {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        if !upstreams.contains_key(host) {
+            upstreams.insert(host.to_string(), UpstreamUsage::default());
+        }
+        let usage = upstreams.get_mut(host).unwrap();
+        usage.active += 1;
+        usage.total += 1;
+        usage.max_active = usage.max_active.max(usage.active);
+        UpstreamGuard {
+            resources: self,
+            host: host.to_string(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn upstreams(&self) -> BTreeMap<String, UpstreamUsage> {
+        self.upstreams.lock().unwrap().clone()
+    }
+}
+
+pub struct RequestGuard<'a>(&'a Resources);
+
+impl Drop for RequestGuard<'_> {
+    fn drop(&mut self) {
+        self.0.active_requests.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct UpstreamGuard<'a> {
+    resources: &'a Resources,
+    host: String,
+    started: Instant,
+}
+
+impl Drop for UpstreamGuard<'_> {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        if let Some(usage) = self.resources.upstreams.lock().unwrap().get_mut(&self.host) {
+            usage.active -= 1;
+            if usage.latency_buckets.is_empty() {
+                usage.latency_buckets = vec![0; LATENCY_BUCKETS.len() + 1];
+            }
+            let i = LATENCY_BUCKETS
+                .iter()
+                .position(|&le| elapsed <= le)
+                .unwrap_or(LATENCY_BUCKETS.len());
+            usage.latency_buckets[i] += 1;
+            usage.latency_sum += elapsed;
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ProcessStats {
+    pub rss: Option<u64>, // resident memory in bytes
+    pub threads: Option<u64>,
+    pub open_fds: Option<usize>,
+    pub open_sockets: Option<usize>, // client, upstream and storage connections and listeners
+}
+
+// Reads the process stats from /proc, they are None on other platforms.
+pub fn process_stats() -> ProcessStats {
+    let mut stats = ProcessStats::default();
+    if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
+        for line in status.lines() {
+            let value = |prefix: &str| {
+                line.strip_prefix(prefix)
+                    .and_then(|v| v.split_whitespace().next())
+                    .and_then(|v| v.parse::<u64>().ok())
+            };
+            if let Some(kb) = value("VmRSS:") {
+                stats.rss = Some(kb * 1024);
+            } else if let Some(n) = value("Threads:") {
+                stats.threads = Some(n);
+            }
+        }
+    }
+    if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+        let (mut open_fds, mut open_sockets) = (0, 0);
+        for fd in fds.flatten() {
+            open_fds += 1;
+            if std::fs::read_link(fd.path())
+                .is_ok_and(|target| target.to_string_lossy().starts_with("socket:"))
+            {
+                open_sockets += 1;
+            }
+        }
+        stats.open_fds = Some(open_fds);
+        stats.open_sockets = Some(open_sockets);
+    }
+    stats
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resources() {
+        let resources = Resources::default();
+        let r1 = resources.request();
+        let r2 = resources.request();
+        assert_eq!(resources.active_requests(), 2);
+        drop(r1);
+        drop(r2);
+        assert_eq!(resources.active_requests(), 0);
+
+        let u1 = resources.upstream("httpbin.org");
+        let u2 = resources.upstream("httpbin.org");
+        let _u3 = resources.upstream("eth.llamarpc.com");
+        drop(u1);
+        let usage = resources.upstreams();
+        assert_eq!(usage["httpbin.org"].active, 1);
+        assert_eq!(usage["httpbin.org"].max_active, 2);
+        assert_eq!(usage["httpbin.org"].total, 2);
+        assert_eq!(usage["httpbin.org"].latency_buckets[0], 1);
+        assert_eq!(usage["httpbin.org"].latency_buckets.iter().sum::<u64>(), 1);
+        assert_eq!(usage["eth.llamarpc.com"].active, 1);
+        drop(u2);
+        assert_eq!(resources.upstreams()["httpbin.org"].active, 0);
+        assert_eq!(
+            resources.upstreams()["httpbin.org"]
+                .latency_buckets
+                .iter()
+                .sum::<u64>(),
+            2
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_process_stats() {
+        let stats = process_stats();
+        assert!(stats.rss.unwrap() > 0);
+        assert!(stats.threads.unwrap() > 0);
+        assert!(stats.open_fds.unwrap() > 0);
+    }
+}
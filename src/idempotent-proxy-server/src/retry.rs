@@ -0,0 +1,248 @@
+use http::{HeaderMap, StatusCode};
+use idempotent_proxy_types::auth::sha3_256;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// Retries of upstream requests that failed without a response worth caching, run while
+// the idempotency lock is held so duplicates keep waiting for the final response.
+// The delay doubles from `backoff` up to `max_backoff` (a Retry-After header in seconds
+// overrides it up to `max_backoff`), with a jitter derived from the idempotency key.
+// The retries to a host are limited by a budget: each request earns `budget` retries,
+// up to `budget_burst` saved ones, so an upstream outage is not amplified.
+#[derive(Clone, Debug)]
+pub struct Retry {
+    pub max_attempts: u32, // including the first one
+    pub backoff: u64,      // in milliseconds
+    pub max_backoff: u64,  // in milliseconds
+    pub statuses: Vec<StatusCode>,
+    pub on_connect: bool, // the connection failed, the upstream never got the request
+    pub on_timeout: bool, // the upstream may have processed the request
+    pub budget: f64,
+    pub budget_burst: f64,
+    tokens: Arc<Mutex<HashMap<String, f64>>>, // saved retries per host
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            max_attempts: 3,
+            backoff: 100,
+            max_backoff: 2000,
+            statuses: vec![
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ],
+            on_connect: true,
+            on_timeout: false,
+            budget: 0.2,
+            budget_burst: 10.0,
+            tokens: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl PartialEq for Retry {
+    fn eq(&self, other: &Self) -> bool {
+        self.max_attempts == other.max_attempts
+            && self.backoff == other.backoff
+            && self.max_backoff == other.max_backoff
+            && self.statuses == other.statuses
+            && self.on_connect == other.on_connect
+            && self.on_timeout == other.on_timeout
+            && self.budget == other.budget
+            && self.budget_burst == other.budget_burst
+    }
+}
+
+impl Retry {
+    pub fn set(&mut self, option: &str, value: &str) -> Result<(), String> {
+        // a route may clone the default retry policy, it must not share the budget.
+        self.tokens = Arc::new(Mutex::new(HashMap::new()));
+        let invalid = || format!("invalid {} value: {}", option, value);
+        match option {
+            "RETRY_MAX_ATTEMPTS" => {
+                self.max_attempts = value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(invalid)?
+            }
+            "RETRY_BACKOFF" => self.backoff = value.trim().parse().map_err(|_| invalid())?,
+            "RETRY_MAX_BACKOFF" => {
+                self.max_backoff = value.trim().parse().map_err(|_| invalid())?
+            }
+            "RETRY_ON" => {
+                self.statuses.clear();
+                self.on_connect = false;
+                self.on_timeout = false;
+                for v in value.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+                    match v.to_ascii_lowercase().as_str() {
+                        "connect" => self.on_connect = true,
+                        "timeout" => self.on_timeout = true,
+                        v => {
+                            let status = v
+                                .parse::<u16>()
+                                .ok()
+                                .and_then(|n| StatusCode::from_u16(n).ok())
+                                .filter(|s| s.is_server_error() || s.as_u16() == 429)
+                                .ok_or_else(|| format!("invalid RETRY_ON item: {}", v))?;
+                            self.statuses.push(status);
+                        }
+                    }
+                }
+            }
+            "RETRY_BUDGET" | "RETRY_BUDGET_BURST" => {
+                let n: f64 = value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|n: &f64| n.is_finite() && *n >= 0.0)
+                    .ok_or_else(invalid)?;
+                if option == "RETRY_BUDGET" {
+                    self.budget = n;
+                } else {
+                    self.budget_burst = n;
+                }
+            }
+            _ => return Err(format!("unknown route option: {}", option)),
+        }
+        Ok(())
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        if self.backoff > self.max_backoff {
+            return Err("RETRY_BACKOFF is larger than RETRY_MAX_BACKOFF".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn retries_status(&self, status: StatusCode) -> bool {
+        self.statuses.contains(&status)
+    }
+
+    pub fn retries_error(&self, err: &reqwest::Error) -> bool {
+        (self.on_connect && err.is_connect()) || (self.on_timeout && err.is_timeout())
+    }
+
+    // Earns the budget of a first attempt to the host.
+    pub fn record_request(&self, host: &str) {
+        let mut tokens = self.tokens.lock().unwrap();
+        let n = tokens.entry(host.to_string()).or_insert(self.budget_burst);
+        *n = (*n + self.budget).min(self.budget_burst);
+    }
+
+    // The delay before the next attempt after `attempt` failed, None if the attempts
+    // or the budget of the host are exhausted.
+    pub fn next_delay(
+        &self,
+        host: &str,
+        idempotency_key: &str,
+        attempt: u32,
+        headers: Option<&HeaderMap>,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        {
+            let mut tokens = self.tokens.lock().unwrap();
+            let n = tokens.entry(host.to_string()).or_insert(self.budget_burst);
+            if *n < 1.0 {
+                return None;
+            }
+            *n -= 1.0;
+        }
+
+        let retry_after = headers
+            .and_then(|h| h.get(http::header::RETRY_AFTER))
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok());
+        if let Some(secs) = retry_after {
+            return Some(Duration::from_millis(
+                secs.saturating_mul(1000).min(self.max_backoff),
+            ));
+        }
+        let delay = self
+            .backoff
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.max_backoff);
+        // between half and the full delay, so retries of many keys spread out
+        let hash = sha3_256(format!("{}:{}", idempotency_key, attempt).as_bytes());
+        let jitter = u64::from_be_bytes(hash[..8].try_into().unwrap()) % (delay / 2 + 1);
+        Some(Duration::from_millis(delay - delay / 2 + jitter))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retry() {
+        let mut retry = Retry::default();
+        retry.set("RETRY_ON", "503, 429, connect").unwrap();
+        retry.set("RETRY_MAX_ATTEMPTS", "4").unwrap();
+        retry.set("RETRY_BACKOFF", "200").unwrap();
+        retry.set("RETRY_MAX_BACKOFF", "500").unwrap();
+        retry.check().unwrap();
+        assert_eq!(
+            retry.statuses,
+            vec![
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+        assert!(retry.retries_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!retry.retries_status(StatusCode::BAD_GATEWAY));
+        assert!(retry.on_connect && !retry.on_timeout);
+
+        let delay = retry.next_delay("a.com", "key", 1, None).unwrap();
+        assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        let delay = retry.next_delay("a.com", "key", 3, None).unwrap();
+        assert!(delay >= Duration::from_millis(250) && delay <= Duration::from_millis(500));
+        assert_eq!(retry.next_delay("a.com", "key", 4, None), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(http::header::RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(
+            retry.next_delay("a.com", "key", 1, Some(&headers)),
+            Some(Duration::from_millis(500))
+        );
+
+        for v in ["0", "x"] {
+            assert!(retry.set("RETRY_MAX_ATTEMPTS", v).is_err());
+        }
+        for v in ["200", "404", "fast"] {
+            assert!(retry.set("RETRY_ON", v).is_err());
+        }
+        assert!(retry.set("RETRY_BUDGET", "-1").is_err());
+        retry.set("RETRY_BACKOFF", "1000").unwrap();
+        assert!(retry.check().is_err());
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        let mut retry = Retry::default();
+        retry.set("RETRY_BUDGET", "0.5").unwrap();
+        retry.set("RETRY_BUDGET_BURST", "2").unwrap();
+        assert!(retry.next_delay("a.com", "k1", 1, None).is_some());
+        assert!(retry.next_delay("a.com", "k2", 1, None).is_some());
+        assert!(retry.next_delay("a.com", "k3", 1, None).is_none());
+        // other hosts have their own budget
+        assert!(retry.next_delay("b.com", "k3", 1, None).is_some());
+
+        retry.record_request("a.com");
+        assert!(retry.next_delay("a.com", "k4", 1, None).is_none());
+        retry.record_request("a.com");
+        assert!(retry.next_delay("a.com", "k4", 1, None).is_some());
+
+        // a route cloned from the default gets its own budget
+        let mut other = retry.clone();
+        other.set("RETRY_MAX_ATTEMPTS", "2").unwrap();
+        assert!(other.next_delay("a.com", "k5", 1, None).is_some());
+    }
+}
@@ -0,0 +1,981 @@
+use http::StatusCode;
+use idempotent_proxy_types::auth::sha3_256;
+use reqwest::Url;
+use std::{collections::HashMap, sync::Arc};
+
+use crate::agents::glob_match;
+use crate::cache_control;
+use crate::canary::Canary;
+use crate::cors::Cors;
+use crate::handler::IdempotencyMode;
+use crate::json_mask::JsonMask;
+use crate::metadata::MetadataToken;
+use crate::mirror::{replace_origin, Mirror};
+use crate::oauth2::OAuth2Client;
+use crate::plugin::ResponsePlugin;
+use crate::pool::Pool;
+use crate::presets::Preset;
+use crate::retry::Retry;
+use crate::schema::ResponseSchema;
+use crate::scripts::Script;
+use crate::sigv4::AwsSigner;
+use crate::static_headers::StaticHeaders;
+
+const ROUTE_PREFIX: &str = "ROUTE_";
+const DEFAULT_ROUTE: &str = "DEFAULT";
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+// Per route options, a route is a `URL_<NAME>` constant.
+// Options are set by environment variables `ROUTE_<NAME>_<OPTION>`,
+// `ROUTE_DEFAULT_<OPTION>` applies to all routes and to x-forwarded-host requests.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RouteConfig {
+    pub idempotency_modes: Vec<IdempotencyMode>, // selectable by the x-idempotency-mode header
+    pub preset: Option<Preset>,
+    pub volatile_fields: Vec<String>,
+    pub graphql_require_mutation_key: bool,
+    pub graphql_allow_mutations: bool, // mutations are rejected by default
+    pub response_schema: Option<Arc<ResponseSchema>>,
+    pub response_plugin: Option<Arc<ResponsePlugin>>,
+    pub script: Option<Arc<Script>>,
+    pub content_types: Vec<String>,
+    pub mirror: Option<Mirror>,
+    pub mirror_percent: Option<u8>, // 100 by default
+    pub canary: Option<Canary>,
+    pub canary_percent: u8,
+    pub replicas: Vec<Url>,
+    pub ic_certification: bool,
+    pub aws_sigv4: Option<AwsSigner>,
+    pub oauth2: Option<OAuth2Client>,
+    pub metadata_token: Option<MetadataToken>,
+    pub metadata_audience: String,
+    pub pool: Option<Pool>,
+    pub failure_ttl: u64, // in seconds, 0 disables negative caching
+    pub failure_statuses: Vec<StatusCode>, // 5xx responses cached for failure_ttl
+    pub retry: Option<Retry>,
+    pub cache_control: bool,
+    pub cache_ttl_min: Option<u64>,   // in seconds
+    pub cache_ttl_max: Option<u64>,   // in seconds
+    pub max_body_size: Option<usize>, // in bytes, 1MiB by default
+    pub keep_cookies: bool,
+    pub forward_headers: Vec<String>, // lowercase names or globs, all client headers if empty
+    pub strip_headers: Vec<String>,   // lowercase names or globs, never forwarded
+    pub request_headers: StaticHeaders,
+    pub response_headers: StaticHeaders,
+    pub strip_response_headers: Vec<String>, // lowercase names or globs, removed before caching
+    pub normalize_response_headers: StaticHeaders, // fixed values of headers, set before caching
+    pub signed_timestamp_ttl: u64,           // in seconds, 0 disables the x-proxy-timestamp header
+    pub cors: Option<Cors>,
+    pub range_passthrough: bool,
+    pub stream_chunk_size: usize, // in bytes, 0 keeps response bodies in memory
+    pub max_cacheable_size: usize, // in bytes, 0 caches responses of any size
+    pub accept_encoding: Option<String>, // pinned upstream Accept-Encoding, negotiated by the proxy otherwise
+    pub json_mask: String,               // used when requests have no x-json-mask header
+    pub websocket: bool,                 // relay WebSocket upgrades
+    pub grpc: bool,                      // unary gRPC and gRPC-web calls over HTTP/2
+}
+
+impl RouteConfig {
+    // Known options, longer names first when one is a suffix of another.
+    const OPTIONS: &'static [&'static str] = &[
+        "IDEMPOTENCY_MODES",
+        "PRESET",
+        "VOLATILE_FIELDS",
+        "GRAPHQL_REQUIRE_MUTATION_KEY",
+        "GRAPHQL_ALLOW_MUTATIONS",
+        "RESPONSE_SCHEMA",
+        "RESPONSE_PLUGIN",
+        "SCRIPT",
+        "CONTENT_TYPES",
+        "MIRROR_URL",
+        "MIRROR_PERCENT",
+        "CANARY_URL",
+        "CANARY_PERCENT",
+        "REPLICAS",
+        "IC_CERTIFICATION",
+        "AWS_REGION",
+        "AWS_SERVICE",
+        "AWS_ACCESS_KEY_ID",
+        "AWS_SECRET_ACCESS_KEY",
+        "AWS_SESSION_TOKEN",
+        "AWS_ROLE_ARN",
+        "OAUTH2_TOKEN_URL",
+        "OAUTH2_CLIENT_ID",
+        "OAUTH2_CLIENT_SECRET",
+        "OAUTH2_SCOPE",
+        "OAUTH2_AUDIENCE",
+        "METADATA_TOKEN",
+        "METADATA_AUDIENCE",
+        "POOL_MAX_IDLE_PER_HOST",
+        "POOL_IDLE_TIMEOUT",
+        "POOL_MAX_LIFETIME",
+        "TCP_KEEPALIVE",
+        "FAILURE_TTL",
+        "FAILURE_STATUSES",
+        "RETRY_MAX_ATTEMPTS",
+        "RETRY_MAX_BACKOFF",
+        "RETRY_BACKOFF",
+        "RETRY_ON",
+        "RETRY_BUDGET_BURST",
+        "RETRY_BUDGET",
+        "CACHE_CONTROL",
+        "CACHE_TTL_MIN",
+        "CACHE_TTL_MAX",
+        "MAX_BODY_SIZE",
+        "REDIRECT_POLICY",
+        "KEEP_COOKIES",
+        "FORWARD_HEADERS",
+        "STRIP_RESPONSE_HEADERS",
+        "STRIP_HEADERS",
+        "NORMALIZE_RESPONSE_HEADERS",
+        "REQUEST_HEADERS",
+        "RESPONSE_HEADERS",
+        "SIGNED_TIMESTAMP_TTL",
+        "CORS_ALLOW_ORIGINS",
+        "CORS_ALLOW_METHODS",
+        "CORS_ALLOW_HEADERS",
+        "CORS_MAX_AGE",
+        "RANGE_PASSTHROUGH",
+        "STREAM_CHUNK_SIZE",
+        "MAX_CACHEABLE_SIZE",
+        "ACCEPT_ENCODING",
+        "JSON_MASK",
+        "WEBSOCKET",
+        "GRPC",
+    ];
+
+    fn set(&mut self, option: &str, value: &str) -> Result<(), String> {
+        match option {
+            "IDEMPOTENCY_MODES" => {
+                self.idempotency_modes = split_list(value)
+                    .iter()
+                    .map(|v| v.parse())
+                    .collect::<Result<_, _>>()?
+            }
+            "PRESET" => self.preset = Some(value.parse()?),
+            "VOLATILE_FIELDS" => self.volatile_fields = split_list(value),
+            "GRAPHQL_REQUIRE_MUTATION_KEY" => {
+                self.graphql_require_mutation_key = parse_bool(value)?
+            }
+            "GRAPHQL_ALLOW_MUTATIONS" => self.graphql_allow_mutations = parse_bool(value)?,
+            "RESPONSE_SCHEMA" => {
+                self.response_schema = Some(Arc::new(ResponseSchema::from_file(value)?))
+            }
+            "RESPONSE_PLUGIN" => {
+                self.response_plugin = Some(Arc::new(ResponsePlugin::from_file(value)?))
+            }
+            "SCRIPT" => self.script = Some(Arc::new(Script::from_file(value)?)),
+            "CONTENT_TYPES" => self.content_types = split_list(&value.to_ascii_lowercase()),
+            "MIRROR_URL" => self.mirror = Some(Mirror::new(value)?),
+            "MIRROR_PERCENT" => self.mirror_percent = Some(parse_percent(value)?),
+            "CANARY_URL" => self.canary = Some(Canary::new(value)?),
+            "CANARY_PERCENT" => self.canary_percent = parse_percent(value)?,
+            "IC_CERTIFICATION" => self.ic_certification = parse_bool(value)?,
+            "REPLICAS" => {
+                self.replicas = split_list(value)
+                    .iter()
+                    .map(|v| match Url::parse(v) {
+                        Ok(u) if u.host().is_some() => Ok(u),
+                        _ => Err(format!("invalid replica url: {}", v)),
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            "METADATA_TOKEN" => {
+                self.metadata_token =
+                    Some(MetadataToken::new(value.parse()?).with_audience(&self.metadata_audience))
+            }
+            "METADATA_AUDIENCE" => {
+                self.metadata_audience = value.trim().to_string();
+                self.metadata_token = self
+                    .metadata_token
+                    .take()
+                    .map(|t| t.with_audience(&self.metadata_audience));
+            }
+            "FAILURE_TTL" => {
+                self.failure_ttl = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid FAILURE_TTL value: {}", value))?
+            }
+            "FAILURE_STATUSES" => {
+                self.failure_statuses = split_list(value)
+                    .iter()
+                    .map(|v| {
+                        v.parse::<u16>()
+                            .ok()
+                            .and_then(|n| StatusCode::from_u16(n).ok())
+                            .filter(|s| s.is_server_error())
+                            .ok_or_else(|| format!("invalid FAILURE_STATUSES item: {}", v))
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            "SIGNED_TIMESTAMP_TTL" => {
+                self.signed_timestamp_ttl = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid SIGNED_TIMESTAMP_TTL value: {}", value))?
+            }
+            "MAX_BODY_SIZE" => {
+                self.max_body_size = Some(
+                    value
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("invalid MAX_BODY_SIZE value: {}", value))?,
+                )
+            }
+            "STREAM_CHUNK_SIZE" => {
+                self.stream_chunk_size = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid STREAM_CHUNK_SIZE value: {}", value))?
+            }
+            "MAX_CACHEABLE_SIZE" => {
+                self.max_cacheable_size = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid MAX_CACHEABLE_SIZE value: {}", value))?
+            }
+            "ACCEPT_ENCODING" => {
+                let value = value.trim();
+                if value.is_empty() || http::HeaderValue::from_str(value).is_err() {
+                    return Err(format!("invalid ACCEPT_ENCODING value: {}", value));
+                }
+                self.accept_encoding = Some(value.to_string())
+            }
+            "JSON_MASK" => {
+                JsonMask::parse(value)?;
+                self.json_mask = value.trim().to_string()
+            }
+            "KEEP_COOKIES" => self.keep_cookies = parse_bool(value)?,
+            "WEBSOCKET" => self.websocket = parse_bool(value)?,
+            "GRPC" => self.grpc = parse_bool(value)?,
+            "RANGE_PASSTHROUGH" => self.range_passthrough = parse_bool(value)?,
+            "REQUEST_HEADERS" => self.request_headers = value.parse()?,
+            "RESPONSE_HEADERS" => self.response_headers = value.parse()?,
+            "FORWARD_HEADERS" => self.forward_headers = split_list(&value.to_ascii_lowercase()),
+            "STRIP_HEADERS" => self.strip_headers = split_list(&value.to_ascii_lowercase()),
+            "STRIP_RESPONSE_HEADERS" => {
+                self.strip_response_headers = split_list(value)
+                    .iter()
+                    .map(|v| v.to_ascii_lowercase())
+                    .collect()
+            }
+            "NORMALIZE_RESPONSE_HEADERS" => self.normalize_response_headers = value.parse()?,
+            "CACHE_CONTROL" => self.cache_control = parse_bool(value)?,
+            "CACHE_TTL_MIN" | "CACHE_TTL_MAX" => {
+                let secs = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid {} value: {}", option, value))?;
+                if option == "CACHE_TTL_MIN" {
+                    self.cache_ttl_min = Some(secs);
+                } else {
+                    self.cache_ttl_max = Some(secs);
+                }
+            }
+            v if v.starts_with("POOL_") || v == "TCP_KEEPALIVE" || v == "REDIRECT_POLICY" => self
+                .pool
+                .get_or_insert_with(Default::default)
+                .set(v, value)?,
+            v if v.starts_with("AWS_") => self
+                .aws_sigv4
+                .get_or_insert_with(Default::default)
+                .set(v, value)?,
+            v if v.starts_with("OAUTH2_") => self
+                .oauth2
+                .get_or_insert_with(Default::default)
+                .set(v, value)?,
+            v if v.starts_with("CORS_") => self
+                .cors
+                .get_or_insert_with(Default::default)
+                .set(v, value)?,
+            v if v.starts_with("RETRY_") => self
+                .retry
+                .get_or_insert_with(Default::default)
+                .set(v, value)?,
+            _ => return Err(format!("unknown route option: {}", option)),
+        }
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if !self.failure_statuses.is_empty() && self.failure_ttl == 0 {
+            return Err("FAILURE_STATUSES needs FAILURE_TTL".to_string());
+        }
+        if self.response_plugin.is_some() && self.ic_certification {
+            return Err("RESPONSE_PLUGIN can not be used with IC_CERTIFICATION".to_string());
+        }
+        if self.ic_certification && self.script.as_ref().is_some_and(|s| s.has_on_response()) {
+            return Err("on_response scripts can not be used with IC_CERTIFICATION".to_string());
+        }
+        if let Some(signer) = &self.aws_sigv4 {
+            signer.check()?;
+        }
+        if let Some(client) = &self.oauth2 {
+            client.check()?;
+        }
+        if let Some(token) = &self.metadata_token {
+            token.check()?;
+        }
+        if let Some(cors) = &self.cors {
+            cors.check()?;
+        }
+        if let Some(retry) = &self.retry {
+            retry.check()?;
+        }
+        Ok(())
+    }
+
+    // Routes the same idempotency key to the same replica with rendezvous hashing,
+    // so adding or removing a replica only moves the keys of that replica.
+    pub fn replica_url(&self, url: &Url, idempotency_key: &str) -> Url {
+        self.replicas
+            .iter()
+            .max_by_key(|origin| {
+                sha3_256(format!("{}{}", origin.as_str(), idempotency_key).as_bytes())
+            })
+            .map(|origin| replace_origin(url, origin))
+            .unwrap_or_else(|| url.clone())
+    }
+
+    pub fn max_body_size(&self) -> usize {
+        self.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE)
+    }
+
+    // The chunk size to stream a response body into the storage with, None if disabled
+    // or if an option needs the whole body.
+    pub fn stream_chunk_size(&self, json_mask: &str) -> Option<usize> {
+        if self.stream_chunk_size == 0
+            || !json_mask.is_empty()
+            || self.preset.is_some()
+            || self.response_schema.is_some()
+            || self.response_plugin.is_some()
+            || self.mirror.is_some()
+            || self.ic_certification
+            || self.grpc
+        {
+            return None;
+        }
+        Some(self.stream_chunk_size)
+    }
+
+    // Removes the client headers not in the allowlist or in the denylist, and cookies unless
+    // kept. Static request headers are applied afterwards, so upstream credentials can be
+    // injected by the proxy without being forwarded from clients.
+    pub fn filter_request_headers(&self, headers: &mut http::HeaderMap) {
+        if !self.keep_cookies {
+            headers.remove(http::header::COOKIE);
+        }
+        if self.forward_headers.is_empty() && self.strip_headers.is_empty() {
+            return;
+        }
+        let matches = |patterns: &[String], name: &str| {
+            patterns
+                .iter()
+                .any(|p| glob_match(p.as_bytes(), name.as_bytes()))
+        };
+        let names: Vec<http::HeaderName> = headers.keys().cloned().collect();
+        for name in names {
+            if (!self.forward_headers.is_empty() && !matches(&self.forward_headers, name.as_str()))
+                || matches(&self.strip_headers, name.as_str())
+            {
+                headers.remove(&name);
+            }
+        }
+    }
+
+    // Removes and normalizes the response headers that differ between otherwise identical
+    // upstream responses (dates, request ids), so every replica of an IC HTTPS outcall
+    // gets the same bytes. Normalized headers keep their fixed value only if present.
+    pub fn deterministic_headers(&self, headers: &mut Vec<(String, String)>) {
+        if !self.strip_response_headers.is_empty() {
+            headers.retain(|(k, _)| {
+                !self
+                    .strip_response_headers
+                    .iter()
+                    .any(|p| glob_match(p.as_bytes(), k.as_bytes()))
+            });
+        }
+        for (name, value) in self.normalize_response_headers.iter() {
+            for (k, v) in headers.iter_mut() {
+                if k == name.as_str() {
+                    *v = value.to_str().unwrap_or_default().to_string();
+                }
+            }
+        }
+    }
+
+    // The TTL (in milliseconds) of a response cached as a failure, None for other responses.
+    pub fn failure_ttl_of(&self, status: StatusCode) -> Option<u64> {
+        (self.failure_ttl > 0 && self.failure_statuses.contains(&status))
+            .then_some(self.failure_ttl * 1000)
+    }
+
+    // The largest response body to cache, None if unbounded.
+    pub fn max_cacheable_size(&self) -> Option<usize> {
+        (self.max_cacheable_size > 0).then_some(self.max_cacheable_size)
+    }
+
+    // The TTL (in milliseconds) of a cached response, derived from the upstream's
+    // Cache-Control or Expires headers when enabled, `default` otherwise.
+    pub fn cache_ttl(&self, headers: &http::HeaderMap, default: u64) -> u64 {
+        if !self.cache_control {
+            return default;
+        }
+        let min = self.cache_ttl_min.map(|s| s * 1000).unwrap_or(1000);
+        let max = self.cache_ttl_max.map(|s| s * 1000).unwrap_or(default);
+        cache_control::ttl_from_headers(headers, min, max).unwrap_or(default)
+    }
+
+    // Checks the content encoding left after the HTTP client decoded the body: identity,
+    // or a coding of the pinned Accept-Encoding. Bodies encoded as each upstream connection
+    // negotiated would differ between callers.
+    pub fn allows_content_encoding(&self, content_encoding: &str) -> bool {
+        let encoding = content_encoding.trim().to_ascii_lowercase();
+        if encoding.is_empty() || encoding == "identity" {
+            return true;
+        }
+        self.accept_encoding.as_ref().is_some_and(|accept| {
+            accept.to_ascii_lowercase().split(',').any(|coding| {
+                let mut parts = coding.split(';').map(str::trim);
+                // a coding with q=0 is refused
+                parts.next() == Some(encoding.as_str())
+                    && !parts.any(|p| {
+                        p.strip_prefix("q=")
+                            .and_then(|q| q.parse::<f32>().ok())
+                            .is_some_and(|q| q == 0.0)
+                    })
+            })
+        })
+    }
+
+    // Checks the response content type against the allowlist, supports `type/*` patterns.
+    pub fn allows_content_type(&self, content_type: &str) -> bool {
+        if self.content_types.is_empty() {
+            return true;
+        }
+
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        self.content_types
+            .iter()
+            .any(|ct| match ct.strip_suffix("/*") {
+                Some(main) => essence
+                    .strip_prefix(main)
+                    .is_some_and(|sub| sub.starts_with('/')),
+                None => ct == &essence || ct == "*/*",
+            })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Routes {
+    default: RouteConfig,
+    routes: HashMap<String, RouteConfig>,
+}
+
+impl Routes {
+    pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Self, String> {
+        let mut default_opts: Vec<(&'static str, String)> = Vec::new();
+        let mut route_opts: Vec<(String, &'static str, String)> = Vec::new();
+        for (k, v) in vars {
+            if let Some(name_option) = k.strip_prefix(ROUTE_PREFIX) {
+                let (name, option) = split_route_option(name_option)
+                    .ok_or_else(|| format!("unknown route option: {}", k))?;
+                if name == DEFAULT_ROUTE {
+                    default_opts.push((option, v));
+                } else {
+                    route_opts.push((format!("URL_{}", name), option, v));
+                }
+            }
+        }
+
+        let mut routes = Routes::default();
+        for (option, v) in default_opts {
+            routes.default.set(option, &v)?;
+        }
+        for (name, option, v) in route_opts {
+            routes
+                .routes
+                .entry(name)
+                .or_insert_with(|| routes.default.clone())
+                .set(option, &v)?;
+        }
+
+        routes.default.check()?;
+        for (name, route) in &routes.routes {
+            route.check().map_err(|err| format!("{}: {}", name, err))?;
+        }
+        Ok(routes)
+    }
+
+    // `name` is the `URL_<NAME>` constant, or empty for x-forwarded-host requests.
+    pub fn get(&self, name: &str) -> &RouteConfig {
+        self.routes.get(name).unwrap_or(&self.default)
+    }
+
+    // The files of the route scripts, watched for changes.
+    pub fn script_paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = std::iter::once(&self.default)
+            .chain(self.routes.values())
+            .filter_map(|route| route.script.as_ref().map(|s| s.path().to_string()))
+            .collect();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+}
+
+fn split_route_option(name_option: &str) -> Option<(&str, &'static str)> {
+    RouteConfig::OPTIONS.iter().find_map(|option| {
+        name_option
+            .strip_suffix(option)
+            .and_then(|name| name.strip_suffix('_'))
+            .filter(|name| !name.is_empty())
+            .map(|name| (name, *option))
+    })
+}
+
+pub fn parse_percent(value: &str) -> Result<u8, String> {
+    match value.trim().parse::<u8>() {
+        Ok(v) if v <= 100 => Ok(v),
+        _ => Err(format!("invalid percent value: {}", value)),
+    }
+}
+
+pub fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" | "" => Ok(false),
+        v => Err(format!("invalid bool value: {}", v)),
+    }
+}
+
+pub fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .filter_map(|s| {
+            let s = s.trim();
+            if s.is_empty() {
+                None
+            } else {
+                Some(s.to_string())
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_routes() {
+        let vars = vec![
+            ("ROUTE_ETH_MAIN_PRESET".to_string(), "ethereum".to_string()),
+            (
+                "ROUTE_ETH_MAIN_IDEMPOTENCY_MODES".to_string(),
+                "jsonrpc, default".to_string(),
+            ),
+            (
+                "ROUTE_GRAPH_GRAPHQL_REQUIRE_MUTATION_KEY".to_string(),
+                "true".to_string(),
+            ),
+            (
+                "ROUTE_GRAPH_GRAPHQL_ALLOW_MUTATIONS".to_string(),
+                "true".to_string(),
+            ),
+            (
+                "ROUTE_DEFAULT_VOLATILE_FIELDS".to_string(),
+                "nonce, ts".to_string(),
+            ),
+            (
+                "URL_ETH_MAIN".to_string(),
+                "https://cloudflare-eth.com".to_string(),
+            ),
+        ];
+        let routes = Routes::from_vars(vars.into_iter()).unwrap();
+        let route = routes.get("URL_ETH_MAIN");
+        assert_eq!(route.preset, Some(Preset::EthereumJsonRpc));
+        assert_eq!(route.volatile_fields, vec!["nonce", "ts"]);
+        assert!(!route.graphql_require_mutation_key);
+        assert_eq!(
+            route.idempotency_modes,
+            vec![IdempotencyMode::JsonRpc, IdempotencyMode::Default]
+        );
+        let route = routes.get("");
+        assert_eq!(route.preset, None);
+        assert!(route.idempotency_modes.is_empty());
+        assert_eq!(route.volatile_fields, vec!["nonce", "ts"]);
+
+        assert!(routes.get("URL_GRAPH").graphql_require_mutation_key);
+        assert!(routes.get("URL_GRAPH").graphql_allow_mutations);
+        assert!(!routes.get("URL_ETH_MAIN").graphql_allow_mutations);
+
+        let routes = Routes::from_vars(
+            vec![
+                (
+                    "ROUTE_HTTPBIN_CONTENT_TYPES".to_string(),
+                    "Application/JSON, text/*".to_string(),
+                ),
+                (
+                    "ROUTE_HTTPBIN_MIRROR_URL".to_string(),
+                    "https://staging.httpbin.org".to_string(),
+                ),
+                ("ROUTE_HTTPBIN_MIRROR_PERCENT".to_string(), "10".to_string()),
+                (
+                    "ROUTE_HTTPBIN_CANARY_URL".to_string(),
+                    "https://eu.httpbin.org".to_string(),
+                ),
+                ("ROUTE_HTTPBIN_CANARY_PERCENT".to_string(), "5".to_string()),
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+        let route = routes.get("URL_HTTPBIN");
+        assert!(route.allows_content_encoding(""));
+        assert!(route.allows_content_encoding("identity"));
+        assert!(!route.allows_content_encoding("br"));
+        assert!(route.allows_content_type("application/json; charset=utf-8"));
+        assert!(route.allows_content_type("text/plain"));
+        assert!(!route.allows_content_type("image/png"));
+        assert!(routes.get("").allows_content_type("image/png"));
+        assert_eq!(
+            route.mirror.as_ref().unwrap().origin.as_str(),
+            "https://staging.httpbin.org/"
+        );
+        assert_eq!(route.mirror_percent, Some(10));
+        assert!(route.canary.is_some());
+        assert_eq!(route.canary_percent, 5);
+        assert!(route.replicas.is_empty());
+        let url = Url::parse("https://httpbin.org/get?a=1").unwrap();
+        assert_eq!(route.replica_url(&url, "alice:GET:key_001"), url);
+
+        let routes = Routes::from_vars(
+            vec![(
+                "ROUTE_ETH_REPLICAS".to_string(),
+                "https://node1.example.com, https://node2.example.com,https://node3.example.com"
+                    .to_string(),
+            )]
+            .into_iter(),
+        )
+        .unwrap();
+        let route = routes.get("URL_ETH");
+        assert_eq!(route.replicas.len(), 3);
+        let picked = route.replica_url(&url, "alice:GET:key_001");
+        assert_eq!(picked.path(), "/get");
+        assert_eq!(picked.query(), Some("a=1"));
+        for _ in 0..10 {
+            assert_eq!(route.replica_url(&url, "alice:GET:key_001"), picked);
+        }
+        let hosts: std::collections::HashSet<_> = (0..100)
+            .map(|i| {
+                route
+                    .replica_url(&url, &format!("alice:GET:key_{}", i))
+                    .host_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(hosts.len(), 3);
+        assert!(Routes::from_vars(
+            vec![("ROUTE_ETH_REPLICAS".to_string(), "node1".to_string())].into_iter()
+        )
+        .is_err());
+
+        let routes = Routes::from_vars(
+            vec![
+                (
+                    "ROUTE_DEFAULT_AWS_REGION".to_string(),
+                    "us-east-1".to_string(),
+                ),
+                ("ROUTE_S3_AWS_SERVICE".to_string(), "s3".to_string()),
+                (
+                    "ROUTE_S3_AWS_ROLE_ARN".to_string(),
+                    "arn:aws:iam::123456789012:role/proxy".to_string(),
+                ),
+            ]
+            .into_iter(),
+        );
+        assert!(routes.is_err()); // AWS_SERVICE is required for the default route
+        let routes = Routes::from_vars(
+            vec![
+                ("ROUTE_S3_AWS_REGION".to_string(), "us-east-1".to_string()),
+                ("ROUTE_S3_AWS_SERVICE".to_string(), "s3".to_string()),
+                (
+                    "ROUTE_S3_AWS_ROLE_ARN".to_string(),
+                    "arn:aws:iam::123456789012:role/proxy".to_string(),
+                ),
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+        let signer = routes.get("URL_S3").aws_sigv4.as_ref().unwrap();
+        assert_eq!(signer.service, "s3");
+        assert_eq!(
+            signer.role_arn.as_deref(),
+            Some("arn:aws:iam::123456789012:role/proxy")
+        );
+        assert!(routes.get("").aws_sigv4.is_none());
+        assert!(Routes::from_vars(
+            vec![(
+                "ROUTE_S3_AWS_ACCESS_KEY_ID".to_string(),
+                "AKIDEXAMPLE".to_string()
+            )]
+            .into_iter()
+        )
+        .is_err());
+
+        let routes = Routes::from_vars(
+            vec![
+                ("ROUTE_DEFAULT_FAILURE_TTL".to_string(), "5".to_string()),
+                ("ROUTE_ETH_FAILURE_TTL".to_string(), "0".to_string()),
+                (
+                    "ROUTE_HTTPBIN_FAILURE_STATUSES".to_string(),
+                    "500, 503".to_string(),
+                ),
+                (
+                    "ROUTE_ETH_SIGNED_TIMESTAMP_TTL".to_string(),
+                    "5".to_string(),
+                ),
+                (
+                    "ROUTE_ETH_REQUEST_HEADERS".to_string(),
+                    "x-tenant-id: acme".to_string(),
+                ),
+                (
+                    "ROUTE_DEFAULT_STREAM_CHUNK_SIZE".to_string(),
+                    "262144".to_string(),
+                ),
+                (
+                    "ROUTE_HTTPBIN_MAX_CACHEABLE_SIZE".to_string(),
+                    "10485760".to_string(),
+                ),
+                ("ROUTE_ETH_PRESET".to_string(), "ethereum".to_string()),
+                ("ROUTE_ETH_RETRY_MAX_ATTEMPTS".to_string(), "2".to_string()),
+                ("ROUTE_ETH_RETRY_ON".to_string(), "503,connect".to_string()),
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+        assert_eq!(routes.get("").failure_ttl, 5);
+        assert_eq!(routes.get("URL_HTTPBIN").failure_ttl, 5);
+        assert_eq!(routes.get("URL_ETH").failure_ttl, 0);
+        assert_eq!(
+            routes
+                .get("URL_HTTPBIN")
+                .failure_ttl_of(StatusCode::SERVICE_UNAVAILABLE),
+            Some(5000)
+        );
+        assert_eq!(
+            routes
+                .get("URL_HTTPBIN")
+                .failure_ttl_of(StatusCode::BAD_GATEWAY),
+            None
+        );
+        assert_eq!(
+            routes
+                .get("")
+                .failure_ttl_of(StatusCode::INTERNAL_SERVER_ERROR),
+            None
+        );
+        for (ttl, statuses) in [("0", "503"), ("5", "404")] {
+            assert!(Routes::from_vars(
+                vec![
+                    ("ROUTE_DEFAULT_FAILURE_TTL".to_string(), ttl.to_string()),
+                    (
+                        "ROUTE_DEFAULT_FAILURE_STATUSES".to_string(),
+                        statuses.to_string()
+                    ),
+                ]
+                .into_iter()
+            )
+            .is_err());
+        }
+        assert_eq!(
+            routes.get("URL_ETH").retry.as_ref().unwrap().max_attempts,
+            2
+        );
+        assert!(routes.get("URL_HTTPBIN").retry.is_none());
+        assert_eq!(routes.get("URL_ETH").max_body_size(), 1024 * 1024);
+        assert!(!routes.get("URL_ETH").keep_cookies);
+        assert_eq!(routes.get("URL_ETH").signed_timestamp_ttl, 5);
+        assert_eq!(routes.get("URL_HTTPBIN").signed_timestamp_ttl, 0);
+        assert!(!routes.get("URL_ETH").request_headers.is_empty());
+        assert!(routes.get("URL_HTTPBIN").request_headers.is_empty());
+        assert_eq!(
+            routes.get("URL_HTTPBIN").stream_chunk_size(""),
+            Some(262144)
+        );
+        assert_eq!(routes.get("URL_HTTPBIN").stream_chunk_size("args"), None);
+        // presets need the whole body
+        assert_eq!(routes.get("URL_ETH").stream_chunk_size(""), None);
+        assert_eq!(
+            routes.get("URL_HTTPBIN").max_cacheable_size(),
+            Some(10485760)
+        );
+        assert_eq!(routes.get("URL_ETH").max_cacheable_size(), None);
+
+        assert!(Routes::from_vars(
+            vec![("ROUTE_ETH_MIRROR_PERCENT".to_string(), "101".to_string())].into_iter()
+        )
+        .is_err());
+
+        assert!(Routes::from_vars(
+            vec![("ROUTE_ETH_UNKNOWN".to_string(), "1".to_string())].into_iter()
+        )
+        .is_err());
+        assert!(Routes::from_vars(
+            vec![("ROUTE_ETH_PRESET".to_string(), "unknown".to_string())].into_iter()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_accept_encoding() {
+        let routes = Routes::from_vars(
+            vec![(
+                "ROUTE_CDN_ACCEPT_ENCODING".to_string(),
+                "br, zstd;q=0".to_string(),
+            )]
+            .into_iter(),
+        )
+        .unwrap();
+        let route = routes.get("URL_CDN");
+        assert_eq!(route.accept_encoding.as_deref(), Some("br, zstd;q=0"));
+        assert!(route.allows_content_encoding("BR"));
+        assert!(route.allows_content_encoding("identity"));
+        assert!(!route.allows_content_encoding("zstd"));
+        assert!(!route.allows_content_encoding("gzip"));
+        assert!(!routes.get("").allows_content_encoding("gzip"));
+
+        assert!(Routes::from_vars(
+            vec![("ROUTE_CDN_ACCEPT_ENCODING".to_string(), " ".to_string())].into_iter()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_filter_request_headers() {
+        let routes = Routes::from_vars(
+            vec![
+                (
+                    "ROUTE_DEFAULT_STRIP_HEADERS".to_string(),
+                    "Authorization, x-debug-*".to_string(),
+                ),
+                (
+                    "ROUTE_API_FORWARD_HEADERS".to_string(),
+                    "content-type, accept, x-*".to_string(),
+                ),
+                (
+                    "ROUTE_API_REQUEST_HEADERS".to_string(),
+                    "authorization: Bearer upstream".to_string(),
+                ),
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+        let client = || {
+            let mut headers = http::HeaderMap::new();
+            for (k, v) in [
+                ("authorization", "Bearer client"),
+                ("content-type", "application/json"),
+                ("cookie", "sid=1"),
+                ("user-agent", "curl"),
+                ("x-debug-level", "1"),
+                ("x-tenant-id", "acme"),
+            ] {
+                headers.insert(k, v.parse().unwrap());
+            }
+            headers
+        };
+
+        let mut headers = client();
+        routes.get("").filter_request_headers(&mut headers);
+        let mut names: Vec<&str> = headers.keys().map(|k| k.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["content-type", "user-agent", "x-tenant-id"]);
+
+        let route = routes.get("URL_API");
+        let mut headers = client();
+        route.filter_request_headers(&mut headers);
+        let mut names: Vec<&str> = headers.keys().map(|k| k.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["content-type", "x-tenant-id"]);
+        // the upstream credentials are injected by the proxy
+        route.request_headers.apply(&mut headers).unwrap();
+        assert_eq!(headers["authorization"], "Bearer upstream");
+    }
+
+    #[test]
+    fn test_deterministic_headers() {
+        let routes = Routes::from_vars(
+            vec![
+                (
+                    "ROUTE_ETH_STRIP_RESPONSE_HEADERS".to_string(),
+                    "X-Request-Id, x-amz-*".to_string(),
+                ),
+                (
+                    "ROUTE_ETH_NORMALIZE_RESPONSE_HEADERS".to_string(),
+                    "date: Thu, 01 Jan 1970 00:00:00 GMT".to_string(),
+                ),
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+        let mut headers = vec![
+            (
+                "date".to_string(),
+                "Wed, 16 Oct 2024 08:00:00 GMT".to_string(),
+            ),
+            ("x-request-id".to_string(), "abc".to_string()),
+            ("x-amz-cf-id".to_string(), "xyz".to_string()),
+            ("etag".to_string(), "\"v1\"".to_string()),
+        ];
+        routes.get("URL_ETH").deterministic_headers(&mut headers);
+        assert_eq!(
+            headers,
+            vec![
+                (
+                    "date".to_string(),
+                    "Thu, 01 Jan 1970 00:00:00 GMT".to_string()
+                ),
+                ("etag".to_string(), "\"v1\"".to_string()),
+            ]
+        );
+
+        let mut headers = vec![("x-request-id".to_string(), "abc".to_string())];
+        routes
+            .get("URL_HTTPBIN")
+            .deterministic_headers(&mut headers);
+        assert_eq!(headers.len(), 1);
+    }
+
+    #[test]
+    fn test_json_mask() {
+        let routes = Routes::from_vars(
+            vec![(
+                "ROUTE_ETH_JSON_MASK".to_string(),
+                " id, result.number ".to_string(),
+            )]
+            .into_iter(),
+        )
+        .unwrap();
+        assert_eq!(routes.get("URL_ETH").json_mask, "id, result.number");
+        assert_eq!(routes.get("URL_HTTPBIN").json_mask, "");
+        assert!(!routes.get("URL_ETH").websocket);
+        assert!(!routes.get("URL_ETH").grpc);
+
+        assert!(Routes::from_vars(
+            vec![(
+                "ROUTE_ETH_JSON_MASK".to_string(),
+                "result..number".to_string()
+            )]
+            .into_iter()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,285 @@
+use axum::body::Bytes;
+use ciborium::{from_reader, into_writer};
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use idempotent_proxy_types::{err_string, unix_ms};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::time::{sleep, Duration};
+
+use crate::cache::Storage;
+use crate::handler::{AppState, ProxyRequest};
+use crate::otel::Trace;
+use crate::redact;
+
+const JOB_PREFIX: &str = "job:";
+const JOB_RUN_PREFIX: &str = "job_run:";
+
+pub struct Scheduler {
+    pub poll_interval: u64, // in milliseconds
+    pub max_delay: u64,     // in milliseconds
+}
+
+// A request accepted with `x-execute-after`, persisted in the cacher until it is executed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledJob {
+    pub execute_at: u64, // unix timestamp in milliseconds
+    pub agent: String,
+    #[serde(default)]
+    pub route: String,
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<ByteBuf>,
+    pub idempotency_key: String,
+    #[serde(default)]
+    pub legacy_key: Option<String>,
+    pub json_mask: String,
+    pub response_headers: String,
+    pub callback_url: Option<String>,
+    #[serde(default)]
+    pub cache_ttl: Option<u64>, // in milliseconds
+    #[serde(default)]
+    pub fingerprint: String,
+}
+
+impl ScheduledJob {
+    pub fn new(execute_at: u64, preq: &ProxyRequest, callback_url: Option<&reqwest::Url>) -> Self {
+        Self {
+            execute_at,
+            agent: preq.agent.clone(),
+            route: preq.route.clone(),
+            method: preq.method.to_string(),
+            url: preq.url.to_string(),
+            headers: preq
+                .headers
+                .iter()
+                .filter_map(|(k, v)| v.to_str().ok().map(|v| (k.to_string(), v.to_string())))
+                .collect(),
+            body: preq.body.as_ref().map(|b| ByteBuf::from(b.to_vec())),
+            idempotency_key: preq.idempotency_key.clone(),
+            legacy_key: preq.legacy_key.clone(),
+            json_mask: preq.json_mask.clone(),
+            response_headers: preq.response_headers.clone(),
+            callback_url: callback_url.map(|u| u.to_string()),
+            cache_ttl: preq.cache_ttl,
+            fingerprint: preq.fingerprint.clone(),
+        }
+    }
+
+    pub fn to_request(&self) -> Result<ProxyRequest, String> {
+        let mut headers = HeaderMap::with_capacity(self.headers.len());
+        for (k, v) in &self.headers {
+            headers.append(
+                HeaderName::from_bytes(k.as_bytes()).map_err(err_string)?,
+                HeaderValue::from_str(v).map_err(err_string)?,
+            );
+        }
+
+        Ok(ProxyRequest {
+            agent: self.agent.clone(),
+            route: self.route.clone(),
+            method: Method::from_bytes(self.method.as_bytes()).map_err(err_string)?,
+            url: reqwest::Url::parse(&self.url).map_err(err_string)?,
+            headers,
+            body: self.body.as_ref().map(|b| Bytes::from(b.to_vec())),
+            idempotency_key: self.idempotency_key.clone(),
+            legacy_key: self.legacy_key.clone(),
+            json_mask: self.json_mask.clone(),
+            response_headers: self.response_headers.clone(),
+            trace: Trace::default(),
+            cache_ttl: self.cache_ttl,
+            fingerprint: self.fingerprint.clone(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut buf = Vec::new();
+        into_writer(self, &mut buf).map_err(err_string)?;
+        Ok(buf)
+    }
+}
+
+impl TryFrom<&[u8]> for ScheduledJob {
+    type Error = String;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        from_reader(value).map_err(err_string)
+    }
+}
+
+impl Scheduler {
+    // Parses the `x-execute-after` header value (unix timestamp in seconds).
+    pub fn parse_execute_at(&self, value: &str) -> Result<u64, String> {
+        let execute_at = value
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| format!("invalid x-execute-after: {}", value))?
+            .saturating_mul(1000);
+        let now = unix_ms();
+        if execute_at > now + self.max_delay {
+            return Err(format!(
+                "x-execute-after exceeds the max delay of {} seconds",
+                self.max_delay / 1000
+            ));
+        }
+        Ok(execute_at)
+    }
+}
+
+// Stores the job durably, scheduling the same idempotency key twice is a no-op.
+pub async fn schedule(app: &AppState, job: &ScheduledJob) -> Result<bool, (StatusCode, String)> {
+    let key = format!("{}{}", JOB_PREFIX, job.idempotency_key);
+    let ttl = job.execute_at.saturating_sub(unix_ms()) + app.cacher.cache_ttl;
+    let data = job
+        .to_bytes()
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+    let lock = app
+        .cacher
+        .obtain(&key, ttl)
+        .await
+        .map_err(|err| (StatusCode::BAD_GATEWAY, err))?;
+    if !lock {
+        return Ok(false);
+    }
+
+    if let Err(err) = app.cacher.set(&key, data, ttl).await {
+        // release the lock, or the key stays taken without a job until the ttl
+        let _ = app.cacher.del(&key).await;
+        return Err((StatusCode::BAD_GATEWAY, err));
+    }
+    Ok(true)
+}
+
+pub async fn run(app: AppState) {
+    loop {
+        sleep(Duration::from_millis(app.scheduler.poll_interval)).await;
+        // scanning the jobs is left to the leader, the per job lock still guards the execution
+        if !app.leader.is_leader() {
+            continue;
+        }
+        if let Err(err) = run_due_jobs(&app).await {
+            log::error!(target: "scheduler", "failed to run scheduled jobs: {}", err);
+        }
+    }
+}
+
+async fn run_due_jobs(app: &AppState) -> Result<(), String> {
+    let now = unix_ms();
+    for key in app.cacher.keys(JOB_PREFIX).await? {
+        let job = match app.cacher.polling_get(&key, 0, 1).await {
+            Ok(data) => match ScheduledJob::try_from(&data[..]) {
+                Ok(job) => job,
+                Err(err) => {
+                    // a bad job would otherwise block the ones after it on every poll
+                    log::error!(target: "scheduler", "dropped undecodable job {}: {}", key, err);
+                    let _ = app.cacher.del(&key).await;
+                    continue;
+                }
+            },
+            // not stored yet or already executed
+            Err(_) => continue,
+        };
+        if job.execute_at > now {
+            continue;
+        }
+
+        // Only one replica runs the job.
+        let run_key = format!("{}{}", JOB_RUN_PREFIX, job.idempotency_key);
+        if !app.cacher.obtain(&run_key, app.cacher.cache_ttl).await? {
+            continue;
+        }
+
+        let app = app.clone();
+        tokio::spawn(async move {
+            match job.to_request() {
+                Ok(preq) => {
+                    let res = app.handle(&preq).await;
+                    log::info!(target: "scheduler",
+                        action = "execute",
+                        method = job.method,
+                        url = redact::url_str(&job.url),
+                        agent = job.agent,
+                        idempotency_key = job.idempotency_key;
+                        "delay {} ms", unix_ms().saturating_sub(job.execute_at));
+                    if let Some(callback_url) = job
+                        .callback_url
+                        .as_ref()
+                        .and_then(|u| reqwest::Url::parse(u).ok())
+                    {
+                        let res = app.load_body(&preq.idempotency_key, res).await;
+                        app.webhook
+                            .deliver(&callback_url, &job.idempotency_key, res)
+                            .await;
+                    }
+                }
+                Err(err) => {
+                    log::error!(target: "scheduler",
+                        action = "execute",
+                        idempotency_key = job.idempotency_key;
+                        "invalid scheduled job: {}", err);
+                }
+            }
+
+            let _ = app.cacher.del(&key).await;
+            let _ = app.cacher.del(&run_key).await;
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scheduled_job() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/json".parse().unwrap());
+        let preq = ProxyRequest {
+            agent: "alice".to_string(),
+            route: "".to_string(),
+            method: Method::POST,
+            url: reqwest::Url::parse("https://httpbin.org/post").unwrap(),
+            headers,
+            body: Some(Bytes::from_static(b"{}")),
+            idempotency_key: "alice:POST:key_001".to_string(),
+            legacy_key: None,
+            json_mask: "".to_string(),
+            response_headers: "date".to_string(),
+            trace: Trace::default(),
+            cache_ttl: Some(60 * 1000),
+            fingerprint: "fp".to_string(),
+        };
+        let callback_url = reqwest::Url::parse("https://example.com/callback").unwrap();
+        let job = ScheduledJob::new(1716376993000, &preq, Some(&callback_url));
+        let data = job.to_bytes().unwrap();
+        let job2 = ScheduledJob::try_from(data.as_slice()).unwrap();
+        assert_eq!(job2, job);
+
+        let preq2 = job2.to_request().unwrap();
+        assert_eq!(preq2.method, preq.method);
+        assert_eq!(preq2.url, preq.url);
+        assert_eq!(preq2.headers, preq.headers);
+        assert_eq!(preq2.body, preq.body);
+        assert_eq!(preq2.idempotency_key, preq.idempotency_key);
+        assert_eq!(preq2.cache_ttl, preq.cache_ttl);
+        assert_eq!(preq2.fingerprint, preq.fingerprint);
+    }
+
+    #[test]
+    fn test_parse_execute_at() {
+        let scheduler = Scheduler {
+            poll_interval: 1000,
+            max_delay: 3600 * 1000,
+        };
+        let now = unix_ms() / 1000;
+        assert_eq!(
+            scheduler.parse_execute_at(&(now + 60).to_string()).unwrap(),
+            (now + 60) * 1000
+        );
+        assert!(scheduler
+            .parse_execute_at(&(now + 7200).to_string())
+            .is_err());
+        assert!(scheduler.parse_execute_at("tomorrow").is_err());
+    }
+}
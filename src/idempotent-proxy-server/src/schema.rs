@@ -0,0 +1,74 @@
+use idempotent_proxy_types::err_string;
+use serde_json::Value;
+
+// JSON Schema attached to a route, successful upstream responses that fail
+// the validation are never cached.
+pub struct ResponseSchema {
+    path: String,
+    validator: jsonschema::Validator,
+}
+
+impl ResponseSchema {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|err| format!("read {} failed: {}", path, err))?;
+        let schema: Value = serde_json::from_slice(&data)
+            .map_err(|err| format!("parse {} failed: {}", path, err))?;
+        Self::new(path, &schema)
+    }
+
+    pub fn new(path: &str, schema: &Value) -> Result<Self, String> {
+        let validator = jsonschema::validator_for(schema)
+            .map_err(|err| format!("invalid JSON schema {}: {}", path, err))?;
+        Ok(Self {
+            path: path.to_string(),
+            validator,
+        })
+    }
+
+    pub fn validate(&self, body: &[u8]) -> Result<(), String> {
+        let instance: Value = serde_json::from_slice(body).map_err(err_string)?;
+        if let Some(err) = self.validator.iter_errors(&instance).next() {
+            return Err(format!("{} at {}", err, err.instance_path));
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for ResponseSchema {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponseSchema")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl PartialEq for ResponseSchema {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_response_schema() {
+        let schema = ResponseSchema::new(
+            "price.json",
+            &json!({
+                "type": "object",
+                "properties": {
+                    "price": {"type": "string"}
+                },
+                "required": ["price"]
+            }),
+        )
+        .unwrap();
+        assert!(schema.validate(br#"{"price":"10.5"}"#).is_ok());
+        assert!(schema.validate(br#"{"price":10.5}"#).is_err());
+        assert!(schema.validate(br#"{"error":"rate limited"}"#).is_err());
+        assert!(schema.validate(b"<html></html>").is_err());
+    }
+}
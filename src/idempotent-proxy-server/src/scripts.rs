@@ -0,0 +1,337 @@
+use http::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::Url;
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use std::collections::BTreeMap;
+
+use crate::cache::ResponseData;
+use crate::config_file;
+
+// Bounds of a single hook call, a script exceeding them fails the request.
+const MAX_OPERATIONS: u64 = 1_000_000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_STRING_SIZE: usize = 1024 * 1024;
+
+// What a hook decided about the request or response.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Continue,
+    Veto(String),
+}
+
+// Rhai script attached to a route, with optional hooks:
+//   fn on_request(req)  req is #{ method, path, query, headers }
+//   fn on_response(res) res is #{ status, headers }
+// A hook returns the changed map to apply its path, query and headers, a string to veto the
+// request (403) or the response (502, not cached), or nothing to leave them as they are.
+// Header names are lowercase, the values of a repeated header are joined with ", ".
+pub struct Script {
+    path: String,
+    engine: Engine,
+    ast: AST,
+    on_request: bool,
+    on_response: bool,
+}
+
+impl Script {
+    // Relative paths are relative to the directory of CONFIG_FILE.
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let path = config_file::resolve(path);
+        let source = std::fs::read_to_string(&path)
+            .map_err(|err| format!("read {} failed: {}", path, err))?;
+        Self::new(&path, &source)
+    }
+
+    pub fn new(path: &str, source: &str) -> Result<Self, String> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_call_levels(MAX_CALL_LEVELS);
+        engine.set_max_string_size(MAX_STRING_SIZE);
+        let ast = engine
+            .compile(source)
+            .map_err(|err| format!("invalid script {}: {}", path, err))?;
+        let has_hook = |name: &str| {
+            ast.iter_functions()
+                .any(|f| f.name == name && f.params.len() == 1)
+        };
+        let (on_request, on_response) = (has_hook("on_request"), has_hook("on_response"));
+        if !on_request && !on_response {
+            return Err(format!(
+                "script {} defines neither on_request(req) nor on_response(res)",
+                path
+            ));
+        }
+        Ok(Self {
+            path: path.to_string(),
+            engine,
+            ast,
+            on_request,
+            on_response,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn has_on_response(&self) -> bool {
+        self.on_response
+    }
+
+    pub fn on_request(
+        &self,
+        method: &str,
+        url: &mut Url,
+        headers: &mut HeaderMap,
+    ) -> Result<Verdict, String> {
+        if !self.on_request {
+            return Ok(Verdict::Continue);
+        }
+        let current: Vec<(String, String)> = headers
+            .iter()
+            .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+            .collect();
+        let mut req = Map::new();
+        req.insert("method".into(), Dynamic::from(method.to_string()));
+        req.insert("path".into(), Dynamic::from(url.path().to_string()));
+        let query = url.query().unwrap_or_default().to_string();
+        req.insert("query".into(), Dynamic::from(query));
+        req.insert("headers".into(), Dynamic::from_map(headers_map(&current)));
+
+        let mut req = match self.call("on_request", req)? {
+            Some(Ok(req)) => req,
+            Some(Err(reason)) => return Ok(Verdict::Veto(reason)),
+            None => return Ok(Verdict::Continue),
+        };
+        if let Some(path) = req.remove("path") {
+            let path = path
+                .into_string()
+                .map_err(|_| "path of on_request must be a string".to_string())?;
+            url.set_path(&path);
+        }
+        if let Some(query) = req.remove("query") {
+            let query = query
+                .into_string()
+                .map_err(|_| "query of on_request must be a string".to_string())?;
+            url.set_query(Some(query.as_str()).filter(|q| !q.is_empty()));
+        }
+        if let Some(changed) = req.remove("headers") {
+            for (name, value) in header_changes(&current, changed)? {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| format!("invalid header name: {}", name))?;
+                match value {
+                    None => {
+                        headers.remove(&name);
+                    }
+                    Some(value) => {
+                        let value = HeaderValue::from_str(&value)
+                            .map_err(|_| format!("invalid value of header {}", name))?;
+                        headers.insert(name, value);
+                    }
+                }
+            }
+        }
+        Ok(Verdict::Continue)
+    }
+
+    // Called with the response headers to cache, content-type included.
+    pub fn on_response(&self, rd: &mut ResponseData) -> Result<Verdict, String> {
+        if !self.on_response {
+            return Ok(Verdict::Continue);
+        }
+        let mut current = rd.headers.clone();
+        if !rd.mime.is_empty() {
+            current.push(("content-type".to_string(), rd.mime.clone()));
+        }
+        let mut res = Map::new();
+        res.insert("status".into(), Dynamic::from(rd.status as i64));
+        res.insert("headers".into(), Dynamic::from_map(headers_map(&current)));
+
+        let mut res = match self.call("on_response", res)? {
+            Some(Ok(res)) => res,
+            Some(Err(reason)) => return Ok(Verdict::Veto(reason)),
+            None => return Ok(Verdict::Continue),
+        };
+        if let Some(changed) = res.remove("headers") {
+            for (name, value) in header_changes(&current, changed)? {
+                rd.headers.retain(|(k, _)| k != &name);
+                match (name.as_str(), value) {
+                    ("content-type", value) => rd.mime = value.unwrap_or_default(),
+                    (_, None) => {}
+                    (_, Some(value)) => rd.headers.push((name, value)),
+                }
+            }
+        }
+        Ok(Verdict::Continue)
+    }
+
+    // The map returned by the hook, Err with the reason of a veto, None if nothing changed.
+    fn call(&self, hook: &str, arg: Map) -> Result<Option<Result<Map, String>>, String> {
+        let rt: Dynamic = self
+            .engine
+            .call_fn(&mut Scope::new(), &self.ast, hook, (arg,))
+            .map_err(|err| format!("{} of script {} failed: {}", hook, self.path, err))?;
+        if rt.is_unit() {
+            return Ok(None);
+        }
+        if rt.is_string() {
+            return Ok(Some(Err(rt.into_string().unwrap_or_default())));
+        }
+        match rt.try_cast::<Map>() {
+            Some(map) => Ok(Some(Ok(map))),
+            None => Err(format!(
+                "{} of script {} must return a map, a string or nothing",
+                hook, self.path
+            )),
+        }
+    }
+}
+
+fn joined_headers(headers: &[(String, String)]) -> BTreeMap<String, String> {
+    let mut joined: BTreeMap<String, String> = BTreeMap::new();
+    for (k, v) in headers {
+        joined
+            .entry(k.to_ascii_lowercase())
+            .and_modify(|s| {
+                s.push_str(", ");
+                s.push_str(v)
+            })
+            .or_insert_with(|| v.clone());
+    }
+    joined
+}
+
+fn headers_map(headers: &[(String, String)]) -> Map {
+    joined_headers(headers)
+        .into_iter()
+        .map(|(k, v)| (k.into(), Dynamic::from(v)))
+        .collect()
+}
+
+// The headers changed by a hook: None removes a header, Some replaces all its values.
+// Headers left as they were keep their repeated values.
+fn header_changes(
+    current: &[(String, String)],
+    changed: Dynamic,
+) -> Result<Vec<(String, Option<String>)>, String> {
+    let changed = changed
+        .try_cast::<Map>()
+        .ok_or_else(|| "headers must be a map".to_string())?;
+    let changed: BTreeMap<String, Dynamic> = changed
+        .into_iter()
+        .map(|(k, v)| (k.to_ascii_lowercase(), v))
+        .collect();
+    let current = joined_headers(current);
+
+    let mut changes: Vec<(String, Option<String>)> = current
+        .keys()
+        .filter(|k| !changed.contains_key(*k))
+        .map(|k| (k.clone(), None))
+        .collect();
+    for (name, value) in changed {
+        let value = if value.is_unit() {
+            None
+        } else {
+            Some(
+                value
+                    .into_string()
+                    .map_err(|_| format!("value of header {} must be a string", name))?,
+            )
+        };
+        if current.get(&name) != value.as_ref() {
+            changes.push((name, value));
+        }
+    }
+    Ok(changes)
+}
+
+impl std::fmt::Debug for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Script").field("path", &self.path).finish()
+    }
+}
+
+impl PartialEq for Script {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SCRIPT: &str = r#"
+        fn on_request(req) {
+            if "x-block" in req.headers {
+                return "blocked by script";
+            }
+            req.path = "/v2" + req.path;
+            req.query = "";
+            req.headers["x-api-version"] = "2";
+            req.headers.remove("x-debug");
+            req
+        }
+
+        fn on_response(res) {
+            if res.status >= 500 {
+                return "upstream is down";
+            }
+            res.headers.remove("x-request-id");
+            res.headers["cache-control"] = "public";
+            res.headers["content-type"] = "application/json";
+            res
+        }
+    "#;
+
+    #[test]
+    fn test_script() {
+        let script = Script::new("api.rhai", SCRIPT).unwrap();
+
+        let mut url = Url::parse("https://api.example.com/users?page=2").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-debug", "1".parse().unwrap());
+        headers.append("accept", "text/plain".parse().unwrap());
+        headers.append("accept", "application/json".parse().unwrap());
+        assert_eq!(
+            script.on_request("GET", &mut url, &mut headers).unwrap(),
+            Verdict::Continue
+        );
+        assert_eq!(url.as_str(), "https://api.example.com/v2/users");
+        assert_eq!(headers.get("x-api-version").unwrap(), "2");
+        assert!(!headers.contains_key("x-debug"));
+        assert_eq!(headers.get_all("accept").iter().count(), 2);
+
+        headers.insert("x-block", "1".parse().unwrap());
+        assert_eq!(
+            script.on_request("GET", &mut url, &mut headers).unwrap(),
+            Verdict::Veto("blocked by script".to_string())
+        );
+
+        let mut rd = ResponseData::new(200);
+        rd.mime = "text/plain".to_string();
+        rd.headers = vec![
+            ("x-request-id".to_string(), "abc".to_string()),
+            ("vary".to_string(), "accept".to_string()),
+        ];
+        assert_eq!(script.on_response(&mut rd).unwrap(), Verdict::Continue);
+        assert_eq!(rd.mime, "application/json");
+        assert_eq!(
+            rd.headers,
+            vec![
+                ("vary".to_string(), "accept".to_string()),
+                ("cache-control".to_string(), "public".to_string()),
+            ]
+        );
+        let mut rd = ResponseData::new(500);
+        assert_eq!(
+            script.on_response(&mut rd).unwrap(),
+            Verdict::Veto("upstream is down".to_string())
+        );
+
+        let script = Script::new("loop.rhai", "fn on_request(req) { loop {} }").unwrap();
+        assert!(script.on_request("GET", &mut url, &mut headers).is_err());
+        assert!(!script.has_on_response());
+        assert!(Script::new("none.rhai", "let x = 1;").is_err());
+        assert!(Script::new("invalid.rhai", "fn on_request(req) {").is_err());
+    }
+}
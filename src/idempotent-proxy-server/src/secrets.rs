@@ -0,0 +1,428 @@
+use axum_server::tls_rustls::RustlsConfig;
+use http::HeaderValue;
+use reqwest::{Client, Url};
+use serde_json::Value;
+use std::{borrow::Cow, collections::BTreeSet, sync::Mutex};
+use tokio::time::{sleep, Duration};
+
+use crate::config_file::set_vars;
+use crate::handler::AppState;
+
+// The variables set from the secrets, and the secrets of the last fetch.
+static APPLIED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+static FETCHED: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+// Variables of .env kept in a secret store instead of the environment, e.g. the keys as
+// ECDSA_PUB_KEY_<ID> or the TLS certificate and key as TLS_CERT_PEM and TLS_KEY_PEM.
+// HashiCorp Vault: VAULT_ADDR, VAULT_TOKEN, optionally VAULT_NAMESPACE, and the KV path
+// SECRETS_VAULT_PATH, e.g. secret/data/idempotent-proxy. AWS Secrets Manager: the JSON
+// object of SECRETS_AWS_SECRET_ID. They override the environment and the config file,
+// and are fetched again every SECRETS_REFRESH seconds if set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Secrets {
+    pub vault: Option<Vault>,
+    pub aws_secret_id: Option<String>,
+    pub refresh: Option<Duration>,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Vault {
+    pub addr: String,
+    pub token: String,
+    pub namespace: Option<String>,
+    pub path: String,
+}
+
+impl std::fmt::Debug for Vault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Vault")
+            .field("addr", &self.addr)
+            .field("namespace", &self.namespace)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl Secrets {
+    // None if no secret store is configured.
+    pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Option<Self>, String> {
+        let mut secrets = Secrets::default();
+        let mut vault = Vault {
+            addr: String::new(),
+            token: String::new(),
+            namespace: None,
+            path: String::new(),
+        };
+        for (k, v) in vars {
+            let v = v.trim();
+            match k.as_str() {
+                "VAULT_ADDR" => vault.addr = v.trim_end_matches('/').to_string(),
+                "VAULT_TOKEN" => vault.token = v.to_string(),
+                "VAULT_NAMESPACE" if !v.is_empty() => vault.namespace = Some(v.to_string()),
+                "SECRETS_VAULT_PATH" => vault.path = v.trim_matches('/').to_string(),
+                "SECRETS_AWS_SECRET_ID" if !v.is_empty() => {
+                    secrets.aws_secret_id = Some(v.to_string())
+                }
+                "SECRETS_REFRESH" => {
+                    let secs: u64 = v
+                        .parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| format!("invalid SECRETS_REFRESH value: {}", v))?;
+                    secrets.refresh = Some(Duration::from_secs(secs));
+                }
+                _ => {}
+            }
+        }
+        if !vault.path.is_empty() {
+            if vault.addr.is_empty() || vault.token.is_empty() {
+                return Err("SECRETS_VAULT_PATH needs VAULT_ADDR and VAULT_TOKEN".to_string());
+            }
+            secrets.vault = Some(vault);
+        }
+        Ok((secrets.vault.is_some() || secrets.aws_secret_id.is_some()).then_some(secrets))
+    }
+
+    pub async fn fetch(&self, http_client: &Client) -> Result<Vec<(String, String)>, String> {
+        let mut vars = Vec::new();
+        if let Some(vault) = &self.vault {
+            vars.extend(object_vars(vault.fetch(http_client).await?)?);
+        }
+        if let Some(secret_id) = &self.aws_secret_id {
+            vars.extend(object_vars(fetch_aws(secret_id).await?)?);
+        }
+        Ok(vars)
+    }
+}
+
+impl Vault {
+    async fn fetch(&self, http_client: &Client) -> Result<Value, String> {
+        let mut req = http_client
+            .get(format!("{}/v1/{}", self.addr, self.path))
+            .header("x-vault-token", &self.token);
+        if let Some(namespace) = &self.namespace {
+            req = req.header("x-vault-namespace", namespace);
+        }
+        let res: Value = req
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|err| format!("fetch Vault secret {} failed: {}", self.path, err))?
+            .json()
+            .await
+            .map_err(|err| format!("fetch Vault secret {} failed: {}", self.path, err))?;
+        Ok(kv_data(res))
+    }
+}
+
+// The secret of a Vault KV response, version 2 nests it in data.data.
+fn kv_data(mut res: Value) -> Value {
+    let mut data = res["data"].take();
+    match data.get_mut("data") {
+        Some(v) if v.is_object() => v.take(),
+        _ => data,
+    }
+}
+
+#[cfg(feature = "aws-secrets")]
+async fn fetch_aws(secret_id: &str) -> Result<Value, String> {
+    let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+        .load()
+        .await;
+    let out = aws_sdk_secretsmanager::Client::new(&config)
+        .get_secret_value()
+        .secret_id(secret_id)
+        .send()
+        .await
+        .map_err(|err| format!("fetch AWS secret {} failed: {}", secret_id, err))?;
+    let secret = out
+        .secret_string()
+        .ok_or_else(|| format!("AWS secret {} has no string value", secret_id))?;
+    serde_json::from_str(secret).map_err(|err| format!("invalid AWS secret {}: {}", secret_id, err))
+}
+
+#[cfg(not(feature = "aws-secrets"))]
+async fn fetch_aws(_secret_id: &str) -> Result<Value, String> {
+    Err("SECRETS_AWS_SECRET_ID needs a build with the aws-secrets feature".to_string())
+}
+
+// The string, number and boolean values of a JSON object as variables.
+fn object_vars(secret: Value) -> Result<Vec<(String, String)>, String> {
+    let Value::Object(map) = secret else {
+        return Err("the secret is not a JSON object".to_string());
+    };
+    Ok(map
+        .into_iter()
+        .filter_map(|(k, v)| match v {
+            Value::String(s) => Some((k, s)),
+            Value::Number(n) => Some((k, n.to_string())),
+            Value::Bool(b) => Some((k, b.to_string())),
+            _ => None,
+        })
+        .collect())
+}
+
+// Fetches the secrets at startup, before the state is built from the environment.
+pub async fn apply_from_env() -> Result<usize, String> {
+    let secrets = match Secrets::from_vars(std::env::vars())? {
+        Some(secrets) => secrets,
+        None => return Ok(0),
+    };
+    let vars = secrets.fetch(&Client::new()).await?;
+    let n = vars.len();
+    *FETCHED.lock().unwrap() = vars;
+    apply_cached();
+    Ok(n)
+}
+
+// Sets the secrets of the last fetch again, e.g. after the .env file is reloaded.
+pub fn apply_cached() {
+    let vars = FETCHED.lock().unwrap();
+    set_vars(&APPLIED, &vars);
+}
+
+// Fetches the secrets every SECRETS_REFRESH seconds, a change reloads the config.
+pub async fn run(app: AppState) {
+    let (secrets, refresh) = match Secrets::from_vars(std::env::vars()) {
+        Ok(Some(secrets)) => match secrets.refresh {
+            Some(refresh) => (secrets, refresh),
+            None => return,
+        },
+        _ => return,
+    };
+    loop {
+        sleep(refresh).await;
+        let vars = match secrets.fetch(&app.http_client).await {
+            Ok(vars) => vars,
+            Err(err) => {
+                log::error!(target: "secrets", "{}", err);
+                continue;
+            }
+        };
+        if *FETCHED.lock().unwrap() == vars {
+            continue;
+        }
+        *FETCHED.lock().unwrap() = vars;
+        match app.reload() {
+            Ok(_) => log::warn!(target: "secrets", "reloaded with the changed secrets"),
+            Err(err) => log::error!(target: "secrets", "reload failed: {}", err),
+        }
+    }
+}
+
+// `${NAME}` placeholders, also in the percent-encoded form of URL paths.
+const PLACEHOLDERS: [(&str, &str); 2] = [("${", "}"), ("$%7B", "%7D")];
+
+// Resolves the `${NAME}` placeholders of a URL_ constant, a HEADER_ constant or a static
+// header from the environment, where the secrets are set too. Templates are resolved when
+// a request is forwarded, so the secrets never reach the canisters, the logs or the
+// request fingerprints, and a rotated secret does not change the responses' keys.
+pub fn expand(template: &str) -> Result<Cow<'_, str>, String> {
+    expand_with(template, |name| std::env::var(name).ok())
+}
+
+pub fn expand_with(
+    template: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<Cow<'_, str>, String> {
+    if !PLACEHOLDERS.iter().any(|(open, _)| template.contains(open)) {
+        return Ok(Cow::Borrowed(template));
+    }
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some((i, open, close)) = PLACEHOLDERS
+        .iter()
+        .filter_map(|(open, close)| rest.find(open).map(|i| (i, *open, *close)))
+        .min_by_key(|(i, _, _)| *i)
+    {
+        out.push_str(&rest[..i]);
+        let after = &rest[i + open.len()..];
+        let end = after
+            .find(close)
+            .ok_or_else(|| "unclosed placeholder".to_string())?;
+        let name = &after[..end];
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+            return Err(format!("invalid placeholder: {}", name));
+        }
+        let value =
+            lookup(name).ok_or_else(|| format!("unknown variable of placeholder: {}", name))?;
+        out.push_str(&value);
+        rest = &after[end + close.len()..];
+    }
+    out.push_str(rest);
+    Ok(Cow::Owned(out))
+}
+
+pub fn expand_url(url: &Url) -> Result<Cow<'_, Url>, String> {
+    match expand(url.as_str())? {
+        Cow::Borrowed(_) => Ok(Cow::Borrowed(url)),
+        Cow::Owned(s) => Url::parse(&s)
+            .map(Cow::Owned)
+            .map_err(|_| "invalid url after resolving the placeholders".to_string()),
+    }
+}
+
+// Resolved values are secrets, they are kept out of debug output.
+pub fn expand_header(value: &HeaderValue) -> Result<Cow<'_, HeaderValue>, String> {
+    let template = match value.to_str() {
+        Ok(s) => s,
+        Err(_) => return Ok(Cow::Borrowed(value)),
+    };
+    match expand(template)? {
+        Cow::Borrowed(_) => Ok(Cow::Borrowed(value)),
+        Cow::Owned(s) => {
+            let mut v = HeaderValue::from_str(&s)
+                .map_err(|_| "invalid header value after resolving the placeholders")?;
+            v.set_sensitive(true);
+            Ok(Cow::Owned(v))
+        }
+    }
+}
+
+// The TLS certificate chain and private key of TLS_CERT_PEM and TLS_KEY_PEM.
+pub fn tls_pem() -> Option<(String, String)> {
+    let cert = std::env::var("TLS_CERT_PEM")
+        .ok()
+        .filter(|v| !v.is_empty())?;
+    let key = std::env::var("TLS_KEY_PEM")
+        .ok()
+        .filter(|v| !v.is_empty())?;
+    Some((cert, key))
+}
+
+// Swaps the certificate of the TLS listener when the secrets bring a new one,
+// established connections keep theirs.
+pub async fn reload_tls(config: RustlsConfig) {
+    let refresh = match Secrets::from_vars(std::env::vars()) {
+        Ok(Some(Secrets {
+            refresh: Some(refresh),
+            ..
+        })) => refresh,
+        _ => return,
+    };
+    let mut current = tls_pem();
+    loop {
+        sleep(refresh).await;
+        let pem = tls_pem();
+        if pem == current {
+            continue;
+        }
+        if let Some((cert, key)) = &pem {
+            match config
+                .reload_from_pem(cert.clone().into_bytes(), key.clone().into_bytes())
+                .await
+            {
+                Ok(_) => log::warn!(target: "secrets", "reloaded the TLS certificate"),
+                Err(err) => {
+                    log::error!(target: "secrets", "reload the TLS certificate failed: {}", err);
+                    continue;
+                }
+            }
+        }
+        current = pem;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_vars;
+
+    #[test]
+    fn test_secrets() {
+        assert_eq!(
+            Secrets::from_vars(test_vars(vec![("VAULT_ADDR", "https://vault:8200")])),
+            Ok(None)
+        );
+        let secrets = Secrets::from_vars(test_vars(vec![
+            ("VAULT_ADDR", "https://vault:8200/"),
+            ("VAULT_TOKEN", "hvs.xxxx"),
+            ("SECRETS_VAULT_PATH", "/secret/data/idempotent-proxy"),
+            ("SECRETS_AWS_SECRET_ID", "prod/idempotent-proxy"),
+            ("SECRETS_REFRESH", "300"),
+        ]))
+        .unwrap()
+        .unwrap();
+        let vault = secrets.vault.as_ref().unwrap();
+        assert_eq!(vault.addr, "https://vault:8200");
+        assert_eq!(vault.path, "secret/data/idempotent-proxy");
+        assert!(!format!("{:?}", vault).contains("hvs.xxxx"));
+        assert_eq!(
+            secrets.aws_secret_id.as_deref(),
+            Some("prod/idempotent-proxy")
+        );
+        assert_eq!(secrets.refresh, Some(Duration::from_secs(300)));
+
+        assert!(
+            Secrets::from_vars(test_vars(vec![("SECRETS_VAULT_PATH", "secret/proxy")])).is_err()
+        );
+        assert!(Secrets::from_vars(test_vars(vec![
+            ("SECRETS_AWS_SECRET_ID", "proxy"),
+            ("SECRETS_REFRESH", "0")
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_expand() {
+        let lookup = |name: &str| (name == "API_KEY").then(|| "s3cr3t".to_string());
+        assert_eq!(
+            expand_with("https://api.example.com/v1?key=${API_KEY}", lookup).unwrap(),
+            "https://api.example.com/v1?key=s3cr3t"
+        );
+        assert!(matches!(
+            expand_with("https://api.example.com/v1", lookup).unwrap(),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(
+            expand_with("Bearer ${API_KEY} ${API_KEY}", lookup).unwrap(),
+            "Bearer s3cr3t s3cr3t"
+        );
+        // a placeholder in a URL path is percent-encoded by the URL parser
+        let url = Url::parse("https://api.example.com/${API_KEY}/price").unwrap();
+        assert_eq!(
+            expand_with(url.as_str(), lookup).unwrap(),
+            "https://api.example.com/s3cr3t/price"
+        );
+        assert!(expand_with("${OTHER}", lookup).is_err());
+        assert!(expand_with("${API_KEY", lookup).is_err());
+        assert!(expand_with("${API-KEY}", lookup).is_err());
+        assert_eq!(expand_with("$5 {}", lookup).unwrap(), "$5 {}");
+
+        std::env::set_var("IDEMPOTENT_PROXY_TEST_SECRET", "k3y");
+        let v = HeaderValue::from_static("Bearer ${IDEMPOTENT_PROXY_TEST_SECRET}");
+        let v = expand_header(&v).unwrap();
+        assert_eq!(*v, "Bearer k3y");
+        assert!(v.is_sensitive());
+        let url =
+            Url::parse("https://api.example.com/?key=${IDEMPOTENT_PROXY_TEST_SECRET}").unwrap();
+        assert_eq!(
+            expand_url(&url).unwrap().as_str(),
+            "https://api.example.com/?key=k3y"
+        );
+    }
+
+    #[test]
+    fn test_object_vars() {
+        let v2 = serde_json::json!({"data": {"data": {
+            "ECDSA_PUB_KEY_2024B": "A6t1U8kc10AbLJ3-V1avU4rYvmAsYjXuzY0kPublttot",
+            "REQUEST_TIMEOUT": 10000,
+            "NESTED": {"a": 1},
+        }, "metadata": {"version": 3}}});
+        let vars = object_vars(kv_data(v2)).unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                (
+                    "ECDSA_PUB_KEY_2024B".to_string(),
+                    "A6t1U8kc10AbLJ3-V1avU4rYvmAsYjXuzY0kPublttot".to_string()
+                ),
+                ("REQUEST_TIMEOUT".to_string(), "10000".to_string()),
+            ]
+        );
+
+        let v1 = serde_json::json!({"data": {"HMAC_KEY": "c2hhcmVkIHNlY3JldCBrZXk"}});
+        assert_eq!(object_vars(kv_data(v1)).unwrap().len(), 1);
+        assert!(object_vars(serde_json::json!("secret")).is_err());
+    }
+}
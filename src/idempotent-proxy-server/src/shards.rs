@@ -0,0 +1,74 @@
+use std::{collections::hash_map::RandomState, hash::BuildHasher};
+
+pub const DEFAULT_SHARDS: usize = 16;
+
+// Spreads keys over independently locked shards by their hash, so thousands of
+// distinct keys locked per second don't contend on one lock.
+#[derive(Debug)]
+pub struct Sharded<T> {
+    shards: Vec<T>,
+    hasher: RandomState,
+}
+
+impl<T> Sharded<T> {
+    pub fn new(n: usize, mut f: impl FnMut() -> T) -> Self {
+        Sharded {
+            shards: (0..n.max(1)).map(|_| f()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub fn index(&self, key: &str) -> usize {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    pub fn get(&self, key: &str) -> &T {
+        &self.shards[self.index(key)]
+    }
+
+    pub fn at(&self, i: usize) -> &T {
+        &self.shards[i]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.shards.iter()
+    }
+}
+
+impl<T: Default> Default for Sharded<T> {
+    fn default() -> Self {
+        Sharded::new(DEFAULT_SHARDS, T::default)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_sharded() {
+        let sharded: Sharded<Mutex<Vec<String>>> = Sharded::new(4, Default::default);
+        assert_eq!(sharded.iter().count(), 4);
+        for i in 0..100 {
+            let key = format!("alice:POST:key_{}", i);
+            assert_eq!(sharded.index(&key), sharded.index(&key));
+            sharded.get(&key).lock().unwrap().push(key);
+        }
+        // every shard gets some of the keys
+        assert!(sharded.iter().all(|s| !s.lock().unwrap().is_empty()));
+        assert_eq!(
+            sharded
+                .iter()
+                .map(|s| s.lock().unwrap().len())
+                .sum::<usize>(),
+            100
+        );
+        assert_eq!(
+            Sharded::<Mutex<()>>::new(0, Default::default)
+                .iter()
+                .count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,483 @@
+use axum::body::Bytes;
+use hmac::{Hmac, Mac};
+use http::{HeaderMap, HeaderValue, Method};
+use idempotent_proxy_types::{err_string, unix_ms};
+use reqwest::{Client, Url};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::metadata;
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+// Assumed role credentials are refreshed 5 minutes before they expire.
+const REFRESH_BEFORE: u64 = 5 * 60 * 1000;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+    pub expires_at: u64, // unix timestamp in milliseconds, 0 for static credentials
+}
+
+impl Credentials {
+    // Falls back to the AWS_* environment variables of the process.
+    fn from_env() -> Option<Self> {
+        Some(Self {
+            access_key_id: std::env::var("AWS_ACCESS_KEY_ID").ok()?,
+            secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            expires_at: 0,
+        })
+    }
+}
+
+// AWS Signature Version 4 signing of upstream requests, configured per route.
+#[derive(Clone, Default)]
+pub struct AwsSigner {
+    pub region: String,
+    pub service: String,
+    pub credentials: Option<Credentials>,
+    pub role_arn: Option<String>,
+    cached: Arc<RwLock<Option<Credentials>>>, // assumed role or instance metadata credentials
+}
+
+impl std::fmt::Debug for AwsSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AwsSigner")
+            .field("region", &self.region)
+            .field("service", &self.service)
+            .field("role_arn", &self.role_arn)
+            .finish()
+    }
+}
+
+impl PartialEq for AwsSigner {
+    fn eq(&self, other: &Self) -> bool {
+        self.region == other.region
+            && self.service == other.service
+            && self.credentials == other.credentials
+            && self.role_arn == other.role_arn
+    }
+}
+
+impl AwsSigner {
+    pub fn set(&mut self, option: &str, value: &str) -> Result<(), String> {
+        let value = value.trim().to_string();
+        match option {
+            "AWS_REGION" => self.region = value,
+            "AWS_SERVICE" => self.service = value,
+            "AWS_ACCESS_KEY_ID" => {
+                self.credentials
+                    .get_or_insert_with(Default::default)
+                    .access_key_id = value
+            }
+            "AWS_SECRET_ACCESS_KEY" => {
+                self.credentials
+                    .get_or_insert_with(Default::default)
+                    .secret_access_key = value
+            }
+            "AWS_SESSION_TOKEN" => {
+                self.credentials
+                    .get_or_insert_with(Default::default)
+                    .session_token = Some(value)
+            }
+            "AWS_ROLE_ARN" => self.role_arn = Some(value),
+            _ => return Err(format!("unknown route option: {}", option)),
+        }
+        // a route may clone the default signer, it must not share the cached credentials.
+        self.cached = Arc::new(RwLock::new(None));
+        Ok(())
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        if self.region.is_empty() || self.service.is_empty() {
+            return Err("AWS_REGION and AWS_SERVICE are required for SigV4 signing".to_string());
+        }
+        if let Some(c) = &self.credentials {
+            if c.access_key_id.is_empty() || c.secret_access_key.is_empty() {
+                return Err(
+                    "AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY are required together".to_string(),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn sign_request(
+        &self,
+        http_client: &Client,
+        req: &mut reqwest::Request,
+        body: Option<&Bytes>,
+    ) -> Result<(), String> {
+        let credentials = self.credentials(http_client).await?;
+        let url = req.url().clone();
+        let method = req.method().clone();
+        sign(
+            &method,
+            &url,
+            req.headers_mut(),
+            body.map(|b| b.as_ref()).unwrap_or_default(),
+            &credentials,
+            &self.region,
+            &self.service,
+            &amz_date(unix_ms()),
+        )
+    }
+
+    async fn credentials(&self, http_client: &Client) -> Result<Credentials, String> {
+        let base = self.credentials.clone().or_else(Credentials::from_env);
+        if self.role_arn.is_none() {
+            if let Some(c) = base {
+                return Ok(c);
+            }
+        }
+
+        if let Some(c) = self.cached.read().await.as_ref() {
+            if c.expires_at > unix_ms() + REFRESH_BEFORE {
+                return Ok(c.clone());
+            }
+        }
+
+        let mut cached = self.cached.write().await;
+        if let Some(c) = cached.as_ref() {
+            if c.expires_at > unix_ms() + REFRESH_BEFORE {
+                return Ok(c.clone());
+            }
+        }
+        // static credentials, then the instance role credentials from the EC2 metadata service.
+        let base = match base {
+            Some(c) => c,
+            None => metadata::aws_credentials(http_client).await?,
+        };
+        let c = match &self.role_arn {
+            Some(role_arn) => assume_role(http_client, &base, &self.region, role_arn).await?,
+            None => base,
+        };
+        *cached = Some(c.clone());
+        Ok(c)
+    }
+}
+
+async fn assume_role(
+    http_client: &Client,
+    base: &Credentials,
+    region: &str,
+    role_arn: &str,
+) -> Result<Credentials, String> {
+    let duration = 3600u64;
+    let url = Url::parse(&format!("https://sts.{}.amazonaws.com/", region)).map_err(err_string)?;
+    let body = Bytes::from(format!(
+        "Action=AssumeRole&Version=2011-06-15&RoleSessionName=idempotent-proxy&DurationSeconds={}&RoleArn={}",
+        duration,
+        uri_encode(role_arn, true)
+    ));
+    let mut req = reqwest::Request::new(Method::POST, url.clone());
+    let headers = req.headers_mut();
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("application/x-www-form-urlencoded"),
+    );
+    headers.insert(
+        http::header::ACCEPT,
+        HeaderValue::from_static("application/json"),
+    );
+    sign(
+        &Method::POST,
+        &url,
+        headers,
+        &body,
+        base,
+        region,
+        "sts",
+        &amz_date(unix_ms()),
+    )?;
+    *req.body_mut() = Some(reqwest::Body::from(body));
+
+    let res = http_client.execute(req).await.map_err(err_string)?;
+    let status = res.status();
+    let data = res.bytes().await.map_err(err_string)?;
+    if !status.is_success() {
+        return Err(format!(
+            "assume role {} failed, status: {}, body: {}",
+            role_arn,
+            status,
+            String::from_utf8_lossy(&data)
+        ));
+    }
+
+    let obj: Value = serde_json::from_slice(&data).map_err(err_string)?;
+    let c = &obj["AssumeRoleResponse"]["AssumeRoleResult"]["Credentials"];
+    let field = |name: &str| -> Result<String, String> {
+        c[name]
+            .as_str()
+            .map(|s| s.to_string())
+            .ok_or_else(|| format!("invalid assume role response, missing {}", name))
+    };
+    let expires_at = match c["Expiration"].as_f64() {
+        Some(secs) => (secs * 1000.0) as u64,
+        None => unix_ms() + duration * 1000,
+    };
+    Ok(Credentials {
+        access_key_id: field("AccessKeyId")?,
+        secret_access_key: field("SecretAccessKey")?,
+        session_token: Some(field("SessionToken")?),
+        expires_at,
+    })
+}
+
+// Signs the request in place: adds the x-amz-* headers and the authorization header.
+#[allow(clippy::too_many_arguments)]
+pub fn sign(
+    method: &Method,
+    url: &Url,
+    headers: &mut HeaderMap,
+    body: &[u8],
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    amz_date: &str,
+) -> Result<(), String> {
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        _ => return Err(format!("invalid url: {}", url)),
+    };
+    let payload_hash = hex_string(&Sha256::digest(body));
+    headers.remove(http::header::AUTHORIZATION);
+    headers.insert("x-amz-date", amz_date.parse().map_err(err_string)?);
+    if service == "s3" {
+        headers.insert(
+            "x-amz-content-sha256",
+            payload_hash.parse().map_err(err_string)?,
+        );
+    }
+    if let Some(token) = &credentials.session_token {
+        headers.insert("x-amz-security-token", token.parse().map_err(err_string)?);
+    }
+
+    let mut signed: Vec<(String, String)> = vec![("host".to_string(), host)];
+    for (k, v) in headers.iter() {
+        let k = k.as_str();
+        if k == "content-type" || k.starts_with("x-amz-") {
+            let v = v.to_str().map_err(err_string)?;
+            signed.push((
+                k.to_string(),
+                v.split_whitespace().collect::<Vec<_>>().join(" "),
+            ));
+        }
+    }
+    signed.sort();
+    let signed_headers = signed
+        .iter()
+        .map(|(k, _)| k.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_headers: String = signed
+        .iter()
+        .map(|(k, v)| format!("{}:{}\n", k, v))
+        .collect();
+
+    let mut query: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| (uri_encode(&k, true), uri_encode(&v, true)))
+        .collect();
+    query.sort();
+    let canonical_query = query
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let path = url
+        .path()
+        .split('/')
+        .map(|s| uri_encode(&percent_decode(s), true))
+        .collect::<Vec<_>>()
+        .join("/");
+    // S3 object keys are not normalized and encoded only once.
+    let canonical_uri = if service == "s3" {
+        path
+    } else {
+        uri_encode(&path, false)
+    };
+
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method.as_str(),
+        canonical_uri,
+        canonical_query,
+        canonical_headers,
+        signed_headers,
+        payload_hash
+    );
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        amz_date,
+        scope,
+        hex_string(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = signing_key(&credentials.secret_access_key, date, region, service);
+    let signature = hex_string(&hmac_sha256(&key, string_to_sign.as_bytes()));
+    let authorization = format!(
+        "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+        ALGORITHM, credentials.access_key_id, scope, signed_headers, signature
+    );
+    headers.insert(
+        http::header::AUTHORIZATION,
+        authorization.parse().map_err(err_string)?,
+    );
+    Ok(())
+}
+
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let k = hmac_sha256(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let k = hmac_sha256(&k, region.as_bytes());
+    let k = hmac_sha256(&k, service.as_bytes());
+    hmac_sha256(&k, b"aws4_request")
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex_string(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// RFC 3986 encoding, unreserved characters are kept.
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            b'/' if !encode_slash => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = |b: u8| (b as char).to_digit(16);
+            if let (Some(h), Some(l)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                out.push((h * 16 + l) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+// Formats a unix timestamp in milliseconds as `YYYYMMDDTHHMMSSZ`.
+fn amz_date(now_ms: u64) -> String {
+    let secs = now_ms / 1000;
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_amz_date() {
+        assert_eq!(amz_date(1440938160000), "20150830T123600Z");
+        assert_eq!(amz_date(951782400000), "20000229T000000Z");
+        assert_eq!(amz_date(0), "19700101T000000Z");
+    }
+
+    #[test]
+    fn test_signing_key() {
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20150830",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex_string(&key),
+            "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9"
+        );
+    }
+
+    #[test]
+    fn test_sign() {
+        // https://docs.aws.amazon.com/IAM/latest/UserGuide/create-signed-request.html
+        let url =
+            Url::parse("https://iam.amazonaws.com/?Version=2010-05-08&Action=ListUsers").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            "application/x-www-form-urlencoded; charset=utf-8"
+                .parse()
+                .unwrap(),
+        );
+        let credentials = Credentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+            expires_at: 0,
+        };
+        sign(
+            &Method::GET,
+            &url,
+            &mut headers,
+            b"",
+            &credentials,
+            "us-east-1",
+            "iam",
+            "20150830T123600Z",
+        )
+        .unwrap();
+        assert_eq!(
+            headers.get(http::header::AUTHORIZATION).unwrap(),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, SignedHeaders=content-type;host;x-amz-date, Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7"
+        );
+        assert_eq!(headers.get("x-amz-date").unwrap(), "20150830T123600Z");
+    }
+
+    #[test]
+    fn test_uri_encode() {
+        assert_eq!(uri_encode("a b/c~", true), "a%20b%2Fc~");
+        assert_eq!(uri_encode("a b/c~", false), "a%20b/c~");
+        assert_eq!(percent_decode("a%20b%2"), "a b%2");
+        assert_eq!(percent_decode("%E4%BD%A0"), "你");
+    }
+}
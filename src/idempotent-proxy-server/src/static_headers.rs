@@ -0,0 +1,154 @@
+use http::{HeaderMap, HeaderName, HeaderValue};
+use std::str::FromStr;
+
+use crate::secrets;
+
+const FILE_PREFIX: char = '@';
+const ENV_PREFIX: char = '$';
+
+// Static headers set on every upstream request or client response of a route,
+// e.g. "x-tenant-id: acme | x-api-key: @/run/secrets/api_key".
+// Items are separated by "|" since header values may contain commas,
+// a value starting with "@" is read from the file (trimmed) when the config is loaded,
+// one starting with "$" from the environment variable, e.g. "authorization: $UPSTREAM_API_KEY".
+// `${NAME}` placeholders are resolved when a request is forwarded instead,
+// e.g. "authorization: Bearer ${UPSTREAM_API_KEY}".
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StaticHeaders(Vec<(HeaderName, HeaderValue)>);
+
+impl FromStr for StaticHeaders {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut headers = Vec::new();
+        for item in s.split('|') {
+            let item = item.trim();
+            if item.is_empty() {
+                continue;
+            }
+            let (name, value) = item
+                .split_once(':')
+                .ok_or_else(|| format!("invalid static header: {}", item))?;
+            let name = HeaderName::from_str(name.trim())
+                .map_err(|_| format!("invalid static header name: {}", name))?;
+            let value = value.trim();
+            // "${NAME}" is a placeholder, not an environment variable read now
+            let var = value
+                .strip_prefix(ENV_PREFIX)
+                .filter(|var| !var.starts_with('{'));
+            let value = match (value.strip_prefix(FILE_PREFIX), var) {
+                (Some(path), _) => {
+                    let content = std::fs::read_to_string(path.trim()).map_err(|err| {
+                        format!("failed to read header {} from {}: {}", name, path, err)
+                    })?;
+                    let mut v = HeaderValue::from_str(content.trim())
+                        .map_err(|_| format!("invalid value of header {} in {}", name, path))?;
+                    // file sourced values are secrets, keep them out of debug output
+                    v.set_sensitive(true);
+                    v
+                }
+                (_, Some(var)) => {
+                    let content = std::env::var(var.trim()).map_err(|_| {
+                        format!("failed to read header {} from ${}", name, var.trim())
+                    })?;
+                    let mut v = HeaderValue::from_str(content.trim())
+                        .map_err(|_| format!("invalid value of header {} in ${}", name, var))?;
+                    v.set_sensitive(true);
+                    v
+                }
+                (None, None) => {
+                    secrets::expand(value).map_err(|err| format!("header {}: {}", name, err))?;
+                    HeaderValue::from_str(value)
+                        .map_err(|_| format!("invalid value of header {}", name))?
+                }
+            };
+            headers.push((name, value));
+        }
+        Ok(StaticHeaders(headers))
+    }
+}
+
+impl StaticHeaders {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(HeaderName, HeaderValue)> {
+        self.0.iter()
+    }
+
+    // Static headers replace the headers of the same name.
+    pub fn apply(&self, headers: &mut HeaderMap) -> Result<(), String> {
+        for (name, value) in &self.0 {
+            let value =
+                secrets::expand_header(value).map_err(|err| format!("header {}: {}", name, err))?;
+            headers.insert(name.clone(), value.into_owned());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_static_headers() {
+        let path = std::env::temp_dir().join("idempotent-proxy-static-header");
+        std::fs::write(&path, "s3cr3t\n").unwrap();
+
+        let sh: StaticHeaders = format!(
+            "x-tenant-id: acme | cache-control: public, max-age=60 | x-api-key: @{}",
+            path.display()
+        )
+        .parse()
+        .unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant-id", HeaderValue::from_static("other"));
+        sh.apply(&mut headers).unwrap();
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers["x-tenant-id"], "acme");
+        assert_eq!(headers["cache-control"], "public, max-age=60");
+        assert_eq!(headers["x-api-key"], "s3cr3t");
+        assert!(headers["x-api-key"].is_sensitive());
+
+        assert!("".parse::<StaticHeaders>().unwrap().is_empty());
+        assert!("x-tenant-id".parse::<StaticHeaders>().is_err());
+        assert!("x-api-key: @/not/exists".parse::<StaticHeaders>().is_err());
+
+        std::env::set_var("IDEMPOTENT_PROXY_TEST_API_KEY", "k3y");
+        let sh: StaticHeaders = "authorization: $IDEMPOTENT_PROXY_TEST_API_KEY"
+            .parse()
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        sh.apply(&mut headers).unwrap();
+        assert_eq!(headers["authorization"], "k3y");
+        assert!(headers["authorization"].is_sensitive());
+        assert!("x-api-key: $IDEMPOTENT_PROXY_TEST_NOT_SET"
+            .parse::<StaticHeaders>()
+            .is_err());
+
+        // resolved when applied
+        let sh: StaticHeaders = "authorization: Bearer ${IDEMPOTENT_PROXY_TEST_API_KEY}"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            sh.iter().next().unwrap().1,
+            "Bearer ${IDEMPOTENT_PROXY_TEST_API_KEY}"
+        );
+        let mut headers = HeaderMap::new();
+        sh.apply(&mut headers).unwrap();
+        assert_eq!(headers["authorization"], "Bearer k3y");
+        let sh: StaticHeaders = "x-api-key: ${IDEMPOTENT_PROXY_TEST_API_KEY}"
+            .parse()
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        sh.apply(&mut headers).unwrap();
+        assert_eq!(headers["x-api-key"], "k3y");
+        assert!("x-api-key: ${IDEMPOTENT_PROXY_TEST_NOT_SET}"
+            .parse::<StaticHeaders>()
+            .is_err());
+    }
+}
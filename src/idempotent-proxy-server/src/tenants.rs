@@ -0,0 +1,329 @@
+use base64::{engine::general_purpose, Engine};
+use idempotent_proxy_types::auth;
+use k256::ecdsa;
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::agents::AgentSet;
+
+use crate::routes::split_list;
+
+const TENANT_PREFIX: &str = "TENANT_";
+
+// A tenant is an independent product served by the same deployment.
+// Options are set by environment variables `TENANT_<NAME>_<OPTION>`, a proxy token
+// belongs to the tenant whose keys verify it. The agents of a tenant are namespaced
+// as `<name>/<agent>` in stats, events, logs and idempotency keys.
+#[derive(Debug, Default)]
+pub struct Tenant {
+    pub name: String,
+    pub ecdsa_pub_keys: Vec<ecdsa::VerifyingKey>,
+    pub ed25519_pub_keys: Vec<ed25519_dalek::VerifyingKey>,
+    pub p256_pub_keys: Vec<p256::ecdsa::VerifyingKey>,
+    pub agents: AgentSet,        // empty for any agent
+    pub key_prefix: String,      // prepended to the idempotency keys in the storage
+    pub rate_limit: Option<u32>, // requests per second
+    pub rate_burst: Option<u32>, // the rate limit by default
+    pub limiter: Option<RateLimiter>,
+}
+
+impl Tenant {
+    const OPTIONS: &'static [&'static str] = &[
+        "ECDSA_PUB_KEYS",
+        "ED25519_PUB_KEYS",
+        "P256_PUB_KEYS",
+        "AGENTS",
+        "KEY_PREFIX",
+        "RATE_LIMIT",
+        "RATE_BURST",
+    ];
+
+    fn set(&mut self, option: &str, value: &str) -> Result<(), String> {
+        match option {
+            "ECDSA_PUB_KEYS" => {
+                self.ecdsa_pub_keys = split_list(value)
+                    .iter()
+                    .map(|v| {
+                        let v = general_purpose::URL_SAFE_NO_PAD
+                            .decode(v)
+                            .map_err(|_| format!("invalid base64 key: {}", v))?;
+                        ecdsa::VerifyingKey::from_sec1_bytes(&v)
+                            .map_err(|_| "invalid ecdsa key".to_string())
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            "ED25519_PUB_KEYS" => {
+                self.ed25519_pub_keys = split_list(value)
+                    .iter()
+                    .map(|v| {
+                        let v = general_purpose::URL_SAFE_NO_PAD
+                            .decode(v)
+                            .map_err(|_| format!("invalid base64 key: {}", v))?;
+                        let key: [u8; 32] = v
+                            .try_into()
+                            .map_err(|_| "invalid ed25519 key".to_string())?;
+                        ed25519_dalek::VerifyingKey::from_bytes(&key)
+                            .map_err(|_| "invalid ed25519 key".to_string())
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            "P256_PUB_KEYS" => {
+                self.p256_pub_keys = split_list(value)
+                    .iter()
+                    .map(|v| {
+                        let v = general_purpose::URL_SAFE_NO_PAD
+                            .decode(v)
+                            .map_err(|_| format!("invalid base64 key: {}", v))?;
+                        p256::ecdsa::VerifyingKey::from_sec1_bytes(&v)
+                            .map_err(|_| "invalid p256 key".to_string())
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            "AGENTS" => self.agents = value.parse()?,
+            "KEY_PREFIX" => self.key_prefix = value.trim().to_string(),
+            "RATE_LIMIT" | "RATE_BURST" => {
+                let n: u32 = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid {} value: {}", option, value))?;
+                if option == "RATE_LIMIT" {
+                    self.rate_limit = Some(n);
+                } else {
+                    self.rate_burst = Some(n);
+                }
+            }
+            _ => return Err(format!("unknown tenant option: {}", option)),
+        }
+        Ok(())
+    }
+
+    // Returns the agent of a token signed by one of the tenant's keys.
+    pub fn verify(&self, token: &[u8]) -> Option<String> {
+        if !self.ecdsa_pub_keys.is_empty() {
+            if let Ok(t) = auth::ecdsa_verify(&self.ecdsa_pub_keys, token) {
+                return Some(t.1);
+            }
+        }
+        if !self.ed25519_pub_keys.is_empty() {
+            if let Ok(t) = auth::ed25519_verify(&self.ed25519_pub_keys, token) {
+                return Some(t.1);
+            }
+        }
+        if !self.p256_pub_keys.is_empty() {
+            if let Ok(t) = auth::p256_verify(&self.p256_pub_keys, token) {
+                return Some(t.1);
+            }
+        }
+        None
+    }
+
+    pub fn allows_agent(&self, agent: &str) -> bool {
+        self.agents.is_empty() || self.agents.contains(agent)
+    }
+
+    pub fn agent_namespace(&self, agent: &str) -> String {
+        format!("{}/{}", self.name, agent)
+    }
+}
+
+// Token bucket of `rate` requests per second with a capacity of `burst` requests.
+#[derive(Debug)]
+pub struct RateLimiter {
+    pub rate: u32,
+    pub burst: u32,
+    state: Mutex<(f64, Instant)>, // available tokens, last refill
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        RateLimiter {
+            rate,
+            burst,
+            state: Mutex::new((burst as f64, Instant::now())),
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    // The time until a request is allowed again.
+    pub fn retry_after(&self) -> Duration {
+        self.retry_after_at(Instant::now())
+    }
+
+    fn retry_after_at(&self, now: Instant) -> Duration {
+        let state = self.state.lock().unwrap();
+        let elapsed = now.saturating_duration_since(state.1).as_secs_f64();
+        let tokens = (state.0 + elapsed * self.rate as f64).min(self.burst as f64);
+        Duration::from_secs_f64(((1.0 - tokens) / self.rate.max(1) as f64).max(0.0))
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let elapsed = now.saturating_duration_since(state.1).as_secs_f64();
+        state.0 = (state.0 + elapsed * self.rate as f64).min(self.burst as f64);
+        state.1 = now;
+        if state.0 >= 1.0 {
+            state.0 -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Tenants(Vec<Tenant>);
+
+impl Tenants {
+    pub fn from_vars(vars: impl Iterator<Item = (String, String)>) -> Result<Self, String> {
+        let mut tenants: BTreeMap<String, Tenant> = BTreeMap::new();
+        for (k, v) in vars {
+            if let Some(name_option) = k.strip_prefix(TENANT_PREFIX) {
+                let (name, option) = Tenant::OPTIONS
+                    .iter()
+                    .find_map(|option| {
+                        name_option
+                            .strip_suffix(option)
+                            .and_then(|name| name.strip_suffix('_'))
+                            .filter(|name| !name.is_empty())
+                            .map(|name| (name.to_ascii_lowercase(), *option))
+                    })
+                    .ok_or_else(|| format!("unknown tenant option: {}", k))?;
+                tenants
+                    .entry(name.clone())
+                    .or_insert_with(|| Tenant {
+                        name,
+                        ..Default::default()
+                    })
+                    .set(option, &v)?;
+            }
+        }
+
+        for tenant in tenants.values_mut() {
+            if tenant.ecdsa_pub_keys.is_empty()
+                && tenant.ed25519_pub_keys.is_empty()
+                && tenant.p256_pub_keys.is_empty()
+            {
+                return Err(format!("tenant {} has no verifying keys", tenant.name));
+            }
+            tenant.limiter = match (tenant.rate_limit, tenant.rate_burst) {
+                (Some(rate), burst) => Some(RateLimiter::new(rate, burst.unwrap_or(rate))),
+                (None, Some(_)) => {
+                    return Err(format!(
+                        "tenant {} has RATE_BURST without RATE_LIMIT",
+                        tenant.name
+                    ))
+                }
+                (None, None) => None,
+            };
+        }
+        Ok(Tenants(tenants.into_values().collect()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Returns the tenant and the (not namespaced) agent of a decoded proxy token.
+    pub fn verify(&self, token: &[u8]) -> Option<(&Tenant, String)> {
+        self.0
+            .iter()
+            .find_map(|t| t.verify(token).map(|agent| (t, agent)))
+    }
+
+    // The storage prefix of the idempotency keys of an agent, e.g. `acme:acme/web:`
+    // for the namespaced agent `acme/web` of the tenant acme.
+    pub fn agent_key_prefix(&self, agent: &str) -> String {
+        let key_prefix = agent
+            .split_once('/')
+            .and_then(|(name, _)| self.0.iter().find(|t| t.name == name))
+            .map(|t| t.key_prefix.as_str())
+            .unwrap_or_default();
+        format!("{}{}:", key_prefix, agent)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use idempotent_proxy_types::unix_ms;
+
+    #[test]
+    fn test_tenants() {
+        let acme = ed25519_dalek::SigningKey::from_bytes(&[1u8; 32]);
+        let globex = ed25519_dalek::SigningKey::from_bytes(&[2u8; 32]);
+        let vars = vec![
+            (
+                "TENANT_ACME_ED25519_PUB_KEYS".to_string(),
+                general_purpose::URL_SAFE_NO_PAD.encode(acme.verifying_key().to_bytes()),
+            ),
+            ("TENANT_ACME_AGENTS".to_string(), "web, batch-*".to_string()),
+            ("TENANT_ACME_KEY_PREFIX".to_string(), "acme:".to_string()),
+            ("TENANT_ACME_RATE_LIMIT".to_string(), "10".to_string()),
+            (
+                "TENANT_GLOBEX_ED25519_PUB_KEYS".to_string(),
+                general_purpose::URL_SAFE_NO_PAD.encode(globex.verifying_key().to_bytes()),
+            ),
+        ];
+        let tenants = Tenants::from_vars(vars.into_iter()).unwrap();
+
+        let token = auth::ed25519_sign(&globex, unix_ms() / 1000 + 60, "web".to_string());
+        let (tenant, agent) = tenants.verify(&token).unwrap();
+        assert_eq!(tenant.name, "globex");
+        assert_eq!(tenant.agent_namespace(&agent), "globex/web");
+        assert!(tenant.allows_agent("any"));
+        assert!(tenant.limiter.is_none());
+
+        let token = auth::ed25519_sign(&acme, unix_ms() / 1000 + 60, "web".to_string());
+        let (tenant, _) = tenants.verify(&token).unwrap();
+        assert_eq!(tenant.name, "acme");
+        assert_eq!(tenant.key_prefix, "acme:");
+        assert_eq!(tenants.agent_key_prefix("acme/web"), "acme:acme/web:");
+        assert_eq!(tenants.agent_key_prefix("globex/web"), "globex/web:");
+        assert_eq!(tenants.agent_key_prefix("alice"), "alice:");
+        assert!(tenant.allows_agent("batch-7f9c"));
+        assert!(!tenant.allows_agent("other"));
+        assert_eq!(tenant.limiter.as_ref().unwrap().burst, 10);
+
+        let other = ed25519_dalek::SigningKey::from_bytes(&[3u8; 32]);
+        let token = auth::ed25519_sign(&other, unix_ms() / 1000 + 60, "web".to_string());
+        assert!(tenants.verify(&token).is_none());
+
+        assert!(Tenants::from_vars(
+            vec![("TENANT_ACME_AGENTS".to_string(), "web".to_string())].into_iter()
+        )
+        .is_err());
+        assert!(Tenants::from_vars(
+            vec![("TENANT_ACME_UNKNOWN".to_string(), "1".to_string())].into_iter()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2, 3);
+        let now = Instant::now();
+        assert!(limiter.try_acquire_at(now));
+        assert!(limiter.try_acquire_at(now));
+        assert!(limiter.try_acquire_at(now));
+        assert!(!limiter.try_acquire_at(now));
+        assert_eq!(limiter.retry_after_at(now), Duration::from_millis(500));
+        assert_eq!(
+            limiter.retry_after_at(now + Duration::from_millis(500)),
+            Duration::ZERO
+        );
+        assert!(limiter.try_acquire_at(now + Duration::from_millis(500)));
+        assert!(!limiter.try_acquire_at(now + Duration::from_millis(500)));
+        // refill is capped by the burst
+        let later = now + Duration::from_secs(10);
+        for _ in 0..3 {
+            assert!(limiter.try_acquire_at(later));
+        }
+        assert!(!limiter.try_acquire_at(later));
+    }
+}
@@ -0,0 +1,254 @@
+use ciborium::{from_reader, into_writer};
+use idempotent_proxy_types::{err_string, unix_ms};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+use crate::agents::AgentPattern;
+use crate::cache::{HybridCacher, Storage};
+use crate::handler::AppState;
+use crate::journal::Outcome;
+
+const KEY_PREFIX: &str = "usage:";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub requests: u64,
+    pub replayed: u64,
+    pub failed: u64, // rejected, failed or refused while the storage was unavailable
+    pub bytes: u64,  // of the response bodies
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.requests += other.requests;
+        self.replayed += other.replayed;
+        self.failed += other.failed;
+        self.bytes += other.bytes;
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct UsageReport {
+    pub from: u64, // unix timestamp in milliseconds, the start of the first bucket
+    pub to: u64,
+    pub bucket: u64, // in milliseconds
+    pub agents: BTreeMap<String, Usage>,
+}
+
+// Per agent request counts and bytes for billing, in buckets of `bucket` milliseconds.
+// Every instance writes its own counters of a bucket under
+// `usage:<bucket start>:<writer>:<agent>`, so the instances never overwrite each other,
+// and a report sums the counters of all the instances.
+#[derive(Debug, Default)]
+pub struct Accounting {
+    pub enabled: bool,
+    pub bucket: u64,         // in milliseconds
+    pub flush_interval: u64, // in milliseconds
+    pub retention: u64,      // in milliseconds
+    writer: String,
+    // the counters of the open buckets and of the ones not flushed since they closed
+    buckets: Mutex<BTreeMap<(u64, String), Usage>>,
+}
+
+impl Accounting {
+    pub fn from_vars(
+        vars: impl Iterator<Item = (String, String)>,
+        instance: &str,
+    ) -> Result<Self, String> {
+        let mut accounting = Accounting {
+            enabled: false,
+            bucket: 3_600_000,
+            flush_interval: 60_000,
+            retention: 90 * 86_400_000,
+            // a restarted instance must not overwrite the counters it wrote before
+            writer: format!("{}@{}", instance.replace(':', "_"), unix_ms()),
+            buckets: Mutex::new(BTreeMap::new()),
+        };
+        let parse = |k: &str, v: &str| {
+            v.trim()
+                .parse::<u64>()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or_else(|| format!("invalid {} value: {}", k, v))
+        };
+        for (k, v) in vars {
+            match k.as_str() {
+                "USAGE_ACCOUNTING" => {
+                    accounting.enabled = v
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("invalid USAGE_ACCOUNTING value: {}", v))?
+                }
+                "USAGE_BUCKET" => accounting.bucket = parse(&k, &v)? * 1000,
+                "USAGE_FLUSH_INTERVAL" => accounting.flush_interval = parse(&k, &v)? * 1000,
+                "USAGE_RETENTION" => accounting.retention = parse(&k, &v)? * 86_400_000,
+                _ => {}
+            }
+        }
+        Ok(accounting)
+    }
+
+    // `at` is when the request finished, a bucket gets no requests once it closed.
+    pub fn record(&self, agent: &str, outcome: Outcome, bytes: u64, at: u64) {
+        if !self.enabled {
+            return;
+        }
+        let start = at - at % self.bucket;
+        let mut buckets = self.buckets.lock().unwrap();
+        let usage = buckets.entry((start, agent.to_string())).or_default();
+        usage.requests += 1;
+        match outcome {
+            Outcome::Proxied => {}
+            Outcome::Replayed => usage.replayed += 1,
+            Outcome::Rejected | Outcome::Failed | Outcome::Unavailable => usage.failed += 1,
+        }
+        usage.bytes += bytes;
+    }
+
+    // Writes the counters to the storage and forgets the closed buckets once written.
+    pub async fn flush(&self, cacher: &HybridCacher) -> Result<(), String> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let now = unix_ms();
+        let buckets = self.buckets.lock().unwrap().clone();
+        let mut written = Vec::new();
+        for ((start, agent), usage) in buckets {
+            let key = format!("{}{}:{}:{}", KEY_PREFIX, start, self.writer, agent);
+            let mut val = Vec::new();
+            into_writer(&usage, &mut val).map_err(err_string)?;
+            // the key exists since the first flush of the bucket
+            cacher.obtain(&key, self.retention).await?;
+            cacher.set(&key, val, self.retention).await?;
+            if start + self.bucket <= now {
+                written.push(((start, agent), usage));
+            }
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        for (k, usage) in written {
+            // a request of the bucket may have been recorded during the flush
+            if buckets.get(&k) == Some(&usage) {
+                buckets.remove(&k);
+            }
+        }
+        Ok(())
+    }
+
+    // Sums the counters of the buckets starting in [from, to) of the matching agents.
+    pub async fn report(
+        &self,
+        cacher: &HybridCacher,
+        agent: Option<&AgentPattern>,
+        from: u64,
+        to: u64,
+    ) -> Result<UsageReport, String> {
+        self.flush(cacher).await?;
+        let from = from - from % self.bucket;
+        let mut report = UsageReport {
+            from,
+            to,
+            bucket: self.bucket,
+            agents: BTreeMap::new(),
+        };
+        for key in cacher.keys(KEY_PREFIX).await? {
+            let (start, name) = match parse_key(&key) {
+                Some(v) => v,
+                None => continue,
+            };
+            if start < from || start >= to || agent.is_some_and(|p| !p.matches(name)) {
+                continue;
+            }
+            let usage: Usage = match cacher.get(&key).await? {
+                Some(val) => from_reader(&val[..]).map_err(err_string)?,
+                None => continue, // expired or a failed flush
+            };
+            report
+                .agents
+                .entry(name.to_string())
+                .or_default()
+                .add(&usage);
+        }
+        Ok(report)
+    }
+}
+
+// The bucket start and the agent of a usage key.
+fn parse_key(key: &str) -> Option<(u64, &str)> {
+    let mut parts = key.strip_prefix(KEY_PREFIX)?.splitn(3, ':');
+    let start = parts.next()?.parse().ok()?;
+    let _writer = parts.next()?;
+    Some((start, parts.next()?))
+}
+
+pub async fn run(app: AppState) {
+    if !app.usage.enabled {
+        return;
+    }
+    loop {
+        tokio::time::sleep(Duration::from_millis(app.usage.flush_interval)).await;
+        if let Err(err) = app.usage.flush(&app.cacher).await {
+            log::error!(target: "usage", "flush failed: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{CacherEntry, MemoryCacher};
+
+    #[tokio::test]
+    async fn test_accounting() {
+        let cacher = HybridCacher::new(10, 1000, CacherEntry::Memory(MemoryCacher::default()));
+        let vars = vec![
+            ("USAGE_ACCOUNTING".to_string(), "true".to_string()),
+            ("USAGE_BUCKET".to_string(), "60".to_string()),
+        ];
+        let a = Accounting::from_vars(vars.clone().into_iter(), "node:1").unwrap();
+        let b = Accounting::from_vars(vars.into_iter(), "node-2").unwrap();
+        a.record("alice", Outcome::Proxied, 100, 60_000);
+        a.record("alice", Outcome::Replayed, 100, 119_999);
+        b.record("alice", Outcome::Failed, 0, 60_001);
+        b.record("bob", Outcome::Proxied, 10, 120_000);
+        a.flush(&cacher).await.unwrap();
+        // the closed buckets are forgotten once written
+        assert!(a.buckets.lock().unwrap().is_empty());
+
+        let report = b.report(&cacher, None, 60_500, 180_000).await.unwrap();
+        assert_eq!(report.from, 60_000);
+        assert_eq!(
+            report.agents["alice"],
+            Usage {
+                requests: 3,
+                replayed: 1,
+                failed: 1,
+                bytes: 200,
+            }
+        );
+        assert_eq!(report.agents["bob"].bytes, 10);
+
+        // the counters of an open bucket are written again, not added twice
+        let now = unix_ms();
+        a.record("alice", Outcome::Proxied, 1, now);
+        a.record("bob", Outcome::Proxied, 1, now);
+        a.flush(&cacher).await.unwrap();
+        a.flush(&cacher).await.unwrap();
+        let alice: AgentPattern = "alice".parse().unwrap();
+        let report = a
+            .report(&cacher, Some(&alice), now, now + 60_000)
+            .await
+            .unwrap();
+        assert_eq!(report.agents.len(), 1);
+        assert_eq!(report.agents["alice"].requests, 1);
+
+        assert_eq!(
+            parse_key("usage:60000:node_1@1:acme/web"),
+            Some((60000, "acme/web"))
+        );
+        assert!(Accounting::from_vars(
+            vec![("USAGE_BUCKET".to_string(), "0".to_string())].into_iter(),
+            "node"
+        )
+        .is_err());
+    }
+}
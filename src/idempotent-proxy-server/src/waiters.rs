@@ -0,0 +1,273 @@
+use axum::body::Bytes;
+use http::StatusCode;
+use std::{collections::HashMap, str::FromStr, sync::Mutex};
+
+use crate::cache::ResponseData;
+
+// Counts the duplicate requests polling for an in-flight idempotency key on this instance,
+// so a stampede on one hot key can't exhaust connections and memory.
+#[derive(Debug, Default)]
+pub struct Waiters {
+    max: usize, // 0 for unlimited
+    counts: Mutex<HashMap<String, usize>>,
+}
+
+impl Waiters {
+    pub fn new(max: usize) -> Self {
+        Waiters {
+            max,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns None when the key already has `max` waiters,
+    // the slot is released when the guard is dropped.
+    pub fn acquire(&self, key: &str) -> Option<WaiterGuard<'_>> {
+        if self.max == 0 {
+            return Some(WaiterGuard {
+                waiters: self,
+                key: None,
+            });
+        }
+
+        let mut counts = self.counts.lock().unwrap();
+        let n = counts.entry(key.to_string()).or_default();
+        if *n >= self.max {
+            return None;
+        }
+        *n += 1;
+        Some(WaiterGuard {
+            waiters: self,
+            key: Some(key.to_string()),
+        })
+    }
+
+    // All the waiting requests of this instance.
+    pub fn total(&self) -> usize {
+        self.counts.lock().unwrap().values().sum()
+    }
+
+    pub fn count(&self, key: &str) -> usize {
+        self.counts
+            .lock()
+            .unwrap()
+            .get(key)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+pub struct WaiterGuard<'a> {
+    waiters: &'a Waiters,
+    key: Option<String>,
+}
+
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = &self.key {
+            let mut counts = self.waiters.counts.lock().unwrap();
+            if let Some(n) = counts.get_mut(key) {
+                *n -= 1;
+                if *n == 0 {
+                    counts.remove(key);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WaitMode {
+    #[default]
+    Wait, // poll for the response of the in-flight request
+    Reject, // answer 425 Too Early at once, the caller retries after Retry-After
+}
+
+impl FromStr for WaitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "wait" => Ok(WaitMode::Wait),
+            "reject" => Ok(WaitMode::Reject),
+            v => Err(format!("invalid wait mode: {}", v)),
+        }
+    }
+}
+
+// How duplicate requests on an in-flight idempotency key wait for its response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WaitPolicy {
+    pub mode: WaitMode,
+    pub max_wait: u64,          // in milliseconds
+    pub poll_interval: u64,     // the first delay between polls, in milliseconds
+    pub backoff: f64,           // multiplies the delay after each poll, 1 keeps it fixed
+    pub max_poll_interval: u64, // in milliseconds
+    pub timeout_status: StatusCode,
+    pub retry_after: u64, // in seconds
+}
+
+impl WaitPolicy {
+    // Waits up to `max_wait` polling every `poll_interval` by default, like the proxy always did.
+    pub fn new(poll_interval: u64, max_wait: u64) -> Self {
+        WaitPolicy {
+            mode: WaitMode::Wait,
+            max_wait,
+            poll_interval,
+            backoff: 1.0,
+            max_poll_interval: poll_interval.max(1000),
+            timeout_status: StatusCode::BAD_GATEWAY,
+            retry_after: 1,
+        }
+    }
+
+    pub fn from_vars(
+        vars: impl Iterator<Item = (String, String)>,
+        poll_interval: u64,
+        max_wait: u64,
+    ) -> Result<Self, String> {
+        let mut policy = WaitPolicy::new(poll_interval, max_wait);
+        let parse = |k: &str, v: &str| {
+            v.trim()
+                .parse::<u64>()
+                .map_err(|_| format!("invalid {} value: {}", k, v))
+        };
+        for (k, v) in vars {
+            match k.as_str() {
+                "WAIT_MODE" => policy.mode = v.parse()?,
+                "WAIT_TIMEOUT" => policy.max_wait = parse(&k, &v)?,
+                "WAIT_BACKOFF" => {
+                    policy.backoff = v
+                        .trim()
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|b| *b >= 1.0 && b.is_finite())
+                        .ok_or_else(|| format!("invalid WAIT_BACKOFF value: {}", v))?
+                }
+                "WAIT_MAX_POLL_INTERVAL" => policy.max_poll_interval = parse(&k, &v)?,
+                "WAIT_TIMEOUT_STATUS" => {
+                    policy.timeout_status = parse(&k, &v)
+                        .ok()
+                        .and_then(|s| StatusCode::from_u16(s as u16).ok())
+                        .filter(|s| s.as_u16() >= 400)
+                        .ok_or_else(|| format!("invalid WAIT_TIMEOUT_STATUS value: {}", v))?
+                }
+                "WAIT_RETRY_AFTER" => policy.retry_after = parse(&k, &v)?,
+                _ => {}
+            }
+        }
+        policy.max_poll_interval = policy.max_poll_interval.max(policy.poll_interval);
+        Ok(policy)
+    }
+
+    // The delays between polls, growing by the backoff up to `max_poll_interval`,
+    // they add up to `max_wait`.
+    pub fn delays(&self) -> impl Iterator<Item = u64> + Send {
+        let (max_wait, max_interval, backoff) =
+            (self.max_wait, self.max_poll_interval, self.backoff);
+        let mut next = self.poll_interval.max(1);
+        let mut waited = 0u64;
+        std::iter::from_fn(move || {
+            if waited >= max_wait {
+                return None;
+            }
+            let delay = next.min(max_wait - waited);
+            waited += delay;
+            next = ((next as f64 * backoff) as u64).min(max_interval);
+            Some(delay)
+        })
+    }
+
+    // The answer to a duplicate in the reject mode.
+    pub fn too_early(&self) -> ResponseData {
+        self.retry_later(
+            StatusCode::from_u16(425).unwrap(),
+            "a request with the same idempotency key is in flight",
+        )
+    }
+
+    // The answer to a duplicate that waited `max_wait` for the in-flight request.
+    pub fn timed_out(&self) -> ResponseData {
+        self.retry_later(
+            self.timeout_status,
+            "timed out waiting for the in-flight request with the same idempotency key",
+        )
+    }
+
+    fn retry_later(&self, status: StatusCode, msg: &'static str) -> ResponseData {
+        let mut rd = ResponseData::new(status.as_u16());
+        rd.headers.push((
+            http::header::RETRY_AFTER.to_string(),
+            self.retry_after.to_string(),
+        ));
+        rd.body = Bytes::from_static(msg.as_bytes());
+        rd.mime = "text/plain".to_string();
+        rd
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_waiters() {
+        let waiters = Waiters::new(2);
+        let g1 = waiters.acquire("k").unwrap();
+        let g2 = waiters.acquire("k").unwrap();
+        assert!(waiters.acquire("k").is_none());
+        assert!(waiters.acquire("other").is_some());
+        assert_eq!(waiters.count("k"), 2);
+        assert_eq!(waiters.total(), 2);
+
+        drop(g1);
+        assert_eq!(waiters.count("k"), 1);
+        let _g3 = waiters.acquire("k").unwrap();
+        drop(g2);
+        assert_eq!(waiters.count("k"), 1);
+
+        let unlimited = Waiters::new(0);
+        let guards: Vec<_> = (0..100).map(|_| unlimited.acquire("k").unwrap()).collect();
+        assert_eq!(guards.len(), 100);
+        assert_eq!(unlimited.count("k"), 0);
+    }
+
+    #[test]
+    fn test_wait_policy() {
+        let policy = WaitPolicy::new(100, 350);
+        assert_eq!(policy.delays().collect::<Vec<_>>(), vec![100, 100, 100, 50]);
+
+        let vars = [
+            ("WAIT_MODE", "reject"),
+            ("WAIT_TIMEOUT", "3000"),
+            ("WAIT_BACKOFF", "2"),
+            ("WAIT_MAX_POLL_INTERVAL", "500"),
+            ("WAIT_TIMEOUT_STATUS", "504"),
+            ("WAIT_RETRY_AFTER", "3"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        let policy = WaitPolicy::from_vars(vars, 100, 10000).unwrap();
+        assert_eq!(policy.mode, WaitMode::Reject);
+        assert_eq!(
+            policy.delays().collect::<Vec<_>>(),
+            vec![100, 200, 400, 500, 500, 500, 500, 300]
+        );
+        let rd = policy.timed_out();
+        assert_eq!(rd.status, 504);
+        assert_eq!(
+            rd.headers,
+            vec![("retry-after".to_string(), "3".to_string())]
+        );
+        assert_eq!(policy.too_early().status, 425);
+
+        for (k, v) in [
+            ("WAIT_MODE", "block"),
+            ("WAIT_BACKOFF", "0.5"),
+            ("WAIT_TIMEOUT_STATUS", "200"),
+        ] {
+            let vars = std::iter::once((k.to_string(), v.to_string()));
+            assert!(WaitPolicy::from_vars(vars, 100, 10000).is_err());
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use axum::body::Bytes;
+use base64::{engine::general_purpose, Engine};
+use ciborium::into_writer;
+use ed25519_dalek::Signer;
+use http::{HeaderValue, StatusCode};
+use idempotent_proxy_types::*;
+use reqwest::{Client, Url};
+use serde_bytes::ByteBuf;
+use tokio::time::{sleep, Duration};
+
+use crate::cache::ResponseData;
+use crate::redact;
+
+pub struct WebhookSender {
+    pub http_client: Client,
+    pub signing_key: Option<ed25519_dalek::SigningKey>,
+    pub max_retries: u32,
+    pub retry_interval: u64, // in milliseconds, doubled after each failed attempt
+}
+
+impl WebhookSender {
+    // Signature message: CBOR encoded [idempotency_key, status, body].
+    pub fn sign(&self, idempotency_key: &str, status: u16, body: &[u8]) -> Option<String> {
+        let key = self.signing_key.as_ref()?;
+        let mut buf: Vec<u8> = Vec::new();
+        into_writer(&(idempotency_key, status, ByteBuf::from(body)), &mut buf)
+            .expect("failed to encode data in CBOR format");
+        let sig = key.sign(&buf).to_bytes();
+        Some(general_purpose::URL_SAFE_NO_PAD.encode(sig))
+    }
+
+    pub async fn deliver(
+        &self,
+        callback_url: &Url,
+        idempotency_key: &str,
+        res: Result<ResponseData, (StatusCode, String)>,
+    ) {
+        let (status, mime, body) = match res {
+            Ok(rd) => (rd.status, rd.mime, rd.body),
+            Err((status, msg)) => (
+                status.as_u16(),
+                "text/plain".to_string(),
+                Bytes::from(msg.into_bytes()),
+            ),
+        };
+        let signature = self.sign(idempotency_key, status, &body);
+        let log_url = redact::url(callback_url);
+
+        let mut interval = self.retry_interval;
+        for attempt in 0..=self.max_retries {
+            if attempt > 0 {
+                sleep(Duration::from_millis(interval)).await;
+                interval = interval.saturating_mul(2);
+            }
+
+            let mut req = self
+                .http_client
+                .post(callback_url.clone())
+                .header(http::header::CONTENT_TYPE, &mime)
+                .header(&HEADER_IDEMPOTENCY_KEY, idempotency_key)
+                .header(&HEADER_X_UPSTREAM_STATUS, HeaderValue::from(status))
+                .body(body.clone());
+            if let Some(sig) = &signature {
+                req = req.header(&HEADER_X_SIGNATURE, sig);
+            }
+
+            match req.send().await {
+                Ok(rres) if rres.status().is_success() => {
+                    log::info!(target: "webhook",
+                        action = "callback",
+                        url = log_url,
+                        status = status,
+                        attempt = attempt,
+                        idempotency_key = idempotency_key;
+                        "");
+                    return;
+                }
+                Ok(rres) => {
+                    log::warn!(target: "webhook",
+                        action = "callback",
+                        url = log_url,
+                        status = rres.status().as_u16(),
+                        attempt = attempt,
+                        idempotency_key = idempotency_key;
+                        "callback rejected");
+                }
+                Err(err) => {
+                    log::warn!(target: "webhook",
+                        action = "callback",
+                        url = log_url,
+                        attempt = attempt,
+                        idempotency_key = idempotency_key;
+                        "{}", err.without_url());
+                }
+            }
+        }
+
+        log::error!(target: "webhook",
+            action = "callback",
+            url = log_url,
+            idempotency_key = idempotency_key;
+            "callback delivery failed after {} retries", self.max_retries);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ed25519_dalek::Verifier;
+
+    #[test]
+    fn test_sign() {
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(&[1u8; 32]);
+        let sender = WebhookSender {
+            http_client: Client::new(),
+            signing_key: Some(signing_key.clone()),
+            max_retries: 3,
+            retry_interval: 1000,
+        };
+
+        let sig = sender.sign("alice:POST:key_001", 200, b"hello").unwrap();
+        let sig = general_purpose::URL_SAFE_NO_PAD.decode(sig).unwrap();
+        let sig = ed25519_dalek::Signature::from_slice(&sig).unwrap();
+        let mut buf: Vec<u8> = Vec::new();
+        into_writer(
+            &(
+                "alice:POST:key_001",
+                200u16,
+                ByteBuf::from(b"hello".to_vec()),
+            ),
+            &mut buf,
+        )
+        .unwrap();
+        assert!(signing_key.verifying_key().verify(&buf, &sig).is_ok());
+
+        let sender = WebhookSender {
+            signing_key: None,
+            ..sender
+        };
+        assert!(sender.sign("alice:POST:key_001", 200, b"hello").is_none());
+    }
+}
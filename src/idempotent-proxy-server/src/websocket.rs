@@ -0,0 +1,257 @@
+use axum::{
+    extract::{
+        ws::{self, WebSocket, WebSocketUpgrade},
+        FromRequestParts, Request,
+    },
+    response::{IntoResponse, Response},
+};
+use futures::{SinkExt, StreamExt};
+use http::{header, HeaderMap, StatusCode};
+use idempotent_proxy_types::*;
+use tokio::time::{timeout, Duration};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{self, client::IntoClientRequest, protocol::frame::coding::CloseCode},
+    MaybeTlsStream, WebSocketStream,
+};
+
+use crate::handler::AppState;
+use crate::redact;
+use crate::secrets;
+
+type Upstream = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+// Headers of the handshake that the upstream connection sets itself.
+const HANDSHAKE_HEADERS: [&str; 7] = [
+    "connection",
+    "upgrade",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+    "content-length",
+    "transfer-encoding",
+];
+
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+// The upstream URL with a ws or wss scheme.
+pub fn ws_url(url: &reqwest::Url) -> Result<reqwest::Url, String> {
+    let scheme = match url.scheme() {
+        "http" | "ws" => "ws",
+        "https" | "wss" => "wss",
+        scheme => return Err(format!("unsupported WebSocket scheme: {}", scheme)),
+    };
+    let mut url = url.clone();
+    url.set_scheme(scheme)
+        .map_err(|_| format!("invalid WebSocket url: {}", url))?;
+    Ok(url)
+}
+
+// The client's handshake headers forwarded upstream, e.g. authorization and
+// sec-websocket-protocol, without the proxy's own headers and the idempotency key headers.
+// Cookies are forwarded only on routes that keep them.
+fn upstream_headers(app: &AppState, route: &str, headers: &HeaderMap) -> Result<HeaderMap, String> {
+    let mut headers = headers.clone();
+    for name in HANDSHAKE_HEADERS {
+        headers.remove(name);
+    }
+    headers.remove(&HEADER_IDEMPOTENCY_KEY);
+    for name in &app.keying.headers {
+        headers.remove(name);
+    }
+    headers.remove(&HEADER_X_IDEMPOTENCY_MODE);
+    app.alter_headers(&mut headers)?;
+    let routes = app.admin.routes();
+    let route = routes.get(route);
+    route.filter_request_headers(&mut headers);
+    route.request_headers.apply(&mut headers)?;
+    Ok(headers)
+}
+
+// Connects to the upstream before accepting the upgrade, so a refused handshake is
+// answered with 502. Frames are relayed in both directions without caching until
+// either side closes.
+pub async fn proxy(
+    app: AppState,
+    req: Request,
+    url: reqwest::Url,
+    agent: String,
+    route: String,
+) -> Result<Response, (StatusCode, String)> {
+    let (mut parts, _) = req.into_parts();
+    let upgrade = WebSocketUpgrade::from_request_parts(&mut parts, &app)
+        .await
+        .map_err(|rejection| (rejection.status(), rejection.body_text()))?;
+
+    let url = ws_url(&url).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    let mut ureq = secrets::expand_url(&url)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?
+        .as_str()
+        .into_client_request()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    let headers = upstream_headers(&app, &route, &parts.headers)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+    for (name, value) in headers.iter() {
+        ureq.headers_mut().append(name, value.clone());
+    }
+
+    let redacted = redact::url(&url);
+    let (upstream, res) = match timeout(
+        Duration::from_millis(app.cacher.cache_ttl),
+        connect_async(ureq),
+    )
+    .await
+    {
+        Ok(Ok(conn)) => conn,
+        Ok(Err(err)) => {
+            log::warn!(target: "websocket",
+                url = redacted.as_str(),
+                agent = agent.as_str();
+                "upstream handshake failed: {}", err);
+            return Err((
+                StatusCode::BAD_GATEWAY,
+                format!("upstream WebSocket handshake failed: {}", err),
+            ));
+        }
+        Err(_) => {
+            return Err((
+                StatusCode::GATEWAY_TIMEOUT,
+                "upstream WebSocket handshake timed out".to_string(),
+            ))
+        }
+    };
+
+    // the subprotocol the upstream selected
+    let upgrade = match res
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(protocol) => upgrade.protocols([protocol.to_string()]),
+        None => upgrade,
+    };
+    log::info!(target: "websocket",
+        url = redacted.as_str(),
+        agent = agent.as_str();
+        "connected");
+    Ok(upgrade
+        .on_upgrade(move |client| async move {
+            let _active = app.resources.request();
+            relay(client, upstream).await;
+            log::info!(target: "websocket",
+                url = redacted.as_str(),
+                agent = agent.as_str();
+                "closed");
+        })
+        .into_response())
+}
+
+async fn relay(client: WebSocket, upstream: Upstream) {
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut upstream_tx, mut upstream_rx) = upstream.split();
+    let to_upstream = async {
+        while let Some(Ok(msg)) = client_rx.next().await {
+            let close = matches!(msg, ws::Message::Close(_));
+            if upstream_tx.send(to_upstream_message(msg)).await.is_err() || close {
+                break;
+            }
+        }
+        let _ = upstream_tx.close().await;
+    };
+    let to_client = async {
+        while let Some(Ok(msg)) = upstream_rx.next().await {
+            let Some(msg) = to_client_message(msg) else {
+                continue;
+            };
+            let close = matches!(msg, ws::Message::Close(_));
+            if client_tx.send(msg).await.is_err() || close {
+                break;
+            }
+        }
+        let _ = client_tx.close().await;
+    };
+    // one side closing ends the other
+    tokio::select! {
+        _ = to_upstream => {},
+        _ = to_client => {},
+    }
+}
+
+fn to_upstream_message(msg: ws::Message) -> tungstenite::Message {
+    match msg {
+        ws::Message::Text(v) => tungstenite::Message::Text(v),
+        ws::Message::Binary(v) => tungstenite::Message::Binary(v),
+        ws::Message::Ping(v) => tungstenite::Message::Ping(v),
+        ws::Message::Pong(v) => tungstenite::Message::Pong(v),
+        ws::Message::Close(frame) => {
+            tungstenite::Message::Close(frame.map(|f| tungstenite::protocol::CloseFrame {
+                code: CloseCode::from(f.code),
+                reason: f.reason,
+            }))
+        }
+    }
+}
+
+fn to_client_message(msg: tungstenite::Message) -> Option<ws::Message> {
+    Some(match msg {
+        tungstenite::Message::Text(v) => ws::Message::Text(v),
+        tungstenite::Message::Binary(v) => ws::Message::Binary(v),
+        tungstenite::Message::Ping(v) => ws::Message::Ping(v),
+        tungstenite::Message::Pong(v) => ws::Message::Pong(v),
+        tungstenite::Message::Close(frame) => ws::Message::Close(frame.map(|f| ws::CloseFrame {
+            code: f.code.into(),
+            reason: f.reason,
+        })),
+        // raw frames are not yielded when reading
+        tungstenite::Message::Frame(_) => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ws_url() {
+        let url = reqwest::Url::parse("https://stream.example.com/v1?a=1").unwrap();
+        assert_eq!(
+            ws_url(&url).unwrap().as_str(),
+            "wss://stream.example.com/v1?a=1"
+        );
+        let url = reqwest::Url::parse("http://127.0.0.1:8545/").unwrap();
+        assert_eq!(ws_url(&url).unwrap().as_str(), "ws://127.0.0.1:8545/");
+        let url = reqwest::Url::parse("ftp://example.com/").unwrap();
+        assert!(ws_url(&url).is_err());
+
+        let mut headers = HeaderMap::new();
+        assert!(!is_upgrade(&headers));
+        headers.insert(header::UPGRADE, "WebSocket".parse().unwrap());
+        assert!(is_upgrade(&headers));
+        headers.insert(header::UPGRADE, "h2c".parse().unwrap());
+        assert!(!is_upgrade(&headers));
+    }
+
+    #[test]
+    fn test_messages() {
+        let msg = to_upstream_message(ws::Message::Close(Some(ws::CloseFrame {
+            code: 1000,
+            reason: "bye".into(),
+        })));
+        assert_eq!(
+            msg,
+            tungstenite::Message::Close(Some(tungstenite::protocol::CloseFrame {
+                code: CloseCode::Normal,
+                reason: "bye".into(),
+            }))
+        );
+        assert!(matches!(
+            to_client_message(tungstenite::Message::Text("hi".to_string())),
+            Some(ws::Message::Text(v)) if v == "hi"
+        ));
+    }
+}
@@ -0,0 +1,45 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use idempotent_proxy_types::{auth, unix_ms};
+use k256::ecdsa;
+use rand_core::OsRng;
+
+fn bench_auth(c: &mut Criterion) {
+    let expire_at = unix_ms() / 1000 + 3600;
+
+    let ed_key = ed25519_dalek::SigningKey::generate(&mut OsRng);
+    let ed_keys = [ed_key.verifying_key()];
+    let ed_token = auth::ed25519_sign(&ed_key, expire_at, "alice".to_string());
+    c.bench_function("ed25519_verify", |b| {
+        b.iter(|| auth::ed25519_verify(black_box(&ed_keys), black_box(&ed_token)).unwrap())
+    });
+
+    let k_key = ecdsa::SigningKey::random(&mut OsRng);
+    let k_keys = [ecdsa::VerifyingKey::from(&k_key)];
+    let k_token = auth::ecdsa_sign(&k_key, expire_at, "alice".to_string());
+    c.bench_function("ecdsa_verify", |b| {
+        b.iter(|| auth::ecdsa_verify(black_box(&k_keys), black_box(&k_token)).unwrap())
+    });
+
+    let p_key = p256::ecdsa::SigningKey::random(&mut OsRng);
+    let p_keys = [p256::ecdsa::VerifyingKey::from(&p_key)];
+    let p_token = auth::p256_sign(&p_key, expire_at, "alice".to_string());
+    c.bench_function("p256_verify", |b| {
+        b.iter(|| auth::p256_verify(black_box(&p_keys), black_box(&p_token)).unwrap())
+    });
+
+    let h_keys = [auth::HmacKey {
+        id: "backend".to_string(),
+        secret: b"shared secret".to_vec(),
+    }];
+    let h_token = auth::hmac_sign(&h_keys[0], expire_at, "alice".to_string());
+    c.bench_function("hmac_verify", |b| {
+        b.iter(|| auth::hmac_verify(black_box(&h_keys), black_box(&h_token)).unwrap())
+    });
+
+    c.bench_function("ed25519_sign", |b| {
+        b.iter(|| auth::ed25519_sign(black_box(&ed_key), expire_at, "alice".to_string()))
+    });
+}
+
+criterion_group!(benches, bench_auth);
+criterion_main!(benches);
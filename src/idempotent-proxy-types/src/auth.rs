@@ -1,32 +1,149 @@
 use ciborium::{from_reader, into_writer};
 use ed25519_dalek::Signer;
+use hmac::{Hmac, Mac};
 use k256::{
     ecdsa,
     ecdsa::signature::hazmat::{PrehashSigner, PrehashVerifier},
 };
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
+use sha2::Sha256;
 use sha3::{Digest, Sha3_256};
+use std::cell::RefCell;
 
 use crate::unix_ms;
 
+#[cfg(feature = "jwt")]
+mod jwt;
+#[cfg(feature = "jwt")]
+pub use jwt::*;
+
 const PERMITTED_DRIFT: u64 = 10; // seconds
 
-// Token format: [expire_at in seconds, agent, signature]
+thread_local! {
+    // Reused per thread for the signed message, verification runs on every request.
+    static MESSAGE_BUF: RefCell<Vec<u8>> = RefCell::new(Vec::with_capacity(128));
+}
+
+// Encodes the signed message `(expire_at, agent)`, or `(expire_at, agent, scope)` for
+// a scoped token, in CBOR into the thread's buffer.
+fn with_message<R>(
+    expire_at: u64,
+    agent: &str,
+    scope: Option<&Scope>,
+    f: impl FnOnce(&[u8]) -> R,
+) -> R {
+    MESSAGE_BUF.with(|buf| {
+        let mut buf = buf.borrow_mut();
+        buf.clear();
+        match scope {
+            None => into_writer(&(expire_at, agent), &mut *buf),
+            Some(scope) => into_writer(&(expire_at, agent, scope), &mut *buf),
+        }
+        .expect("failed to encode data in CBOR format");
+        f(&buf)
+    })
+}
+
+// Token format: [expire_at in seconds, agent, signature], version 2 tokens append
+// the scope: [expire_at, agent, signature, scope]. The scope is signed with the agent.
+// A key id may follow: [expire_at, agent, signature, scope or null, key id], it is not
+// signed and only selects the verification keys of a key generation.
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
-pub struct Token(pub u64, pub String, pub ByteBuf);
+pub struct Token(
+    pub u64,
+    pub String,
+    pub ByteBuf,
+    #[serde(default)] pub Option<Scope>,
+    #[serde(default)] pub Option<String>,
+);
 
-pub fn ed25519_sign(key: &ed25519_dalek::SigningKey, expire_at: u64, agent: String) -> Vec<u8> {
-    let mut buf: Vec<u8> = Vec::new();
-    into_writer(&(expire_at, &agent), &mut buf).expect("failed to encode data in CBOR format");
+impl Token {
+    // The key id of an encoded token, None for a token without or an invalid token.
+    pub fn key_id(data: &[u8]) -> Option<String> {
+        from_reader::<Token, _>(data).ok()?.4
+    }
+}
 
-    let sig = key.sign(&buf).to_bytes();
-    buf.clear();
-    into_writer(&(expire_at, agent, ByteBuf::from(sig)), &mut buf)
-        .expect("failed to encode in CBOR format");
+// Adds the id of the signing key to a signed token, e.g. `ecdsa_sign(..)` with the key
+// of the generation `2024-06`.
+pub fn with_key_id(data: &[u8], key_id: &str) -> Result<Vec<u8>, String> {
+    let mut token: Token = from_reader(data).map_err(|_err| "failed to decode CBOR data")?;
+    token.4 = (!key_id.is_empty()).then(|| key_id.to_string());
+    let mut buf: Vec<u8> = Vec::with_capacity(data.len() + key_id.len() + 2);
+    into_writer(&token, &mut buf).map_err(|err| err.to_string())?;
+    Ok(buf)
+}
+
+// The requests a scoped token may forward, an empty list allows anything.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct Scope {
+    #[serde(default)]
+    pub hosts: Vec<String>, // exact hosts, or *.example.com for the subdomains
+    #[serde(default)]
+    pub paths: Vec<String>, // path prefixes
+    #[serde(default)]
+    pub methods: Vec<String>,
+    // makes the token single-use, the server records the nonce until the token expires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+impl Scope {
+    pub fn allows(&self, method: &str, host: &str, path: &str) -> bool {
+        (self.hosts.is_empty() || self.hosts.iter().any(|h| host_match(h, host)))
+            && (self.paths.is_empty() || self.paths.iter().any(|p| path.starts_with(p.as_str())))
+            && (self.methods.is_empty()
+                || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+    }
+}
+
+fn host_match(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => {
+            host.len() > suffix.len() + 1 && {
+                let (sub, domain) = host.split_at(host.len() - suffix.len());
+                sub.ends_with('.') && domain.eq_ignore_ascii_case(suffix)
+            }
+        }
+        None => pattern == "*" || pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+// Signs the message of the token with `sign`, a scope makes it a version 2 token.
+fn sign_token(
+    expire_at: u64,
+    agent: String,
+    scope: Option<Scope>,
+    sign: impl FnOnce(&[u8]) -> Vec<u8>,
+) -> Vec<u8> {
+    let sig = with_message(expire_at, &agent, scope.as_ref(), sign);
+    let mut buf: Vec<u8> = Vec::with_capacity(agent.len() + sig.len() + 16);
+    match scope {
+        None => into_writer(&(expire_at, agent, ByteBuf::from(sig)), &mut buf),
+        Some(scope) => into_writer(&(expire_at, agent, ByteBuf::from(sig), scope), &mut buf),
+    }
+    .expect("failed to encode in CBOR format");
     buf
 }
 
+fn ed25519_signer(key: &ed25519_dalek::SigningKey) -> impl FnOnce(&[u8]) -> Vec<u8> + '_ {
+    move |msg| key.sign(msg).to_bytes().to_vec()
+}
+
+pub fn ed25519_sign(key: &ed25519_dalek::SigningKey, expire_at: u64, agent: String) -> Vec<u8> {
+    sign_token(expire_at, agent, None, ed25519_signer(key))
+}
+
+pub fn ed25519_sign_scoped(
+    key: &ed25519_dalek::SigningKey,
+    expire_at: u64,
+    agent: String,
+    scope: Scope,
+) -> Vec<u8> {
+    sign_token(expire_at, agent, Some(scope), ed25519_signer(key))
+}
+
 pub fn ed25519_verify(keys: &[ed25519_dalek::VerifyingKey], data: &[u8]) -> Result<Token, String> {
     let token: Token = from_reader(data).map_err(|_err| "failed to decode CBOR data")?;
     if token.0 + PERMITTED_DRIFT < unix_ms() / 1000 {
@@ -34,29 +151,38 @@ pub fn ed25519_verify(keys: &[ed25519_dalek::VerifyingKey], data: &[u8]) -> Resu
     }
     let sig = ed25519_dalek::Signature::from_slice(token.2.as_slice())
         .map_err(|_err| "failed to parse Ed25519 signature")?;
-    let mut buf: Vec<u8> = Vec::new();
-    into_writer(&(token.0, &token.1), &mut buf).expect("failed to encode data in CBOR format");
-    for key in keys.iter() {
-        if key.verify_strict(&buf, &sig).is_ok() {
-            return Ok(token);
-        }
+    let verified = with_message(token.0, &token.1, token.3.as_ref(), |msg| {
+        keys.iter().any(|key| key.verify_strict(msg, &sig).is_ok())
+    });
+    if verified {
+        return Ok(token);
     }
 
     Err("failed to verify Ed25519 signature".to_string())
 }
 
+fn ecdsa_signer(key: &ecdsa::SigningKey) -> impl FnOnce(&[u8]) -> Vec<u8> + '_ {
+    move |msg| {
+        let sig: ecdsa::Signature = key
+            .sign_prehash(&sha3_256(msg))
+            .expect("failed to sign Secp256k1 signature");
+        sig.to_vec()
+    }
+}
+
 // Secp256k1
 pub fn ecdsa_sign(key: &ecdsa::SigningKey, expire_at: u64, agent: String) -> Vec<u8> {
-    let mut buf: Vec<u8> = Vec::new();
-    into_writer(&(expire_at, &agent), &mut buf).expect("failed to encode data in CBOR format");
-    let digest = sha3_256(&buf);
-    let sig: ecdsa::Signature = key
-        .sign_prehash(&digest)
-        .expect("failed to sign Secp256k1 signature");
-    buf.clear();
-    into_writer(&(expire_at, agent, ByteBuf::from(sig.to_vec())), &mut buf)
-        .expect("failed to encode in CBOR format");
-    buf
+    sign_token(expire_at, agent, None, ecdsa_signer(key))
+}
+
+// Secp256k1
+pub fn ecdsa_sign_scoped(
+    key: &ecdsa::SigningKey,
+    expire_at: u64,
+    agent: String,
+    scope: Scope,
+) -> Vec<u8> {
+    sign_token(expire_at, agent, Some(scope), ecdsa_signer(key))
 }
 
 // Secp256k1
@@ -67,9 +193,7 @@ pub fn ecdsa_verify(keys: &[ecdsa::VerifyingKey], data: &[u8]) -> Result<Token,
     }
     let sig = ecdsa::Signature::try_from(token.2.as_slice())
         .map_err(|_err| "failed to parse Secp256k1 signature")?;
-    let mut buf: Vec<u8> = Vec::new();
-    into_writer(&(token.0, &token.1), &mut buf).expect("failed to encode data in CBOR format");
-    let digest = sha3_256(&buf);
+    let digest = with_message(token.0, &token.1, token.3.as_ref(), sha3_256);
 
     for key in keys.iter() {
         if key.verify_prehash(digest.as_slice(), &sig).is_ok() {
@@ -80,6 +204,135 @@ pub fn ecdsa_verify(keys: &[ecdsa::VerifyingKey], data: &[u8]) -> Result<Token,
     Err("failed to verify ECDSA/Secp256k1 signature".to_string())
 }
 
+// A shared secret for HMAC-SHA256 tokens, the id selects the key when verifying.
+#[derive(Clone, PartialEq, Eq)]
+pub struct HmacKey {
+    pub id: String,
+    pub secret: Vec<u8>,
+}
+
+impl std::fmt::Debug for HmacKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HmacKey").field("id", &self.id).finish()
+    }
+}
+
+fn hmac_sha256(secret: &[u8]) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size")
+}
+
+// The mac of the token is the key id followed by the 32 bytes HMAC of the message.
+fn hmac_signer(key: &HmacKey) -> impl FnOnce(&[u8]) -> Vec<u8> + '_ {
+    move |msg| {
+        let mut mac = hmac_sha256(&key.secret);
+        mac.update(msg);
+        let mut sig = Vec::with_capacity(key.id.len() + 32);
+        sig.extend_from_slice(key.id.as_bytes());
+        sig.extend_from_slice(&mac.finalize().into_bytes());
+        sig
+    }
+}
+
+// HMAC-SHA256, for clients that can't sign with Ed25519 or Secp256k1.
+pub fn hmac_sign(key: &HmacKey, expire_at: u64, agent: String) -> Vec<u8> {
+    sign_token(expire_at, agent, None, hmac_signer(key))
+}
+
+pub fn hmac_sign_scoped(key: &HmacKey, expire_at: u64, agent: String, scope: Scope) -> Vec<u8> {
+    sign_token(expire_at, agent, Some(scope), hmac_signer(key))
+}
+
+pub fn hmac_verify(keys: &[HmacKey], data: &[u8]) -> Result<Token, String> {
+    let token: Token = from_reader(data).map_err(|_err| "failed to decode CBOR data")?;
+    if token.0 + PERMITTED_DRIFT < unix_ms() / 1000 {
+        return Err("token expired".to_string());
+    }
+    let n = token
+        .2
+        .len()
+        .checked_sub(32)
+        .ok_or("failed to parse HMAC-SHA256 mac")?;
+    let (id, tag) = token.2.split_at(n);
+    let verified = with_message(token.0, &token.1, token.3.as_ref(), |msg| {
+        keys.iter().filter(|k| k.id.as_bytes() == id).any(|k| {
+            let mut mac = hmac_sha256(&k.secret);
+            mac.update(msg);
+            mac.verify_slice(tag).is_ok()
+        })
+    });
+    if verified {
+        return Ok(token);
+    }
+
+    Err("failed to verify HMAC-SHA256 mac".to_string())
+}
+
+fn p256_signer(key: &p256::ecdsa::SigningKey) -> impl FnOnce(&[u8]) -> Vec<u8> + '_ {
+    move |msg| {
+        let sig: p256::ecdsa::Signature = key
+            .sign_prehash(&sha3_256(msg))
+            .expect("failed to sign Secp256r1 signature");
+        sig.to_vec()
+    }
+}
+
+// Secp256r1 (P-256), e.g. with the IC threshold ECDSA or an HSM
+pub fn p256_sign(key: &p256::ecdsa::SigningKey, expire_at: u64, agent: String) -> Vec<u8> {
+    sign_token(expire_at, agent, None, p256_signer(key))
+}
+
+// Secp256r1 (P-256)
+pub fn p256_sign_scoped(
+    key: &p256::ecdsa::SigningKey,
+    expire_at: u64,
+    agent: String,
+    scope: Scope,
+) -> Vec<u8> {
+    sign_token(expire_at, agent, Some(scope), p256_signer(key))
+}
+
+// Secp256r1 (P-256)
+pub fn p256_verify(keys: &[p256::ecdsa::VerifyingKey], data: &[u8]) -> Result<Token, String> {
+    let token: Token = from_reader(data).map_err(|_err| "failed to decode CBOR data")?;
+    if token.0 + PERMITTED_DRIFT < unix_ms() / 1000 {
+        return Err("token expired".to_string());
+    }
+    let sig = p256::ecdsa::Signature::try_from(token.2.as_slice())
+        .map_err(|_err| "failed to parse Secp256r1 signature")?;
+    let digest = with_message(token.0, &token.1, token.3.as_ref(), sha3_256);
+
+    for key in keys.iter() {
+        if key.verify_prehash(digest.as_slice(), &sig).is_ok() {
+            return Ok(token);
+        }
+    }
+
+    Err("failed to verify ECDSA/Secp256r1 signature".to_string())
+}
+
+// BLS12-381 signatures of the IC threshold keys, e.g. a canister signing with vetKD.
+// The keys are 96 bytes compressed G2 points, the signatures 48 bytes G1 points.
+#[cfg(feature = "bls")]
+pub fn bls_verify(keys: &[[u8; 96]], data: &[u8]) -> Result<Token, String> {
+    let token: Token = from_reader(data).map_err(|_err| "failed to decode CBOR data")?;
+    if token.0 + PERMITTED_DRIFT < unix_ms() / 1000 {
+        return Err("token expired".to_string());
+    }
+    if token.2.len() != 48 {
+        return Err("failed to parse BLS12-381 signature".to_string());
+    }
+    let verified = with_message(token.0, &token.1, token.3.as_ref(), |msg| {
+        keys.iter().any(|key| {
+            ic_verify_bls_signature::verify_bls_signature(token.2.as_slice(), msg, key).is_ok()
+        })
+    });
+    if verified {
+        return Ok(token);
+    }
+
+    Err("failed to verify BLS12-381 signature".to_string())
+}
+
 pub fn sha3_256(data: &[u8]) -> [u8; 32] {
     let mut hasher = Sha3_256::new();
     hasher.update(data);
@@ -105,6 +358,245 @@ mod test {
         let token = super::ed25519_verify(&[signing_key.verifying_key()], &signed).unwrap();
         assert_eq!(token.0, expire_at);
         assert_eq!(token.1, agent);
+
+        // the message buffer is reused, a second verification must not see stale data
+        let signed2 = super::ed25519_sign(&signing_key, expire_at, "bob".to_string());
+        let token = super::ed25519_verify(&[signing_key.verifying_key()], &signed2).unwrap();
+        assert_eq!(token.1, "bob");
+        assert!(super::ed25519_verify(&[signing_key.verifying_key()], &signed[1..]).is_err());
+    }
+
+    #[test]
+    fn test_p256_token() {
+        let signing_key = p256::ecdsa::SigningKey::random(&mut OsRng);
+        let keys = [p256::ecdsa::VerifyingKey::from(&signing_key)];
+        let expire_at = unix_ms() / 1000 + 3600;
+        let signed = super::p256_sign(&signing_key, expire_at, "alice".to_string());
+        let token = super::p256_verify(&keys, &signed).unwrap();
+        assert_eq!(token.0, expire_at);
+        assert_eq!(token.1, "alice");
+
+        let other = p256::ecdsa::SigningKey::random(&mut OsRng);
+        assert!(super::p256_verify(&[p256::ecdsa::VerifyingKey::from(&other)], &signed).is_err());
+        // a Secp256k1 signature of the same message doesn't verify
+        let k_key = ecdsa::SigningKey::random(&mut OsRng);
+        let k_signed = super::ecdsa_sign(&k_key, expire_at, "alice".to_string());
+        assert!(super::p256_verify(&keys, &k_signed).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "bls")]
+    fn test_bls_token() {
+        use ic_verify_bls_signature::PrivateKey;
+
+        let sign = |secret_key: &PrivateKey, expire_at: u64, agent: &str| {
+            let mut msg = Vec::new();
+            into_writer(&(expire_at, agent), &mut msg).unwrap();
+            let sig = secret_key.sign(&msg).serialize();
+            let mut data = Vec::new();
+            into_writer(&(expire_at, agent, ByteBuf::from(sig.to_vec())), &mut data).unwrap();
+            data
+        };
+        let secret_key = PrivateKey::deserialize(&[7u8; 32]).unwrap();
+        let public_key = secret_key.public_key().serialize();
+        let expire_at = unix_ms() / 1000 + 3600;
+        let signed = sign(&secret_key, expire_at, "alice");
+        let token = super::bls_verify(&[public_key], &signed).unwrap();
+        assert_eq!(token.0, expire_at);
+        assert_eq!(token.1, "alice");
+
+        // signed by another key
+        let other = PrivateKey::deserialize(&[8u8; 32]).unwrap();
+        let other_signed = sign(&other, expire_at, "alice");
+        assert_eq!(
+            super::bls_verify(&[public_key], &other_signed).unwrap_err(),
+            "failed to verify BLS12-381 signature"
+        );
+        // any of the keys may verify
+        let other_key = other.public_key().serialize();
+        assert!(super::bls_verify(&[public_key, other_key], &other_signed).is_ok());
+
+        let key = [0u8; 96];
+        let mut data = Vec::new();
+        into_writer(
+            &(expire_at, "alice", ByteBuf::from(vec![0u8; 48])),
+            &mut data,
+        )
+        .unwrap();
+        assert_eq!(
+            super::bls_verify(&[key], &data).unwrap_err(),
+            "failed to verify BLS12-381 signature"
+        );
+
+        data.clear();
+        into_writer(
+            &(expire_at, "alice", ByteBuf::from(vec![0u8; 64])),
+            &mut data,
+        )
+        .unwrap();
+        assert_eq!(
+            super::bls_verify(&[key], &data).unwrap_err(),
+            "failed to parse BLS12-381 signature"
+        );
+
+        data.clear();
+        into_writer(&(1u64, "alice", ByteBuf::from(vec![0u8; 48])), &mut data).unwrap();
+        assert_eq!(
+            super::bls_verify(&[key], &data).unwrap_err(),
+            "token expired"
+        );
+    }
+
+    #[test]
+    fn test_hmac_token() {
+        let key = HmacKey {
+            id: "backend".to_string(),
+            secret: b"shared secret".to_vec(),
+        };
+        let other = HmacKey {
+            id: "other".to_string(),
+            secret: b"other secret".to_vec(),
+        };
+        let expire_at = unix_ms() / 1000 + 3600;
+        let signed = super::hmac_sign(&key, expire_at, "alice".to_string());
+        let token = super::hmac_verify(&[other.clone(), key.clone()], &signed).unwrap();
+        assert_eq!(token.0, expire_at);
+        assert_eq!(token.1, "alice");
+        assert_eq!(&token.2[..7], b"backend");
+
+        // the key id must match
+        let renamed = HmacKey {
+            id: "renamed".to_string(),
+            secret: key.secret.clone(),
+        };
+        assert!(super::hmac_verify(&[renamed], &signed).is_err());
+        assert!(super::hmac_verify(&[other], &signed).is_err());
+        let expired = super::hmac_sign(&key, 1, "alice".to_string());
+        assert_eq!(
+            super::hmac_verify(std::slice::from_ref(&key), &expired).unwrap_err(),
+            "token expired"
+        );
+
+        // without a key id
+        let key = HmacKey {
+            id: String::new(),
+            secret: b"shared secret".to_vec(),
+        };
+        let signed = super::hmac_sign(&key, expire_at, "bob".to_string());
+        assert_eq!(super::hmac_verify(&[key], &signed).unwrap().1, "bob");
+    }
+
+    #[test]
+    fn test_scoped_token() {
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]);
+        let keys = [signing_key.verifying_key()];
+        let expire_at = unix_ms() / 1000 + 3600;
+        let scope = Scope {
+            hosts: vec![
+                "api.example.com".to_string(),
+                "*.feeds.example.com".to_string(),
+            ],
+            paths: vec!["/v1/prices".to_string()],
+            methods: vec!["GET".to_string()],
+            nonce: None,
+        };
+        let signed = super::ed25519_sign_scoped(
+            &signing_key,
+            expire_at,
+            "price-feed".to_string(),
+            scope.clone(),
+        );
+        let token = super::ed25519_verify(&keys, &signed).unwrap();
+        assert_eq!(token.1, "price-feed");
+        assert_eq!(token.3.as_ref(), Some(&scope));
+
+        // version 1 tokens have no scope
+        let signed_v1 = super::ed25519_sign(&signing_key, expire_at, "price-feed".to_string());
+        assert_eq!(super::ed25519_verify(&keys, &signed_v1).unwrap().3, None);
+
+        // the scope is signed, it can't be widened or dropped
+        let mut widened = token.clone();
+        widened.3 = Some(Scope::default());
+        let mut buf = Vec::new();
+        into_writer(&widened, &mut buf).unwrap();
+        assert!(super::ed25519_verify(&keys, &buf).is_err());
+        let mut dropped = Vec::new();
+        into_writer(&(token.0, &token.1, &token.2), &mut dropped).unwrap();
+        assert!(super::ed25519_verify(&keys, &dropped).is_err());
+
+        let k_key = ecdsa::SigningKey::random(&mut OsRng);
+        let signed = super::ecdsa_sign_scoped(&k_key, expire_at, "a".to_string(), scope.clone());
+        let token = super::ecdsa_verify(&[ecdsa::VerifyingKey::from(&k_key)], &signed).unwrap();
+        assert_eq!(token.3, Some(scope.clone()));
+
+        assert!(scope.allows("GET", "api.example.com", "/v1/prices/btc"));
+        assert!(scope.allows("get", "eu.feeds.example.com", "/v1/prices"));
+        assert!(!scope.allows("POST", "api.example.com", "/v1/prices"));
+        assert!(!scope.allows("GET", "payments.example.com", "/v1/prices"));
+        assert!(!scope.allows("GET", "feeds.example.com", "/v1/prices"));
+        assert!(!scope.allows("GET", "evilfeeds.example.com", "/v1/prices"));
+        assert!(!scope.allows("GET", "api.example.com", "/v1/charges"));
+        assert!(Scope::default().allows("DELETE", "any.host", "/"));
+
+        // the nonce is signed with the scope
+        let single_use = Scope {
+            nonce: Some("batch-0001".to_string()),
+            ..Default::default()
+        };
+        let signed = super::ed25519_sign_scoped(
+            &signing_key,
+            expire_at,
+            "price-feed".to_string(),
+            single_use.clone(),
+        );
+        let mut token = super::ed25519_verify(&keys, &signed).unwrap();
+        assert_eq!(token.3.as_ref(), Some(&single_use));
+        token.3 = Some(Scope::default());
+        let mut buf = Vec::new();
+        into_writer(&token, &mut buf).unwrap();
+        assert!(super::ed25519_verify(&keys, &buf).is_err());
+    }
+
+    #[test]
+    fn test_key_id() {
+        let signing_key = ecdsa::SigningKey::random(&mut OsRng);
+        let keys = [ecdsa::VerifyingKey::from(&signing_key)];
+        let expire_at = unix_ms() / 1000 + 3600;
+        let signed = super::ecdsa_sign(&signing_key, expire_at, "alice".to_string());
+        assert_eq!(Token::key_id(&signed), None);
+
+        let with_id = super::with_key_id(&signed, "2024-06").unwrap();
+        assert_eq!(Token::key_id(&with_id), Some("2024-06".to_string()));
+        // the key id is not signed, the token verifies as before
+        let token = super::ecdsa_verify(&keys, &with_id).unwrap();
+        assert_eq!(token.1, "alice");
+        assert_eq!(token.4.as_deref(), Some("2024-06"));
+
+        let scoped = super::ecdsa_sign_scoped(
+            &signing_key,
+            expire_at,
+            "alice".to_string(),
+            Scope {
+                methods: vec!["GET".to_string()],
+                ..Default::default()
+            },
+        );
+        let with_id = super::with_key_id(&scoped, "2024-06").unwrap();
+        assert!(super::ecdsa_verify(&keys, &with_id).unwrap().3.is_some());
+        assert_eq!(Token::key_id(b"not a token"), None);
+        assert!(super::with_key_id(b"not a token", "2024-06").is_err());
+    }
+
+    #[test]
+    fn test_with_message() {
+        let mut buf = Vec::new();
+        into_writer(&(1716376993u64, "alice"), &mut buf).unwrap();
+        with_message(1716376993, "alice", None, |msg| {
+            assert_eq!(msg, buf.as_slice())
+        });
+        buf.clear();
+        into_writer(&(1u64, "a"), &mut buf).unwrap();
+        with_message(1, "a", None, |msg| assert_eq!(msg, buf.as_slice()));
     }
 
     #[test]
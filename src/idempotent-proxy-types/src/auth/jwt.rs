@@ -0,0 +1,260 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use p256::ecdsa::signature::Verifier;
+use rsa::{pkcs1v15, BigUint, RsaPublicKey};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use sha2::Sha256;
+
+use super::{Token, PERMITTED_DRIFT};
+use crate::unix_ms;
+
+#[derive(Clone, Debug)]
+pub enum JwtKey {
+    Rs256(pkcs1v15::VerifyingKey<Sha256>),
+    Es256(p256::ecdsa::VerifyingKey),
+}
+
+// A signing key of a JWKS, a key without kid is tried for every token.
+#[derive(Clone, Debug)]
+pub struct Jwk {
+    pub kid: Option<String>,
+    pub key: JwtKey,
+}
+
+// The claims checked besides the signature and the expiration, if set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct JwtValidation {
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkJson {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    key_use: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(untagged)]
+enum Audience {
+    #[default]
+    None,
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct JwtClaims {
+    sub: Option<String>,
+    exp: Option<u64>,
+    nbf: Option<u64>,
+    iss: Option<String>,
+    #[serde(default)]
+    aud: Audience,
+}
+
+fn decode(part: &str) -> Result<Vec<u8>, String> {
+    URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_err| "failed to decode JWT base64url data".to_string())
+}
+
+impl JwkJson {
+    fn into_jwk(self) -> Option<Jwk> {
+        let key = match (self.kty.as_str(), self.alg.as_deref()) {
+            ("RSA", None | Some("RS256")) => {
+                let n = BigUint::from_bytes_be(&decode(self.n.as_deref()?).ok()?);
+                let e = BigUint::from_bytes_be(&decode(self.e.as_deref()?).ok()?);
+                JwtKey::Rs256(pkcs1v15::VerifyingKey::new(RsaPublicKey::new(n, e).ok()?))
+            }
+            ("EC", None | Some("ES256")) if self.crv.as_deref() == Some("P-256") => {
+                let mut point = vec![0x04];
+                point.extend(decode(self.x.as_deref()?).ok()?);
+                point.extend(decode(self.y.as_deref()?).ok()?);
+                JwtKey::Es256(p256::ecdsa::VerifyingKey::from_sec1_bytes(&point).ok()?)
+            }
+            _ => return None,
+        };
+        Some(Jwk { kid: self.kid, key })
+    }
+}
+
+// Parses the RS256 and ES256 signing keys of a JWKS document, other keys are skipped.
+pub fn jwks_parse(data: &[u8]) -> Result<Vec<Jwk>, String> {
+    #[derive(Deserialize)]
+    struct Jwks {
+        keys: Vec<JwkJson>,
+    }
+
+    let jwks: Jwks =
+        serde_json::from_slice(data).map_err(|err| format!("failed to parse JWKS: {}", err))?;
+    Ok(jwks
+        .keys
+        .into_iter()
+        .filter(|k| k.key_use.as_deref().unwrap_or("sig") == "sig")
+        .filter_map(JwkJson::into_jwk)
+        .collect())
+}
+
+// Verifies a compact RS256 or ES256 JWT, the `sub` claim is the agent of the token.
+pub fn jwt_verify(keys: &[Jwk], validation: &JwtValidation, jwt: &str) -> Result<Token, String> {
+    let mut parts = jwt.splitn(3, '.');
+    let (Some(header), Some(claims), Some(sig)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err("invalid JWT".to_string());
+    };
+    let signed = &jwt[..header.len() + 1 + claims.len()];
+    let header: JwtHeader =
+        serde_json::from_slice(&decode(header)?).map_err(|_err| "failed to decode JWT header")?;
+    let sig = decode(sig)?;
+
+    let verified = keys
+        .iter()
+        .filter(|k| match (&header.kid, &k.kid) {
+            (Some(kid), Some(key_id)) => kid == key_id,
+            _ => true,
+        })
+        .any(|k| match (&k.key, header.alg.as_str()) {
+            (JwtKey::Rs256(key), "RS256") => pkcs1v15::Signature::try_from(sig.as_slice())
+                .is_ok_and(|sig| key.verify(signed.as_bytes(), &sig).is_ok()),
+            (JwtKey::Es256(key), "ES256") => p256::ecdsa::Signature::from_slice(&sig)
+                .is_ok_and(|sig| key.verify(signed.as_bytes(), &sig).is_ok()),
+            _ => false,
+        });
+    if !verified {
+        return Err(format!("failed to verify JWT {} signature", header.alg));
+    }
+
+    let claims: JwtClaims =
+        serde_json::from_slice(&decode(claims)?).map_err(|_err| "failed to decode JWT claims")?;
+    let now = unix_ms() / 1000;
+    let exp = claims.exp.ok_or("JWT without exp claim")?;
+    if exp + PERMITTED_DRIFT < now {
+        return Err("token expired".to_string());
+    }
+    if claims.nbf.is_some_and(|nbf| nbf > now + PERMITTED_DRIFT) {
+        return Err("token not yet valid".to_string());
+    }
+    if let Some(issuer) = &validation.issuer {
+        if claims.iss.as_ref() != Some(issuer) {
+            return Err("invalid JWT issuer".to_string());
+        }
+    }
+    if let Some(audience) = &validation.audience {
+        let valid = match &claims.aud {
+            Audience::One(aud) => aud == audience,
+            Audience::Many(auds) => auds.contains(audience),
+            Audience::None => false,
+        };
+        if !valid {
+            return Err("invalid JWT audience".to_string());
+        }
+    }
+    match claims.sub {
+        Some(sub) if !sub.is_empty() => Ok(Token(exp, sub, ByteBuf::from(sig), None, header.kid)),
+        _ => Err("JWT without sub claim".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+    use rand_core::OsRng;
+
+    fn es256_jwt(key: &SigningKey, kid: &str, claims: serde_json::Value) -> String {
+        let header = serde_json::json!({"alg": "ES256", "typ": "JWT", "kid": kid});
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let sig: Signature = key.sign(signed.as_bytes());
+        format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(sig.to_bytes()))
+    }
+
+    #[test]
+    fn test_jwt_verify() {
+        let key = SigningKey::random(&mut OsRng);
+        let point = key.verifying_key().to_encoded_point(false);
+        let jwks = serde_json::json!({"keys": [
+            {"kty": "RSA", "kid": "rsa", "alg": "RS256", "use": "sig", "e": "AQAB",
+             "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw"},
+            {"kty": "EC", "kid": "ec", "crv": "P-256",
+             "x": URL_SAFE_NO_PAD.encode(point.x().unwrap()),
+             "y": URL_SAFE_NO_PAD.encode(point.y().unwrap())},
+            {"kty": "EC", "kid": "enc", "use": "enc", "crv": "P-256", "x": "", "y": ""},
+            {"kty": "OKP", "kid": "ed", "crv": "Ed25519", "x": "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo"},
+        ]});
+        let keys = jwks_parse(jwks.to_string().as_bytes()).unwrap();
+        assert_eq!(keys.len(), 2);
+
+        let validation = JwtValidation {
+            issuer: Some("https://auth.example.com".to_string()),
+            audience: Some("idempotent-proxy".to_string()),
+        };
+        let exp = unix_ms() / 1000 + 3600;
+        let jwt = es256_jwt(
+            &key,
+            "ec",
+            serde_json::json!({"sub": "alice", "exp": exp, "iss": "https://auth.example.com", "aud": ["idempotent-proxy", "other"]}),
+        );
+        let token = jwt_verify(&keys, &validation, &jwt).unwrap();
+        assert_eq!(token.0, exp);
+        assert_eq!(token.1, "alice");
+
+        // the kid selects the key
+        let jwt = es256_jwt(
+            &key,
+            "rsa",
+            serde_json::json!({"sub": "alice", "exp": exp, "iss": "https://auth.example.com", "aud": "idempotent-proxy"}),
+        );
+        assert!(jwt_verify(&keys, &validation, &jwt).is_err());
+
+        for (claims, err) in [
+            (
+                serde_json::json!({"sub": "alice", "exp": 1, "iss": "https://auth.example.com", "aud": "idempotent-proxy"}),
+                "token expired",
+            ),
+            (
+                serde_json::json!({"sub": "alice", "exp": exp, "iss": "https://evil.example.com", "aud": "idempotent-proxy"}),
+                "invalid JWT issuer",
+            ),
+            (
+                serde_json::json!({"sub": "alice", "exp": exp, "iss": "https://auth.example.com"}),
+                "invalid JWT audience",
+            ),
+            (
+                serde_json::json!({"exp": exp, "iss": "https://auth.example.com", "aud": "idempotent-proxy"}),
+                "JWT without sub claim",
+            ),
+        ] {
+            let jwt = es256_jwt(&key, "ec", claims);
+            assert_eq!(jwt_verify(&keys, &validation, &jwt).unwrap_err(), err);
+        }
+
+        let jwt = es256_jwt(&key, "ec", serde_json::json!({"sub": "alice", "exp": exp}));
+        assert!(jwt_verify(&keys, &JwtValidation::default(), &jwt).is_ok());
+        let other = SigningKey::random(&mut OsRng);
+        let forged = es256_jwt(
+            &other,
+            "ec",
+            serde_json::json!({"sub": "alice", "exp": exp}),
+        );
+        assert!(jwt_verify(&keys, &JwtValidation::default(), &forged).is_err());
+        assert!(jwt_verify(&keys, &JwtValidation::default(), "a.b").is_err());
+    }
+}
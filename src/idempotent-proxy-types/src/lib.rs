@@ -10,6 +10,15 @@ pub static HEADER_X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-for
 pub static HEADER_IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
 pub static HEADER_X_JSON_MASK: HeaderName = HeaderName::from_static("x-json-mask");
 pub static HEADER_RESPONSE_HEADERS: HeaderName = HeaderName::from_static("response-headers");
+pub static HEADER_X_CALLBACK_URL: HeaderName = HeaderName::from_static("x-callback-url");
+pub static HEADER_X_UPSTREAM_STATUS: HeaderName = HeaderName::from_static("x-upstream-status");
+pub static HEADER_X_SIGNATURE: HeaderName = HeaderName::from_static("x-signature");
+pub static HEADER_X_EXECUTE_AFTER: HeaderName = HeaderName::from_static("x-execute-after");
+pub static HEADER_X_IDEMPOTENCY_MODE: HeaderName = HeaderName::from_static("x-idempotency-mode");
+pub static HEADER_X_IDEMPOTENCY_TTL: HeaderName = HeaderName::from_static("x-idempotency-ttl");
+pub static HEADER_X_IDEMPOTENCY_STATUS: HeaderName =
+    HeaderName::from_static("x-idempotency-status");
+pub static HEADER_X_PROXY_TIMESTAMP: HeaderName = HeaderName::from_static("x-proxy-timestamp");
 
 pub fn err_string(err: impl std::fmt::Display) -> String {
     err.to_string()